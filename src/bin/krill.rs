@@ -2,13 +2,17 @@ extern crate krill;
 
 use std::sync::Arc;
 
+use krill::daemon::config::Config;
 use krill::daemon::http::server;
+use krill::daemon::krillserver::KrillServer;
 
 #[tokio::main]
 async fn main() {
     match server::parse_config() {
         Ok(config) => {
-            if let Err(e) = server::start_krill_daemon(Arc::new(config)).await {
+            if Config::check_only() {
+                check_config(config);
+            } else if let Err(e) = server::start_krill_daemon(Arc::new(config)).await {
                 eprintln!("Krill failed to start: {}", e);
                 ::std::process::exit(1);
             }
@@ -19,3 +23,19 @@ async fn main() {
         }
     }
 }
+
+fn check_config(config: Config) {
+    match KrillServer::check_config(Arc::new(config)) {
+        Ok(problems) if problems.is_empty() => println!("Config OK"),
+        Ok(problems) => {
+            for problem in problems {
+                eprintln!("{}", problem);
+            }
+            ::std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("Could not check config: {}", e);
+            ::std::process::exit(1);
+        }
+    }
+}
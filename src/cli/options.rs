@@ -1734,6 +1734,9 @@ impl Options {
         let days = matches.value_of("days").unwrap();
         let days =
             i64::from_str(days).map_err(|e| Error::GeneralArgumentError(format!("Invalid number of days: {}", e)))?;
+        if days < 1 {
+            return Err(Error::general("The number of days must be at least 1"));
+        }
 
         let in_file = matches.value_of("in").unwrap();
         let in_file = PathBuf::from_str(in_file)
@@ -1804,6 +1807,9 @@ impl Options {
         let days = matches.value_of("days").unwrap();
         let days =
             i64::from_str(days).map_err(|e| Error::GeneralArgumentError(format!("Invalid number of days: {}", e)))?;
+        if days < 1 {
+            return Err(Error::general("The number of days must be at least 1"));
+        }
         let validity = SignSupport::sign_validity_days(days);
 
         let request = RtaPrepareRequest::new(resources, validity);
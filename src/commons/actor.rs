@@ -44,12 +44,20 @@ pub enum Attributes {
     None,
     RoleOnly(&'static str),
     UserDefined(HashMap<String, String>),
+    /// Attributes of which some may have more than one value, e.g. all the
+    /// groups that a user is a member of. Where a single value is expected
+    /// the first value is used.
+    MultiValued(HashMap<String, Vec<String>>),
 }
 
 impl Attributes {
     pub fn as_map(&self) -> HashMap<String, String> {
         match &self {
             Attributes::UserDefined(map) => map.clone(),
+            Attributes::MultiValued(map) => map
+                .iter()
+                .filter_map(|(k, values)| values.first().map(|v| (k.clone(), v.clone())))
+                .collect(),
             Attributes::RoleOnly(role) => {
                 let mut map = HashMap::new();
                 map.insert("role".to_string(), role.to_string());
@@ -58,6 +66,26 @@ impl Attributes {
             Attributes::None => HashMap::new(),
         }
     }
+
+    /// Returns the first value of the attribute, if any.
+    pub fn value(&self, attr_name: &str) -> Option<String> {
+        match &self {
+            Attributes::UserDefined(map) => map.get(attr_name).cloned(),
+            Attributes::MultiValued(map) => map.get(attr_name).and_then(|values| values.first().cloned()),
+            Attributes::RoleOnly(role) if attr_name == "role" => Some(role.to_string()),
+            Attributes::RoleOnly(_) => None,
+            Attributes::None => None,
+        }
+    }
+
+    /// Returns all values of the attribute, or an empty list if the actor
+    /// does not have the attribute.
+    pub fn values(&self, attr_name: &str) -> Vec<String> {
+        match &self {
+            Attributes::MultiValued(map) => map.get(attr_name).cloned().unwrap_or_default(),
+            _ => self.value(attr_name).into_iter().collect(),
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -91,10 +119,14 @@ impl ActorDef {
     }
 
     pub fn user(name: String, attributes: HashMap<String, String>, new_auth: Option<Auth>) -> ActorDef {
+        Self::user_with_attributes(name, Attributes::UserDefined(attributes), new_auth)
+    }
+
+    pub fn user_with_attributes(name: String, attributes: Attributes, new_auth: Option<Auth>) -> ActorDef {
         ActorDef {
             name: ActorName::AsString(name),
             is_user: true,
-            attributes: Attributes::UserDefined(attributes),
+            attributes,
             new_auth,
             auth_error: None,
         }
@@ -177,16 +209,30 @@ impl Actor {
         self.new_auth.clone()
     }
 
+    pub fn auth_error(&self) -> Option<&ApiAuthError> {
+        self.auth_error.as_ref()
+    }
+
     pub fn attributes(&self) -> HashMap<String, String> {
         self.attributes.as_map()
     }
 
     pub fn attribute(&self, attr_name: String) -> Option<String> {
-        match &self.attributes {
-            Attributes::UserDefined(map) => map.get(&attr_name).cloned(),
-            Attributes::RoleOnly(role) if &attr_name == "role" => Some(role.to_string()),
-            Attributes::RoleOnly(_) => None,
-            Attributes::None => None,
+        self.attributes.value(&attr_name)
+    }
+
+    pub fn attribute_values(&self, attr_name: String) -> Vec<String> {
+        self.attributes.values(&attr_name)
+    }
+
+    /// Returns the role of this actor, if any, followed by the roles that
+    /// it implies according to the configured role hierarchy.
+    #[cfg(feature = "multi-user")]
+    pub fn roles(&self) -> Vec<String> {
+        match (self.attribute("role".to_string()), &self.policy) {
+            (Some(role), Some(policy)) => policy.expand_role(&role),
+            (Some(role), None) => vec![role],
+            (None, _) => vec![],
         }
     }
 
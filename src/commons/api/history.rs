@@ -85,14 +85,21 @@ pub struct CommandHistory {
     offset: usize,
     total: usize,
     commands: Vec<CommandHistoryRecord>,
+    /// The highest sequence of the returned commands, if any. Callers polling
+    /// for new commands can use this as the 'after sequence' of their next
+    /// request.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_sequence: Option<u64>,
 }
 
 impl CommandHistory {
     pub fn new(offset: usize, total: usize, commands: Vec<CommandHistoryRecord>) -> Self {
+        let last_sequence = commands.iter().map(|command| command.sequence).max();
         CommandHistory {
             offset,
             total,
             commands,
+            last_sequence,
         }
     }
 
@@ -107,6 +114,10 @@ impl CommandHistory {
     pub fn commands(&self) -> &Vec<CommandHistoryRecord> {
         &self.commands
     }
+
+    pub fn last_sequence(&self) -> Option<u64> {
+        self.last_sequence
+    }
 }
 
 impl fmt::Display for CommandHistory {
@@ -156,6 +167,12 @@ impl CommandHistoryRecord {
         Time::from(DateTime::from_utc(time, Utc))
     }
 
+    /// Returns whether the command succeeded, and the versions of the events
+    /// or the error message that it resulted in.
+    pub fn effect(&self) -> &StoredEffect {
+        &self.effect
+    }
+
     pub fn resulting_version(&self) -> u64 {
         if let Some(versions) = self.effect.events() {
             if let Some(last) = versions.last() {
@@ -175,6 +192,14 @@ impl CommandHistoryRecord {
 
 //------------ StoredEffect --------------------------------------------------
 
+/// The outcome of a stored command: either the versions of the events that it
+/// resulted in, or the error that was recorded for it.
+///
+/// This is included as 'effect' in each command of the command history
+/// response, as:
+///
+///   {"result": "success", "events": [ 12, 13 ]}
+///   {"result": "error", "msg": "some error message"}
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 #[serde(rename_all = "snake_case", tag = "result")]
 pub enum StoredEffect {
@@ -190,12 +215,21 @@ impl StoredEffect {
         }
     }
 
+    /// Returns the versions of the events, if the command succeeded.
     pub fn events(&self) -> Option<&Vec<u64>> {
         match self {
             StoredEffect::Error { .. } => None,
             StoredEffect::Success { events } => Some(events),
         }
     }
+
+    /// Returns the error message, if the command failed.
+    pub fn error(&self) -> Option<&str> {
+        match self {
+            StoredEffect::Error { msg } => Some(msg),
+            StoredEffect::Success { .. } => None,
+        }
+    }
 }
 
 //------------ CommandSummary ------------------------------------------------
@@ -278,6 +312,112 @@ impl CommandSummary {
     pub fn with_rta_name(self, name: &str) -> Self {
         self.with_arg("rta_name", name)
     }
+
+    pub fn with_created_keys(self, created_keys: &Option<CreatedKeys>) -> Self {
+        match created_keys {
+            Some(created) if !created.is_empty() => self
+                .with_arg("signer", created.signer())
+                .with_arg("created_keys", created.keys_string()),
+            _ => self,
+        }
+    }
+}
+
+//------------ Command Labels ------------------------------------------------
+
+/// The labels of the commands stored for CAs and the publication server, as
+/// used in their `CommandSummary`. These can be used to select commands, e.g.
+/// for archiving.
+pub const COMMAND_LABELS: &[&str] = &[
+    "cmd-ca-make-ta",
+    "cmd-ca-child-add",
+    "cmd-ca-child-update-res",
+    "cmd-ca-child-update-id",
+    "cmd-ca-child-certify",
+    "cmd-ca-child-remove",
+    "cmd-ca-child-revoke",
+    "cmd-ca-generate-new-id",
+    "cmd-ca-parent-add",
+    "cmd-ca-parent-update",
+    "cmd-ca-parent-remove",
+    "cmd-ca-parent-entitlements",
+    "cmd-ca-rcn-receive",
+    "cmd-ca-keyroll-init",
+    "cmd-ca-keyroll-activate",
+    "cmd-ca-keyroll-finish",
+    "cmd-ca-roas-updated",
+    "cmd-ca-repo-update",
+    "cmd-ca-reissue-before-expiring",
+    "cmd-ca-rta-prepare",
+    "cmd-ca-rta-sign",
+    "cmd-ca-rta-cosign",
+    "cmd-ca-deactivate",
+    "pubd-publisher-add",
+    "pubd-publisher-remove",
+];
+
+/// The labels of commands which are no longer created, but which can still be
+/// found in the history of CAs and publication servers that were upgraded from
+/// versions before 0.9.0, where publishing was done through commands.
+pub const LEGACY_COMMAND_LABELS: &[&str] = &["cmd-ca-publish", "pubd-publish"];
+
+/// Returns true if commands with the given label are, or were, stored.
+pub fn is_known_command_label(label: &str) -> bool {
+    COMMAND_LABELS.contains(&label) || LEGACY_COMMAND_LABELS.contains(&label)
+}
+
+//------------ CreatedKeys ---------------------------------------------------
+
+/// The keys that were created when a command was processed, and the signer
+/// backend in which they were created. This is kept in the stored command so
+/// that the command history shows where each key was created, e.g. to verify
+/// a migration to another signer backend.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct CreatedKeys {
+    signer: String,
+    keys: Vec<KeyIdentifier>,
+}
+
+impl CreatedKeys {
+    pub fn new(signer: &str) -> Self {
+        CreatedKeys {
+            signer: signer.to_string(),
+            keys: vec![],
+        }
+    }
+
+    pub fn add(&mut self, key: KeyIdentifier) {
+        self.keys.push(key);
+    }
+
+    pub fn signer(&self) -> &str {
+        &self.signer
+    }
+
+    pub fn keys(&self) -> &Vec<KeyIdentifier> {
+        &self.keys
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    fn keys_string(&self) -> String {
+        let keys: Vec<_> = self.keys.iter().map(|key| key.to_string()).collect();
+        keys.join(",")
+    }
+
+    /// Skip serializing when no keys were created, e.g. when a command that
+    /// may create keys did not need to.
+    fn none_created(created_keys: &Option<CreatedKeys>) -> bool {
+        created_keys.as_ref().map(CreatedKeys::is_empty).unwrap_or(true)
+    }
+}
+
+impl fmt::Display for CreatedKeys {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "created key(s) '{}' in signer '{}'", self.keys_string(), self.signer)
+    }
 }
 
 //------------ CommandHistoryCriteria ----------------------------------------
@@ -295,6 +435,8 @@ pub struct CommandHistoryCriteria {
     label_includes: Option<Vec<String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     label_excludes: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    actor: Option<String>,
 
     offset: usize,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -318,10 +460,26 @@ impl CommandHistoryCriteria {
         self.before = Some(timestamp);
     }
 
+    /// Only include commands with a sequence higher than the given sequence.
+    ///
+    /// Sequences only ever increase, so this can be used as a cursor to poll
+    /// for new commands: pass the `last_sequence` of the previous history
+    /// response. Unlike paging with an offset, this is not affected by commands
+    /// that are added in between requests.
     pub fn set_after_sequence(&mut self, sequence: u64) {
         self.after_sequence = Some(sequence)
     }
 
+    /// Only include commands sent by the given actor.
+    ///
+    /// Note that the actor is not part of the command key, so when this is
+    /// set every command matching the other criteria has to be loaded from
+    /// the store to check its actor. This is considerably more expensive than
+    /// filtering on time, label or sequence alone.
+    pub fn set_actor(&mut self, actor: &str) {
+        self.actor = Some(actor.to_string());
+    }
+
     pub fn set_rows(&mut self, rows: usize) {
         self.rows_limit = Some(rows);
     }
@@ -371,6 +529,19 @@ impl CommandHistoryCriteria {
         true
     }
 
+    /// Returns true if this criteria has an actor filter, which can only be
+    /// checked against the full stored command.
+    pub fn has_actor(&self) -> bool {
+        self.actor.is_some()
+    }
+
+    pub fn matches_actor(&self, actor: &str) -> bool {
+        match &self.actor {
+            None => true,
+            Some(actor_crit) => actor_crit == actor,
+        }
+    }
+
     pub fn offset(&self) -> usize {
         self.offset
     }
@@ -388,6 +559,7 @@ impl Default for CommandHistoryCriteria {
             after_sequence: None,
             label_includes: None,
             label_excludes: None,
+            actor: None,
             offset: 0,
             rows_limit: Some(100),
         }
@@ -428,7 +600,10 @@ pub enum StorableCaCommand {
     ChildRemove {
         child: ChildHandle,
     },
-    GenerateNewIdKey,
+    GenerateNewIdKey {
+        #[serde(default, skip_serializing_if = "CreatedKeys::none_created")]
+        created_keys: Option<CreatedKeys>,
+    },
     AddParent {
         parent: ParentHandle,
         contact: StorableParentContact,
@@ -443,6 +618,8 @@ pub enum StorableCaCommand {
     UpdateResourceEntitlements {
         parent: ParentHandle,
         entitlements: Vec<StorableRcEntitlement>,
+        #[serde(default, skip_serializing_if = "CreatedKeys::none_created")]
+        created_keys: Option<CreatedKeys>,
     },
     UpdateRcvdCert {
         resource_class_name: ResourceClassName,
@@ -450,6 +627,8 @@ pub enum StorableCaCommand {
     },
     KeyRollInitiate {
         older_than_seconds: i64,
+        #[serde(default, skip_serializing_if = "CreatedKeys::none_created")]
+        created_keys: Option<CreatedKeys>,
     },
     KeyRollActivate {
         staged_for_seconds: i64,
@@ -516,7 +695,9 @@ impl WithStorableDetails for StorableCaCommand {
                     .with_rcn(revoke_req.class_name())
                     .with_key(revoke_req.key())
             }
-            StorableCaCommand::GenerateNewIdKey => CommandSummary::new("cmd-ca-generate-new-id", &self),
+            StorableCaCommand::GenerateNewIdKey { created_keys } => {
+                CommandSummary::new("cmd-ca-generate-new-id", &self).with_created_keys(created_keys)
+            }
             StorableCaCommand::AddParent { parent, contact } => CommandSummary::new("cmd-ca-parent-add", &self)
                 .with_parent(parent)
                 .with_parent_contact(contact),
@@ -528,18 +709,23 @@ impl WithStorableDetails for StorableCaCommand {
             StorableCaCommand::RemoveParent { parent } => {
                 CommandSummary::new("cmd-ca-parent-remove", &self).with_parent(parent)
             }
-            StorableCaCommand::UpdateResourceEntitlements { parent, .. } => {
-                CommandSummary::new("cmd-ca-parent-entitlements", &self).with_parent(parent)
-            }
+            StorableCaCommand::UpdateResourceEntitlements {
+                parent, created_keys, ..
+            } => CommandSummary::new("cmd-ca-parent-entitlements", &self)
+                .with_parent(parent)
+                .with_created_keys(created_keys),
             StorableCaCommand::UpdateRcvdCert {
                 resource_class_name,
                 resources,
             } => CommandSummary::new("cmd-ca-rcn-receive", &self)
                 .with_rcn(resource_class_name)
                 .with_resources(resources),
-            StorableCaCommand::KeyRollInitiate { older_than_seconds } => {
-                CommandSummary::new("cmd-ca-keyroll-init", &self).with_seconds(*older_than_seconds)
-            }
+            StorableCaCommand::KeyRollInitiate {
+                older_than_seconds,
+                created_keys,
+            } => CommandSummary::new("cmd-ca-keyroll-init", &self)
+                .with_seconds(*older_than_seconds)
+                .with_created_keys(created_keys),
             StorableCaCommand::KeyRollActivate { staged_for_seconds } => {
                 CommandSummary::new("cmd-ca-keyroll-activate", &self).with_seconds(*staged_for_seconds)
             }
@@ -611,14 +797,21 @@ impl fmt::Display for StorableCaCommand {
             // ------------------------------------------------------------
             // Being a child (only allowed if this CA is not self-signed)
             // ------------------------------------------------------------
-            StorableCaCommand::GenerateNewIdKey => write!(f, "Generate a new RFC8183 ID."),
+            StorableCaCommand::GenerateNewIdKey { created_keys } => {
+                write!(f, "Generate a new RFC8183 ID.")?;
+                write_created_keys(f, created_keys)
+            }
             StorableCaCommand::AddParent { parent, contact } => write!(f, "Add parent '{}' as '{}'", parent, contact),
             StorableCaCommand::UpdateParentContact { parent, contact } => {
                 write!(f, "Update contact for parent '{}' to '{}'", parent, contact)
             }
             StorableCaCommand::RemoveParent { parent } => write!(f, "Remove parent '{}'", parent),
 
-            StorableCaCommand::UpdateResourceEntitlements { parent, entitlements } => {
+            StorableCaCommand::UpdateResourceEntitlements {
+                parent,
+                entitlements,
+                created_keys,
+            } => {
                 let mut summary = format!("Update entitlements under parent '{}': ", parent);
 
                 for entitlement in entitlements.iter() {
@@ -628,7 +821,8 @@ impl fmt::Display for StorableCaCommand {
                     ))
                 }
 
-                write!(f, "{}", summary)
+                write!(f, "{}", summary)?;
+                write_created_keys(f, created_keys)
             }
             // Process a new certificate received from a parent.
             StorableCaCommand::UpdateRcvdCert {
@@ -644,12 +838,16 @@ impl fmt::Display for StorableCaCommand {
             // ------------------------------------------------------------
             // Key rolls
             // ------------------------------------------------------------
-            StorableCaCommand::KeyRollInitiate { older_than_seconds } => {
+            StorableCaCommand::KeyRollInitiate {
+                older_than_seconds,
+                created_keys,
+            } => {
                 write!(
                     f,
                     "Initiate key roll for keys older than '{}' seconds",
                     older_than_seconds
-                )
+                )?;
+                write_created_keys(f, created_keys)
             }
             StorableCaCommand::KeyRollActivate { staged_for_seconds } => {
                 write!(
@@ -706,6 +904,13 @@ impl fmt::Display for StorableCaCommand {
     }
 }
 
+fn write_created_keys(f: &mut fmt::Formatter, created_keys: &Option<CreatedKeys>) -> fmt::Result {
+    match created_keys {
+        Some(created) if !created.is_empty() => write!(f, " ({})", created),
+        _ => Ok(()),
+    }
+}
+
 //------------ StorableRepositoryCommand -----------------------------------
 
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
@@ -739,3 +944,25 @@ impl fmt::Display for StorableRepositoryCommand {
         }
     }
 }
+
+//------------ Tests ---------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn stored_effect_json() {
+        let success = StoredEffect::Success { events: vec![12, 13] };
+        let json = serde_json::to_string(&success).unwrap();
+        assert_eq!(json, r#"{"result":"success","events":[12,13]}"#);
+        assert_eq!(success.events(), Some(&vec![12, 13]));
+        assert_eq!(success.error(), None);
+
+        let error: StoredEffect = serde_json::from_str(r#"{"result":"error","msg":"nope"}"#).unwrap();
+        assert!(!error.successful());
+        assert_eq!(error.events(), None);
+        assert_eq!(error.error(), Some("nope"));
+    }
+}
@@ -239,6 +239,10 @@ impl ErrorResponse {
         }
     }
 
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
     pub fn delta_error(&self) -> Option<&RoaDeltaError> {
         self.delta_error.as_ref()
     }
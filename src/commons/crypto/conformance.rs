@@ -0,0 +1,66 @@
+//! A conformance suite for implementations of the rpki `Signer` trait.
+//!
+//! Every signer backend must pass `check_signer`, so that the rest of Krill
+//! can rely on the same behaviour regardless of where keys are kept.
+use std::fmt::Debug;
+
+use rpki::crypto::{PublicKeyFormat, SignatureAlgorithm, Signer};
+
+/// Checks the full life cycle of a key in the given signer, and its one-off
+/// signing and random number generation. Panics if the signer misbehaves.
+pub fn check_signer<S>(signer: &mut S)
+where
+    S: Signer,
+    S::KeyId: Debug,
+    S::Error: Debug,
+{
+    let data = b"krill signer conformance";
+    let algorithm = SignatureAlgorithm::default();
+
+    // create -> get_key_info -> sign -> verify -> destroy
+    let key_id = signer.create_key(PublicKeyFormat::Rsa).unwrap();
+    let key = signer.get_key_info(&key_id).unwrap();
+
+    let signature = signer.sign(&key_id, algorithm, data).unwrap();
+    assert_eq!(signature.algorithm(), algorithm);
+    key.verify(data, &signature).unwrap();
+    assert!(key.verify(b"other data", &signature).is_err());
+
+    signer.destroy_key(&key_id).unwrap();
+    assert!(signer.get_key_info(&key_id).is_err());
+    assert!(signer.sign(&key_id, algorithm, data).is_err());
+
+    // one-off signatures are made with a key that is not kept
+    let (signature, key) = signer.sign_one_off(algorithm, data).unwrap();
+    key.verify(data, &signature).unwrap();
+
+    // random bytes are filled in, and differ between calls
+    let mut first = [0; 32];
+    let mut second = [0; 32];
+    signer.rand(&mut first).unwrap();
+    signer.rand(&mut second).unwrap();
+    assert_ne!(first, second);
+}
+
+//------------ Tests ---------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use crate::commons::util::softsigner::OpenSslSigner;
+    use crate::test;
+
+    #[test]
+    fn openssl_signer_conforms() {
+        test::test_under_tmp(|d| {
+            let mut signer = OpenSslSigner::build(&d).unwrap();
+            check_signer(&mut signer);
+
+            let mut signer = OpenSslSigner::build(&d).unwrap();
+            signer.set_sharded(true);
+            check_signer(&mut signer);
+        })
+    }
+}
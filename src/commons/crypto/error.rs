@@ -9,6 +9,10 @@ pub enum Error {
     SigningError(String),
     KeyNotFound,
     SignerError(String),
+    /// The signer could not be reached, or could not complete an operation,
+    /// but it is expected to work again later. E.g. because retrying after
+    /// a lost connection to an HSM failed.
+    SignerUnavailable(String),
     DecodeError(decode::Error),
 }
 
@@ -17,6 +21,7 @@ impl fmt::Display for Error {
         match self {
             Error::KeyError(e) => e.fmt(f),
             Error::SignerError(e) => e.fmt(f),
+            Error::SignerUnavailable(e) => write!(f, "Signer temporarily unavailable: {}", e),
             Error::KeyNotFound => write!(f, "Could not find key"),
             Error::SigningError(e) => e.fmt(f),
             Error::DecodeError(e) => e.fmt(f),
@@ -36,6 +41,10 @@ impl Error {
     pub fn signer(e: impl Display) -> Self {
         Error::SignerError(e.to_string())
     }
+
+    pub fn unavailable(e: impl Display) -> Self {
+        Error::SignerUnavailable(e.to_string())
+    }
 }
 
 impl From<decode::Error> for Error {
@@ -4,6 +4,9 @@ pub use self::cms::*;
 mod cert;
 pub use self::cert::*;
 
+#[cfg(test)]
+pub mod conformance;
+
 mod error;
 pub use self::error::*;
 
@@ -1,10 +1,12 @@
 //! Support for signing mft, crl, certificates, roas..
 //! Common objects for TAs and CAs
 use std::ops::Deref;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
 use std::sync::{Arc, RwLock};
 use std::{convert::TryFrom, path::Path};
 
 use bytes::Bytes;
+use openssl::rsa::Rsa;
 
 use rpki::cert::{Cert, KeyUsage, Overclaim, TbsCert};
 use rpki::crl::{Crl, CrlEntry, TbsCertList};
@@ -20,32 +22,154 @@ use crate::commons::api::{IssuedCert, RcvdCert, ReplacedObject, RepoInfo, Reques
 use crate::commons::crypto::{self, CryptoResult};
 use crate::commons::error::Error;
 use crate::commons::util::softsigner::OpenSslSigner;
+#[cfg(test)]
+use crate::commons::util::softsigner::SignerError;
 use crate::commons::util::AllowedUri;
 use crate::commons::KrillResult;
+use crate::constants::SIGN_VALIDITY_BACKDATE_SECS_DFLT;
 use crate::daemon::ca::CertifiedKey;
 
 //------------ Signer --------------------------------------------------------
 
+const OPENSSL_BACKEND: &str = "openssl";
+
 // This is an enum in preparation of other supported signer types
 #[derive(Clone, Debug)]
 pub struct KrillSigner {
     // use a blocking lock to avoid having to be async, for signing operations
     // this should be fine.
     signer: Arc<RwLock<OpenSslSigner>>,
+
+    // Shared by all clones, so that all operations are counted.
+    stats: Arc<SignerStatsCounters>,
+
+    #[cfg(test)]
+    behavior: Arc<RwLock<SignerBehavior>>,
 }
 
 impl KrillSigner {
     pub fn build(work_dir: &Path) -> KrillResult<Self> {
         let signer = OpenSslSigner::build(work_dir)?;
+        Ok(Self::with_signer(signer))
+    }
+
+    /// Builds a signer which keeps its keys in the given dir, rather than in
+    /// "keys" under the work dir.
+    pub fn build_with_keys_dir(keys_dir: &Path) -> KrillResult<Self> {
+        let signer = OpenSslSigner::build_with_keys_dir(keys_dir)?;
+        Ok(Self::with_signer(signer))
+    }
+
+    fn with_signer(signer: OpenSslSigner) -> Self {
         let signer = Arc::new(RwLock::new(signer));
-        Ok(KrillSigner { signer })
+        KrillSigner {
+            signer,
+            stats: Arc::new(SignerStatsCounters::default()),
+            #[cfg(test)]
+            behavior: Arc::new(RwLock::new(SignerBehavior::default())),
+        }
+    }
+
+    /// Builds a signer which fails the operations set in the behavior, so that
+    /// tests can exercise how callers handle signer errors. Other operations
+    /// are done by the real signer.
+    #[cfg(test)]
+    pub fn test_with_behavior(work_dir: &Path, behavior: SignerBehavior) -> KrillResult<Self> {
+        let signer = Self::build(work_dir)?;
+        signer.set_behavior(behavior);
+        Ok(signer)
+    }
+
+    /// Changes the behavior of this signer, and all its clones. E.g. to let
+    /// signing fail only after a CA was set up.
+    #[cfg(test)]
+    pub fn set_behavior(&self, behavior: SignerBehavior) {
+        *self.behavior.write().unwrap() = behavior;
+    }
+
+    #[cfg(test)]
+    fn check(&self, operation: SignerOperation) -> CryptoResult<()> {
+        self.behavior.read().unwrap().check(operation)
+    }
+
+    #[cfg(not(test))]
+    #[inline]
+    fn check(&self, _operation: SignerOperation) -> CryptoResult<()> {
+        Ok(())
+    }
+
+    /// Returns when the signer last succeeded to create a key or sign, and how
+    /// often these operations failed since the server started. Monitoring
+    /// can use this to detect a signer that stopped working, before objects
+    /// go stale.
+    pub fn stats(&self) -> SignerStats {
+        self.stats.snapshot()
+    }
+
+    /// Records the outcome of an operation in the stats.
+    fn track<T>(&self, operation: SignerOperation, res: CryptoResult<T>) -> CryptoResult<T> {
+        self.stats.record(operation, res.is_ok());
+        res
+    }
+
+    /// Returns the name of the backend in which this signer creates keys, so
+    /// that it can be recorded where keys are created, e.g. in the command
+    /// history of a CA.
+    pub fn backend(&self) -> &'static str {
+        OPENSSL_BACKEND
+    }
+
+    /// Re-initializes the signer backend from the state kept in its keys dir,
+    /// so that Krill can recover from changes to the keys made while it was
+    /// running without a restart. The backend is opened again, as when Krill
+    /// starts, and only replaces the current backend if it responds. Waits for
+    /// operations in progress to finish, and new operations wait until the
+    /// signer is re-initialized.
+    pub fn reinit(&self) -> KrillResult<()> {
+        let mut signer = self.signer.write().unwrap();
+
+        let reopened = signer.reopen()?;
+        let mut random = [0; 16];
+        reopened.rand(&mut random)?;
+
+        *signer = reopened;
+        info!("Re-initialized signer");
+        Ok(())
+    }
+
+    /// Sets whether new keys are stored in a sharded directory layout. When
+    /// enabled, existing keys are moved into the sharded layout as well.
+    pub fn set_sharded_keys(&self, sharded: bool) -> KrillResult<()> {
+        let mut signer = self.signer.write().unwrap();
+        signer.set_sharded(sharded);
+        if sharded {
+            signer.migrate_to_sharded()?;
+        }
+        Ok(())
     }
 }
 
 impl KrillSigner {
     pub fn create_key(&self) -> CryptoResult<KeyIdentifier> {
+        let res = self.check(SignerOperation::CreateKey).and_then(|_| {
+            let mut signer = self.signer.write().unwrap();
+            signer.create_key(PublicKeyFormat::Rsa).map_err(crypto::Error::signer)
+        });
+        self.track(SignerOperation::CreateKey, res)
+    }
+
+    /// Creates a new key and remembers the context, e.g. the handle of the
+    /// CA, that it was created for. See `destroy_context_keys`.
+    pub fn create_key_in_context(&self, context: &str) -> CryptoResult<KeyIdentifier> {
+        let key_id = self.create_key()?;
+        self.set_key_context(&key_id, context)?;
+        Ok(key_id)
+    }
+
+    /// Remembers the context, e.g. the handle of the CA, for an existing key.
+    pub fn set_key_context(&self, key_id: &KeyIdentifier, context: &str) -> CryptoResult<()> {
         let mut signer = self.signer.write().unwrap();
-        signer.create_key(PublicKeyFormat::Rsa).map_err(crypto::Error::signer)
+        signer.set_key_context(key_id, context).map_err(crypto::Error::signer)
     }
 
     pub fn destroy_key(&self, key_id: &KeyIdentifier) -> CryptoResult<()> {
@@ -53,7 +177,21 @@ impl KrillSigner {
         signer.destroy_key(key_id).map_err(crypto::Error::key_error)
     }
 
+    /// Destroys all keys which were created for the given context, and
+    /// returns their identifiers.
+    pub fn destroy_context_keys(&self, context: &str) -> CryptoResult<Vec<KeyIdentifier>> {
+        let mut signer = self.signer.write().unwrap();
+        signer.destroy_context_keys(context).map_err(crypto::Error::signer)
+    }
+
+    /// Returns the identifiers of all keys that the signer has, in no particular
+    /// order. This includes keys that are no longer used.
+    pub fn list_keys(&self) -> CryptoResult<Vec<KeyIdentifier>> {
+        self.signer.read().unwrap().list_keys().map_err(crypto::Error::signer)
+    }
+
     pub fn get_key_info(&self, key_id: &KeyIdentifier) -> CryptoResult<PublicKey> {
+        self.check(SignerOperation::GetKeyInfo)?;
         self.signer
             .read()
             .unwrap()
@@ -61,49 +199,138 @@ impl KrillSigner {
             .map_err(crypto::Error::key_error)
     }
 
+    /// Does a round-trip with the signer: gets some random bytes, and makes
+    /// and verifies a one-off signature. Use this at startup to find out early
+    /// if the signer does not work, rather than when a CA needs to sign. This
+    /// generates a new key, so it is too expensive to do on every request.
+    pub fn health_check(&self) -> CryptoResult<()> {
+        self.ready_check()?;
+
+        let data = b"krill signer health check";
+        let (signature, key) = self.sign_one_off(data)?;
+        self.verify(&key, data, &signature)
+    }
+
+    /// Checks that the signer responds by getting some random bytes from it.
+    /// This is cheap enough to do whenever readiness is probed.
+    pub fn ready_check(&self) -> CryptoResult<()> {
+        self.check(SignerOperation::Random)?;
+        let mut random = [0; 16];
+        self.signer
+            .read()
+            .unwrap()
+            .rand(&mut random)
+            .map_err(crypto::Error::signer)
+    }
+
+    /// Verifies that the signature was made over the data with the private key
+    /// of the given public key. Use this to check that signing actually worked.
+    /// The signature is verified locally, as keys of the OpenSSL signer are not
+    /// kept anywhere that could do it for us.
+    pub fn verify<D: AsRef<[u8]> + ?Sized>(
+        &self,
+        key: &PublicKey,
+        data: &D,
+        signature: &Signature,
+    ) -> CryptoResult<()> {
+        key.verify(data.as_ref(), signature)
+            .map_err(|_| crypto::Error::signing("signature does not verify with the public key"))
+    }
+
     pub fn random_serial(&self) -> CryptoResult<Serial> {
+        self.check(SignerOperation::Random)?;
         let signer = self.signer.read().unwrap();
         Serial::random(signer.deref()).map_err(crypto::Error::signer)
     }
 
     pub fn sign<D: AsRef<[u8]> + ?Sized>(&self, key_id: &KeyIdentifier, data: &D) -> CryptoResult<Signature> {
-        self.signer
-            .read()
-            .unwrap()
-            .sign(key_id, SignatureAlgorithm::default(), data)
-            .map_err(crypto::Error::signing)
+        self.sign_with_algorithm(key_id, SignatureAlgorithm::default(), data)
+    }
+
+    /// Signs with the given algorithm. The signer returns an error if it does
+    /// not support the algorithm, rather than using a different one.
+    pub fn sign_with_algorithm<D: AsRef<[u8]> + ?Sized>(
+        &self,
+        key_id: &KeyIdentifier,
+        algorithm: SignatureAlgorithm,
+        data: &D,
+    ) -> CryptoResult<Signature> {
+        let res = self.check(SignerOperation::Sign).and_then(|_| {
+            self.signer
+                .read()
+                .unwrap()
+                .sign(key_id, algorithm, data)
+                .map_err(crypto::Error::signing)
+        });
+        self.track(SignerOperation::Sign, res)
+    }
+
+    /// Signs many objects at once, e.g. when all objects of a CA are re-issued,
+    /// and returns the signatures in the same order. The signer lock is taken
+    /// only once and each key is loaded only once for the whole batch. Fails
+    /// as a whole if any of the objects cannot be signed.
+    pub fn sign_batch<D: AsRef<[u8]>>(&self, batch: &[(KeyIdentifier, D)]) -> CryptoResult<Vec<Signature>> {
+        let res = self.check(SignerOperation::Sign).and_then(|_| {
+            self.signer
+                .read()
+                .unwrap()
+                .sign_batch(batch, SignatureAlgorithm::default())
+                .map_err(crypto::Error::signing)
+        });
+        self.track(SignerOperation::Sign, res)
     }
 
     pub fn sign_one_off<D: AsRef<[u8]> + ?Sized>(&self, data: &D) -> CryptoResult<(Signature, PublicKey)> {
-        self.signer
-            .read()
-            .unwrap()
-            .sign_one_off(SignatureAlgorithm::default(), data)
-            .map_err(crypto::Error::signer)
+        self.sign_one_off_with_algorithm(SignatureAlgorithm::default(), data)
+    }
+
+    pub fn sign_one_off_with_algorithm<D: AsRef<[u8]> + ?Sized>(
+        &self,
+        algorithm: SignatureAlgorithm,
+        data: &D,
+    ) -> CryptoResult<(Signature, PublicKey)> {
+        let res = self.check(SignerOperation::SignOneOff).and_then(|_| {
+            self.signer
+                .read()
+                .unwrap()
+                .sign_one_off(algorithm, data)
+                .map_err(crypto::Error::signer)
+        });
+        self.track(SignerOperation::SignOneOff, res)
     }
 
     pub fn sign_csr(&self, base_repo: &RepoInfo, name_space: &str, key: &KeyIdentifier) -> CryptoResult<Csr> {
-        let signer = self.signer.read().unwrap();
-        let pub_key = signer.get_key_info(key).map_err(crypto::Error::key_error)?;
-        let enc = Csr::construct(
-            signer.deref(),
-            key,
-            &base_repo.ca_repository(name_space).join(&[]), // force trailing slash
-            &base_repo.rpki_manifest(name_space, &pub_key.key_identifier()),
-            Some(&base_repo.rpki_notify()),
-        )
-        .map_err(crypto::Error::signing)?;
-        Ok(Csr::decode(enc.as_slice())?)
+        self.check(SignerOperation::GetKeyInfo)?;
+        let res = self.check(SignerOperation::Sign).and_then(|_| {
+            let signer = self.signer.read().unwrap();
+            let pub_key = signer.get_key_info(key).map_err(crypto::Error::key_error)?;
+            let enc = Csr::construct(
+                signer.deref(),
+                key,
+                &base_repo.ca_repository(name_space).join(&[]), // force trailing slash
+                &base_repo.rpki_manifest(name_space, &pub_key.key_identifier()),
+                Some(&base_repo.rpki_notify()),
+            )
+            .map_err(crypto::Error::signing)?;
+            Ok(Csr::decode(enc.as_slice())?)
+        });
+        self.track(SignerOperation::Sign, res)
     }
 
     pub fn sign_cert(&self, tbs: TbsCert, key_id: &KeyIdentifier) -> CryptoResult<Cert> {
-        let signer = self.signer.read().unwrap();
-        tbs.into_cert(signer.deref(), key_id).map_err(crypto::Error::signing)
+        let res = self.check(SignerOperation::Sign).and_then(|_| {
+            let signer = self.signer.read().unwrap();
+            tbs.into_cert(signer.deref(), key_id).map_err(crypto::Error::signing)
+        });
+        self.track(SignerOperation::Sign, res)
     }
 
     pub fn sign_crl(&self, tbs: TbsCertList<Vec<CrlEntry>>, key_id: &KeyIdentifier) -> CryptoResult<Crl> {
-        let signer = self.signer.read().unwrap();
-        tbs.into_crl(signer.deref(), key_id).map_err(crypto::Error::signing)
+        let res = self.check(SignerOperation::Sign).and_then(|_| {
+            let signer = self.signer.read().unwrap();
+            tbs.into_crl(signer.deref(), key_id).map_err(crypto::Error::signing)
+        });
+        self.track(SignerOperation::Sign, res)
     }
 
     pub fn sign_manifest(
@@ -112,10 +339,13 @@ impl KrillSigner {
         builder: SignedObjectBuilder,
         key_id: &KeyIdentifier,
     ) -> CryptoResult<Manifest> {
-        let signer = self.signer.read().unwrap();
-        content
-            .into_manifest(builder, signer.deref(), key_id)
-            .map_err(crypto::Error::signing)
+        let res = self.check(SignerOperation::Sign).and_then(|_| {
+            let signer = self.signer.read().unwrap();
+            content
+                .into_manifest(builder, signer.deref(), key_id)
+                .map_err(crypto::Error::signing)
+        });
+        self.track(SignerOperation::Sign, res)
     }
 
     pub fn sign_roa(
@@ -124,22 +354,178 @@ impl KrillSigner {
         object_builder: SignedObjectBuilder,
         key_id: &KeyIdentifier,
     ) -> CryptoResult<Roa> {
-        let signer = self.signer.read().unwrap();
-        roa_builder
-            .finalize(object_builder, signer.deref(), key_id)
-            .map_err(crypto::Error::signing)
+        let res = self.check(SignerOperation::Sign).and_then(|_| {
+            let signer = self.signer.read().unwrap();
+            roa_builder
+                .finalize(object_builder, signer.deref(), key_id)
+                .map_err(crypto::Error::signing)
+        });
+        self.track(SignerOperation::Sign, res)
     }
 
     pub fn sign_rta(&self, rta_builder: &mut rta::RtaBuilder, ee: Cert) -> CryptoResult<()> {
-        let signer = self.signer.read().unwrap();
-        let key = ee.subject_key_identifier();
-        rta_builder.push_cert(ee);
-        rta_builder
-            .sign(signer.deref(), &key, None, None)
-            .map_err(crypto::Error::signing)
+        let res = self.check(SignerOperation::Sign).and_then(|_| {
+            let signer = self.signer.read().unwrap();
+            let key = ee.subject_key_identifier();
+            rta_builder.push_cert(ee);
+            rta_builder
+                .sign(signer.deref(), &key, None, None)
+                .map_err(crypto::Error::signing)
+        });
+        self.track(SignerOperation::Sign, res)
     }
 }
 
+/// # Async signing
+///
+/// The signer is guarded by a blocking lock, and signing may be slow, e.g. when keys
+/// are kept in an HSM. These functions do the work on the blocking thread pool of the
+/// tokio runtime, so that async code, like HTTP request handlers, can await them without
+/// blocking a worker thread of the runtime. Code which does not run on the worker threads
+/// can keep using the synchronous functions.
+impl KrillSigner {
+    pub async fn create_key_async(&self) -> CryptoResult<KeyIdentifier> {
+        let signer = self.clone();
+        Self::spawn_blocking(move || signer.create_key()).await
+    }
+
+    pub async fn sign_async(&self, key_id: KeyIdentifier, data: Bytes) -> CryptoResult<Signature> {
+        let signer = self.clone();
+        Self::spawn_blocking(move || signer.sign(&key_id, &data)).await
+    }
+
+    pub async fn sign_batch_async(&self, batch: Vec<(KeyIdentifier, Bytes)>) -> CryptoResult<Vec<Signature>> {
+        let signer = self.clone();
+        Self::spawn_blocking(move || signer.sign_batch(&batch)).await
+    }
+
+    pub async fn sign_one_off_async(&self, data: Bytes) -> CryptoResult<(Signature, PublicKey)> {
+        let signer = self.clone();
+        Self::spawn_blocking(move || signer.sign_one_off(&data)).await
+    }
+
+    pub async fn sign_cert_async(&self, tbs: TbsCert, key_id: KeyIdentifier) -> CryptoResult<Cert> {
+        let signer = self.clone();
+        Self::spawn_blocking(move || signer.sign_cert(tbs, &key_id)).await
+    }
+
+    pub async fn sign_crl_async(&self, tbs: TbsCertList<Vec<CrlEntry>>, key_id: KeyIdentifier) -> CryptoResult<Crl> {
+        let signer = self.clone();
+        Self::spawn_blocking(move || signer.sign_crl(tbs, &key_id)).await
+    }
+
+    async fn spawn_blocking<F, T>(f: F) -> CryptoResult<T>
+    where
+        F: FnOnce() -> CryptoResult<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        tokio::task::spawn_blocking(f).await.map_err(crypto::Error::signer)?
+    }
+}
+
+//------------ SignerStats ---------------------------------------------------
+
+/// When the signer last succeeded to create a key or sign, and how often
+/// each kind of operation failed since the server started.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SignerStats {
+    /// Timestamp of the last success, if any.
+    pub last_success: Option<i64>,
+    pub create_key_failures: u64,
+    pub sign_failures: u64,
+    pub sign_one_off_failures: u64,
+}
+
+#[derive(Debug, Default)]
+struct SignerStatsCounters {
+    // 0 means that there was no success yet
+    last_success: AtomicI64,
+    create_key_failures: AtomicU64,
+    sign_failures: AtomicU64,
+    sign_one_off_failures: AtomicU64,
+}
+
+impl SignerStatsCounters {
+    fn record(&self, operation: SignerOperation, success: bool) {
+        if success {
+            self.last_success.store(Time::now().timestamp(), Ordering::Relaxed);
+        } else {
+            let counter = match operation {
+                SignerOperation::CreateKey => &self.create_key_failures,
+                SignerOperation::SignOneOff => &self.sign_one_off_failures,
+                _ => &self.sign_failures,
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn snapshot(&self) -> SignerStats {
+        let last_success = self.last_success.load(Ordering::Relaxed);
+        SignerStats {
+            last_success: if last_success == 0 { None } else { Some(last_success) },
+            create_key_failures: self.create_key_failures.load(Ordering::Relaxed),
+            sign_failures: self.sign_failures.load(Ordering::Relaxed),
+            sign_one_off_failures: self.sign_one_off_failures.load(Ordering::Relaxed),
+        }
+    }
+}
+
+//------------ SignerBehavior ------------------------------------------------
+
+/// Programs which operations of a test signer fail. The default behavior is
+/// to fail nothing.
+#[cfg(test)]
+#[derive(Clone, Debug, Default)]
+pub struct SignerBehavior {
+    /// Creating keys fails with a signer error.
+    pub fail_create_key: bool,
+
+    /// Getting the public key info fails as if the key was not found.
+    pub fail_get_key_info: bool,
+
+    /// Signing with an existing key, including certificates, CRLs and signed
+    /// objects, fails as if the key was not found.
+    pub fail_sign: bool,
+
+    /// Signing with a one-off key fails with a signer error.
+    pub fail_sign_one_off: bool,
+
+    /// Generating random values, e.g. serial numbers, fails with a signer
+    /// error.
+    pub fail_random: bool,
+}
+
+#[cfg(test)]
+impl SignerBehavior {
+    fn check(&self, operation: SignerOperation) -> CryptoResult<()> {
+        match operation {
+            SignerOperation::CreateKey if self.fail_create_key => {
+                Err(crypto::Error::signer("test signer failed to create key"))
+            }
+            SignerOperation::GetKeyInfo if self.fail_get_key_info => {
+                Err(crypto::Error::key_error(SignerError::KeyNotFound))
+            }
+            SignerOperation::Sign if self.fail_sign => Err(crypto::Error::signing(SignerError::KeyNotFound)),
+            SignerOperation::SignOneOff if self.fail_sign_one_off => {
+                Err(crypto::Error::signer("test signer failed to sign with one-off key"))
+            }
+            SignerOperation::Random if self.fail_random => {
+                Err(crypto::Error::signer("test signer failed to generate random value"))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum SignerOperation {
+    CreateKey,
+    GetKeyInfo,
+    Sign,
+    SignOneOff,
+    Random,
+}
+
 // //------------ Signer --------------------------------------------------------
 //
 // pub trait Signer: crypto::Signer<KeyId = KeyIdentifier> + Clone + Sized + Sync + Send + 'static {}
@@ -174,13 +560,21 @@ impl CsrInfo {
     }
 
     pub fn global_uris(&self) -> bool {
-        self.ca_repository.seems_global_uri()
-            && self.rpki_manifest.seems_global_uri()
-            && self
-                .rpki_notify
+        self.non_global_uri().is_none()
+    }
+
+    /// Returns the first URI which does not seem to be global, if any.
+    pub fn non_global_uri(&self) -> Option<String> {
+        if !self.ca_repository.seems_global_uri() {
+            Some(self.ca_repository.to_string())
+        } else if !self.rpki_manifest.seems_global_uri() {
+            Some(self.rpki_manifest.to_string())
+        } else {
+            self.rpki_notify
                 .as_ref()
-                .map(|uri| uri.seems_global_uri())
-                .unwrap_or_else(|| true)
+                .filter(|uri| !uri.seems_global_uri())
+                .map(|uri| uri.to_string())
+        }
     }
 
     pub fn unpack(self) -> (CaRepository, RpkiManifest, Option<RpkiNotify>, PublicKey) {
@@ -190,6 +584,27 @@ impl CsrInfo {
     pub fn key_id(&self) -> KeyIdentifier {
         self.key.key_identifier()
     }
+
+    /// Checks that the requested key is an RSA key of at least the given
+    /// size in bits, using the public exponent 65537.
+    pub fn check_key(&self, min_rsa_bits: u32) -> KrillResult<()> {
+        let rsa = Rsa::public_key_from_der_pkcs1(self.key.bits())
+            .map_err(|_| Error::invalid_csr("public key is not a valid RSA key"))?;
+
+        let bits = rsa.n().num_bits();
+        if bits < min_rsa_bits as i32 {
+            return Err(Error::invalid_csr(&format!(
+                "RSA key size {} is below the minimum of {} bits",
+                bits, min_rsa_bits
+            )));
+        }
+
+        if rsa.e().to_vec() != [1, 0, 1] {
+            return Err(Error::invalid_csr("RSA public exponent must be 65537"));
+        }
+
+        Ok(())
+    }
 }
 
 impl TryFrom<&Csr> for CsrInfo {
@@ -233,11 +648,17 @@ impl From<&Cert> for CsrInfo {
 
 //------------ CaSignSupport -------------------------------------------------
 
+// The number of seconds by which the start of validity periods is backdated,
+// see `SignSupport::set_backdate_seconds`.
+static SIGN_BACKDATE_SECONDS: AtomicI64 = AtomicI64::new(SIGN_VALIDITY_BACKDATE_SECS_DFLT);
+
 /// Support signing by CAs
 pub struct SignSupport;
 
 impl SignSupport {
-    /// Create an IssuedCert
+    /// Create an IssuedCert. If global URIs are required, then this fails if
+    /// any of the URIs in the CSR info does not seem to be global.
+    #[allow(clippy::too_many_arguments)]
     pub fn make_issued_cert(
         csr: CsrInfo,
         resources: &ResourceSet,
@@ -245,15 +666,22 @@ impl SignSupport {
         replaces: Option<ReplacedObject>,
         signing_key: &CertifiedKey,
         weeks: i64,
+        require_global_uris: bool,
         signer: &KrillSigner,
     ) -> KrillResult<IssuedCert> {
+        if require_global_uris {
+            if let Some(uri) = csr.non_global_uri() {
+                return Err(Error::CaChildUriNotGlobal(uri));
+            }
+        }
+
         let signing_cert = signing_key.incoming_cert();
         let resources = resources.apply_limit(&limit)?;
         if !signing_cert.resources().contains(&resources) {
             return Err(Error::MissingResources);
         }
 
-        let validity = Self::sign_validity_weeks(weeks);
+        let validity = Self::sign_validity(chrono::Duration::weeks(weeks))?;
         let request = CertRequest::Ca(csr, validity);
 
         let tbs = Self::make_tbs_cert(&resources, signing_cert, request, signer)?;
@@ -295,6 +723,7 @@ impl SignSupport {
             CertRequest::Ca(_, validity) => *validity,
             CertRequest::Ee(_, validity) => *validity,
         };
+        Self::check_validity(&validity)?;
 
         let pub_key = match &request {
             CertRequest::Ca(info, _) => info.key.clone(),
@@ -347,18 +776,59 @@ impl SignSupport {
         Ok(cert)
     }
 
-    /// Returns a validity period from 5 minutes ago (in case of NTP mess-up), to
-    /// X weeks from now.
+    /// Sets the number of seconds by which the start of validity periods is
+    /// backdated, in case of NTP mess-up. Defaults to 5 minutes.
+    pub fn set_backdate_seconds(seconds: i64) {
+        SIGN_BACKDATE_SECONDS.store(seconds, Ordering::Relaxed);
+    }
+
+    /// Returns the start time for new validity periods, i.e. now minus the
+    /// configured backdating grace.
+    pub fn validity_from() -> Time {
+        Time::now() - chrono::Duration::seconds(SIGN_BACKDATE_SECONDS.load(Ordering::Relaxed))
+    }
+
+    /// Returns a validity period from the configured grace ago, to the given
+    /// duration from now. Fails if the duration is not positive.
+    pub fn sign_validity(duration: chrono::Duration) -> KrillResult<Validity> {
+        if duration <= chrono::Duration::zero() {
+            return Err(Error::Custom(format!(
+                "Validity duration must be positive, got {} seconds",
+                duration.num_seconds()
+            )));
+        }
+        let validity = Validity::new(Self::validity_from(), Time::now() + duration);
+        Self::check_validity(&validity)?;
+        Ok(validity)
+    }
+
+    /// Fails if the validity period does not start before it ends.
+    pub fn check_validity(validity: &Validity) -> KrillResult<()> {
+        if validity.not_before() < validity.not_after() {
+            Ok(())
+        } else {
+            Err(Error::Custom(format!(
+                "Invalid validity period, {} is not before {}",
+                validity.not_before().to_rfc3339(),
+                validity.not_after().to_rfc3339()
+            )))
+        }
+    }
+
+    /// Returns a validity period from the configured grace ago, to X weeks
+    /// from now.
     pub fn sign_validity_weeks(weeks: i64) -> Validity {
-        let from = Time::five_minutes_ago();
-        let until = Time::now() + chrono::Duration::weeks(weeks);
-        Validity::new(from, until)
+        Validity::new(Self::validity_from(), Time::now() + chrono::Duration::weeks(weeks))
     }
 
     pub fn sign_validity_days(days: i64) -> Validity {
-        let from = Time::five_minutes_ago();
-        let until = Time::now() + chrono::Duration::days(days);
-        Validity::new(from, until)
+        Validity::new(Self::validity_from(), Time::now() + chrono::Duration::days(days))
+    }
+
+    /// Returns a validity period from the configured grace ago, to X hours
+    /// from now. Intended for short-lived EE certificates, e.g. for RTAs.
+    pub fn sign_validity_hours(hours: i64) -> Validity {
+        Validity::new(Self::validity_from(), Time::now() + chrono::Duration::hours(hours))
     }
 }
 
@@ -384,3 +854,198 @@ impl ManifestEntry for Crl {
         self.to_captured().into_bytes()
     }
 }
+
+//------------ Tests ---------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use crate::test;
+
+    use super::*;
+
+    #[test]
+    fn reinit_picks_up_changes() {
+        let d = test::tmp_dir();
+        let signer = KrillSigner::build(&d).unwrap();
+        let other = KrillSigner::build(&d).unwrap();
+
+        let key_id = other.create_key_in_context("ca").unwrap();
+        assert!(signer.destroy_context_keys("ca").unwrap().is_empty());
+
+        signer.reinit().unwrap();
+        assert_eq!(signer.destroy_context_keys("ca").unwrap(), vec![key_id]);
+
+        // Keys restored in the flat layout are moved to the sharded layout
+        signer.set_sharded_keys(true).unwrap();
+        let other = KrillSigner::build(&d).unwrap();
+        let key_id = other.create_key_in_context("ca").unwrap();
+        let flat_path = d.join("keys").join(key_id.to_string());
+        assert!(flat_path.exists());
+
+        signer.reinit().unwrap();
+        assert!(!flat_path.exists());
+        assert_eq!(signer.destroy_context_keys("ca").unwrap(), vec![key_id]);
+
+        // The signer is kept if the keys dir cannot be opened
+        std::fs::remove_dir_all(d.join("keys")).unwrap();
+        std::fs::write(d.join("keys"), b"not a dir").unwrap();
+        assert!(signer.reinit().is_err());
+        assert!(signer.ready_check().is_ok());
+
+        let _ = std::fs::remove_dir_all(d);
+    }
+
+    #[tokio::test]
+    async fn sign_async() {
+        let d = test::tmp_dir();
+        let signer = KrillSigner::build(&d).unwrap();
+
+        let key_id = signer.create_key_async().await.unwrap();
+        let data = Bytes::from_static(b"data");
+
+        let signature = signer.sign_async(key_id, data.clone()).await.unwrap();
+        let key = signer.get_key_info(&key_id).unwrap();
+        signer.verify(&key, &data, &signature).unwrap();
+        assert!(signer.verify(&key, b"other data", &signature).is_err());
+
+        let _ = std::fs::remove_dir_all(d);
+    }
+
+    #[test]
+    fn health_check() {
+        test::test_under_tmp(|d| {
+            let signer = KrillSigner::build(&d).unwrap();
+            signer.health_check().unwrap();
+            signer.ready_check().unwrap();
+        })
+    }
+
+    #[test]
+    fn test_signer_fails_as_programmed() {
+        test::test_under_tmp(|d| {
+            let behavior = SignerBehavior {
+                fail_create_key: true,
+                ..Default::default()
+            };
+            let signer = KrillSigner::test_with_behavior(&d, behavior).unwrap();
+            assert!(matches!(signer.create_key(), Err(crypto::Error::SignerError(_))));
+
+            signer.set_behavior(SignerBehavior::default());
+            let key_id = signer.create_key().unwrap();
+            signer.sign(&key_id, b"data").unwrap();
+
+            // Clones share the behavior
+            let clone = signer.clone();
+            signer.set_behavior(SignerBehavior {
+                fail_sign: true,
+                fail_get_key_info: true,
+                fail_random: true,
+                ..Default::default()
+            });
+            assert!(matches!(
+                clone.sign(&key_id, b"data"),
+                Err(crypto::Error::SigningError(_))
+            ));
+            assert!(matches!(clone.get_key_info(&key_id), Err(crypto::Error::KeyError(_))));
+            assert!(clone.random_serial().is_err());
+            assert!(clone.health_check().is_err());
+            assert!(clone.ready_check().is_err());
+            assert!(clone.sign_one_off(b"data").is_ok());
+        })
+    }
+
+    #[test]
+    fn signer_stats() {
+        test::test_under_tmp(|d| {
+            let signer = KrillSigner::build(&d).unwrap();
+            assert_eq!(signer.stats(), SignerStats::default());
+
+            let key_id = signer.create_key().unwrap();
+            assert!(signer.stats().last_success.is_some());
+
+            signer.set_behavior(SignerBehavior {
+                fail_create_key: true,
+                fail_sign: true,
+                fail_sign_one_off: true,
+                ..Default::default()
+            });
+            assert!(signer.create_key().is_err());
+            assert!(signer.sign(&key_id, b"data").is_err());
+            assert!(signer.sign_batch(&[(key_id, b"data")]).is_err());
+            assert!(signer.clone().sign_one_off(b"data").is_err());
+
+            let stats = signer.stats();
+            assert_eq!(stats.create_key_failures, 1);
+            assert_eq!(stats.sign_failures, 2);
+            assert_eq!(stats.sign_one_off_failures, 1);
+        })
+    }
+
+    #[test]
+    fn non_global_uri() {
+        let mut der = Bytes::from(Rsa::generate(2048).unwrap().public_key_to_der().unwrap());
+        let key = PublicKey::decode(&mut der).unwrap();
+        let csr_info = |base: &str, notify: Option<&str>| {
+            CsrInfo::new(
+                test::rsync(base),
+                test::rsync(&format!("{}ca.mft", base)),
+                notify.map(test::https),
+                key.clone(),
+            )
+        };
+
+        assert_eq!(csr_info("rsync://example.com/repo/ca/", None).non_global_uri(), None);
+        assert_eq!(
+            csr_info("rsync://localhost/repo/ca/", None).non_global_uri(),
+            Some("rsync://localhost/repo/ca/".to_string())
+        );
+        let notify = "https://127.0.0.1/rrdp/notification.xml";
+        assert_eq!(
+            csr_info("rsync://example.com/repo/ca/", Some(notify)).non_global_uri(),
+            Some(notify.to_string())
+        );
+    }
+
+    #[test]
+    fn check_csr_key_size() {
+        test::test_under_tmp(|d| {
+            let csr_info = |key| {
+                CsrInfo::new(
+                    test::rsync("rsync://localhost/repo/ca/"),
+                    test::rsync("rsync://localhost/repo/ca/ca.mft"),
+                    None,
+                    key,
+                )
+            };
+
+            let signer = KrillSigner::build(&d).unwrap();
+            let key_id = signer.create_key().unwrap();
+            let key = signer.get_key_info(&key_id).unwrap();
+            assert!(csr_info(key.clone()).check_key(2048).is_ok());
+            assert!(matches!(
+                csr_info(key).check_key(4096),
+                Err(Error::Rfc6492InvalidCsrSent(_))
+            ));
+
+            let weak = Rsa::generate(1024).unwrap();
+            let mut der = Bytes::from(weak.public_key_to_der().unwrap());
+            let key = PublicKey::decode(&mut der).unwrap();
+            assert!(matches!(
+                csr_info(key).check_key(2048),
+                Err(Error::Rfc6492InvalidCsrSent(_))
+            ));
+        })
+    }
+
+    #[test]
+    fn validity_periods() {
+        let validity = SignSupport::sign_validity(chrono::Duration::hours(1)).unwrap();
+        assert!(validity.not_before() < Time::now());
+        assert!(validity.not_after() > Time::now());
+        assert!(SignSupport::check_validity(&SignSupport::sign_validity_hours(1)).is_ok());
+
+        assert!(SignSupport::sign_validity(chrono::Duration::zero()).is_err());
+        assert!(SignSupport::sign_validity(chrono::Duration::days(-1)).is_err());
+        assert!(SignSupport::check_validity(&SignSupport::sign_validity_days(-1)).is_err());
+    }
+}
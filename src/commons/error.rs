@@ -124,6 +124,7 @@ pub enum ApiAuthError {
     ApiAuthPermanentError(String),
     ApiAuthTransientError(String),
     ApiAuthSessionExpired(String),
+    ApiAuthProviderTimeout(String),
     ApiInsufficientRights(String),
 }
 
@@ -135,6 +136,7 @@ impl Display for ApiAuthError {
             | ApiAuthError::ApiAuthPermanentError(err)
             | ApiAuthError::ApiAuthTransientError(err)
             | ApiAuthError::ApiAuthSessionExpired(err)
+            | ApiAuthError::ApiAuthProviderTimeout(err)
             | ApiAuthError::ApiInsufficientRights(err) => write!(f, "{}", &err),
         }
     }
@@ -148,6 +150,7 @@ impl From<Error> for ApiAuthError {
             Error::ApiInsufficientRights(e) => ApiAuthError::ApiInsufficientRights(e),
             Error::ApiAuthTransientError(e) => ApiAuthError::ApiAuthTransientError(e),
             Error::ApiAuthSessionExpired(e) => ApiAuthError::ApiAuthSessionExpired(e),
+            Error::ApiAuthProviderTimeout(e) => ApiAuthError::ApiAuthProviderTimeout(e),
             Error::ApiInvalidCredentials(e) => ApiAuthError::ApiInvalidCredentials(e),
             _ => ApiAuthError::ApiAuthPermanentError(e.to_string()),
         }
@@ -164,9 +167,11 @@ pub enum Error {
     KeyValueError(KeyValueError),
     AggregateStoreError(AggregateStoreError),
     SignerError(String),
+    SignerUnavailable(String),
     HttpsSetup(String),
     HttpClientError(httpclient::Error),
     ConfigError(String),
+    SessionKeyCorrupt(String, usize),
     //-----------------------------------------------------------------
     // General API Client Issues
     //-----------------------------------------------------------------
@@ -184,7 +189,9 @@ pub enum Error {
     ApiAuthPermanentError(String),
     ApiAuthTransientError(String),
     ApiAuthSessionExpired(String),
+    ApiAuthProviderTimeout(String),
     ApiInsufficientRights(String),
+    ApiTooManyRequests(String),
 
     //-----------------------------------------------------------------
     // Repository Issues
@@ -253,6 +260,7 @@ pub enum Error {
     CaChildMustHaveResources(Handle, ChildHandle),
     CaChildExtraResources(Handle, ChildHandle),
     CaChildUnauthorized(Handle, ChildHandle),
+    CaChildUriNotGlobal(String),
 
     //-----------------------------------------------------------------
     // RouteAuthorizations - ROAs
@@ -309,9 +317,15 @@ impl fmt::Display for Error {
             Error::KeyValueError(e) => write!(f, "Key/Value error: {}", e),
             Error::AggregateStoreError(e) => write!(f, "Persistence error: {}", e),
             Error::SignerError(e) => write!(f, "Signing issue: {}", e),
+            Error::SignerUnavailable(e) => write!(f, "Signer temporarily unavailable: {}", e),
             Error::HttpsSetup(e) => write!(f, "Cannot set up HTTPS: {}", e),
             Error::HttpClientError(e) => write!(f, "HTTP client error: {}", e),
             Error::ConfigError(e) => write!(f, "Configuration error: {}", e),
+            Error::SessionKeyCorrupt(path, len) => write!(
+                f,
+                "Login session key file '{}' is corrupt, expected 32 bytes but found {}. Remove it to generate a new key, this ends all login sessions",
+                path, len
+            ),
 
             //-----------------------------------------------------------------
             // General API Client Issues
@@ -330,7 +344,9 @@ impl fmt::Display for Error {
             Error::ApiAuthPermanentError(e) => write!(f, "Authentication error: {}", e),
             Error::ApiAuthTransientError(e) => write!(f, "Transient authentication error: {}", e),
             Error::ApiAuthSessionExpired(e) => write!(f, "Session expired: {}", e),
+            Error::ApiAuthProviderTimeout(e) => write!(f, "Authentication provider timed out: {}", e),
             Error::ApiInsufficientRights(e) => write!(f, "Insufficient rights: {}", e),
+            Error::ApiTooManyRequests(e) => write!(f, "Too many requests: {}", e),
 
             //-----------------------------------------------------------------
             // Repository Issues
@@ -402,6 +418,7 @@ impl fmt::Display for Error {
             Error::CaChildMustHaveResources(ca, child) => write!(f, "Child '{}' for CA '{}' MUST have resources specified", child, ca),
             Error::CaChildExtraResources(ca, child) => write!(f, "Child '{}' cannot have resources not held by CA '{}'", child, ca),
             Error::CaChildUnauthorized(ca, child) => write!(f, "CA '{}' does not know id certificate for child '{}'", ca, child),
+            Error::CaChildUriNotGlobal(uri) => write!(f, "Will not issue a certificate with non-global URI '{}'", uri),
 
             //-----------------------------------------------------------------
             // RouteAuthorizations - ROAs
@@ -501,7 +518,10 @@ impl From<tls_keys::Error> for Error {
 
 impl From<crate::commons::crypto::Error> for Error {
     fn from(e: crate::commons::crypto::Error) -> Self {
-        Error::signer(e)
+        match e {
+            crate::commons::crypto::Error::SignerUnavailable(e) => Error::SignerUnavailable(e),
+            e => Error::signer(e),
+        }
     }
 }
 
@@ -513,6 +533,7 @@ impl From<ApiAuthError> for Error {
             ApiAuthError::ApiInsufficientRights(e) => Error::ApiInsufficientRights(e),
             ApiAuthError::ApiAuthTransientError(e) => Error::ApiAuthTransientError(e),
             ApiAuthError::ApiAuthSessionExpired(e) => Error::ApiAuthSessionExpired(e),
+            ApiAuthError::ApiAuthProviderTimeout(e) => Error::ApiAuthProviderTimeout(e),
             ApiAuthError::ApiInvalidCredentials(e) => Error::ApiInvalidCredentials(e),
         }
     }
@@ -553,9 +574,11 @@ impl Error {
     pub fn status(&self) -> StatusCode {
         match self {
             // Most is bad requests by users, so just mapping the things that are not
-            Error::IoError(_) | Error::SignerError(_) | Error::AggregateStoreError(_) | Error::PublishingObjects(_) => {
-                StatusCode::INTERNAL_SERVER_ERROR
-            }
+            Error::IoError(_)
+            | Error::SignerError(_)
+            | Error::AggregateStoreError(_)
+            | Error::PublishingObjects(_)
+            | Error::SessionKeyCorrupt(_, _) => StatusCode::INTERNAL_SERVER_ERROR,
             Error::PublisherUnknown(_)
             | Error::CaUnknown(_)
             | Error::CaChildUnknown(_, _)
@@ -568,6 +591,9 @@ impl Error {
             | Error::ApiAuthSessionExpired(_)
             | Error::ApiLoginError(_) => StatusCode::UNAUTHORIZED,
             Error::ApiInsufficientRights(_) => StatusCode::FORBIDDEN,
+            Error::SignerUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Error::ApiAuthProviderTimeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            Error::ApiTooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
 
             _ => StatusCode::BAD_REQUEST,
         }
@@ -591,6 +617,9 @@ impl Error {
             // internal server error
             Error::SignerError(e) => ErrorResponse::new("sys-signer", &self).with_cause(e),
 
+            // service unavailable, clients should retry later
+            Error::SignerUnavailable(e) => ErrorResponse::new("sys-signer-unavailable", &self).with_cause(e),
+
             // internal server error
             Error::HttpsSetup(e) => ErrorResponse::new("sys-https", &self).with_cause(e),
 
@@ -600,6 +629,9 @@ impl Error {
             // internal configuration error
             Error::ConfigError(e) => ErrorResponse::new("sys-config", &self).with_cause(e),
 
+            // internal server error
+            Error::SessionKeyCorrupt(path, _) => ErrorResponse::new("sys-session-key", &self).with_cause(path),
+
             //-----------------------------------------------------------------
             // General API Client Issues (label: api-*)
             //-----------------------------------------------------------------
@@ -632,8 +664,12 @@ impl Error {
 
             Error::ApiAuthSessionExpired(e) => ErrorResponse::new("api-auth-session-expired", &self).with_cause(e),
 
+            Error::ApiAuthProviderTimeout(e) => ErrorResponse::new("api-auth-provider-timeout", &self).with_cause(e),
+
             Error::ApiInsufficientRights(e) => ErrorResponse::new("api-insufficient-rights", &self).with_cause(e),
 
+            Error::ApiTooManyRequests(e) => ErrorResponse::new("api-too-many-requests", &self).with_cause(e),
+
             //-----------------------------------------------------------------
             // Repository Issues (label: repo-*)
             //-----------------------------------------------------------------
@@ -743,6 +779,7 @@ impl Error {
             Error::CaChildUnauthorized(ca, child) => ErrorResponse::new("ca-child-unauthorized", &self)
                 .with_ca(ca)
                 .with_child(child),
+            Error::CaChildUriNotGlobal(uri) => ErrorResponse::new("ca-child-uri-not-global", &self).with_uri(uri),
 
             // RouteAuthorizations
             Error::CaAuthorizationUnknown(ca, auth) => {
@@ -889,6 +926,10 @@ mod tests {
             include_str!("../../test-resources/errors/sys-signer.json"),
             Error::SignerError("signer issue".to_string()),
         );
+        verify(
+            include_str!("../../test-resources/errors/sys-signer-unavailable.json"),
+            Error::SignerUnavailable("session reset".to_string()),
+        );
         verify(
             include_str!("../../test-resources/errors/sys-https.json"),
             Error::HttpsSetup("can't find pem file".to_string()),
@@ -1052,6 +1093,10 @@ mod tests {
             include_str!("../../test-resources/errors/ca-child-unauthorized.json"),
             Error::CaChildUnauthorized(ca.clone(), child),
         );
+        verify(
+            include_str!("../../test-resources/errors/ca-child-uri-not-global.json"),
+            Error::CaChildUriNotGlobal("rsync://localhost/repo/child/".to_string()),
+        );
 
         verify(
             include_str!("../../test-resources/errors/ca-roa-unknown.json"),
@@ -60,4 +60,9 @@ pub trait Aggregate: Storable + Send + Sync + 'static {
     /// The command is moved, because we want to enable moving its data
     /// without reallocating.
     fn process_command(&self, command: Self::Command) -> Result<Vec<Self::Event>, Self::Error>;
+
+    /// Lets the stored details of a successful command record information that
+    /// is only known after it was processed, e.g. the identifiers of keys that
+    /// were created. The default does nothing.
+    fn record_effect(_details: &mut Self::StorableCommandDetails, _events: &[Self::Event]) {}
 }
@@ -0,0 +1,456 @@
+//! Append-only audit log of the commands sent to aggregate stores.
+//!
+//! Each line in the log is a json entry recording who did what to which
+//! aggregate, and whether it succeeded. The log is kept independently of the
+//! command files of the aggregates, so that it survives archiving, compaction
+//! and the removal of aggregates.
+//!
+//! Entries include the hash of the previous entry of the same store, so that
+//! changing or removing entries can be detected with `AuditLog::verify`. Several
+//! stores may share a log file, each keeps its own chain.
+//!
+//! The hash of the last entry of each store is also saved in a small file next
+//! to the log, so that the log does not need to be read in full when it is
+//! opened.
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use rpki::x509::Time;
+
+use crate::commons::api::Handle;
+use crate::commons::error::KrillIoError;
+use crate::commons::eventsourcing::{AggregateStoreError, KeyValueError};
+use crate::commons::util::sha256;
+
+//------------ AuditOutcome --------------------------------------------------
+
+/// The outcome of a command.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case", tag = "result")]
+pub enum AuditOutcome {
+    Success { events: usize },
+    NoOp,
+    Error { msg: String },
+}
+
+//------------ AuditEntry ----------------------------------------------------
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct AuditEntry {
+    pub time: i64,
+    pub store: String,
+    pub actor: String,
+    pub action: String,
+    pub handle: Handle,
+    pub outcome: AuditOutcome,
+    /// Hash of the previous entry for the same store, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prev_hash: Option<String>,
+}
+
+impl AuditEntry {
+    fn hash(line: &str) -> String {
+        hex::encode(sha256(line.as_bytes()))
+    }
+}
+
+//------------ AuditHead -----------------------------------------------------
+
+/// The hash of the last entry of a store, and the size of the log after it was
+/// appended. Saved next to the log, so that only entries appended after it need
+/// to be read when the log is opened.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+struct AuditHead {
+    hash: String,
+    log_size: u64,
+}
+
+//------------ AuditChains ---------------------------------------------------
+
+/// The result of reading the entries of a log.
+struct AuditChains {
+    /// The hash of the last entry for each store that was read.
+    last_hashes: HashMap<String, String>,
+    /// The number of entries read.
+    entries: usize,
+    /// The number of the last line and its offset, if it could not be parsed.
+    torn: Option<(usize, u64)>,
+}
+
+//------------ AuditLog ------------------------------------------------------
+
+/// Appends the entries for one aggregate store to an audit log file.
+pub struct AuditLog {
+    path: PathBuf,
+    store: String,
+    // The hash of the last entry for the store. The lock also ensures that
+    // entries are appended one at a time.
+    last_hash: Mutex<Option<String>>,
+}
+
+impl AuditLog {
+    /// Opens the log file, creating it if needed. The last entry for the store
+    /// is looked up, so that new entries continue its chain.
+    ///
+    /// If the last line of the log cannot be parsed, then it is assumed that
+    /// Krill stopped while appending it. The line is removed, and a warning is
+    /// logged. Other unparsable lines, and broken chains, are an error.
+    pub fn open(path: &Path, store: &str) -> Result<Self, AggregateStoreError> {
+        let last_hash = if path.exists() {
+            Self::find_last_hash(path, store)?
+        } else {
+            None
+        };
+
+        Ok(AuditLog {
+            path: path.to_path_buf(),
+            store: store.to_string(),
+            last_hash: Mutex::new(last_hash),
+        })
+    }
+
+    /// Returns the hash of the last entry for the store. Only the entries after
+    /// the saved head of the store are read, unless the head is missing or does
+    /// not match the log, e.g. because the log was rotated.
+    fn find_last_hash(path: &Path, store: &str) -> Result<Option<String>, AggregateStoreError> {
+        if let Some(head) = Self::read_head(path, store) {
+            if let Some(chains) = Self::read_chains_after(path, store, head) {
+                Self::remove_torn_line(path, &chains)?;
+                return Ok(chains.last_hashes.get(store).cloned());
+            }
+
+            warn!(
+                "Saved last entry for '{}' does not match audit log '{}', will read the full log",
+                store,
+                path.to_string_lossy()
+            );
+        }
+
+        let chains = Self::read_chains(path, 0, HashMap::new(), None)?;
+        Self::remove_torn_line(path, &chains)?;
+        Ok(chains.last_hashes.get(store).cloned())
+    }
+
+    /// Reads the entries for the store after its saved head. Returns None if the
+    /// head is not at the start of a line in the log, or if the entries do not
+    /// continue its chain.
+    fn read_chains_after(path: &Path, store: &str, head: AuditHead) -> Option<AuditChains> {
+        if head.log_size > 0 {
+            let mut file = File::open(path).ok()?;
+            let mut byte = [0u8];
+            file.seek(SeekFrom::Start(head.log_size - 1)).ok()?;
+            file.read_exact(&mut byte).ok()?;
+            if byte[0] != b'\n' {
+                return None;
+            }
+        }
+
+        let mut last_hashes = HashMap::new();
+        last_hashes.insert(store.to_string(), head.hash);
+        Self::read_chains(path, head.log_size, last_hashes, Some(store)).ok()
+    }
+
+    fn remove_torn_line(path: &Path, chains: &AuditChains) -> Result<(), AggregateStoreError> {
+        if let Some((_, offset)) = chains.torn {
+            warn!(
+                "Removing incomplete last line at byte {} from audit log '{}', Krill probably stopped while writing it",
+                offset,
+                path.to_string_lossy()
+            );
+            OpenOptions::new()
+                .write(true)
+                .open(path)
+                .and_then(|file| file.set_len(offset))
+                .map_err(|e| Self::io_error(path, e))?;
+        }
+        Ok(())
+    }
+
+    fn head_path(path: &Path, store: &str) -> PathBuf {
+        let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+        file_name.push(format!(".{}.head", store));
+        path.with_file_name(file_name)
+    }
+
+    fn read_head(path: &Path, store: &str) -> Option<AuditHead> {
+        let bytes = fs::read(Self::head_path(path, store)).ok()?;
+        serde_json::from_slice(&bytes).ok()
+    }
+
+    /// Appends an entry. Errors are logged, rather than returned, because the
+    /// command was already processed when it is audited.
+    pub fn log(&self, actor: &str, action: &str, handle: &Handle, outcome: AuditOutcome) {
+        let mut last_hash = self.last_hash.lock().unwrap();
+
+        let entry = AuditEntry {
+            time: Time::now().timestamp(),
+            store: self.store.clone(),
+            actor: actor.to_string(),
+            action: action.to_string(),
+            handle: handle.clone(),
+            outcome,
+            prev_hash: last_hash.clone(),
+        };
+
+        match self.append(&entry) {
+            Ok(head) => {
+                if let Err(e) = self.save_head(&head) {
+                    warn!("Could not save last audit log entry for '{}'. Error: {}", self.store, e);
+                }
+                *last_hash = Some(head.hash)
+            }
+            Err(e) => error!(
+                "Could not write audit log entry for '{}' by '{}' on '{}'. Error: {}",
+                entry.action, entry.actor, entry.handle, e
+            ),
+        }
+    }
+
+    fn append(&self, entry: &AuditEntry) -> Result<AuditHead, AggregateStoreError> {
+        let line = serde_json::to_string(entry).map_err(KeyValueError::JsonError)?;
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|e| Self::io_error(&self.path, e))?;
+        file.write_all(format!("{}\n", line).as_bytes())
+            .map_err(|e| Self::io_error(&self.path, e))?;
+        let log_size = file.metadata().map_err(|e| Self::io_error(&self.path, e))?.len();
+
+        Ok(AuditHead {
+            hash: AuditEntry::hash(&line),
+            log_size,
+        })
+    }
+
+    fn save_head(&self, head: &AuditHead) -> Result<(), AggregateStoreError> {
+        let path = Self::head_path(&self.path, &self.store);
+        let json = serde_json::to_vec(head).map_err(KeyValueError::JsonError)?;
+        fs::write(&path, json).map_err(|e| Self::io_error(&path, e))
+    }
+
+    /// Verifies that the entries of all stores in the log form unbroken chains,
+    /// and returns the number of entries. Fails with the number of the first line
+    /// that was changed, that follows a removed line, or that is incomplete.
+    pub fn verify(path: &Path) -> Result<usize, AggregateStoreError> {
+        let chains = Self::read_chains(path, 0, HashMap::new(), None)?;
+        match chains.torn {
+            Some((line_nr, _)) => Err(AggregateStoreError::AuditLogCorrupt(
+                line_nr,
+                "incomplete last line".to_string(),
+            )),
+            None => Ok(chains.entries),
+        }
+    }
+
+    /// Reads the entries from the given offset, and checks their chains starting
+    /// from the given last hashes. Only the chain of the given store is checked,
+    /// if any. The last line is returned as torn if it cannot be parsed, other
+    /// lines which cannot be parsed are an error.
+    fn read_chains(
+        path: &Path,
+        offset: u64,
+        mut last_hashes: HashMap<String, String>,
+        store: Option<&str>,
+    ) -> Result<AuditChains, AggregateStoreError> {
+        let mut file = File::open(path).map_err(|e| Self::io_error(path, e))?;
+        let size = file.metadata().map_err(|e| Self::io_error(path, e))?.len();
+        file.seek(SeekFrom::Start(offset))
+            .map_err(|e| Self::io_error(path, e))?;
+        let mut reader = BufReader::new(file);
+
+        let mut entries = 0;
+        let mut line_start = offset;
+        let mut line_nr = 0;
+
+        loop {
+            let mut bytes = vec![];
+            let read = reader
+                .read_until(b'\n', &mut bytes)
+                .map_err(|e| Self::io_error(path, e))?;
+            if read == 0 {
+                break;
+            }
+            line_nr += 1;
+
+            let is_last = line_start + read as u64 == size;
+            if bytes.last() == Some(&b'\n') {
+                bytes.pop();
+            }
+
+            let parsed = String::from_utf8(bytes).map_err(|e| e.to_string()).and_then(|line| {
+                serde_json::from_str::<AuditEntry>(&line)
+                    .map(|entry| (line, entry))
+                    .map_err(|e| e.to_string())
+            });
+
+            let (line, entry) = match parsed {
+                Ok(parsed) => parsed,
+                Err(_) if is_last => {
+                    return Ok(AuditChains {
+                        last_hashes,
+                        entries,
+                        torn: Some((line_nr, line_start)),
+                    })
+                }
+                Err(e) => return Err(AggregateStoreError::AuditLogCorrupt(line_nr, e)),
+            };
+
+            if store.map_or(true, |store| store == entry.store) {
+                if entry.prev_hash.as_ref() != last_hashes.get(&entry.store) {
+                    return Err(AggregateStoreError::AuditLogCorrupt(
+                        line_nr,
+                        format!("previous entry for store '{}' does not match", entry.store),
+                    ));
+                }
+                last_hashes.insert(entry.store, AuditEntry::hash(&line));
+            }
+
+            entries += 1;
+            line_start += read as u64;
+        }
+
+        Ok(AuditChains {
+            last_hashes,
+            entries,
+            torn: None,
+        })
+    }
+
+    fn io_error(path: &Path, e: std::io::Error) -> AggregateStoreError {
+        AggregateStoreError::IoError(KrillIoError::new(
+            format!("Could not use audit log '{}'", path.to_string_lossy()),
+            e,
+        ))
+    }
+}
+
+//------------ Tests ---------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+
+    use std::fs;
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::test;
+
+    #[test]
+    fn chained_entries_detect_changes() {
+        test::test_under_tmp(|d| {
+            let path = d.join("audit.log");
+            let handle = Handle::from_str("ca").unwrap();
+
+            let cas = AuditLog::open(&path, "cas").unwrap();
+            let pubd = AuditLog::open(&path, "pubd").unwrap();
+            cas.log(
+                "user:alice",
+                "cmd-ca-roas-updated",
+                &handle,
+                AuditOutcome::Success { events: 1 },
+            );
+            pubd.log("krill", "pubd-publish", &handle, AuditOutcome::NoOp);
+
+            // Entries continue the chain of the store after a restart
+            let cas = AuditLog::open(&path, "cas").unwrap();
+            cas.log(
+                "user:bob",
+                "cmd-ca-roas-updated",
+                &handle,
+                AuditOutcome::Error {
+                    msg: "nope".to_string(),
+                },
+            );
+            assert_eq!(AuditLog::verify(&path).unwrap(), 3);
+
+            let content = fs::read_to_string(&path).unwrap();
+            let lines: Vec<&str> = content.lines().collect();
+
+            let changed = content.replace("user:alice", "user:mallory");
+            fs::write(&path, changed).unwrap();
+            assert!(matches!(
+                AuditLog::verify(&path),
+                Err(AggregateStoreError::AuditLogCorrupt(3, _))
+            ));
+
+            let removed = format!("{}\n{}\n", lines[1], lines[2]);
+            fs::write(&path, removed).unwrap();
+            assert!(matches!(
+                AuditLog::verify(&path),
+                Err(AggregateStoreError::AuditLogCorrupt(2, _))
+            ));
+        })
+    }
+
+    #[test]
+    fn torn_last_line_is_removed_at_open() {
+        test::test_under_tmp(|d| {
+            let path = d.join("audit.log");
+            let handle = Handle::from_str("ca").unwrap();
+
+            let cas = AuditLog::open(&path, "cas").unwrap();
+            cas.log("krill", "cmd-ca-publish", &handle, AuditOutcome::NoOp);
+            cas.log("krill", "cmd-ca-publish", &handle, AuditOutcome::NoOp);
+
+            let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+            file.write_all(b"{\"time\":16").unwrap();
+
+            assert!(matches!(
+                AuditLog::verify(&path),
+                Err(AggregateStoreError::AuditLogCorrupt(3, _))
+            ));
+
+            // With and without the saved head
+            for remove_head in &[false, true] {
+                if *remove_head {
+                    fs::remove_file(AuditLog::head_path(&path, "cas")).unwrap();
+                    let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+                    file.write_all(b"{\"time\":16").unwrap();
+                }
+
+                let cas = AuditLog::open(&path, "cas").unwrap();
+                cas.log("krill", "cmd-ca-publish", &handle, AuditOutcome::NoOp);
+            }
+            assert_eq!(AuditLog::verify(&path).unwrap(), 4);
+        })
+    }
+
+    #[test]
+    fn saved_head_is_used_at_open() {
+        test::test_under_tmp(|d| {
+            let path = d.join("audit.log");
+            let handle = Handle::from_str("ca").unwrap();
+
+            let cas = AuditLog::open(&path, "cas").unwrap();
+            cas.log("user:alice", "cmd-ca-publish", &handle, AuditOutcome::NoOp);
+            let head = fs::read(AuditLog::head_path(&path, "cas")).unwrap();
+
+            // Entries after the saved head continue the chain, e.g. if Krill stopped
+            // before the head was saved.
+            cas.log("krill", "cmd-ca-publish", &handle, AuditOutcome::NoOp);
+            let pubd = AuditLog::open(&path, "pubd").unwrap();
+            pubd.log("krill", "pubd-publish", &handle, AuditOutcome::NoOp);
+            fs::write(AuditLog::head_path(&path, "cas"), &head).unwrap();
+
+            let cas = AuditLog::open(&path, "cas").unwrap();
+            cas.log("krill", "cmd-ca-publish", &handle, AuditOutcome::NoOp);
+            assert_eq!(AuditLog::verify(&path).unwrap(), 4);
+
+            // Entries before the saved head are not read
+            let content = fs::read_to_string(&path).unwrap();
+            fs::write(&path, content.replace("user:alice", "user:carol")).unwrap();
+            assert!(AuditLog::verify(&path).is_err());
+            assert!(AuditLog::open(&path, "cas").is_ok());
+
+            // Unless the head does not match the log
+            fs::write(&path, content.replace("user:alice", "user:carol")).unwrap();
+            fs::remove_file(AuditLog::head_path(&path, "cas")).unwrap();
+            assert!(AuditLog::open(&path, "cas").is_err());
+        })
+    }
+}
@@ -9,6 +9,10 @@ use crate::commons::{
     api::{CommandHistoryRecord, CommandSummary, Handle, StoredEffect},
 };
 
+/// Recorded as the actor of commands sent by an actor without a name, so that
+/// every stored command has an author.
+const UNKNOWN_ACTOR: &str = "unknown";
+
 //------------ WithStorableDetails -------------------------------------------
 
 /// Must be implemented for all 'StorableDetails' used in Commands.
@@ -48,6 +52,13 @@ pub trait Command: fmt::Display + Send + Sync {
 
     /// Get the storable information for this command
     fn store(&self) -> Self::StorableDetails;
+
+    /// Returns true if this command can be built again and re-applied to a
+    /// newer version of the aggregate, when another command updated the
+    /// aggregate first. See `AggregateStore::command_with_retry`.
+    fn retry_safe(&self) -> bool {
+        false
+    }
 }
 
 //------------ SentCommand ---------------------------------------------------
@@ -80,14 +91,21 @@ impl<C: CommandDetails> Command for SentCommand<C> {
     fn actor(&self) -> &str {
         &self.actor
     }
+
+    fn retry_safe(&self) -> bool {
+        self.details.retry_safe()
+    }
 }
 
 impl<C: CommandDetails> SentCommand<C> {
     pub fn new(id: &Handle, version: Option<u64>, details: C, actor: &Actor) -> Self {
-        let actor_name = if actor.is_user() {
-            format!("user:{}", actor.name())
-        } else {
-            actor.name().to_string()
+        let actor_name = match actor.name() {
+            "" => {
+                warn!("Command for '{}' was sent by an actor without a name", id);
+                UNKNOWN_ACTOR.to_string()
+            }
+            name if actor.is_user() => format!("user:{}", name),
+            name => name.to_string(),
         };
 
         SentCommand {
@@ -98,6 +116,17 @@ impl<C: CommandDetails> SentCommand<C> {
         }
     }
 
+    /// Returns a copy of this command for the given version of the aggregate.
+    pub fn with_version(&self, version: u64) -> Self
+    where
+        C: Clone,
+    {
+        SentCommand {
+            version: Some(version),
+            ..self.clone()
+        }
+    }
+
     pub fn into_details(self) -> C {
         self.details
     }
@@ -127,6 +156,14 @@ pub trait CommandDetails: fmt::Display + Send + Sync + 'static {
     type StorableDetails: WithStorableDetails;
 
     fn store(&self) -> Self::StorableDetails;
+
+    /// Returns true if the command only depends on its own details, and not
+    /// on the version of the aggregate it was built for. E.g. commands that
+    /// set a value, rather than commands that change a value, or that were
+    /// decided on based on what the sender saw. Defaults to false.
+    fn retry_safe(&self) -> bool {
+        false
+    }
 }
 
 //------------ StoredCommand -------------------------------------------------
@@ -167,6 +204,10 @@ impl<S: WithStorableDetails> StoredCommand<S> {
         }
     }
 
+    pub fn actor(&self) -> &str {
+        &self.actor
+    }
+
     pub fn time(&self) -> Time {
         self.time
     }
@@ -234,6 +275,12 @@ impl<C: Command> StoredCommandBuilder<C> {
         }
     }
 
+    /// Gives access to the details, so that they can be completed once the
+    /// command has been processed.
+    pub fn details_mut(&mut self) -> &mut C::StorableDetails {
+        &mut self.details
+    }
+
     fn finish(self, effect: StoredEffect) -> StoredCommand<C::StorableDetails> {
         StoredCommand {
             actor: self.actor,
@@ -1,12 +1,18 @@
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
-use std::io::Write;
+use std::io::{self, BufReader, Write};
 use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::UNIX_EPOCH;
 use std::{any::Any, path::Path};
 use std::{fmt, fs};
 
+use libflate::gzip;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 
+use rpki::x509::Time;
+
 use crate::commons::{error::KrillIoError, util::file};
 
 use super::KeyStoreVersion;
@@ -80,11 +86,40 @@ impl fmt::Display for KeyStoreKey {
     }
 }
 
+//------------ ArchivedKey ---------------------------------------------------
+
+/// A key in one of the archive scopes, see `KeyValueStore::archived_keys`.
+#[derive(Clone, Debug)]
+pub struct ArchivedKey {
+    /// The key under which the value is archived, which can be used to get it.
+    pub key: KeyStoreKey,
+    /// The archive scope: "archived", "corrupt" or "surplus".
+    pub archive: String,
+    /// The time the value was archived, in seconds since the epoch.
+    pub archived_at: i64,
+}
+
+impl ArchivedKey {
+    /// Returns the key that the value had before it was archived.
+    pub fn original(&self) -> KeyStoreKey {
+        let scope = self
+            .key
+            .scope()
+            .and_then(|scope| scope.rsplitn(2, '/').nth(1))
+            .map(str::to_string);
+        KeyStoreKey::new(scope, self.key.name().to_string())
+    }
+}
+
 /// Using an enum here, because we expect to have more implementations in future.
 /// Not using generics because it's harder on the compiler.
-#[derive(Debug)]
+///
+/// Clones use the same stored values, so that e.g. a worker thread can store
+/// values as well.
+#[derive(Clone, Debug)]
 pub enum KeyValueStore {
     Disk(KeyValueStoreDiskImpl),
+    Memory(KeyValueStoreMemoryImpl),
 }
 
 impl KeyValueStore {
@@ -96,28 +131,96 @@ impl KeyValueStore {
             file::create_dir(&base)?;
         }
 
-        Ok(KeyValueStore::Disk(KeyValueStoreDiskImpl { base }))
+        Ok(KeyValueStore::Disk(KeyValueStoreDiskImpl {
+            base,
+            archive_base: None,
+            archive_max_entries: 0,
+        }))
+    }
+
+    /// Creates a store which keeps all values in memory. Nothing is written to
+    /// disk, so this is intended for tests.
+    pub fn memory(name_space: &str) -> Self {
+        KeyValueStore::Memory(KeyValueStoreMemoryImpl {
+            name_space: name_space.to_string(),
+            archive_max_entries: 0,
+            content: Arc::new(RwLock::new(MemoryContent::default())),
+        })
+    }
+
+    /// Sets where archived, corrupt and surplus values are kept, and how many
+    /// of them are kept in each archive scope.
+    ///
+    /// If a dir is given then archived values are moved to a directory named
+    /// after the name space of this store under that dir, rather than to the
+    /// store itself. They can still be found using their archive keys.
+    ///
+    /// If max_entries is not 0, then the oldest values in an archive scope are
+    /// removed whenever a value is archived to it, so that at most max_entries
+    /// are kept. Values outside of archive scopes are never removed by this.
+    ///
+    /// In memory stores ignore the dir.
+    pub fn set_archive(&mut self, dir: Option<&Path>, max_entries: usize) {
+        match self {
+            KeyValueStore::Disk(disk_store) => disk_store.set_archive(dir, max_entries),
+            KeyValueStore::Memory(memory_store) => memory_store.set_archive(max_entries),
+        }
     }
 
     /// Stores a key value pair, serialized as json, overwrite existing
     pub fn store<V: Any + Serialize>(&self, key: &KeyStoreKey, value: &V) -> Result<(), KeyValueError> {
         match self {
-            KeyValueStore::Disk(disk_store) => disk_store.store(key, value),
+            KeyValueStore::Disk(disk_store) => disk_store.store(key, value, false, false),
+            KeyValueStore::Memory(memory_store) => memory_store.store(key, value),
+        }
+    }
+
+    /// Stores a key value pair, serialized as gzip compressed json, overwrite existing
+    pub fn store_compressed<V: Any + Serialize>(&self, key: &KeyStoreKey, value: &V) -> Result<(), KeyValueError> {
+        match self {
+            KeyValueStore::Disk(disk_store) => disk_store.store(key, value, true, false),
+            KeyValueStore::Memory(memory_store) => memory_store.store(key, value),
+        }
+    }
+
+    /// Stores a key value pair, optionally compressed, overwrite existing. Only
+    /// returns when the value and the directory entry for it were written to
+    /// disk, so that the value is complete if it is found after a crash.
+    pub fn store_synced<V: Any + Serialize>(
+        &self,
+        key: &KeyStoreKey,
+        value: &V,
+        compress: bool,
+    ) -> Result<(), KeyValueError> {
+        match self {
+            KeyValueStore::Disk(disk_store) => disk_store.store(key, value, compress, true),
+            KeyValueStore::Memory(memory_store) => memory_store.store(key, value),
         }
     }
 
     /// Stores a new key value pair, returns an error if the key exists
     pub fn store_new<V: Any + Serialize>(&self, key: &KeyStoreKey, value: &V) -> Result<(), KeyValueError> {
         match self {
-            KeyValueStore::Disk(disk_store) => disk_store.store_new(key, value),
+            KeyValueStore::Disk(disk_store) => disk_store.store_new(key, value, false),
+            KeyValueStore::Memory(memory_store) => memory_store.store_new(key, value),
+        }
+    }
+
+    /// Stores a new key value pair as gzip compressed json, returns an error if the key exists
+    pub fn store_new_compressed<V: Any + Serialize>(&self, key: &KeyStoreKey, value: &V) -> Result<(), KeyValueError> {
+        match self {
+            KeyValueStore::Disk(disk_store) => disk_store.store_new(key, value, true),
+            KeyValueStore::Memory(memory_store) => memory_store.store_new(key, value),
         }
     }
 
     /// Gets a value for a key, returns an error if the value cannot be deserialized,
-    /// returns None if it cannot be found.
+    /// returns None if it cannot be found. Values stored compressed are decompressed
+    /// transparently.
     pub fn get<V: DeserializeOwned>(&self, key: &KeyStoreKey) -> Result<Option<V>, KeyValueError> {
         match self {
             KeyValueStore::Disk(disk_store) => disk_store.get(key),
+            KeyValueStore::Memory(memory_store) => memory_store.get(key),
         }
     }
 
@@ -125,6 +228,7 @@ impl KeyValueStore {
     pub fn has(&self, key: &KeyStoreKey) -> Result<bool, KeyValueError> {
         match self {
             KeyValueStore::Disk(disk_store) => Ok(disk_store.has(key)),
+            KeyValueStore::Memory(memory_store) => Ok(memory_store.has(key)),
         }
     }
 
@@ -132,6 +236,7 @@ impl KeyValueStore {
     pub fn drop_key(&self, key: &KeyStoreKey) -> Result<(), KeyValueError> {
         match self {
             KeyValueStore::Disk(disk_store) => disk_store.drop_key(key),
+            KeyValueStore::Memory(memory_store) => memory_store.drop_key(key),
         }
     }
 
@@ -139,6 +244,7 @@ impl KeyValueStore {
     pub fn drop_scope(&self, scope: &str) -> Result<(), KeyValueError> {
         match self {
             KeyValueStore::Disk(disk_store) => disk_store.drop_scope(scope),
+            KeyValueStore::Memory(memory_store) => memory_store.drop_scope(scope),
         }
     }
 
@@ -146,12 +252,13 @@ impl KeyValueStore {
     pub fn move_key(&self, from: &KeyStoreKey, to: &KeyStoreKey) -> Result<(), KeyValueError> {
         match self {
             KeyValueStore::Disk(disk_store) => disk_store.move_key(from, to),
+            KeyValueStore::Memory(memory_store) => memory_store.move_key(from, to),
         }
     }
 
     /// Archive a key
     pub fn archive(&self, key: &KeyStoreKey) -> Result<(), KeyValueError> {
-        self.move_key(key, &key.archived())
+        self.archive_as(key, &key.archived())
     }
 
     /// Archive a key to an arbitrary scope
@@ -161,18 +268,63 @@ impl KeyValueStore {
 
     /// Archive a key as corrupt
     pub fn archive_corrupt(&self, key: &KeyStoreKey) -> Result<(), KeyValueError> {
-        self.move_key(key, &key.corrupt())
+        self.archive_as(key, &key.corrupt())
     }
 
     /// Archive a key as surplus
     pub fn archive_surplus(&self, key: &KeyStoreKey) -> Result<(), KeyValueError> {
-        self.move_key(key, &key.surplus())
+        self.archive_as(key, &key.surplus())
+    }
+
+    /// Moves the key to its archive key, and prunes the archive scope if a
+    /// maximum number of archived entries was set.
+    fn archive_as(&self, key: &KeyStoreKey, archive_key: &KeyStoreKey) -> Result<(), KeyValueError> {
+        self.move_key(key, archive_key)?;
+        match self {
+            KeyValueStore::Disk(disk_store) => {
+                disk_store.mark_archived(archive_key)?;
+                disk_store.prune_archive(archive_key.scope())
+            }
+            KeyValueStore::Memory(memory_store) => {
+                memory_store.mark_archived(archive_key);
+                memory_store.prune_archive(archive_key.scope());
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns the keys of the archived, corrupt and surplus values of the
+    /// given scope, with the time they were archived.
+    pub fn archived_keys(&self, scope: &str) -> Result<Vec<ArchivedKey>, KeyValueError> {
+        let mut res = vec![];
+        for archive in ARCHIVE_SCOPES {
+            let archive_scope = format!("{}/{}", scope, archive);
+            if !self.has_scope(archive_scope.clone())? {
+                continue;
+            }
+            for key in self.keys(Some(archive_scope), "")? {
+                if key.name().starts_with('.') {
+                    continue;
+                }
+                let archived_at = match self {
+                    KeyValueStore::Disk(disk_store) => disk_store.modified(&key)?,
+                    KeyValueStore::Memory(memory_store) => memory_store.modified(&key)?,
+                };
+                res.push(ArchivedKey {
+                    key,
+                    archive: archive.to_string(),
+                    archived_at,
+                });
+            }
+        }
+        Ok(res)
     }
 
     /// Returns all 1st level scopes
     pub fn scopes(&self) -> Result<Vec<String>, KeyValueError> {
         match self {
             KeyValueStore::Disk(disk_store) => disk_store.scopes(),
+            KeyValueStore::Memory(memory_store) => Ok(memory_store.scopes()),
         }
     }
 
@@ -180,6 +332,7 @@ impl KeyValueStore {
     pub fn scope_archive(&self, scope: &str, sub_scope: &str) -> Result<(), KeyValueError> {
         match self {
             KeyValueStore::Disk(disk_store) => disk_store.scope_archive(scope, sub_scope),
+            KeyValueStore::Memory(memory_store) => memory_store.scope_archive(scope, sub_scope),
         }
     }
 
@@ -187,6 +340,7 @@ impl KeyValueStore {
     pub fn has_scope(&self, scope: String) -> Result<bool, KeyValueError> {
         match self {
             KeyValueStore::Disk(disk_store) => Ok(disk_store.has_scope(scope)),
+            KeyValueStore::Memory(memory_store) => Ok(memory_store.has_scope(&scope)),
         }
     }
 
@@ -198,6 +352,7 @@ impl KeyValueStore {
     pub fn keys(&self, scope: Option<String>, matching: &str) -> Result<Vec<KeyStoreKey>, KeyValueError> {
         match self {
             KeyValueStore::Disk(disk_store) => disk_store.keys(scope, matching),
+            KeyValueStore::Memory(memory_store) => memory_store.keys(scope, matching),
         }
     }
 
@@ -224,12 +379,30 @@ impl KeyValueStore {
 impl KeyValueStore {}
 
 /// This type can store and retrieve values to/from disk, using json
-/// serialization
-#[derive(Debug)]
+/// serialization.
+///
+/// Values may optionally be stored gzip compressed, in which case the file
+/// name for the key gets an additional '.gz' extension. Keys are the same
+/// for both forms, and values are read from either form transparently, so
+/// that stores can contain a mix of compressed and uncompressed values.
+///
+/// Compressing json typically reduces the size of large snapshots by an
+/// order of magnitude, at the cost of some CPU time when values are written
+/// and read. For small values there is little benefit.
+///
+/// Archived, corrupt and surplus values are kept in sub-scopes of the scope
+/// of their original key. These archive scopes can optionally be kept under
+/// a separate archive base dir, see `KeyValueStore::set_archive`.
+#[derive(Clone, Debug)]
 pub struct KeyValueStoreDiskImpl {
     base: PathBuf,
+    archive_base: Option<PathBuf>,
+    archive_max_entries: usize,
 }
 
+const GZ_EXTENSION: &str = ".gz";
+const ARCHIVE_SCOPES: &[&str] = &["archived", "corrupt", "surplus"];
+
 impl KeyValueStoreDiskImpl {
     fn file_path(&self, key: &KeyStoreKey) -> PathBuf {
         let mut path = self.scope_path(key.scope.as_ref());
@@ -237,32 +410,215 @@ impl KeyValueStoreDiskImpl {
         path
     }
 
+    fn gz_file_path(&self, key: &KeyStoreKey) -> PathBuf {
+        let mut path = self.scope_path(key.scope.as_ref());
+        path.push(format!("{}{}", key.name(), GZ_EXTENSION));
+        path
+    }
+
+    /// Returns the path for the key, in its compressed or uncompressed form
+    fn file_path_for(&self, key: &KeyStoreKey, compress: bool) -> PathBuf {
+        if compress {
+            self.gz_file_path(key)
+        } else {
+            self.file_path(key)
+        }
+    }
+
+    /// Returns the path of the file for the key if it exists, and whether the
+    /// file is compressed.
+    fn existing_file_path(&self, key: &KeyStoreKey) -> Option<(PathBuf, bool)> {
+        let path = self.file_path(key);
+        if path.exists() {
+            return Some((path, false));
+        }
+        let gz_path = self.gz_file_path(key);
+        if gz_path.exists() {
+            return Some((gz_path, true));
+        }
+        None
+    }
+
     /// creates a file path, prefixing the name with '.' much like vi
-    fn swap_file_path(&self, key: &KeyStoreKey) -> PathBuf {
+    fn swap_file_path(&self, key: &KeyStoreKey, compress: bool) -> PathBuf {
         let mut path = self.scope_path(key.scope.as_ref());
-        path.push(format!(".{}", key.name()));
+        if compress {
+            path.push(format!(".{}{}", key.name(), GZ_EXTENSION));
+        } else {
+            path.push(format!(".{}", key.name()));
+        }
         path
     }
 
+    /// Serializes the value to json, and compresses it if needed.
+    fn serialize<V: Any + Serialize>(key: &KeyStoreKey, value: &V, compress: bool) -> Result<Vec<u8>, KeyValueError> {
+        let json = serde_json::to_string_pretty(value)?;
+        if compress {
+            let map_err = |e| KrillIoError::new(format!("Could not compress value for key '{}'", key), e);
+            let mut encoder = gzip::Encoder::new(Vec::new()).map_err(map_err)?;
+            encoder.write_all(json.as_ref()).map_err(map_err)?;
+            let bytes = encoder.finish().into_result().map_err(map_err)?;
+            Ok(bytes)
+        } else {
+            Ok(json.into_bytes())
+        }
+    }
+
     fn scope_path<P: AsRef<Path>>(&self, scope: Option<P>) -> PathBuf {
-        let mut path = self.base.clone();
+        let base = match (&self.archive_base, scope.as_ref()) {
+            (Some(archive_base), Some(scope)) if Self::is_archive_scope(scope.as_ref()) => archive_base,
+            _ => &self.base,
+        };
+
+        let mut path = base.clone();
         if let Some(scope) = scope {
             path.push(scope);
         }
         path
     }
 
-    fn store<V: Any + Serialize>(&self, key: &KeyStoreKey, value: &V) -> Result<(), KeyValueError> {
-        let swap_file_path = self.swap_file_path(key);
-        let file_path = self.file_path(key);
+    /// Returns whether the scope is an archive scope, i.e. its last part is
+    /// one of the sub-scopes used for archived, corrupt or surplus values.
+    fn is_archive_scope(scope: &Path) -> bool {
+        scope
+            .file_name()
+            .map(|name| ARCHIVE_SCOPES.iter().any(|archive| name == *archive))
+            .unwrap_or(false)
+    }
+
+    /// Sets the modification time of an archived value to now, so that it
+    /// reflects when the value was archived rather than when it was written.
+    fn mark_archived(&self, key: &KeyStoreKey) -> Result<(), KeyValueError> {
+        if let Some((path, _)) = self.existing_file_path(key) {
+            Self::touch(&path).map_err(|e| {
+                KrillIoError::new(
+                    format!("Could not set modification time of '{}'", path.to_string_lossy()),
+                    e,
+                )
+            })?;
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn touch(path: &Path) -> io::Result<()> {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = CString::new(path.as_os_str().as_bytes())?;
+        // A null times argument sets both the access and modification time to now.
+        if unsafe { libc::utimes(path.as_ptr(), std::ptr::null()) } == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn touch(_path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Returns the modification time of the value, in seconds since the epoch.
+    fn modified(&self, key: &KeyStoreKey) -> Result<i64, KeyValueError> {
+        let path = self
+            .existing_file_path(key)
+            .map(|(path, _)| path)
+            .ok_or_else(|| KeyValueError::UnknownKey(key.clone()))?;
+        let modified = fs::metadata(&path).and_then(|meta| meta.modified()).map_err(|e| {
+            KrillIoError::new(
+                format!("Could not read modification time of '{}'", path.to_string_lossy()),
+                e,
+            )
+        })?;
+        Ok(modified
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_secs() as i64)
+            .unwrap_or(0))
+    }
+
+    fn set_archive(&mut self, dir: Option<&Path>, max_entries: usize) {
+        self.archive_base = dir.map(|dir| match self.base.file_name() {
+            Some(name_space) => dir.join(name_space),
+            None => dir.to_path_buf(),
+        });
+        self.archive_max_entries = max_entries;
+    }
+
+    /// Removes the oldest files, by modification time, from the archive scope
+    /// until no more than the maximum number of archived entries remain. Does
+    /// nothing if there is no maximum, or if the scope is not an archive scope.
+    fn prune_archive(&self, scope: Option<&String>) -> Result<(), KeyValueError> {
+        let scope = match scope {
+            Some(scope) if self.archive_max_entries > 0 && Self::is_archive_scope(Path::new(scope)) => scope,
+            _ => return Ok(()),
+        };
+
+        let dir = self.scope_path(Some(scope));
+        let mut entries = vec![];
+        for name in Self::read_dir(&dir, true, false)? {
+            let path = dir.join(&name);
+            let modified = fs::metadata(&path).and_then(|meta| meta.modified()).map_err(|e| {
+                KrillIoError::new(
+                    format!("Could not read modification time of '{}'", path.to_string_lossy()),
+                    e,
+                )
+            })?;
+            entries.push((modified, path));
+        }
+
+        if entries.len() <= self.archive_max_entries {
+            return Ok(());
+        }
+
+        entries.sort();
+        let surplus = entries.len() - self.archive_max_entries;
+        for (_, path) in entries.into_iter().take(surplus) {
+            warn!(
+                "Removing archived file '{}', there are more than {} entries in archive '{}'",
+                path.to_string_lossy(),
+                self.archive_max_entries,
+                scope
+            );
+            fs::remove_file(&path).map_err(|e| {
+                KrillIoError::new(
+                    format!("Could not remove archived file '{}'", path.to_string_lossy()),
+                    e,
+                )
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the value to a swap file, which is renamed to the file for the
+    /// key, so that the value is replaced as a whole. If sync is set then the
+    /// swap file and the rename are synced to disk as well.
+    fn store<V: Any + Serialize>(
+        &self,
+        key: &KeyStoreKey,
+        value: &V,
+        compress: bool,
+        sync: bool,
+    ) -> Result<(), KeyValueError> {
+        let swap_file_path = self.swap_file_path(key, compress);
+        let file_path = self.file_path_for(key, compress);
         let mut swap_file = file::create_file_with_path(&swap_file_path)?;
-        let json = serde_json::to_string_pretty(value)?;
-        swap_file.write_all(json.as_ref()).map_err(|e| {
+        let bytes = Self::serialize(key, value, compress)?;
+        swap_file.write_all(&bytes).map_err(|e| {
             KrillIoError::new(
                 format!("Could not write to tmp file: {}", swap_file_path.to_string_lossy()),
                 e,
             )
         })?;
+        if sync {
+            swap_file.sync_all().map_err(|e| {
+                KrillIoError::new(
+                    format!("Could not sync tmp file: {}", swap_file_path.to_string_lossy()),
+                    e,
+                )
+            })?;
+        }
 
         fs::rename(&swap_file_path, &file_path).map_err(|e| {
             KrillIoError::new(
@@ -275,17 +631,45 @@ impl KeyValueStoreDiskImpl {
             )
         })?;
 
+        // Remove the value in the other form, if present, so that it cannot shadow this value.
+        let other_path = self.file_path_for(key, !compress);
+        if other_path.exists() {
+            fs::remove_file(&other_path).map_err(|e| {
+                KrillIoError::new(
+                    format!("Could not remove replaced file {}", other_path.to_string_lossy()),
+                    e,
+                )
+            })?;
+        }
+
+        if sync {
+            if let Some(dir) = file_path.parent() {
+                Self::sync_dir(dir)
+                    .map_err(|e| KrillIoError::new(format!("Could not sync directory {}", dir.to_string_lossy()), e))?;
+            }
+        }
+
         Ok(())
     }
 
-    fn store_new<V: Any + Serialize>(&self, key: &KeyStoreKey, value: &V) -> Result<(), KeyValueError> {
-        let path = self.file_path(key);
-        if path.exists() {
+    #[cfg(unix)]
+    fn sync_dir(dir: &Path) -> io::Result<()> {
+        File::open(dir)?.sync_all()
+    }
+
+    #[cfg(not(unix))]
+    fn sync_dir(_dir: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn store_new<V: Any + Serialize>(&self, key: &KeyStoreKey, value: &V, compress: bool) -> Result<(), KeyValueError> {
+        if self.has(key) {
             Err(KeyValueError::DuplicateKey(key.clone()))
         } else {
+            let path = self.file_path_for(key, compress);
             let mut f = file::create_file_with_path(&path)?;
-            let json = serde_json::to_string_pretty(value)?;
-            f.write_all(json.as_ref()).map_err(|e| {
+            let bytes = Self::serialize(key, value, compress)?;
+            f.write_all(&bytes).map_err(|e| {
                 KrillIoError::new(
                     format!(
                         "Could not store value for key '{}' in file '{}'",
@@ -300,46 +684,54 @@ impl KeyValueStoreDiskImpl {
     }
 
     fn get<V: DeserializeOwned>(&self, key: &KeyStoreKey) -> Result<Option<V>, KeyValueError> {
-        let path = self.file_path(key);
-        let path_str = path.to_string_lossy().into_owned();
-
-        if path.exists() {
-            let f = File::open(path).map_err(|e| {
-                KrillIoError::new(
-                    format!(
-                        "Could not read value for key '{}' from file '{}'",
-                        key.to_string(),
-                        path_str
-                    ),
-                    e,
-                )
-            })?;
-            let v = serde_json::from_reader(f)?;
-            Ok(Some(v))
-        } else {
-            trace!("Could not find file at: {}", path_str);
-            Ok(None)
+        match self.existing_file_path(key) {
+            Some((path, compressed)) => {
+                let path_str = path.to_string_lossy().into_owned();
+                let map_err = |e| {
+                    KrillIoError::new(
+                        format!(
+                            "Could not read value for key '{}' from file '{}'",
+                            key.to_string(),
+                            path_str
+                        ),
+                        e,
+                    )
+                };
+
+                let f = File::open(&path).map_err(map_err)?;
+                let v = if compressed {
+                    let decoder = gzip::Decoder::new(BufReader::new(f)).map_err(map_err)?;
+                    serde_json::from_reader(decoder)?
+                } else {
+                    serde_json::from_reader(f)?
+                };
+                Ok(Some(v))
+            }
+            None => {
+                trace!("Could not find file at: {}", self.file_path(key).to_string_lossy());
+                Ok(None)
+            }
         }
     }
 
     pub fn has(&self, key: &KeyStoreKey) -> bool {
-        let path = self.file_path(key);
-        path.exists()
+        self.existing_file_path(key).is_some()
     }
 
     pub fn drop_key(&self, key: &KeyStoreKey) -> Result<(), KeyValueError> {
-        let path = self.file_path(key);
-        if path.exists() {
-            fs::remove_file(&path).map_err(|e| {
-                KrillIoError::new(
-                    format!(
-                        "Could not drop key '{}', removing file '{}' failed",
-                        key.to_string(),
-                        path.to_string_lossy()
-                    ),
-                    e,
-                )
-            })?;
+        for path in &[self.file_path(key), self.gz_file_path(key)] {
+            if path.exists() {
+                fs::remove_file(path).map_err(|e| {
+                    KrillIoError::new(
+                        format!(
+                            "Could not drop key '{}', removing file '{}' failed",
+                            key.to_string(),
+                            path.to_string_lossy()
+                        ),
+                        e,
+                    )
+                })?;
+            }
         }
         Ok(())
     }
@@ -362,15 +754,22 @@ impl KeyValueStoreDiskImpl {
     }
 
     pub fn move_key(&self, from: &KeyStoreKey, to: &KeyStoreKey) -> Result<(), KeyValueError> {
-        let from_path = self.file_path(from);
-        let to_path = self.file_path(to);
+        if let Some((from_path, compressed)) = self.existing_file_path(from) {
+            // keep the value in the same form, and do not leave a stale value at the target
+            let to_path = self.file_path_for(to, compressed);
+            let other_to_path = self.file_path_for(to, !compressed);
+            if other_to_path.exists() {
+                fs::remove_file(&other_to_path).map_err(|e| {
+                    KrillIoError::new(
+                        format!("Could not remove replaced file {}", other_to_path.to_string_lossy()),
+                        e,
+                    )
+                })?;
+            }
 
-        if !from_path.exists() {
-            Err(KeyValueError::UnknownKey(from.clone()))
-        } else {
             if let Some(parent) = to_path.parent() {
                 if !parent.exists() {
-                    fs::create_dir(parent).map_err(|e| {
+                    fs::create_dir_all(parent).map_err(|e| {
                         KrillIoError::new(
                             format!(
                                 "Could not rename key from '{}' to '{}'. Creating parent dir '{}' failed.",
@@ -384,10 +783,23 @@ impl KeyValueStoreDiskImpl {
                 }
             }
 
-            fs::rename(from_path, to_path)
+            Self::rename_or_copy(&from_path, &to_path)
                 .map_err(|e| KrillIoError::new(format!("Could not rename key from '{}' to '{}'", from, to,), e))?;
             Ok(())
+        } else {
+            Err(KeyValueError::UnknownKey(from.clone()))
+        }
+    }
+
+    /// Renames the file, or copies it and then removes the original if it
+    /// cannot be renamed, e.g. because an archive dir is on another file
+    /// system.
+    fn rename_or_copy(from: &Path, to: &Path) -> io::Result<()> {
+        if fs::rename(from, to).is_err() {
+            fs::copy(from, to)?;
+            fs::remove_file(from)?;
         }
+        Ok(())
     }
 
     fn has_scope(&self, scope: String) -> bool {
@@ -441,6 +853,11 @@ impl KeyValueStoreDiskImpl {
 
         let mut res = vec![];
         for name in Self::read_dir(&path, true, false)? {
+            // compressed values use the same key as uncompressed values
+            let name = match name.strip_suffix(GZ_EXTENSION) {
+                Some(stripped) => stripped.to_string(),
+                None => name,
+            };
             if matching.is_empty() || name.contains(matching) {
                 match scope.as_ref() {
                     None => res.push(KeyStoreKey::simple(name)),
@@ -476,6 +893,264 @@ impl KeyValueStoreDiskImpl {
     }
 }
 
+//------------ KeyValueStoreMemoryImpl ---------------------------------------
+
+/// This type keeps values in memory, serialized as json, with the same
+/// semantics as the disk based store. E.g. `store_new` fails if the key
+/// exists, moving an unknown key fails, and scopes exist once a value was
+/// stored in them, or in any of their sub-scopes, until they are dropped.
+///
+/// Values are never compressed, but can be stored and read using the
+/// compressed variants of the functions. Archived values are ordered by the
+/// order in which they were archived, rather than by time, so that pruning
+/// archive scopes is deterministic.
+#[derive(Clone, Debug)]
+pub struct KeyValueStoreMemoryImpl {
+    name_space: String,
+    archive_max_entries: usize,
+    content: Arc<RwLock<MemoryContent>>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryContent {
+    values: HashMap<(Option<String>, String), MemoryValue>,
+    scopes: HashSet<String>,
+    // Incremented whenever a value is written or archived.
+    seq: u64,
+}
+
+#[derive(Clone, Debug)]
+struct MemoryValue {
+    json: String,
+    seq: u64,
+    modified: i64,
+}
+
+impl MemoryContent {
+    fn insert(&mut self, key: &KeyStoreKey, json: String) {
+        if let Some(scope) = key.scope() {
+            self.add_scope(scope);
+        }
+        self.seq += 1;
+        let value = MemoryValue {
+            json,
+            seq: self.seq,
+            modified: Time::now().timestamp(),
+        };
+        self.values.insert(KeyValueStoreMemoryImpl::map_key(key), value);
+    }
+
+    /// Adds the scope, and all its parent scopes.
+    fn add_scope(&mut self, scope: &str) {
+        let mut path = String::new();
+        for part in scope.split('/') {
+            if !path.is_empty() {
+                path.push('/');
+            }
+            path.push_str(part);
+            self.scopes.insert(path.clone());
+        }
+    }
+}
+
+impl KeyValueStoreMemoryImpl {
+    fn map_key(key: &KeyStoreKey) -> (Option<String>, String) {
+        (key.scope.clone(), key.name.clone())
+    }
+
+    /// Returns whether the scope is the given scope, or one of its sub-scopes.
+    fn in_scope(candidate: &str, scope: &str) -> bool {
+        candidate == scope || candidate.starts_with(&format!("{}/", scope))
+    }
+
+    fn set_archive(&mut self, max_entries: usize) {
+        self.archive_max_entries = max_entries;
+    }
+
+    fn store<V: Any + Serialize>(&self, key: &KeyStoreKey, value: &V) -> Result<(), KeyValueError> {
+        let json = serde_json::to_string_pretty(value)?;
+        self.content.write().unwrap().insert(key, json);
+        Ok(())
+    }
+
+    fn store_new<V: Any + Serialize>(&self, key: &KeyStoreKey, value: &V) -> Result<(), KeyValueError> {
+        let json = serde_json::to_string_pretty(value)?;
+        let mut content = self.content.write().unwrap();
+        if content.values.contains_key(&Self::map_key(key)) {
+            Err(KeyValueError::DuplicateKey(key.clone()))
+        } else {
+            content.insert(key, json);
+            Ok(())
+        }
+    }
+
+    fn get<V: DeserializeOwned>(&self, key: &KeyStoreKey) -> Result<Option<V>, KeyValueError> {
+        match self.content.read().unwrap().values.get(&Self::map_key(key)) {
+            Some(value) => Ok(Some(serde_json::from_str(&value.json)?)),
+            None => {
+                trace!("Could not find key '{}' in memory store '{}'", key, self.name_space);
+                Ok(None)
+            }
+        }
+    }
+
+    fn has(&self, key: &KeyStoreKey) -> bool {
+        self.content.read().unwrap().values.contains_key(&Self::map_key(key))
+    }
+
+    fn drop_key(&self, key: &KeyStoreKey) -> Result<(), KeyValueError> {
+        self.content.write().unwrap().values.remove(&Self::map_key(key));
+        Ok(())
+    }
+
+    fn drop_scope(&self, scope: &str) -> Result<(), KeyValueError> {
+        let mut content = self.content.write().unwrap();
+        content.values.retain(|(key_scope, _), _| match key_scope {
+            Some(key_scope) => !Self::in_scope(key_scope, scope),
+            None => true,
+        });
+        content.scopes.retain(|existing| !Self::in_scope(existing, scope));
+        Ok(())
+    }
+
+    fn move_key(&self, from: &KeyStoreKey, to: &KeyStoreKey) -> Result<(), KeyValueError> {
+        let mut content = self.content.write().unwrap();
+        match content.values.remove(&Self::map_key(from)) {
+            Some(value) => {
+                if let Some(scope) = to.scope() {
+                    content.add_scope(scope);
+                }
+                content.values.insert(Self::map_key(to), value);
+                Ok(())
+            }
+            None => Err(KeyValueError::UnknownKey(from.clone())),
+        }
+    }
+
+    /// Marks the value as archived now, so that it is the newest value in its
+    /// archive scope.
+    fn mark_archived(&self, key: &KeyStoreKey) {
+        let mut content = self.content.write().unwrap();
+        content.seq += 1;
+        let seq = content.seq;
+        if let Some(value) = content.values.get_mut(&Self::map_key(key)) {
+            value.seq = seq;
+            value.modified = Time::now().timestamp();
+        }
+    }
+
+    fn modified(&self, key: &KeyStoreKey) -> Result<i64, KeyValueError> {
+        match self.content.read().unwrap().values.get(&Self::map_key(key)) {
+            Some(value) => Ok(value.modified),
+            None => Err(KeyValueError::UnknownKey(key.clone())),
+        }
+    }
+
+    /// Removes the values which were archived first from the archive scope,
+    /// until no more than the maximum number of archived entries remain.
+    fn prune_archive(&self, scope: Option<&String>) {
+        let scope = match scope {
+            Some(scope)
+                if self.archive_max_entries > 0 && KeyValueStoreDiskImpl::is_archive_scope(Path::new(scope)) =>
+            {
+                scope
+            }
+            _ => return,
+        };
+
+        let mut content = self.content.write().unwrap();
+        let mut entries: Vec<(u64, String)> = content
+            .values
+            .iter()
+            .filter(|((key_scope, _), _)| key_scope.as_ref() == Some(scope))
+            .map(|((_, name), value)| (value.seq, name.clone()))
+            .collect();
+
+        if entries.len() <= self.archive_max_entries {
+            return;
+        }
+
+        entries.sort();
+        let surplus = entries.len() - self.archive_max_entries;
+        for (_, name) in entries.into_iter().take(surplus) {
+            warn!(
+                "Removing archived value '{}/{}', there are more than {} entries in archive '{}'",
+                scope, name, self.archive_max_entries, scope
+            );
+            content.values.remove(&(Some(scope.clone()), name));
+        }
+    }
+
+    fn has_scope(&self, scope: &str) -> bool {
+        self.content.read().unwrap().scopes.contains(scope)
+    }
+
+    fn scopes(&self) -> Vec<String> {
+        self.content
+            .read()
+            .unwrap()
+            .scopes
+            .iter()
+            .filter(|scope| !scope.contains('/'))
+            .cloned()
+            .collect()
+    }
+
+    fn scope_archive(&self, scope: &str, sub_scope: &str) -> Result<(), KeyValueError> {
+        let mut content = self.content.write().unwrap();
+        if !content.scopes.contains(scope) {
+            return Err(self.unknown_scope(scope));
+        }
+
+        let archive_scope = |existing: &str| format!("{}/{}{}", scope, sub_scope, &existing[scope.len()..]);
+
+        let values = std::mem::take(&mut content.values);
+        for ((key_scope, name), value) in values {
+            let key_scope = match key_scope {
+                Some(key_scope) if Self::in_scope(&key_scope, scope) => Some(archive_scope(&key_scope)),
+                other => other,
+            };
+            content.values.insert((key_scope, name), value);
+        }
+
+        let scopes = std::mem::take(&mut content.scopes);
+        for existing in scopes {
+            if Self::in_scope(&existing, scope) {
+                content.add_scope(&archive_scope(&existing));
+            } else {
+                content.scopes.insert(existing);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn keys(&self, scope: Option<String>, matching: &str) -> Result<Vec<KeyStoreKey>, KeyValueError> {
+        let content = self.content.read().unwrap();
+        if let Some(scope) = scope.as_ref() {
+            if !content.scopes.contains(scope) {
+                return Err(self.unknown_scope(scope));
+            }
+        }
+
+        Ok(content
+            .values
+            .keys()
+            .filter(|(key_scope, name)| *key_scope == scope && (matching.is_empty() || name.contains(matching)))
+            .map(|(key_scope, name)| KeyStoreKey::new(key_scope.clone(), name.clone()))
+            .collect())
+    }
+
+    /// Returns the same kind of error as the disk store does when it cannot
+    /// read the directory for a scope.
+    fn unknown_scope(&self, scope: &str) -> KeyValueError {
+        KeyValueError::IoError(KrillIoError::new(
+            format!("Could not find scope '{}' in memory store '{}'", scope, self.name_space),
+            io::Error::from(io::ErrorKind::NotFound),
+        ))
+    }
+}
+
 //------------ KeyValueError -------------------------------------------------
 
 /// This type defines possible Errors for KeyStore
@@ -546,4 +1221,122 @@ mod tests {
             assert!(expected_target.exists());
         })
     }
+
+    #[test]
+    fn disk_store_compressed() {
+        test::test_under_tmp(|d| {
+            let store = KeyValueStore::disk(&d, "store").unwrap();
+
+            let content = "abc".to_string();
+            let key = KeyStoreKey::simple("id.json".to_string());
+
+            store.store_compressed(&key, &content).unwrap();
+
+            let mut expected_file_path = d.clone();
+            expected_file_path.push("store");
+            expected_file_path.push("id.json.gz");
+            assert!(expected_file_path.exists());
+
+            assert!(store.has(&key).unwrap());
+            assert_eq!(Some(content.clone()), store.get::<String>(&key).unwrap());
+
+            let keys = store.keys(None, "id").unwrap();
+            assert_eq!(1, keys.len());
+            assert_eq!("id.json", keys[0].name());
+
+            assert!(store.store_new(&key, &content).is_err());
+
+            // Storing uncompressed replaces the compressed value
+            store.store(&key, &"def".to_string()).unwrap();
+            assert!(!expected_file_path.exists());
+            assert_eq!(Some("def".to_string()), store.get::<String>(&key).unwrap());
+
+            store.store_compressed(&key, &content).unwrap();
+            store.archive_corrupt(&key).unwrap();
+            assert!(!store.has(&key).unwrap());
+            assert_eq!(Some(content), store.get::<String>(&key.corrupt()).unwrap());
+        })
+    }
+
+    #[test]
+    fn disk_store_archive_dir_and_pruning() {
+        test::test_under_tmp(|d| {
+            let archive_dir = d.join("archive");
+            let mut store = KeyValueStore::disk(&d, "store").unwrap();
+            store.set_archive(Some(&archive_dir), 2);
+
+            let keys: Vec<_> = (0..3)
+                .map(|nr| KeyStoreKey::scoped("ca".to_string(), format!("delta-{}.json", nr)))
+                .collect();
+            for key in &keys {
+                store.store(key, &key.name().to_string()).unwrap();
+                // make sure that modification times differ
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+            store
+                .store(&KeyStoreKey::scoped("ca".to_string(), "live.json".to_string()), &"live")
+                .unwrap();
+
+            for key in &keys {
+                store.archive_surplus(key).unwrap();
+            }
+
+            // Archived values are kept under the archive dir, and can still be read
+            assert!(archive_dir.join("store/ca/surplus/delta-2.json").exists());
+            assert!(!d.join("store/ca/surplus").exists());
+            assert!(store.has(&keys[2].surplus()).unwrap());
+
+            // Only the oldest archived value is removed, live values are kept
+            assert!(!store.has(&keys[0].surplus()).unwrap());
+            assert!(store.has(&keys[1].surplus()).unwrap());
+            assert_eq!(1, store.keys(Some("ca".to_string()), "").unwrap().len());
+        })
+    }
+
+    #[test]
+    fn memory_store() {
+        let mut store = KeyValueStore::memory("store");
+        store.set_archive(None, 1);
+
+        let key = KeyStoreKey::scoped("ca".to_string(), "id".to_string());
+        store.store_new(&key, &"abc").unwrap();
+        assert!(matches!(
+            store.store_new(&key, &"def"),
+            Err(KeyValueError::DuplicateKey(_))
+        ));
+        store.store_compressed(&key, &"def").unwrap();
+        assert_eq!(Some("def".to_string()), store.get::<String>(&key).unwrap());
+
+        assert!(store.keys(Some("unknown".to_string()), "").is_err());
+        assert!(matches!(
+            store.move_key(&KeyStoreKey::simple("unknown".to_string()), &key),
+            Err(KeyValueError::UnknownKey(_))
+        ));
+
+        // Only the value which was archived last is kept
+        let other = KeyStoreKey::scoped("ca".to_string(), "other".to_string());
+        store.store(&other, &"other").unwrap();
+        store.archive(&key).unwrap();
+        store.archive(&other).unwrap();
+        assert!(!store.has(&key.archived()).unwrap());
+        assert!(store.has(&other.archived()).unwrap());
+        assert_eq!(1, store.archived_keys("ca").unwrap().len());
+        assert!(store.has_scope("ca".to_string()).unwrap());
+        assert!(store.keys(Some("ca".to_string()), "").unwrap().is_empty());
+
+        store.store(&key, &"abc").unwrap();
+        store.scope_archive("ca", "old").unwrap();
+        assert!(store
+            .has(&KeyStoreKey::scoped("ca/old".to_string(), "id".to_string()))
+            .unwrap());
+        assert!(!store.has(&other.archived()).unwrap());
+        assert!(store
+            .has(&KeyStoreKey::scoped("ca/old/archived".to_string(), "other".to_string()))
+            .unwrap());
+        assert_eq!(vec!["ca".to_string()], store.scopes().unwrap());
+
+        store.drop_scope("ca").unwrap();
+        assert!(!store.has_scope("ca/old".to_string()).unwrap());
+        assert!(store.scopes().unwrap().is_empty());
+    }
 }
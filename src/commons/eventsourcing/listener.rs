@@ -1,6 +1,11 @@
-use std::sync::RwLock;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::mpsc::{sync_channel, SyncSender, TryRecvError};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
 
-use super::Aggregate;
+use crate::commons::api::Handle;
+
+use super::{Aggregate, Event, KeyStoreKey, KeyValueStore};
 
 //------------ PreSaveEventListener ------------------------------------------
 
@@ -21,6 +26,214 @@ pub trait PostSaveEventListener<A: Aggregate>: Send + Sync + 'static {
     fn listen(&self, agg: &A, events: &[A::Event]);
 }
 
+//------------ PostSaveAsyncEventListener -------------------------------------
+
+/// This trait defines a listener for events which receives them *after* the
+/// updated Aggregate is saved, like the PostSaveEventListener, but on its own
+/// worker thread. So, unlike a PostSaveEventListener, a slow listener of this
+/// type does not hold up command processing.
+///
+/// Delivery guarantees:
+///  - Events are only delivered after they were saved, i.e. when they are
+///    committed, and they are delivered together with the updated Aggregate.
+///  - The events for each Aggregate are delivered in the order in which they
+///    were saved. There is no ordering between different Aggregates.
+///  - Events are delivered at least once. The version up to which events were
+///    delivered is saved for each Aggregate after the listener returns, once
+///    there are no more queued events, and when the listener is added again
+///    after a restart, the events saved after that version are delivered first. So, events may be delivered again if
+///    Krill stopped while the listener handled them, and listeners should be
+///    idempotent. Events which were archived meanwhile are skipped.
+///  - Events are queued in a bounded queue. When the queue is full, command
+///    processing waits for the listener to catch up, but without holding the
+///    store lock, so a listener can still read from the store.
+pub trait PostSaveAsyncEventListener<A: Aggregate>: Send + Sync + 'static {
+    fn listen(&self, agg: Arc<A>, events: Vec<A::Event>);
+}
+
+//------------ PostSaveAsyncEventQueue ----------------------------------------
+
+/// An item in a PostSaveAsyncEventQueue.
+enum QueueItem<A: Aggregate> {
+    /// The updated Aggregate and its new events.
+    Saved(Arc<A>, Vec<A::Event>),
+    /// The Aggregate was dropped, so a new Aggregate with the same handle
+    /// starts at version 1 again.
+    Dropped(Handle),
+}
+
+/// Updates which arrived before the updates with the events before them, by
+/// Aggregate and the version of their first event.
+type EarlyUpdates<A> = HashMap<Handle, BTreeMap<u64, (Arc<A>, Vec<<A as Aggregate>::Event>)>>;
+
+/// Queues saved events for a PostSaveAsyncEventListener, and delivers them on
+/// a worker thread.
+///
+/// Updates may be queued in a different order than they were saved, because
+/// they are queued after the store lock is released. The worker keeps updates
+/// that arrive early until the updates before them were delivered.
+pub struct PostSaveAsyncEventQueue<A: Aggregate> {
+    sender: Mutex<Option<SyncSender<QueueItem<A>>>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl<A: Aggregate> PostSaveAsyncEventQueue<A> {
+    /// Starts a worker thread for the listener, which will accept up to
+    /// 'capacity' updates before senders have to wait.
+    ///
+    /// The cursor has the version of the next event to deliver for each
+    /// Aggregate, Aggregates which are not included start at version 1. When
+    /// the worker has delivered all queued events, it saves the cursor under
+    /// 'cursor_key' in 'kv', so that it is not saved for every delivery when
+    /// many events are queued.
+    pub fn start<L: PostSaveAsyncEventListener<A>>(
+        listener: Arc<L>,
+        capacity: usize,
+        kv: KeyValueStore,
+        cursor_key: KeyStoreKey,
+        mut cursor: HashMap<Handle, u64>,
+    ) -> Self {
+        let (sender, receiver) = sync_channel::<QueueItem<A>>(capacity);
+
+        let worker = thread::spawn(move || {
+            let mut early: EarlyUpdates<A> = HashMap::new();
+            let mut changed = false;
+
+            let save_cursor = |cursor: &HashMap<Handle, u64>| {
+                if let Err(e) = kv.store(&cursor_key, cursor) {
+                    error!(
+                        "Could not save which events were delivered to asynchronous listener '{}'. Error: {}",
+                        cursor_key, e
+                    );
+                }
+            };
+
+            loop {
+                let item = match receiver.try_recv() {
+                    Ok(item) => item,
+                    Err(TryRecvError::Empty) => {
+                        if changed {
+                            save_cursor(&cursor);
+                            changed = false;
+                        }
+                        match receiver.recv() {
+                            Ok(item) => item,
+                            Err(_) => break,
+                        }
+                    }
+                    Err(TryRecvError::Disconnected) => break,
+                };
+
+                changed |= match item {
+                    QueueItem::Saved(agg, events) => {
+                        let (handle, version) = match events.first() {
+                            Some(event) => (event.handle().clone(), event.version()),
+                            None => continue,
+                        };
+                        early.entry(handle.clone()).or_default().insert(version, (agg, events));
+                        Self::deliver(listener.as_ref(), &handle, &mut cursor, &mut early)
+                    }
+                    QueueItem::Dropped(handle) => {
+                        early.remove(&handle);
+                        cursor.remove(&handle).is_some()
+                    }
+                };
+            }
+
+            if changed {
+                save_cursor(&cursor);
+            }
+        });
+
+        PostSaveAsyncEventQueue {
+            sender: Mutex::new(Some(sender)),
+            worker: Some(worker),
+        }
+    }
+
+    /// Delivers the updates for the Aggregate which are next in line, and
+    /// removes updates with events that were delivered before, e.g. when they
+    /// were replayed. Returns whether any events were delivered.
+    fn deliver<L: PostSaveAsyncEventListener<A>>(
+        listener: &L,
+        handle: &Handle,
+        cursor: &mut HashMap<Handle, u64>,
+        early: &mut EarlyUpdates<A>,
+    ) -> bool {
+        let updates = match early.get_mut(handle) {
+            Some(updates) => updates,
+            None => return false,
+        };
+        let next = cursor.entry(handle.clone()).or_insert(1);
+        let mut delivered = false;
+
+        while let Some(first) = updates.keys().next().copied() {
+            if first > *next {
+                // Wait for the events before this update.
+                break;
+            }
+
+            let (agg, mut events) = updates.remove(&first).unwrap();
+            events.retain(|event| event.version() >= *next);
+            if let Some(last) = events.last() {
+                let last = last.version();
+                listener.listen(agg, events);
+                *next = last + 1;
+                delivered = true;
+            }
+        }
+
+        if updates.is_empty() {
+            early.remove(handle);
+        }
+
+        delivered
+    }
+
+    /// Queues the events, waiting if the queue is full. If the worker is gone,
+    /// e.g. because the listener panicked, then the events are logged as lost
+    /// until the listener is added again.
+    ///
+    /// The sender is cloned, so that other threads can still queue events, or
+    /// close the queue, while this thread waits.
+    pub fn send(&self, agg: Arc<A>, events: Vec<A::Event>) {
+        let sender = self.sender.lock().unwrap().clone();
+        if let Some(sender) = sender {
+            if let Err(e) = sender.send(QueueItem::Saved(agg, events)) {
+                if let QueueItem::Saved(agg, events) = e.0 {
+                    if let Some(event) = events.first() {
+                        error!(
+                            "Asynchronous event listener stopped, cannot deliver {} events for '{}' up to version {}",
+                            events.len(),
+                            event.handle(),
+                            agg.version()
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    /// Tells the worker that the Aggregate was dropped, so that the events of a
+    /// new Aggregate with the same handle are delivered from version 1.
+    pub fn dropped(&self, handle: &Handle) {
+        let sender = self.sender.lock().unwrap().clone();
+        if let Some(sender) = sender {
+            let _ = sender.send(QueueItem::Dropped(handle.clone()));
+        }
+    }
+}
+
+impl<A: Aggregate> Drop for PostSaveAsyncEventQueue<A> {
+    /// Closes the queue and waits for the worker to deliver the queued events.
+    fn drop(&mut self) {
+        self.sender.lock().unwrap().take();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
 //------------ EventCounter --------------------------------------------------
 
 /// Example listener that simply counts all events
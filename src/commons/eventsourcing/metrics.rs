@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::commons::api::Handle;
+
+//------------ AggregateStoreMetrics -----------------------------------------
+
+/// Counters for the operations done by an AggregateStore.
+///
+/// Counters are atomics so that they can be updated without locking. The only
+/// exception are the per aggregate command counters: these live in a map which
+/// needs a read lock, and a write lock only when the first command for an
+/// aggregate is counted. The per aggregate snapshot sizes need a write lock,
+/// but they are only updated when a snapshot is written.
+#[derive(Debug, Default)]
+pub struct AggregateStoreMetrics {
+    commands: RwLock<HashMap<Handle, AtomicU64>>,
+    events_stored: AtomicU64,
+    snapshots_written: AtomicU64,
+    snapshot_sizes: RwLock<HashMap<Handle, u64>>,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    cache_evictions: AtomicU64,
+    replays: AtomicU64,
+    replay_micros: AtomicU64,
+}
+
+impl AggregateStoreMetrics {
+    pub fn command_processed(&self, handle: &Handle) {
+        if let Some(counter) = self.commands.read().unwrap().get(handle) {
+            counter.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        self.commands
+            .write()
+            .unwrap()
+            .entry(handle.clone())
+            .or_default()
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn event_stored(&self) {
+        self.events_stored.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot_written(&self) {
+        self.snapshots_written.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records the size in bytes of the snapshot last written for an aggregate.
+    pub fn snapshot_size(&self, handle: &Handle, size: usize) {
+        self.snapshot_sizes.write().unwrap().insert(handle.clone(), size as u64);
+    }
+
+    pub fn cache_hit(&self) {
+        self.cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts an aggregate which had to be loaded from disk.
+    pub fn cache_miss(&self) {
+        self.cache_misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts aggregates which were evicted from the cache.
+    pub fn cache_evictions(&self, nr: usize) {
+        if nr > 0 {
+            self.cache_evictions.fetch_add(nr as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Counts a replay of events onto an aggregate, and the time it took.
+    pub fn replayed(&self, duration: Duration) {
+        self.replays.fetch_add(1, Ordering::Relaxed);
+        self.replay_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Returns a copy of the current values of all counters.
+    pub fn report(&self) -> AggregateStoreMetricsReport {
+        let commands = self
+            .commands
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(handle, counter)| (handle.clone(), counter.load(Ordering::Relaxed)))
+            .collect();
+
+        AggregateStoreMetricsReport {
+            commands,
+            events_stored: self.events_stored.load(Ordering::Relaxed),
+            snapshots_written: self.snapshots_written.load(Ordering::Relaxed),
+            snapshot_bytes: self.snapshot_sizes.read().unwrap().clone(),
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+            cache_misses: self.cache_misses.load(Ordering::Relaxed),
+            cache_evictions: self.cache_evictions.load(Ordering::Relaxed),
+            replays: self.replays.load(Ordering::Relaxed),
+            replay_seconds: self.replay_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0,
+        }
+    }
+}
+
+//------------ AggregateStoreMetricsReport -----------------------------------
+
+/// The values of the AggregateStoreMetrics counters, since the store was
+/// created.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct AggregateStoreMetricsReport {
+    pub commands: HashMap<Handle, u64>,
+    pub events_stored: u64,
+    pub snapshots_written: u64,
+    /// The size of the snapshot last written for each aggregate, as compact
+    /// json. Only aggregates for which a snapshot was written since the store
+    /// was created are included.
+    pub snapshot_bytes: HashMap<Handle, u64>,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    pub cache_evictions: u64,
+    pub replays: u64,
+    pub replay_seconds: f64,
+}
+
+impl AggregateStoreMetricsReport {
+    /// Total number of commands processed for all aggregates.
+    pub fn commands_total(&self) -> u64 {
+        self.commands.values().sum()
+    }
+}
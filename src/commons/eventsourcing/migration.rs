@@ -0,0 +1,88 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde_json::Value;
+
+use crate::commons::eventsourcing::{KeyStoreVersion, StoreResult};
+
+//------------ StoreMigration ------------------------------------------------
+
+/// Implement this to migrate the stored json for the commands, events and
+/// snapshots of aggregates from one `KeyStoreVersion` to the next.
+///
+/// The default implementations leave values unchanged, so that a migration
+/// only needs to implement the transformations for values it changes.
+pub trait StoreMigration: Send + Sync {
+    fn migrate_command(&self, command: Value) -> StoreResult<Value> {
+        Ok(command)
+    }
+
+    fn migrate_event(&self, event: Value) -> StoreResult<Value> {
+        Ok(event)
+    }
+
+    fn migrate_snapshot(&self, snapshot: Value) -> StoreResult<Value> {
+        Ok(snapshot)
+    }
+}
+
+//------------ StoreMigrations -----------------------------------------------
+
+/// Registry of migrations, keyed by the `KeyStoreVersion` they migrate from
+/// and to. See `AggregateStore::add_migration`.
+#[derive(Default)]
+pub struct StoreMigrations {
+    migrations: BTreeMap<(KeyStoreVersion, KeyStoreVersion), Box<dyn StoreMigration>>,
+}
+
+impl StoreMigrations {
+    /// Registers a migration. Migrations must go forward, i.e. 'to' must be
+    /// a later version than 'from'.
+    pub fn add(&mut self, from: KeyStoreVersion, to: KeyStoreVersion, migration: Box<dyn StoreMigration>) {
+        assert!(from < to, "Migrations must go to a later version");
+        self.migrations.insert((from, to), migration);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.migrations.is_empty()
+    }
+
+    /// Returns the next migration step from the given version, if any. If
+    /// there are several, then the one to the earliest version is returned
+    /// so that no steps are skipped.
+    pub fn step_from(&self, version: &KeyStoreVersion) -> Option<(&KeyStoreVersion, &dyn StoreMigration)> {
+        self.migrations
+            .iter()
+            .find(|((from, _), _)| from == version)
+            .map(|((_, to), migration)| (to, migration.as_ref()))
+    }
+}
+
+impl fmt::Debug for StoreMigrations {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.migrations.keys()).finish()
+    }
+}
+
+//------------ Tests ---------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    struct Noop;
+
+    impl StoreMigration for Noop {}
+
+    #[test]
+    fn step_from_picks_earliest_target() {
+        let mut migrations = StoreMigrations::default();
+        migrations.add(KeyStoreVersion::V0_8, KeyStoreVersion::V0_9_0, Box::new(Noop));
+        migrations.add(KeyStoreVersion::V0_8, KeyStoreVersion::V0_8_1, Box::new(Noop));
+
+        let (to, _) = migrations.step_from(&KeyStoreVersion::V0_8).unwrap();
+        assert_eq!(to, &KeyStoreVersion::V0_8_1);
+        assert!(migrations.step_from(&KeyStoreVersion::V0_8_1).is_none());
+    }
+}
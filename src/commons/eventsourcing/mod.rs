@@ -12,12 +12,23 @@ pub use self::cmd::{Command, CommandDetails, SentCommand, StoredCommand, WithSto
 mod store;
 pub use self::store::*;
 
+mod audit;
+pub use self::audit::{AuditEntry, AuditLog, AuditOutcome};
+
 mod listener;
-pub use self::listener::{EventCounter, PostSaveEventListener, PreSaveEventListener};
+pub use self::listener::{
+    EventCounter, PostSaveAsyncEventListener, PostSaveAsyncEventQueue, PostSaveEventListener, PreSaveEventListener,
+};
 
 mod kv;
 pub use self::kv::*;
 
+mod metrics;
+pub use self::metrics::{AggregateStoreMetrics, AggregateStoreMetricsReport};
+
+mod migration;
+pub use self::migration::{StoreMigration, StoreMigrations};
+
 //------------ Tests ---------------------------------------------------------
 
 #[cfg(test)]
@@ -28,6 +39,8 @@ mod tests {
     //! Goal is two-fold: document using a simple domain, and test the module.
     //!
 
+    use std::cell::Cell;
+    use std::collections::HashMap;
     use std::str::FromStr;
     use std::sync::Arc;
     use std::{fmt, fs};
@@ -159,6 +172,10 @@ mod tests {
         fn store(&self) -> Self::StorableDetails {
             self.clone()
         }
+
+        fn retry_safe(&self) -> bool {
+            matches!(self, PersonCommandDetails::ChangeName(_))
+        }
     }
 
     impl PersonCommand {
@@ -342,12 +359,1174 @@ mod tests {
         assert_eq!(history.commands().len(), 10);
         assert_eq!(history.commands().first().unwrap().sequence, 4);
 
+        // Get the last, partial, page of history
+        let mut crit = CommandHistoryCriteria::default();
+        crit.set_offset(20);
+        crit.set_rows(10);
+
+        let history = manager.command_history(&id_alice, crit).unwrap();
+        assert_eq!(history.total(), 22);
+        assert_eq!(history.commands().len(), 2);
+        assert_eq!(history.commands().first().unwrap().sequence, 21);
+        assert_eq!(history.commands().last().unwrap().sequence, 22);
+
+        // Poll for new commands using the last sequence as a cursor
+        let mut crit = CommandHistoryCriteria::default();
+        crit.set_after_sequence(18);
+        crit.set_rows(3);
+
+        let history = manager.command_history(&id_alice, crit).unwrap();
+        assert_eq!(history.total(), 4);
+        assert_eq!(history.commands().first().unwrap().sequence, 19);
+        assert_eq!(history.last_sequence(), Some(21));
+
+        let mut crit = CommandHistoryCriteria::default();
+        crit.set_after_sequence(history.last_sequence().unwrap());
+        let history = manager.command_history(&id_alice, crit).unwrap();
+        assert_eq!(history.commands().len(), 1);
+        assert_eq!(history.last_sequence(), Some(22));
+
+        let mut crit = CommandHistoryCriteria::default();
+        crit.set_after_sequence(22);
+        let history = manager.command_history(&id_alice, crit).unwrap();
+        assert!(history.commands().is_empty());
+        assert_eq!(history.last_sequence(), None);
+
         // Get history excluding 'around the sun' commands
         let mut crit = CommandHistoryCriteria::default();
         crit.set_excludes(&["person-around-sun"]);
         let history = manager.command_history(&id_alice, crit).unwrap();
         assert_eq!(history.total(), 1);
 
+        // Get history filtered by actor
+        let mut crit = CommandHistoryCriteria::default();
+        crit.set_actor("test");
+        crit.set_offset(20);
+        let history = manager.command_history(&id_alice, crit).unwrap();
+        assert_eq!(history.total(), 22);
+        assert_eq!(history.commands().len(), 2);
+
+        let mut crit = CommandHistoryCriteria::default();
+        crit.set_actor("someone-else");
+        let history = manager.command_history(&id_alice, crit).unwrap();
+        assert_eq!(history.total(), 0);
+
+        let _ = fs::remove_dir_all(d);
+    }
+
+    #[test]
+    fn archive_old_commands() {
+        let d = test::tmp_dir();
+
+        let manager = AggregateStore::<Person>::disk(&d, "person").unwrap();
+
+        let id_alice = Handle::from_str("alice").unwrap();
+        manager.add(InitPersonEvent::init(&id_alice, "alice smith")).unwrap();
+
+        for _ in 0..5 {
+            manager.command(PersonCommand::go_around_sun(&id_alice, None)).unwrap();
+        }
+
+        // The backup snapshot is at version 5, so only the commands for events
+        // 1 to 4 can be archived.
+        manager
+            .archive_old_commands(&id_alice, 0, &["person-around-sun"])
+            .unwrap();
+
+        let history = manager
+            .command_history(&id_alice, CommandHistoryCriteria::default())
+            .unwrap();
+        assert_eq!(history.total(), 1);
+        assert_eq!(history.commands().first().unwrap().sequence, 5);
+
+        // The archived events are recorded, so they are not reported as missing
+        assert!(manager.check_info(&id_alice).unwrap().is_empty());
+
+        let d2 = test::tmp_dir();
+        let backup = manager.backup(&d2, "person").unwrap();
+        assert_eq!(backup.events, 2);
+        assert_eq!(backup.missing, 0);
+
+        // Should still be able to rebuild state from disk
+        let manager = AggregateStore::<Person>::disk(&d, "person").unwrap();
+        manager.warm().unwrap();
+        let alice = manager.get_latest(&id_alice).unwrap();
+        assert_eq!(5, alice.age());
+
+        let _ = fs::remove_dir_all(d);
+        let _ = fs::remove_dir_all(d2);
+    }
+
+    #[test]
+    fn in_memory_store() {
+        let manager = AggregateStore::<Person>::in_memory("person").unwrap();
+
+        let id_alice = Handle::from_str("alice").unwrap();
+        manager.add(InitPersonEvent::init(&id_alice, "alice smith")).unwrap();
+        assert!(manager.add(InitPersonEvent::init(&id_alice, "alice jones")).is_err());
+
+        for _ in 0..5 {
+            manager.command(PersonCommand::go_around_sun(&id_alice, None)).unwrap();
+        }
+
+        manager
+            .archive_old_commands(&id_alice, 0, &["person-around-sun"])
+            .unwrap();
+        let history = manager
+            .command_history(&id_alice, CommandHistoryCriteria::default())
+            .unwrap();
+        assert_eq!(history.total(), 1);
+
+        manager.warm().unwrap();
+        assert_eq!(manager.list().unwrap(), vec![id_alice.clone()]);
+        assert_eq!(5, manager.get_latest(&id_alice).unwrap().age());
+    }
+
+    #[test]
+    fn init_event_missing() {
+        let d = test::tmp_dir();
+
+        let manager = AggregateStore::<Person>::disk(&d, "person").unwrap();
+
+        let id_alice = Handle::from_str("alice").unwrap();
+        manager.add(InitPersonEvent::init(&id_alice, "alice smith")).unwrap();
+        manager.command(PersonCommand::go_around_sun(&id_alice, None)).unwrap();
+
+        for file in &["delta-0.json", "snapshot.json", "snapshot-bk.json"] {
+            let mut path = d.clone();
+            path.push("person/alice");
+            path.push(file);
+            fs::remove_file(path).unwrap();
+        }
+
+        let manager = AggregateStore::<Person>::disk(&d, "person").unwrap();
+        assert!(matches!(
+            manager.get_latest(&id_alice),
+            Err(AggregateStoreError::InitEventMissing(_))
+        ));
+
+        let id_bob = Handle::from_str("bob").unwrap();
+        assert!(matches!(
+            manager.get_latest(&id_bob),
+            Err(AggregateStoreError::UnknownAggregate(_))
+        ));
+
+        let _ = fs::remove_dir_all(d);
+    }
+
+    #[test]
+    fn audit_log() {
+        let d = test::tmp_dir();
+        let audit_log_path = d.join("audit.log");
+
+        let mut manager = AggregateStore::<Person>::disk(&d, "person").unwrap();
+        manager.set_audit_log(Some(&audit_log_path), "person").unwrap();
+
+        let id_alice = Handle::from_str("alice").unwrap();
+        manager.add(InitPersonEvent::init(&id_alice, "alice smith")).unwrap();
+        manager.command(PersonCommand::go_around_sun(&id_alice, None)).unwrap();
+        assert!(manager
+            .command(PersonCommand::change_name(&id_alice, Some(42), "alice jones"))
+            .is_err());
+
+        assert_eq!(AuditLog::verify(&audit_log_path).unwrap(), 2);
+
+        let entries: Vec<AuditEntry> = fs::read_to_string(&audit_log_path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        assert_eq!(entries[0].store, "person");
+        assert_eq!(entries[0].handle, id_alice);
+        assert_eq!(entries[0].action, "person-around-sun");
+        assert_eq!(entries[0].outcome, AuditOutcome::Success { events: 1 });
+        assert_eq!(entries[1].actor, entries[0].actor);
+        assert_eq!(entries[1].action, "person-change-name");
+        assert!(matches!(entries[1].outcome, AuditOutcome::Error { .. }));
+
+        let _ = fs::remove_dir_all(d);
+    }
+
+    #[test]
+    fn compact() {
+        let d = test::tmp_dir();
+
+        let manager = AggregateStore::<Person>::disk(&d, "person").unwrap();
+
+        let id_alice = Handle::from_str("alice").unwrap();
+        manager.add(InitPersonEvent::init(&id_alice, "alice smith")).unwrap();
+
+        for _ in 0..5 {
+            manager.command(PersonCommand::go_around_sun(&id_alice, None)).unwrap();
+        }
+
+        assert_eq!(manager.compact(&id_alice).unwrap(), 6);
+        manager.command(PersonCommand::go_around_sun(&id_alice, None)).unwrap();
+
+        let mut init_path = d.clone();
+        init_path.push("person/alice/delta-0.json");
+        assert!(!init_path.exists());
+
+        // The aggregate is rebuilt from the snapshot, and its history is kept
+        let manager = AggregateStore::<Person>::disk(&d, "person").unwrap();
+        manager.warm().unwrap();
+        assert_eq!(6, manager.get_latest(&id_alice).unwrap().age());
+        assert!(manager.check_info(&id_alice).unwrap().is_empty());
+        assert!(manager.check_aggregate_integrity(&id_alice).unwrap().is_ok());
+        let history = manager
+            .command_history(&id_alice, CommandHistoryCriteria::default())
+            .unwrap();
+        assert_eq!(history.total(), 6);
+
+        // But it can no longer be replayed from before the baseline
+        assert_eq!(5, manager.get_aggregate_at(&id_alice, 5).unwrap().age());
+        assert!(matches!(
+            manager.get_aggregate_at(&id_alice, 4),
+            Err(AggregateStoreError::Compacted(_, 6))
+        ));
+        assert!(manager.export_aggregate(&id_alice).is_err());
+
+        // Recovery keeps the compacted state
+        manager.recover().unwrap();
+        let manager = AggregateStore::<Person>::disk(&d, "person").unwrap();
+        manager.warm().unwrap();
+        assert_eq!(6, manager.get_latest(&id_alice).unwrap().age());
+
+        let _ = fs::remove_dir_all(d);
+    }
+
+    #[test]
+    fn check_integrity() {
+        let d = test::tmp_dir();
+
+        let manager = AggregateStore::<Person>::disk(&d, "person").unwrap();
+
+        let id_alice = Handle::from_str("alice").unwrap();
+        manager.add(InitPersonEvent::init(&id_alice, "alice smith")).unwrap();
+
+        for _ in 0..5 {
+            manager.command(PersonCommand::go_around_sun(&id_alice, None)).unwrap();
+        }
+
+        let reports = manager.check_integrity().unwrap();
+        assert_eq!(reports.len(), 1);
+        assert!(reports[0].is_ok());
+        assert_eq!(reports[0].recoverable_version, Some(6));
+
+        // Remove an event, and verify that the check reports the issue without
+        // archiving anything.
+        let mut event_path = d.clone();
+        event_path.push("person/alice/delta-3.json");
+        fs::remove_file(&event_path).unwrap();
+
+        let report = manager.check_aggregate_integrity(&id_alice).unwrap();
+        assert!(!report.is_ok());
+        assert_eq!(report.last_good_event, 2);
+        assert_eq!(report.missing_events, vec![3]);
+        assert_eq!(report.surplus_commands.len(), 3);
+        assert_eq!(report.surplus_events, vec![4, 5]);
+        assert_eq!(report.snapshot, SnapshotStatus::AfterLimit(6));
+        assert_eq!(report.backup_snapshot, SnapshotStatus::AfterLimit(5));
+        assert_eq!(report.recoverable_version, Some(3));
+
+        let mut surplus_event_path = d.clone();
+        surplus_event_path.push("person/alice/delta-4.json");
+        assert!(surplus_event_path.exists());
+
+        // Recover only alice, to the version found by the check
+        let plan = manager.recover_aggregate(&id_alice).unwrap();
+        assert_eq!(plan.last_event, 2);
+        assert_eq!(plan.surplus_events, vec![4, 5]);
+        assert!(!surplus_event_path.exists());
+        assert!(manager.check_aggregate_integrity(&id_alice).unwrap().is_ok());
+        assert_eq!(manager.get_latest(&id_alice).unwrap().version(), 3);
+
+        let id_bob = Handle::from_str("bob").unwrap();
+        assert!(matches!(
+            manager.recover_aggregate(&id_bob),
+            Err(AggregateStoreError::UnknownAggregate(_))
+        ));
+
+        let _ = fs::remove_dir_all(d);
+    }
+    #[test]
+    fn check_info() {
+        let d = test::tmp_dir();
+
+        let manager = AggregateStore::<Person>::disk(&d, "person").unwrap();
+
+        let id_alice = Handle::from_str("alice").unwrap();
+        manager.add(InitPersonEvent::init(&id_alice, "alice smith")).unwrap();
+
+        for _ in 0..5 {
+            manager.command(PersonCommand::go_around_sun(&id_alice, None)).unwrap();
+        }
+
+        assert!(manager.check_info(&id_alice).unwrap().is_empty());
+
+        let mut alice_dir = d.clone();
+        alice_dir.push("person/alice");
+        fs::remove_file(alice_dir.join("delta-3.json")).unwrap();
+        fs::remove_file(alice_dir.join("delta-5.json")).unwrap();
+        fs::remove_file(alice_dir.join("snapshot.json")).unwrap();
+
+        let discrepancies = manager.check_info(&id_alice).unwrap();
+        assert_eq!(
+            discrepancies,
+            vec![
+                InfoDiscrepancy::EventsMissing(vec![3, 5]),
+                InfoDiscrepancy::LastEventTooHigh { info: 5, found: 4 },
+                InfoDiscrepancy::SnapshotMissing(6),
+            ]
+        );
+
+        let manager = AggregateStore::<Person>::disk(&d, "person").unwrap();
+        match manager.warm() {
+            Err(AggregateStoreError::WarmupFailed(_, e)) => assert!(e.contains("missing event(s) 3, 5")),
+            _ => panic!("Expected warmup to fail"),
+        }
+
+        fs::remove_file(alice_dir.join("info.json")).unwrap();
+        assert_eq!(
+            manager.check_info(&id_alice).unwrap(),
+            vec![InfoDiscrepancy::InfoMissing]
+        );
+
+        let _ = fs::remove_dir_all(d);
+    }
+
+    #[test]
+    fn store_metrics() {
+        let d = test::tmp_dir();
+
+        let manager = AggregateStore::<Person>::disk(&d, "person").unwrap();
+
+        let id_alice = Handle::from_str("alice").unwrap();
+        manager.add(InitPersonEvent::init(&id_alice, "alice smith")).unwrap();
+        manager.command(PersonCommand::go_around_sun(&id_alice, None)).unwrap();
+        manager.command(PersonCommand::go_around_sun(&id_alice, None)).unwrap();
+
+        let metrics = manager.metrics();
+        assert_eq!(metrics.commands.get(&id_alice), Some(&2));
+        assert_eq!(metrics.commands_total(), 2);
+        assert_eq!(metrics.events_stored, 3);
+        assert_eq!(metrics.snapshots_written, 3);
+        assert_eq!(metrics.cache_hits, 2);
+        assert_eq!(metrics.cache_misses, 0);
+
+        let manager = AggregateStore::<Person>::disk(&d, "person").unwrap();
+        manager.get_latest(&id_alice).unwrap();
+        manager.get_latest(&id_alice).unwrap();
+
+        let metrics = manager.metrics();
+        assert_eq!(metrics.commands_total(), 0);
+        assert_eq!(metrics.cache_misses, 1);
+        assert_eq!(metrics.cache_hits, 1);
+
+        let _ = fs::remove_dir_all(d);
+    }
+
+    struct UpperCaseNames;
+
+    impl StoreMigration for UpperCaseNames {
+        fn migrate_snapshot(&self, mut snapshot: serde_json::Value) -> StoreResult<serde_json::Value> {
+            let name = snapshot["name"].as_str().unwrap().to_uppercase();
+            snapshot["name"] = serde_json::Value::String(name);
+            Ok(snapshot)
+        }
+    }
+
+    #[test]
+    fn migrate() {
+        let d = test::tmp_dir();
+
+        let manager = AggregateStore::<Person>::disk(&d, "person").unwrap();
+
+        let id_alice = Handle::from_str("alice").unwrap();
+        manager.add(InitPersonEvent::init(&id_alice, "alice smith")).unwrap();
+        manager.command(PersonCommand::go_around_sun(&id_alice, None)).unwrap();
+        manager.set_version(&KeyStoreVersion::V0_9_0_RC1).unwrap();
+
+        // Leave an incomplete migration behind, as if Krill stopped halfway.
+        let mut incomplete_path = d.clone();
+        incomplete_path.push("person/.alice.migrating");
+        fs::create_dir_all(&incomplete_path).unwrap();
+
+        let mut manager = AggregateStore::<Person>::disk(&d, "person").unwrap();
+        manager.add_migration(
+            KeyStoreVersion::V0_9_0_RC1,
+            KeyStoreVersion::V0_9_0,
+            Box::new(UpperCaseNames),
+        );
+        manager.warm().unwrap();
+
+        assert!(!incomplete_path.exists());
+        assert_eq!(manager.get_version().unwrap(), KeyStoreVersion::V0_9_0);
+
+        let alice = manager.get_latest(&id_alice).unwrap();
+        assert_eq!(alice.name(), "ALICE SMITH");
+        assert_eq!(alice.age(), 1);
+
+        let _ = fs::remove_dir_all(d);
+    }
+
+    #[test]
+    fn read_only_store() {
+        let d = test::tmp_dir();
+
+        let primary = AggregateStore::<Person>::disk(&d, "person").unwrap();
+
+        let id_alice = Handle::from_str("alice").unwrap();
+        primary.add(InitPersonEvent::init(&id_alice, "alice smith")).unwrap();
+
+        let replica = AggregateStore::<Person>::disk_read_only(&d, "person").unwrap();
+        replica.warm().unwrap();
+        assert_eq!(0, replica.get_latest(&id_alice).unwrap().age());
+
+        assert!(replica.command(PersonCommand::go_around_sun(&id_alice, None)).is_err());
+        let id_bob = Handle::from_str("bob").unwrap();
+        assert!(replica.add(InitPersonEvent::init(&id_bob, "bob")).is_err());
+
+        // The replica picks up changes made by the primary
+        primary.command(PersonCommand::go_around_sun(&id_alice, None)).unwrap();
+        assert_eq!(1, replica.get_latest(&id_alice).unwrap().age());
+
+        let history = replica
+            .command_history(&id_alice, CommandHistoryCriteria::default())
+            .unwrap();
+        assert_eq!(history.total(), 1);
+
+        let mut empty = d.clone();
+        empty.push("empty");
+        assert!(AggregateStore::<Person>::disk_read_only(&d, "empty").is_err());
+        assert!(!empty.exists());
+
+        let _ = fs::remove_dir_all(d);
+    }
+
+    #[test]
+    fn cached_aggregates_are_not_probed_by_writable_store() {
+        let d = test::tmp_dir();
+
+        let primary = AggregateStore::<Person>::disk(&d, "person").unwrap();
+        let other = AggregateStore::<Person>::disk(&d, "person").unwrap();
+        let replica = AggregateStore::<Person>::disk_read_only(&d, "person").unwrap();
+
+        let id_alice = Handle::from_str("alice").unwrap();
+        primary.add(InitPersonEvent::init(&id_alice, "alice smith")).unwrap();
+        assert_eq!(0, primary.get_latest(&id_alice).unwrap().age());
+        assert_eq!(0, replica.get_latest(&id_alice).unwrap().age());
+
+        // Another writer is not expected, so the primary trusts its cache, but
+        // the replica picks up the change.
+        other.command(PersonCommand::go_around_sun(&id_alice, None)).unwrap();
+        assert_eq!(0, primary.get_latest(&id_alice).unwrap().age());
+        assert_eq!(1, replica.get_latest(&id_alice).unwrap().age());
+
+        let _ = fs::remove_dir_all(d);
+    }
+
+    #[test]
+    fn lazy_warm() {
+        let d = test::tmp_dir();
+
+        let manager = AggregateStore::<Person>::disk(&d, "person").unwrap();
+        let id_alice = Handle::from_str("alice").unwrap();
+        manager.add(InitPersonEvent::init(&id_alice, "alice smith")).unwrap();
+        manager.command(PersonCommand::go_around_sun(&id_alice, None)).unwrap();
+
+        let eager = AggregateStore::<Person>::disk(&d, "person").unwrap();
+        eager.warm().unwrap();
+        assert_eq!(eager.metrics().cache_misses, 1);
+
+        let mut lazy = AggregateStore::<Person>::disk(&d, "person").unwrap();
+        lazy.set_lazy_warm(true);
+        lazy.warm().unwrap();
+        assert_eq!(lazy.metrics().cache_misses, 0);
+
+        assert_eq!(lazy.get_latest(&id_alice).unwrap().age(), 1);
+        assert_eq!(lazy.metrics().cache_misses, 1);
+
+        // A snapshot which can be parsed, but which is not the snapshot that was
+        // saved, is found when warming, and the aggregate is rebuilt.
+        let snapshot_path = d.join("person/alice/snapshot.json");
+        let snapshot = fs::read_to_string(&snapshot_path).unwrap();
+        fs::write(&snapshot_path, snapshot.replace("alice smith", "alice jones")).unwrap();
+
+        let mut lazy = AggregateStore::<Person>::disk(&d, "person").unwrap();
+        lazy.set_lazy_warm(true);
+        lazy.warm().unwrap();
+        assert_eq!(lazy.metrics().cache_misses, 1);
+        assert_eq!(lazy.get_latest(&id_alice).unwrap().name(), "alice smith");
+
+        let _ = fs::remove_dir_all(d);
+    }
+
+    #[test]
+    fn lazy_warm_with_warm_set() {
+        let d = test::tmp_dir();
+
+        let manager = AggregateStore::<Person>::disk(&d, "person").unwrap();
+        let handles: Vec<Handle> = ["carol", "alice", "bob"]
+            .iter()
+            .map(|name| Handle::from_str(name).unwrap())
+            .collect();
+        for handle in &handles {
+            manager.add(InitPersonEvent::init(handle, handle.as_str())).unwrap();
+        }
+        manager
+            .command(PersonCommand::go_around_sun(&handles[2], None))
+            .unwrap();
+
+        // carol was updated first, so she is only warmed because she is given
+        // explicitly, and one of alice and bob is warmed as most recent.
+        let mut lazy = AggregateStore::<Person>::disk(&d, "person").unwrap();
+        lazy.set_lazy_warm(true);
+        lazy.set_warm_set(1, vec![handles[0].clone()]);
+        lazy.warm().unwrap();
+        assert_eq!(lazy.metrics().cache_misses, 2);
+
+        lazy.get_latest(&handles[0]).unwrap();
+        assert_eq!(lazy.metrics().cache_misses, 2);
+
+        // Without lazy warming everything is loaded
+        let mut eager = AggregateStore::<Person>::disk(&d, "person").unwrap();
+        eager.set_warm_set(1, vec![]);
+        eager.warm().unwrap();
+        assert_eq!(eager.metrics().cache_misses, 3);
+
+        let _ = fs::remove_dir_all(d);
+    }
+
+    #[test]
+    fn command_with_retry() {
+        let d = test::tmp_dir();
+
+        let manager = AggregateStore::<Person>::disk(&d, "person").unwrap();
+        let id_alice = Handle::from_str("alice").unwrap();
+        manager.add(InitPersonEvent::init(&id_alice, "alice smith")).unwrap();
+
+        // Another command updates alice after the first command was built
+        let built = Cell::new(0);
+        let interfere = |cmd: PersonCommand| {
+            built.set(built.get() + 1);
+            if built.get() == 1 {
+                manager.command(PersonCommand::go_around_sun(&id_alice, None)).unwrap();
+            }
+            cmd
+        };
+
+        let alice = manager
+            .command_with_retry(&id_alice, 1, |p| {
+                interfere(PersonCommand::change_name(&id_alice, Some(p.version()), "alice jones"))
+            })
+            .unwrap();
+        assert_eq!(alice.name(), "alice jones");
+        assert_eq!(built.get(), 2);
+
+        // Commands which are not retry safe are not retried
+        built.set(0);
+        assert!(manager
+            .command_with_retry(&id_alice, 1, |p| {
+                interfere(PersonCommand::go_around_sun(&id_alice, Some(p.version())))
+            })
+            .is_err());
+        assert_eq!(built.get(), 1);
+        assert_eq!(manager.get_latest(&id_alice).unwrap().age(), 2);
+
+        let _ = fs::remove_dir_all(d);
+    }
+
+    #[derive(Default)]
+    struct SlowListener {
+        versions: std::sync::Mutex<Vec<u64>>,
+    }
+
+    impl PostSaveAsyncEventListener<Person> for SlowListener {
+        fn listen(&self, agg: Arc<Person>, events: Vec<PersonEvent>) {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            let mut versions = self.versions.lock().unwrap();
+            versions.extend(events.iter().map(|e| e.version()));
+            assert_eq!(versions.last(), Some(&(agg.version() - 1)));
+        }
+    }
+
+    #[test]
+    fn post_save_async_listener() {
+        let d = test::tmp_dir();
+
+        let listener = Arc::new(SlowListener::default());
+        let mut manager = AggregateStore::<Person>::disk(&d, "person").unwrap();
+        manager
+            .add_post_save_async_listener("slow", listener.clone(), 1)
+            .unwrap();
+
+        let id_alice = Handle::from_str("alice").unwrap();
+        manager.add(InitPersonEvent::init(&id_alice, "alice smith")).unwrap();
+        for _ in 0..5 {
+            manager.command(PersonCommand::go_around_sun(&id_alice, None)).unwrap();
+        }
+
+        // Dropping the store waits for all queued events to be delivered, in order
+        drop(manager);
+        assert_eq!(*listener.versions.lock().unwrap(), vec![1, 2, 3, 4, 5]);
+
+        // Read-only stores cannot save which events were delivered
+        let mut read_only = AggregateStore::<Person>::disk_read_only(&d, "person").unwrap();
+        assert!(read_only
+            .add_post_save_async_listener("slow", listener.clone(), 1)
+            .is_err());
+
+        let _ = fs::remove_dir_all(d);
+    }
+
+    #[test]
+    fn post_save_async_listener_catches_up() {
+        let d = test::tmp_dir();
+        let id_alice = Handle::from_str("alice").unwrap();
+
+        let listener = Arc::new(SlowListener::default());
+        let mut manager = AggregateStore::<Person>::disk(&d, "person").unwrap();
+        manager
+            .add_post_save_async_listener("slow", listener.clone(), 1)
+            .unwrap();
+        manager.add(InitPersonEvent::init(&id_alice, "alice smith")).unwrap();
+        for _ in 0..2 {
+            manager.command(PersonCommand::go_around_sun(&id_alice, None)).unwrap();
+        }
+        drop(manager);
+        assert_eq!(*listener.versions.lock().unwrap(), vec![1, 2]);
+
+        // Events saved while the listener was not added, e.g. because Krill stopped
+        // before they were delivered, are delivered when it is added again
+        let manager = AggregateStore::<Person>::disk(&d, "person").unwrap();
+        for _ in 0..2 {
+            manager.command(PersonCommand::go_around_sun(&id_alice, None)).unwrap();
+        }
+        drop(manager);
+
+        let listener = Arc::new(SlowListener::default());
+        let new_listener = Arc::new(SlowListener::default());
+        let mut manager = AggregateStore::<Person>::disk(&d, "person").unwrap();
+        manager
+            .add_post_save_async_listener("slow", listener.clone(), 1)
+            .unwrap();
+        manager
+            .add_post_save_async_listener("new", new_listener.clone(), 1)
+            .unwrap();
+        manager.command(PersonCommand::go_around_sun(&id_alice, None)).unwrap();
+
+        // A new aggregate with the handle of a dropped aggregate starts again
+        manager.drop_aggregate(&id_alice).unwrap();
+        manager.add(InitPersonEvent::init(&id_alice, "alice jones")).unwrap();
+        manager.command(PersonCommand::go_around_sun(&id_alice, None)).unwrap();
+        drop(manager);
+
+        assert_eq!(*listener.versions.lock().unwrap(), vec![3, 4, 5, 1]);
+        assert_eq!(*new_listener.versions.lock().unwrap(), vec![5, 1]);
+
+        let _ = fs::remove_dir_all(d);
+    }
+
+    #[test]
+    fn post_save_async_queue_restores_order() {
+        let manager = AggregateStore::<Person>::in_memory("person").unwrap();
+        let id_alice = Handle::from_str("alice").unwrap();
+        manager.add(InitPersonEvent::init(&id_alice, "alice smith")).unwrap();
+
+        let mut updates = vec![];
+        for _ in 0..3 {
+            let agg = manager.command(PersonCommand::go_around_sun(&id_alice, None)).unwrap();
+            let event = manager
+                .get_event::<PersonEvent>(&id_alice, agg.version() - 1)
+                .unwrap()
+                .unwrap();
+            updates.push((agg, vec![event]));
+        }
+
+        let listener = Arc::new(SlowListener::default());
+        let kv = KeyValueStore::memory("listener");
+        let key = KeyStoreKey::simple("listener-slow.json".to_string());
+        let queue = PostSaveAsyncEventQueue::start(listener.clone(), 1, kv.clone(), key.clone(), HashMap::new());
+
+        // Updates are queued in reverse, and the first one is queued again,
+        // e.g. because it was replayed
+        for (agg, events) in updates.iter().rev().chain(updates.first()) {
+            queue.send(agg.clone(), events.clone());
+        }
+        drop(queue);
+
+        assert_eq!(*listener.versions.lock().unwrap(), vec![1, 2, 3]);
+        let cursor: HashMap<Handle, u64> = kv.get(&key).unwrap().unwrap();
+        assert_eq!(cursor.get(&id_alice), Some(&4));
+    }
+
+    #[test]
+    fn list_page() {
+        let d = test::tmp_dir();
+
+        let manager = AggregateStore::<Person>::disk(&d, "person").unwrap();
+        for name in &["carol", "alice", "bob"] {
+            let id = Handle::from_str(name).unwrap();
+            manager.add(InitPersonEvent::init(&id, name)).unwrap();
+        }
+
+        let page = manager.list_page(AggregateListSort::Handle, 1, Some(1)).unwrap();
+        assert_eq!(page.total, 3);
+        assert_eq!(page.handles, vec![Handle::from_str("bob").unwrap()]);
+
+        let page = manager.list_page(AggregateListSort::Handle, 2, None).unwrap();
+        assert_eq!(page.handles, vec![Handle::from_str("carol").unwrap()]);
+
+        let page = manager.list_page(AggregateListSort::LastUpdate, 0, Some(10)).unwrap();
+        assert_eq!(page.total, 3);
+        assert_eq!(page.handles.len(), 3);
+
+        assert!(manager
+            .list_page(AggregateListSort::Handle, 3, None)
+            .unwrap()
+            .handles
+            .is_empty());
+
+        let _ = fs::remove_dir_all(d);
+    }
+
+    #[test]
+    fn bounded_cache() {
+        let d = test::tmp_dir();
+
+        let mut manager = AggregateStore::<Person>::disk(&d, "person").unwrap();
+        manager.set_cache_size(1);
+
+        let id_alice = Handle::from_str("alice").unwrap();
+        let id_bob = Handle::from_str("bob").unwrap();
+        manager.add(InitPersonEvent::init(&id_alice, "alice smith")).unwrap();
+        manager.add(InitPersonEvent::init(&id_bob, "bob jones")).unwrap();
+        assert_eq!(manager.metrics().cache_evictions, 1);
+
+        manager.command(PersonCommand::go_around_sun(&id_alice, None)).unwrap();
+        manager.command(PersonCommand::go_around_sun(&id_bob, None)).unwrap();
+        manager.command(PersonCommand::go_around_sun(&id_alice, None)).unwrap();
+
+        assert_eq!(manager.get_latest(&id_alice).unwrap().age(), 2);
+        assert_eq!(manager.get_latest(&id_bob).unwrap().age(), 1);
+
+        let metrics = manager.metrics();
+        assert_eq!(metrics.cache_evictions, 5);
+        assert_eq!(metrics.cache_misses, 4);
+
+        let _ = fs::remove_dir_all(d);
+    }
+
+    #[test]
+    fn every_command_has_an_actor() {
+        let d = test::tmp_dir();
+
+        let manager = AggregateStore::<Person>::disk(&d, "person").unwrap();
+
+        let id_alice = Handle::from_str("alice").unwrap();
+        manager.add(InitPersonEvent::init(&id_alice, "alice smith")).unwrap();
+
+        manager.command(PersonCommand::go_around_sun(&id_alice, None)).unwrap();
+
+        let nameless = Actor::test_from_details(String::new(), HashMap::new());
+        let cmd = PersonCommand::new(&id_alice, None, PersonCommandDetails::GoAroundTheSun, &nameless);
+        manager.command(cmd).unwrap();
+
+        let history = manager
+            .command_history(&id_alice, CommandHistoryCriteria::default())
+            .unwrap();
+        let actors: Vec<&str> = history.commands().iter().map(|c| c.actor.as_str()).collect();
+        assert_eq!(actors, vec!["test", "unknown"]);
+
+        let _ = fs::remove_dir_all(d);
+    }
+
+    #[test]
+    fn refuse_values_over_size_limit() {
+        let d = test::tmp_dir();
+
+        let mut manager = AggregateStore::<Person>::disk(&d, "person").unwrap();
+
+        let id_alice = Handle::from_str("alice").unwrap();
+        manager.add(InitPersonEvent::init(&id_alice, "alice smith")).unwrap();
+
+        let size = *manager.metrics().snapshot_bytes.get(&id_alice).unwrap() as usize;
+        manager.set_size_limits(size, size + 20);
+
+        // A slightly larger snapshot is saved, with a warning
+        manager
+            .command(PersonCommand::change_name(&id_alice, None, "alice smiths"))
+            .unwrap();
+        let new_size = *manager.metrics().snapshot_bytes.get(&id_alice).unwrap() as usize;
+        assert_eq!(new_size, size + 1);
+
+        // An event over the limit is refused, and nothing is changed
+        let res = manager.command(PersonCommand::change_name(
+            &id_alice,
+            None,
+            "alice smith-doe-jones-van-der-berg",
+        ));
+        assert!(matches!(res, Err(PersonError::Custom(_))));
+        assert_eq!(manager.get_latest(&id_alice).unwrap().name(), "alice smiths");
+
+        // A snapshot over the limit is refused as well, even if its event is small,
+        // and the command is not saved
+        for _ in 0..9 {
+            manager.command(PersonCommand::go_around_sun(&id_alice, None)).unwrap();
+        }
+        let size = *manager.metrics().snapshot_bytes.get(&id_alice).unwrap() as usize;
+        manager.set_size_limits(0, size);
+
+        let res = manager.command(PersonCommand::go_around_sun(&id_alice, None));
+        assert!(matches!(res, Err(PersonError::Custom(_))));
+        assert_eq!(manager.get_latest(&id_alice).unwrap().age(), 9);
+        let history = manager
+            .command_history(&id_alice, CommandHistoryCriteria::default())
+            .unwrap();
+        assert_eq!(history.total(), 10);
+
+        // The next command gets the next sequence, rather than skipping one
+        manager.set_size_limits(0, 0);
+        manager.command(PersonCommand::go_around_sun(&id_alice, None)).unwrap();
+        let history = manager
+            .command_history(&id_alice, CommandHistoryCriteria::default())
+            .unwrap();
+        assert_eq!(history.last_sequence(), Some(11));
+
+        let _ = fs::remove_dir_all(d);
+    }
+
+    #[test]
+    fn replay_ignores_command_timestamps() {
+        let d = test::tmp_dir();
+
+        let manager = AggregateStore::<Person>::disk(&d, "person").unwrap();
+
+        let id_alice = Handle::from_str("alice").unwrap();
+        manager.add(InitPersonEvent::init(&id_alice, "alice smith")).unwrap();
+
+        manager.command(PersonCommand::go_around_sun(&id_alice, None)).unwrap();
+        manager
+            .command(PersonCommand::change_name(&id_alice, None, "alice jones"))
+            .unwrap();
+        manager.command(PersonCommand::go_around_sun(&id_alice, None)).unwrap();
+
+        // Rename the commands so that their timestamps go backwards, as if the
+        // system clock was changed before each command.
+        let mut alice_dir = d.clone();
+        alice_dir.push("person/alice");
+        let mut second = None;
+        for entry in fs::read_dir(&alice_dir).unwrap() {
+            let path = entry.unwrap().path();
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+            if name.starts_with("command--") {
+                let mut key = CommandKey::from_str(&name).unwrap();
+                key.timestamp_secs = 1_600_000_000 - key.sequence as i64 * 3600;
+                fs::rename(&path, alice_dir.join(format!("{}.json", key))).unwrap();
+                if key.sequence == 2 {
+                    second = Some(key);
+                }
+            }
+        }
+
+        // Add a duplicate for the second command, with a later timestamp.
+        let second = second.unwrap();
+        let mut duplicate = second.clone();
+        duplicate.timestamp_secs = 1_700_000_000;
+        fs::copy(
+            alice_dir.join(format!("{}.json", second)),
+            alice_dir.join(format!("{}.json", duplicate)),
+        )
+        .unwrap();
+
+        let report = manager.check_aggregate_integrity(&id_alice).unwrap();
+        assert_eq!(report.last_good_command, 3);
+        assert_eq!(report.surplus_commands, vec![duplicate.clone()]);
+
+        // A dry run reports the same, but leaves the duplicate in place
+        let plans = manager.recover_dry_run().unwrap();
+        assert_eq!(plans.len(), 1);
+        assert_eq!(plans[0].last_command, 3);
+        assert_eq!(plans[0].last_event, 3);
+        assert_eq!(plans[0].surplus_commands, vec![duplicate.clone()]);
+        assert!(alice_dir.join(format!("{}.json", duplicate)).exists());
+
+        let manager = AggregateStore::<Person>::disk(&d, "person").unwrap();
+        manager.recover().unwrap();
+
+        let alice = manager.get_latest(&id_alice).unwrap();
+        assert_eq!(alice.name(), "alice jones");
+        assert_eq!(alice.age(), 2);
+
+        let history = manager
+            .command_history(&id_alice, CommandHistoryCriteria::default())
+            .unwrap();
+        let sequences: Vec<u64> = history.commands().iter().map(|c| c.sequence).collect();
+        assert_eq!(sequences, vec![1, 2, 3]);
+
+        let mut surplus_path = alice_dir.clone();
+        surplus_path.push(format!("surplus/{}.json", duplicate));
+        assert!(surplus_path.exists());
+
+        let _ = fs::remove_dir_all(d);
+    }
+
+    #[test]
+    fn recover_dry_run_leaves_corrupt_values() {
+        let d = test::tmp_dir();
+
+        let manager = AggregateStore::<Person>::disk(&d, "person").unwrap();
+        let id_alice = Handle::from_str("alice").unwrap();
+        let id_bob = Handle::from_str("bob").unwrap();
+        for id in &[&id_alice, &id_bob] {
+            manager.add(InitPersonEvent::init(id, id.as_str())).unwrap();
+            manager.command(PersonCommand::go_around_sun(id, None)).unwrap();
+            manager.command(PersonCommand::go_around_sun(id, None)).unwrap();
+        }
+
+        // Corrupt the second command of alice, and the last event of bob
+        let alice_dir = d.join("person/alice");
+        let command_path = fs::read_dir(&alice_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| {
+                let name = path.file_name().unwrap().to_string_lossy().to_string();
+                name.starts_with("command--") && CommandKey::from_str(&name).unwrap().sequence == 2
+            })
+            .unwrap();
+        let event_path = d.join("person/bob/delta-2.json");
+        fs::write(&command_path, b"{ \"corrupt").unwrap();
+        fs::write(&event_path, b"{ \"corrupt").unwrap();
+
+        let plans = manager.recover_dry_run().unwrap();
+        assert_eq!(plans.len(), 2);
+        assert!(plans.iter().all(|plan| plan.last_event == 1));
+
+        // Nothing was archived
+        assert!(command_path.exists());
+        assert!(event_path.exists());
+        assert!(!alice_dir.join("corrupt").exists());
+        assert!(!d.join("person/bob/corrupt").exists());
+
+        let _ = fs::remove_dir_all(d);
+    }
+
+    #[test]
+    fn get_aggregate_at_version() {
+        let d = test::tmp_dir();
+
+        let manager = AggregateStore::<Person>::disk(&d, "person").unwrap();
+
+        let id_alice = Handle::from_str("alice").unwrap();
+        manager.add(InitPersonEvent::init(&id_alice, "alice smith")).unwrap();
+
+        for _ in 0..5 {
+            manager.command(PersonCommand::go_around_sun(&id_alice, None)).unwrap();
+        }
+
+        let alice = manager.get_aggregate_at(&id_alice, 2).unwrap();
+        assert_eq!(3, alice.version());
+        assert_eq!(2, alice.age());
+
+        let alice = manager.get_aggregate_at(&id_alice, 0).unwrap();
+        assert_eq!(0, alice.age());
+
+        assert!(matches!(
+            manager.get_aggregate_at(&id_alice, 6),
+            Err(AggregateStoreError::UnknownVersion(_, 6, 5))
+        ));
+
+        // The latest state and snapshots are not affected
+        assert_eq!(5, manager.get_latest(&id_alice).unwrap().age());
+        let manager = AggregateStore::<Person>::disk(&d, "person").unwrap();
+        assert_eq!(5, manager.get_latest(&id_alice).unwrap().age());
+
+        let _ = fs::remove_dir_all(d);
+    }
+
+    #[test]
+    fn export_and_import_aggregate() {
+        let d = test::tmp_dir();
+
+        let manager = AggregateStore::<Person>::disk(&d, "person").unwrap();
+
+        let id_alice = Handle::from_str("alice").unwrap();
+        manager.add(InitPersonEvent::init(&id_alice, "alice smith")).unwrap();
+
+        for _ in 0..3 {
+            manager.command(PersonCommand::go_around_sun(&id_alice, None)).unwrap();
+        }
+
+        let export = manager.export_aggregate(&id_alice).unwrap();
+        assert_eq!(3, export.events.len());
+        assert_eq!(3, export.commands.len());
+
+        // The export survives a round trip through json
+        let json = serde_json::to_string(&export).unwrap();
+        let export: AggregateExport<Person> = serde_json::from_str(&json).unwrap();
+
+        // Existing aggregates are not overwritten
+        assert!(matches!(
+            manager.import_aggregate(export.clone()),
+            Err(AggregateStoreError::AggregateExists(_))
+        ));
+
+        let d2 = test::tmp_dir();
+        let other = AggregateStore::<Person>::disk(&d2, "person").unwrap();
+
+        // Exports with a gap in the events are rejected
+        let mut gap = export.clone();
+        gap.events.remove(1);
+        assert!(matches!(
+            other.import_aggregate(gap),
+            Err(AggregateStoreError::InvalidExport(_, _))
+        ));
+        assert!(!other.has(&id_alice).unwrap());
+
+        other.import_aggregate(export).unwrap();
+        let alice = other.get_latest(&id_alice).unwrap();
+        assert_eq!(3, alice.age());
+        let history = other.command_history(&id_alice, CommandHistoryCriteria::default());
+        assert_eq!(3, history.unwrap().total());
+
+        // The imported aggregate can be loaded from disk, and used
+        let other = AggregateStore::<Person>::disk(&d2, "person").unwrap();
+        other.command(PersonCommand::go_around_sun(&id_alice, None)).unwrap();
+        assert_eq!(4, other.get_latest(&id_alice).unwrap().age());
+
+        let _ = fs::remove_dir_all(d);
+        let _ = fs::remove_dir_all(d2);
+    }
+
+    #[test]
+    fn backup() {
+        let d = test::tmp_dir();
+
+        let manager = AggregateStore::<Person>::disk(&d, "person").unwrap();
+
+        let id_alice = Handle::from_str("alice").unwrap();
+        manager.add(InitPersonEvent::init(&id_alice, "alice smith")).unwrap();
+        for _ in 0..3 {
+            manager.command(PersonCommand::go_around_sun(&id_alice, None)).unwrap();
+        }
+
+        let id_bob = Handle::from_str("bob").unwrap();
+        manager.add(InitPersonEvent::init(&id_bob, "bob")).unwrap();
+        manager.command(PersonCommand::go_around_sun(&id_bob, None)).unwrap();
+        manager.compact(&id_bob).unwrap();
+
+        let d2 = test::tmp_dir();
+        let backup = manager.backup(&d2, "person").unwrap();
+        assert_eq!(
+            backup,
+            AggregateStoreBackup {
+                aggregates: 2,
+                commands: 4,
+                events: 4,
+                missing: 0
+            }
+        );
+
+        // The backup can be used as a store, and continues where it left off
+        let restored = AggregateStore::<Person>::disk(&d2, "person").unwrap();
+        restored.warm().unwrap();
+        assert!(restored.check_aggregate_integrity(&id_alice).unwrap().is_ok());
+        assert!(restored.check_aggregate_integrity(&id_bob).unwrap().is_ok());
+        assert_eq!(3, restored.get_latest(&id_alice).unwrap().age());
+        assert_eq!(1, restored.get_latest(&id_bob).unwrap().age());
+
+        restored.command(PersonCommand::go_around_sun(&id_alice, None)).unwrap();
+        assert_eq!(4, restored.get_latest(&id_alice).unwrap().age());
+        assert_eq!(3, manager.get_latest(&id_alice).unwrap().age());
+
+        let _ = fs::remove_dir_all(d);
+        let _ = fs::remove_dir_all(d2);
+    }
+
+    #[test]
+    fn interrupted_snapshot_write_is_ignored() {
+        let d = test::tmp_dir();
+
+        let manager = AggregateStore::<Person>::disk(&d, "person").unwrap();
+
+        let id_alice = Handle::from_str("alice").unwrap();
+        manager.add(InitPersonEvent::init(&id_alice, "alice smith")).unwrap();
+        for _ in 0..3 {
+            manager.command(PersonCommand::go_around_sun(&id_alice, None)).unwrap();
+        }
+
+        let mut alice_dir = d.clone();
+        alice_dir.push("person/alice");
+        let snapshot_path = alice_dir.join("snapshot.json");
+        let backup_snapshot_path = alice_dir.join("snapshot-bk.json");
+        let new_snapshot_path = alice_dir.join("snapshot-new.json");
+
+        // Crash while the new snapshot was written: a torn new snapshot is left
+        fs::write(&new_snapshot_path, b"{ \"id\": \"ali").unwrap();
+
+        let manager = AggregateStore::<Person>::disk(&d, "person").unwrap();
+        assert_eq!(3, manager.get_latest(&id_alice).unwrap().age());
+        assert!(new_snapshot_path.exists());
+        manager.warm().unwrap();
+        assert!(!new_snapshot_path.exists());
+
+        // Crash after the current snapshot was moved to the backup, but before
+        // the complete new snapshot was promoted: it is still never used.
+        fs::copy(&snapshot_path, &new_snapshot_path).unwrap();
+        fs::rename(&snapshot_path, &backup_snapshot_path).unwrap();
+
+        let manager = AggregateStore::<Person>::disk(&d, "person").unwrap();
+        assert_eq!(3, manager.get_latest(&id_alice).unwrap().age());
+        assert!(new_snapshot_path.exists());
+        manager.warm().unwrap();
+        assert!(!new_snapshot_path.exists());
+
+        manager.command(PersonCommand::go_around_sun(&id_alice, None)).unwrap();
+        assert!(snapshot_path.exists());
+        assert!(!new_snapshot_path.exists());
+
+        let _ = fs::remove_dir_all(d);
+    }
+
+    #[test]
+    fn stale_snapshot_is_detected() {
+        let d = test::tmp_dir();
+
+        let manager = AggregateStore::<Person>::disk(&d, "person").unwrap();
+
+        let id_alice = Handle::from_str("alice").unwrap();
+        manager.add(InitPersonEvent::init(&id_alice, "alice smith")).unwrap();
+
+        for _ in 0..5 {
+            manager.command(PersonCommand::go_around_sun(&id_alice, None)).unwrap();
+        }
+
+        // Replace the snapshot with the older backup snapshot. It can be parsed
+        // fine, but it does not match the hash and version in the info.
+        let mut snapshot_path = d.clone();
+        snapshot_path.push("person/alice/snapshot.json");
+        let mut backup_snapshot_path = d.clone();
+        backup_snapshot_path.push("person/alice/snapshot-bk.json");
+        fs::copy(&backup_snapshot_path, &snapshot_path).unwrap();
+
+        let manager = AggregateStore::<Person>::disk(&d, "person").unwrap();
+        let alice = manager.get_latest(&id_alice).unwrap();
+        assert_eq!(6, alice.version());
+        assert_eq!(5, alice.age());
+
+        let mut corrupt_snapshot_path = d.clone();
+        corrupt_snapshot_path.push("person/alice/corrupt/snapshot.json");
+        assert!(corrupt_snapshot_path.exists());
+
+        let archived = manager.archived_entries(&id_alice).unwrap();
+        assert_eq!(archived.len(), 1);
+        assert_eq!(archived[0].name, "snapshot.json");
+        assert_eq!(archived[0].archive, "corrupt");
+        assert_eq!(archived[0].kind, ArchivedEntryKind::Snapshot);
+        assert!(manager.archived_value(&id_alice, &archived[0]).unwrap().is_some());
+
         let _ = fs::remove_dir_all(d);
     }
 }
@@ -1,22 +1,31 @@
+use std::cmp::Ordering;
 use std::fmt;
-use std::{collections::HashMap, path::Path};
+use std::{
+    collections::{BinaryHeap, HashMap, HashSet},
+    path::Path,
+};
 
 use std::str::FromStr;
+use std::sync::atomic::{self, AtomicU64};
 use std::sync::{Arc, RwLock};
+use std::time::Instant;
 
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 use rpki::x509::Time;
 
 use crate::commons::eventsourcing::cmd::{Command, StoredCommandBuilder};
 use crate::commons::eventsourcing::{
-    Aggregate, Event, KeyStoreKey, KeyValueError, KeyValueStore, PostSaveEventListener, StoredCommand,
-    WithStorableDetails,
+    Aggregate, AggregateStoreMetrics, AggregateStoreMetricsReport, AuditLog, AuditOutcome, Event, KeyStoreKey,
+    KeyValueError, KeyValueStore, PostSaveAsyncEventListener, PostSaveAsyncEventQueue, PostSaveEventListener,
+    StoreMigration, StoreMigrations, StoredCommand, WithStorableDetails,
 };
 use crate::commons::{
     api::{CommandHistory, CommandHistoryCriteria, CommandHistoryRecord, Handle, Label},
     error::KrillIoError,
+    util::sha256,
 };
 
 use super::PreSaveEventListener;
@@ -34,6 +43,58 @@ pub struct StoredValueInfo {
     pub last_event: u64,
     pub last_command: u64,
     pub last_update: Time,
+    /// Hex encoded SHA-256 hash of the snapshot at 'snapshot_version', used
+    /// to detect snapshots which can be parsed, but which are not the snapshot
+    /// that was saved. Absent in info saved by older versions of Krill.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub snapshot_hash: Option<String>,
+    /// Version of the snapshot that was made authoritative when the aggregate
+    /// was compacted. Events before this version were archived, so the aggregate
+    /// can no longer be rebuilt from before it. Absent if it was never compacted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compaction_baseline: Option<u64>,
+    /// Inclusive ranges of the versions of events which were archived together
+    /// with old commands, see `AggregateStore::archive_old_commands`. These are
+    /// no longer expected to be found. Absent if no events were archived.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub archived_events: Vec<(u64, u64)>,
+}
+
+impl StoredValueInfo {
+    /// Returns the first version of which the events are still kept.
+    fn first_kept_event(&self) -> u64 {
+        self.compaction_baseline.unwrap_or(0)
+    }
+
+    /// Returns whether the event for the version is expected to be found, i.e.
+    /// it was not archived when the aggregate was compacted or old commands
+    /// were archived.
+    fn is_kept_event(&self, version: u64) -> bool {
+        version >= self.first_kept_event()
+            && !self
+                .archived_events
+                .iter()
+                .any(|(first, last)| *first <= version && version <= *last)
+    }
+
+    /// Records that the event for the version was archived on purpose. Adjacent
+    /// versions are merged into a single range, so that this stays small.
+    fn add_archived_event(&mut self, version: u64) {
+        if !self.is_kept_event(version) {
+            return;
+        }
+        self.archived_events.push((version, version));
+        self.archived_events.sort_unstable();
+
+        let mut merged: Vec<(u64, u64)> = vec![];
+        for (first, last) in self.archived_events.drain(..) {
+            match merged.last_mut() {
+                Some(previous) if previous.1 + 1 >= first => previous.1 = previous.1.max(last),
+                _ => merged.push((first, last)),
+            }
+        }
+        self.archived_events = merged;
+    }
 }
 
 impl Default for StoredValueInfo {
@@ -43,6 +104,9 @@ impl Default for StoredValueInfo {
             last_event: 0,
             last_command: 0,
             last_update: Time::now(),
+            snapshot_hash: None,
+            compaction_baseline: None,
+            archived_events: vec![],
         }
     }
 }
@@ -99,6 +163,23 @@ impl CommandKey {
     }
 }
 
+/// Command keys are ordered by their sequence first. The timestamp and label
+/// are only used as tie-breakers to keep the ordering consistent with `Eq`.
+impl Ord for CommandKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.sequence
+            .cmp(&other.sequence)
+            .then_with(|| self.timestamp_secs.cmp(&other.timestamp_secs))
+            .then_with(|| self.label.cmp(&other.label))
+    }
+}
+
+impl PartialOrd for CommandKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
 impl fmt::Display for CommandKey {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "command--{}--{}--{}", self.timestamp_secs, self.sequence, self.label)
@@ -108,8 +189,10 @@ impl fmt::Display for CommandKey {
 impl FromStr for CommandKey {
     type Err = CommandKeyError;
 
+    /// Only the first three "--" delimiters are used, everything after the
+    /// sequence is the label. So labels may contain "--" as well.
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let parts: Vec<&str> = s.split("--").collect();
+        let parts: Vec<&str> = s.splitn(4, "--").collect();
         if parts.len() != 4 || parts[0] != "command" {
             Err(CommandKeyError(s.to_string()))
         } else {
@@ -146,15 +229,101 @@ impl fmt::Display for CommandKeyError {
     }
 }
 
+//------------ AggregateListSort ---------------------------------------------
+
+/// The order in which aggregates are listed.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AggregateListSort {
+    /// Sort by handle. This is cheap, it only needs the names of the aggregates.
+    Handle,
+    /// Sort by the time of the last update, most recent first. This reads the
+    /// info of every aggregate.
+    LastUpdate,
+}
+
+impl Default for AggregateListSort {
+    fn default() -> Self {
+        AggregateListSort::Handle
+    }
+}
+
+//------------ AggregateListPage ---------------------------------------------
+
+/// A page of aggregates, see `AggregateStore::list_page`.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct AggregateListPage {
+    /// The total number of aggregates, not just the number on this page.
+    pub total: usize,
+    pub offset: usize,
+    pub handles: Vec<Handle>,
+}
+
+//------------ ArchivedEntry -------------------------------------------------
+
+/// The kind of value that was archived.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArchivedEntryKind {
+    Command,
+    Event,
+    Snapshot,
+    Info,
+    Other,
+}
+
+impl ArchivedEntryKind {
+    fn for_name(name: &str) -> Self {
+        if name.starts_with("command--") {
+            ArchivedEntryKind::Command
+        } else if name.starts_with("delta-") {
+            ArchivedEntryKind::Event
+        } else if name.starts_with("snapshot") {
+            ArchivedEntryKind::Snapshot
+        } else if name == "info.json" {
+            ArchivedEntryKind::Info
+        } else {
+            ArchivedEntryKind::Other
+        }
+    }
+}
+
+/// A value of an aggregate that was archived, e.g. because it was corrupt or
+/// surplus when the aggregate was recovered. See `AggregateStore::archived_entries`.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ArchivedEntry {
+    /// The name of the value, before and after it was archived.
+    pub name: String,
+    /// Why the value was archived: "archived", "corrupt" or "surplus".
+    pub archive: String,
+    pub kind: ArchivedEntryKind,
+    /// The time the value was archived, in seconds since the epoch.
+    pub archived: i64,
+}
+
 //------------ AggregateStore ------------------------------------------------
 
 /// This type is responsible for managing aggregates.
 pub struct AggregateStore<A: Aggregate> {
     kv: KeyValueStore,
-    cache: RwLock<HashMap<Handle, Arc<A>>>,
+    cache: RwLock<AggregateCache<A>>,
+    // Aggregates for which events may have been saved since their cache entry
+    // was last updated, e.g. because saving a command failed halfway.
+    dirty: RwLock<HashSet<Handle>>,
     pre_save_listeners: Vec<Arc<dyn PreSaveEventListener<A>>>,
     post_save_listeners: Vec<Arc<dyn PostSaveEventListener<A>>>,
+    post_save_async_queues: Vec<PostSaveAsyncEventQueue<A>>,
     outer_lock: RwLock<()>,
+    compress: bool,
+    lazy_warm: bool,
+    warm_recent: usize,
+    warm_handles: Vec<Handle>,
+    size_warn: usize,
+    size_max: usize,
+    metrics: AggregateStoreMetrics,
+    migrations: StoreMigrations,
+    read_only: bool,
+    audit_log: Option<AuditLog>,
 }
 
 /// # Starting up
@@ -170,17 +339,53 @@ where
         let existed = path.exists();
 
         let kv = KeyValueStore::disk(work_dir, name_space)?;
-        let cache = RwLock::new(HashMap::new());
+        Self::with_kv(kv, existed)
+    }
+
+    /// Creates an AggregateStore which keeps everything in memory. This lets tests
+    /// use the store without touching disk. Nothing survives dropping the store.
+    pub fn in_memory(name_space: &str) -> StoreResult<Self> {
+        Self::with_kv(KeyValueStore::memory(name_space), false)
+    }
+
+    /// Creates an AggregateStore using the given KeyValueStore, and sets the current
+    /// version if the store did not exist before.
+    fn with_kv(kv: KeyValueStore, existed: bool) -> StoreResult<Self> {
+        let cache = RwLock::new(AggregateCache::default());
+        let dirty = RwLock::new(HashSet::new());
         let pre_save_listeners = vec![];
         let post_save_listeners = vec![];
+        let post_save_async_queues = vec![];
         let outer_lock = RwLock::new(());
+        let compress = false;
+        let lazy_warm = false;
+        let warm_recent = 0;
+        let warm_handles = vec![];
+        let size_warn = 0;
+        let size_max = 0;
+        let metrics = AggregateStoreMetrics::default();
+        let migrations = StoreMigrations::default();
+        let read_only = false;
+        let audit_log = None;
 
         let store = AggregateStore {
             kv,
             cache,
+            dirty,
             pre_save_listeners,
             post_save_listeners,
+            post_save_async_queues,
             outer_lock,
+            compress,
+            lazy_warm,
+            warm_recent,
+            warm_handles,
+            size_warn,
+            size_max,
+            metrics,
+            migrations,
+            read_only,
+            audit_log,
         };
 
         if !existed {
@@ -190,15 +395,156 @@ where
         Ok(store)
     }
 
+    /// Creates a read-only AggregateStore using an existing disk based KeyValueStore.
+    ///
+    /// This is intended for a standby which reads the data of a primary Krill instance.
+    /// Aggregates and their history can be read, and any new events saved by the primary
+    /// are replayed when aggregates are retrieved. But all functions that would change the
+    /// stored data return an `AggregateStoreError::ReadOnly`, and corrupt or surplus values
+    /// are never archived, as they may still be in the process of being saved.
+    pub fn disk_read_only(work_dir: &Path, name_space: &str) -> StoreResult<Self> {
+        let mut path = work_dir.to_path_buf();
+        path.push(name_space);
+        if !path.exists() {
+            return Err(AggregateStoreError::NotInitialized);
+        }
+
+        let mut store = Self::disk(work_dir, name_space)?;
+        store.read_only = true;
+        Ok(store)
+    }
+
+    /// Returns whether this store was opened read-only.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
     /// Warms up the cache, to be used after startup. Will fail if any aggregates fail to load
     /// in which case a 'recover' operation can be tried.
+    ///
+    /// Any migrations needed for the stored values are applied first. Discrepancies between
+    /// the info of an aggregate and its stored values are logged, and included in the error
+    /// if the aggregate cannot be loaded, so that operators know what is wrong.
+    ///
+    /// If lazy warming is set, aggregates are only loaded into the cache if this is needed
+    /// to check them, or if they are in the warm set. See `set_lazy_warm` and `set_warm_set`.
     pub fn warm(&self) -> StoreResult<()> {
-        for handle in self.list()? {
-            self.warm_aggregate(&handle)?;
+        self.migrate()?;
+        let handles = self.list()?;
+        let warm_set = self.warm_set(&handles);
+        for handle in handles {
+            let discrepancies = self.check_info(&handle)?;
+            for discrepancy in &discrepancies {
+                warn!("Stored value info for '{}' is inconsistent: {}", handle, discrepancy);
+            }
+
+            {
+                let _lock = self.outer_lock.write().unwrap();
+                self.remove_new_snapshot(&handle)?;
+            }
+
+            let res = if self.lazy_warm && !warm_set.contains(&handle) {
+                self.warm_aggregate_lazily(&handle)
+            } else {
+                self.warm_aggregate(&handle)
+            };
+
+            res.map_err(|e| match e {
+                AggregateStoreError::WarmupFailed(handle, e) if !discrepancies.is_empty() => {
+                    let found: Vec<String> = discrepancies.iter().map(|d| d.to_string()).collect();
+                    AggregateStoreError::WarmupFailed(handle, format!("{}. Info inconsistent: {}", e, found.join(", ")))
+                }
+                e => e,
+            })?;
         }
         Ok(())
     }
 
+    /// Returns the handles of the aggregates that `warm` loads fully even if lazy warming
+    /// is set: the most recently updated aggregates, and the aggregates that were given
+    /// explicitly. Returns an empty set if lazy warming is not set.
+    fn warm_set(&self, handles: &[Handle]) -> HashSet<Handle> {
+        if !self.lazy_warm {
+            return HashSet::new();
+        }
+
+        for handle in &self.warm_handles {
+            if !handles.contains(handle) {
+                warn!("Cannot warm '{}', it is not in the store", handle);
+            }
+        }
+
+        let mut updated: Vec<(Option<i64>, &Handle)> = handles
+            .iter()
+            .map(|handle| (self.get_info(handle).ok().map(|i| i.last_update.timestamp()), handle))
+            .collect();
+        updated.sort_by(|(a_time, a), (b_time, b)| b_time.cmp(a_time).then_with(|| a.as_str().cmp(b.as_str())));
+
+        updated
+            .into_iter()
+            .take(self.warm_recent)
+            .map(|(_, handle)| handle.clone())
+            .chain(self.warm_handles.iter().cloned())
+            .collect()
+    }
+
+    /// Compares the info of an aggregate with the events, commands and snapshot that are
+    /// actually stored, and returns the discrepancies found. Nothing is changed on disk.
+    pub fn check_info(&self, handle: &Handle) -> StoreResult<Vec<InfoDiscrepancy>> {
+        let _lock = self.outer_lock.read().unwrap();
+
+        let info = match self.get_info(handle) {
+            Ok(info) => info,
+            Err(AggregateStoreError::InfoMissing(_)) => return Ok(vec![InfoDiscrepancy::InfoMissing]),
+            Err(AggregateStoreError::InfoCorrupt(_)) => return Ok(vec![InfoDiscrepancy::InfoCorrupt]),
+            Err(e) => return Err(e),
+        };
+
+        let mut discrepancies = vec![];
+
+        let mut events = self.event_versions(handle)?;
+        events.sort_unstable();
+
+        let missing: Vec<u64> = (info.first_kept_event()..info.last_event + 1)
+            .filter(|version| info.is_kept_event(*version) && events.binary_search(version).is_err())
+            .collect();
+        if !missing.is_empty() {
+            discrepancies.push(InfoDiscrepancy::EventsMissing(missing));
+        }
+
+        if let Some(found) = events.last().copied() {
+            if found > info.last_event {
+                discrepancies.push(InfoDiscrepancy::LastEventTooLow {
+                    info: info.last_event,
+                    found,
+                });
+            } else if found < info.last_event {
+                discrepancies.push(InfoDiscrepancy::LastEventTooHigh {
+                    info: info.last_event,
+                    found,
+                });
+            }
+        }
+
+        let found = self
+            .command_keys_ascending(handle, &CommandHistoryCriteria::default())?
+            .last()
+            .map(|key| key.sequence)
+            .unwrap_or(0);
+        if found != info.last_command {
+            discrepancies.push(InfoDiscrepancy::LastCommandMismatch {
+                info: info.last_command,
+                found,
+            });
+        }
+
+        if info.snapshot_version > 0 && !self.kv.has(&Self::key_for_snapshot(handle))? {
+            discrepancies.push(InfoDiscrepancy::SnapshotMissing(info.snapshot_version));
+        }
+
+        Ok(discrepancies)
+    }
+
     /// Warm the cache for a specific aggregate. If successful save the latest snapshot
     /// as well (will help in case of migrations where snapshots were dropped).
     ///
@@ -206,15 +552,73 @@ where
     /// recorded in the 'info.json' which is always saved last on state changes - then it is
     /// assumed that an incomplete transaction took place. The surplus entries will be archived
     /// and warnings will be reported.
+    ///
+    /// Read-only stores just load the aggregate, they leave it to the primary to resolve
+    /// any surplus entries and to save snapshots.
     pub fn warm_aggregate(&self, handle: &Handle) -> StoreResult<()> {
         let agg = self
             .get_latest(handle)
             .map_err(|e| AggregateStoreError::WarmupFailed(handle.clone(), e.to_string()))?;
 
+        if self.read_only {
+            return Ok(());
+        }
+
         // check that last command and event are consistent with
         // the info, if not fail warmup and force recover
-        let info = self.get_info(handle)?;
+        let mut info = self.get_info(handle)?;
+        self.archive_surplus_values(handle, &info)?;
+
+        // Save the snapshot if it does not yet match the latest state
+        if info.snapshot_version != agg.version() {
+            info!("Updating snapshot for '{}', to decrease future load times.", handle);
+            info.snapshot_hash = Some(self.store_snapshot(handle, agg.as_ref())?);
+            info.snapshot_version = agg.version();
+            self.save_info(handle, &info)?;
+        }
 
+        Ok(())
+    }
+
+    /// Checks that an aggregate can be loaded from its snapshot alone, without loading
+    /// it into the cache. The snapshot is only parsed as json and checked against the
+    /// hash in the info, it is deserialized when the aggregate is first used. Aggregates
+    /// which have events after their snapshot, or of which the snapshot is missing,
+    /// cannot be parsed or does not match the hash, are warmed eagerly.
+    fn warm_aggregate_lazily(&self, handle: &Handle) -> StoreResult<()> {
+        let info = match self.get_info(handle) {
+            Ok(info) if info.snapshot_version == info.last_event + 1 => info,
+            _ => return self.warm_aggregate(handle),
+        };
+
+        let snapshot = match self.kv.get::<serde_json::Value>(&Self::key_for_snapshot(handle)) {
+            Ok(Some(snapshot)) => snapshot,
+            _ => return self.warm_aggregate(handle),
+        };
+
+        // The value is in the same canonical form as the json used for the hash,
+        // see `snapshot_json`.
+        if let Some(hash) = &info.snapshot_hash {
+            let found = serde_json::to_vec(&snapshot).map(|json| hex::encode(sha256(&json)));
+            if found.ok().as_ref() != Some(hash) {
+                warn!(
+                    "Snapshot for '{}' does not match the hash in its info, will rebuild it",
+                    handle
+                );
+                return self.warm_aggregate(handle);
+            }
+        }
+
+        if !self.read_only {
+            self.archive_surplus_values(handle, &info)?;
+        }
+
+        Ok(())
+    }
+
+    /// Archives events and commands that were saved after the last event and command
+    /// in the info, i.e. of which saving the info failed.
+    fn archive_surplus_values(&self, handle: &Handle, info: &StoredValueInfo) -> StoreResult<()> {
         // for events we can just check if the next event, after
         // the last event in the info exists
         if self.get_event::<A::Event>(handle, info.last_event + 1)?.is_some() {
@@ -241,12 +645,6 @@ where
             }
         }
 
-        // Save the snapshot if it does not yet match the latest state
-        if info.snapshot_version != agg.version() {
-            info!("Updating snapshot for '{}', to decrease future load times.", handle);
-            self.store_snapshot(handle, agg.as_ref())?;
-        }
-
         Ok(())
     }
 
@@ -259,189 +657,1083 @@ where
     /// is that `recover` can take longer, and that it could lead silent recovery without
     /// alerting to operators to underlying issues.
     pub fn recover(&self) -> StoreResult<()> {
-        let criteria = CommandHistoryCriteria::default();
+        self.recover_with(false).map(|_| ())
+    }
+
+    /// Does the same scan as `recover`, but only logs and returns what it would do for
+    /// each aggregate. Nothing is archived, and no snapshots, info or cache entries are
+    /// updated. Note that pending store migrations are not done either.
+    pub fn recover_dry_run(&self) -> StoreResult<Vec<RecoveryPlan>> {
+        self.recover_with(true)
+    }
+
+    /// Recovers a single aggregate, in the same way as `recover`. Commands for the
+    /// aggregate wait until the recovery is done.
+    pub fn recover_aggregate(&self, handle: &Handle) -> StoreResult<RecoveryPlan> {
+        self.check_writable()?;
+        self.migrate()?;
+        if !self.has(handle)? {
+            return Err(AggregateStoreError::UnknownAggregate(handle.clone()));
+        }
+        self.recover_aggregate_with(handle, false)
+    }
+
+    fn recover_with(&self, dry_run: bool) -> StoreResult<Vec<RecoveryPlan>> {
+        if !dry_run {
+            self.check_writable()?;
+            self.migrate()?;
+        }
+        let mut plans = vec![];
         for handle in self.list()? {
+            plans.push(self.recover_aggregate_with(&handle, dry_run)?);
+        }
+        Ok(plans)
+    }
+
+    /// Recovers, or checks in a dry run, the aggregate. The outer lock is held so that
+    /// no commands are processed meanwhile, but only for this aggregate, so that a
+    /// recovery of all aggregates does not block commands for its entire duration.
+    fn recover_aggregate_with(&self, handle: &Handle, dry_run: bool) -> StoreResult<RecoveryPlan> {
+        let _read_lock = if dry_run {
+            Some(self.outer_lock.read().unwrap())
+        } else {
+            None
+        };
+        let _write_lock = if dry_run {
+            None
+        } else {
+            Some(self.outer_lock.write().unwrap())
+        };
+
+        let handle = handle.clone();
+        let criteria = CommandHistoryCriteria::default();
+        if dry_run {
+            info!("Dry run: will check how state for '{}' would be recovered", &handle);
+        } else {
             info!("Will recover state for '{}'", &handle);
+        }
+        let mut plan = RecoveryPlan::new(handle.clone());
+
+        // Events before the compaction baseline, if any, and events archived with old
+        // commands were archived on purpose.
+        let (compaction_baseline, archived_events) = match self.get_info(&handle) {
+            Ok(info) => (info.compaction_baseline, info.archived_events),
+            Err(_) => (None, vec![]),
+        };
+        let first_kept_event = compaction_baseline.unwrap_or(0);
+
+        // Check
+        // - All commands, archive bad commands
+        // - All events, archive bad events
+        // - Keep track of last known good command and event
+        // - Archive all commands and events after
+        //
+        // Rebuild state up to event:
+        //   - use snapshot - archive if bad
+        //   - use back-up snapshot if snapshot is no good - archive if bad
+        //   - start from init event if back-up snapshot is bad, or if the version exceeds last good event
+        //   - process events from (back-up) snapshot up to last good event
+        //
+        //  If still good:
+        //   - save snapshot
+        //   - save info
+
+        let mut last_good_cmd = 0;
+        let mut last_good_evt = 0;
+        let mut last_update = Time::now();
+
+        // Check all commands and associated events
+        let mut all_ok = true;
+
+        let command_keys = self.command_keys_ascending(&handle, &criteria)?;
+        info!("Processing {} commands for {}", command_keys.len(), handle);
+        for (counter, command_key) in command_keys.into_iter().enumerate() {
+            if counter % 100 == 0 {
+                info!("Processed {} commands", counter);
+            }
 
-            // Check
-            // - All commands, archive bad commands
-            // - All events, archive bad events
-            // - Keep track of last known good command and event
-            // - Archive all commands and events after
-            //
-            // Rebuild state up to event:
-            //   - use snapshot - archive if bad
-            //   - use back-up snapshot if snapshot is no good - archive if bad
-            //   - start from init event if back-up snapshot is bad, or if the version exceeds last good event
-            //   - process events from (back-up) snapshot up to last good event
-            //
-            //  If still good:
-            //   - save snapshot
-            //   - save info
-
-            let mut last_good_cmd = 0;
-            let mut last_good_evt = 0;
-            let mut last_update = Time::now();
-
-            // Check all commands and associated events
-            let mut all_ok = true;
-
-            let command_keys = self.command_keys_ascending(&handle, &criteria)?;
-            info!("Processing {} commands for {}", command_keys.len(), handle);
-            for (counter, command_key) in command_keys.into_iter().enumerate() {
-                if counter % 100 == 0 {
-                    info!("Processed {} commands", counter);
+            if all_ok && command_key.sequence <= last_good_cmd {
+                // Keys are ordered by sequence, so this can only be a duplicate, e.g. saved with
+                // a different timestamp after the system clock was changed. Keep the first.
+                warn!(
+                    "Command {} has the same sequence as an earlier command. Will archive as surplus",
+                    command_key
+                );
+                if !dry_run {
+                    self.archive_surplus_command(&handle, &command_key)?;
                 }
+                plan.surplus_commands.push(command_key);
+                continue;
+            }
 
-                if all_ok {
-                    if let Ok(cmd) = self.get_command::<A::StorableCommandDetails>(&handle, &command_key) {
-                        if let Some(events) = cmd.effect().events() {
-                            for version in events {
-                                if let Ok(Some(_)) = self.get_event::<A::Event>(&handle, *version) {
-                                    last_good_evt = *version;
-                                } else {
-                                    all_ok = false;
-                                }
+            if all_ok {
+                if let Some(cmd) = self.recovery_command(&handle, &command_key, dry_run) {
+                    if let Some(events) = cmd.effect().events() {
+                        for version in events {
+                            if *version < first_kept_event {
+                                last_good_evt = *version;
+                            } else if self.recovery_event_exists(&handle, *version, dry_run) {
+                                last_good_evt = *version;
+                            } else {
+                                all_ok = false;
                             }
                         }
-                        last_good_cmd = cmd.sequence();
-                        last_update = cmd.time();
-                    } else {
-                        all_ok = false;
                     }
-                }
-                if !all_ok {
-                    warn!(
-                        "Command {} was corrupt, or not all events could be loaded. Will archive surplus",
-                        command_key
-                    );
-                    // Bad command or event encountered.. archive surplus commands
-                    // note that we will clean surplus events later
-                    self.archive_surplus_command(&handle, &command_key)?;
+                    last_good_cmd = cmd.sequence();
+                    last_update = cmd.time();
+                } else {
+                    all_ok = false;
                 }
             }
-
-            self.archive_surplus_events(&handle, last_good_evt + 1)?;
-
             if !all_ok {
                 warn!(
-                    "State for '{}' can only be recovered to version: {}. Check corrupt and surplus dirs",
-                    &handle, last_good_evt
+                    "Command {} was corrupt, or not all events could be loaded. Will archive surplus",
+                    command_key
                 );
+                // Bad command or event encountered.. archive surplus commands
+                // note that we will clean surplus events later
+                if !dry_run {
+                    self.archive_surplus_command(&handle, &command_key)?;
+                }
+                plan.surplus_commands.push(command_key);
             }
+        }
 
-            // Get the latest aggregate, not that this ensures that the snapshots
-            // are checked, and archived if corrupt, or if they are after the last_good_evt
-            let agg = self
-                .get_aggregate(&handle, Some(last_good_evt))?
-                .ok_or_else(|| AggregateStoreError::CouldNotRecover(handle.clone()))?;
+        plan.surplus_events = self
+            .event_versions(&handle)?
+            .into_iter()
+            .filter(|version| *version > last_good_evt)
+            .collect();
+        plan.surplus_events.sort_unstable();
 
-            let snapshot_version = agg.version();
+        if !dry_run {
+            self.archive_surplus_events(&handle, last_good_evt + 1)?;
+        }
 
-            let info = StoredValueInfo {
-                last_event: last_good_evt,
-                last_command: last_good_cmd,
-                last_update,
-                snapshot_version,
-            };
+        if !all_ok {
+            warn!(
+                "State for '{}' can only be recovered to version: {}. Check corrupt and surplus dirs",
+                &handle, last_good_evt
+            );
+        }
 
-            self.store_snapshot(&handle, &agg)?;
+        plan.last_command = last_good_cmd;
+        plan.last_event = last_good_evt;
 
-            self.cache_update(&handle, Arc::new(agg));
+        if dry_run {
+            let info = self.get_info(&handle).ok();
+            plan.snapshot = self.snapshot_status(&Self::key_for_snapshot(&handle), last_good_evt, info.as_ref());
+            plan.backup_snapshot = self.snapshot_status(&Self::key_for_backup_snapshot(&handle), last_good_evt, None);
 
-            self.save_info(&handle, &info)?;
+            info!("Dry run: {}", plan);
+            return Ok(plan);
         }
 
-        Ok(())
-    }
-
-    /// Adds a listener that will receive all events before they are stored.
-    pub fn add_pre_save_listener<L: PreSaveEventListener<A>>(&mut self, sync_listener: Arc<L>) {
-        self.pre_save_listeners.push(sync_listener);
-    }
-
-    /// Adds a listener that will receive a reference to all events after they are stored.
-    pub fn add_post_save_listener<L: PostSaveEventListener<A>>(&mut self, listener: Arc<L>) {
-        self.post_save_listeners.push(listener);
-    }
-}
+        self.remove_new_snapshot(&handle)?;
 
-/// # Manage Aggregates
-///
-impl<A: Aggregate> AggregateStore<A>
-where
-    A::Error: From<AggregateStoreError>,
-{
-    /// Gets the latest version for the given aggregate. Returns
-    /// an AggregateStoreError::UnknownAggregate in case the aggregate
-    /// does not exist.
-    pub fn get_latest(&self, handle: &Handle) -> StoreResult<Arc<A>> {
-        let _lock = self.outer_lock.read().unwrap();
-        self.get_latest_no_lock(handle)
-    }
+        // Get the latest aggregate, not that this ensures that the snapshots
+        // are checked, and archived if corrupt, or if they are after the last_good_evt
+        let agg = self
+            .get_aggregate(&handle, Some(last_good_evt))?
+            .ok_or_else(|| AggregateStoreError::CouldNotRecover(handle.clone()))?;
 
-    /// Adds a new aggregate instance based on the init event.
-    pub fn add(&self, init: A::InitEvent) -> StoreResult<Arc<A>> {
-        let _lock = self.outer_lock.write().unwrap();
+        let snapshot_version = agg.version();
 
-        self.store_event(&init)?;
+        let snapshot_hash = Some(self.store_snapshot(&handle, &agg)?);
 
-        let handle = init.handle().clone();
+        let info = StoredValueInfo {
+            last_event: last_good_evt,
+            last_command: last_good_cmd,
+            last_update,
+            snapshot_version,
+            snapshot_hash,
+            compaction_baseline,
+            archived_events,
+        };
 
-        let aggregate = A::init(init).map_err(|_| AggregateStoreError::InitError(handle.clone()))?;
-        self.store_snapshot(&handle, &aggregate)?;
+        self.cache_update(&handle, Arc::new(agg));
 
-        let info = StoredValueInfo::default();
         self.save_info(&handle, &info)?;
 
-        let arc = Arc::new(aggregate);
-        self.cache_update(&handle, arc.clone());
+        plan.snapshot = SnapshotStatus::Usable(snapshot_version);
+        Ok(plan)
+    }
 
-        Ok(arc)
+    /// Returns the command if it can be read. A corrupt command is archived, unless this
+    /// is a dry run, in which case nothing may be changed.
+    fn recovery_command(
+        &self,
+        handle: &Handle,
+        command_key: &CommandKey,
+        dry_run: bool,
+    ) -> Option<StoredCommand<A::StorableCommandDetails>> {
+        if dry_run {
+            self.kv.get(&Self::key_for_command(handle, command_key)).ok().flatten()
+        } else {
+            self.get_command(handle, command_key).ok()
+        }
     }
 
-    /// Send a command to the latest aggregate referenced by the handle in the command.
+    /// Returns whether the event exists and can be read. A corrupt event is archived,
+    /// unless this is a dry run.
+    fn recovery_event_exists(&self, handle: &Handle, version: u64, dry_run: bool) -> bool {
+        if dry_run {
+            matches!(
+                self.kv.get::<A::Event>(&Self::key_for_event(handle, version)),
+                Ok(Some(_))
+            )
+        } else {
+            matches!(self.get_event::<A::Event>(handle, version), Ok(Some(_)))
+        }
+    }
+
+    /// Archives old commands, and the events they resulted in, for the given aggregate.
     ///
-    /// This will:
-    /// - Retrieve the latest aggregate for this command.
-    /// - Call the A::process_command function
-    /// on success:
-    ///   - call pre-save listeners with events
-    ///   - save command and events
-    ///   - call post-save listeners with events
-    ///   - return aggregate
-    /// on no-op (empty event list):
-    ///   - do not save anything, return aggregate
-    /// on error:
-    ///   - save command and error, return error
-    pub fn command(&self, cmd: A::Command) -> Result<Arc<A>, A::Error> {
-        debug!("Processing command {}", cmd);
+    /// Commands are only archived if they are:
+    /// - older than the given number of days, AND
+    /// - have a label that is included in the given eligible labels, AND
+    /// - only resulted in events that are already included in the backup snapshot
+    ///
+    /// The last condition ensures that the aggregate can still be rebuilt from either
+    /// its current or backup snapshot. Archived commands and events are moved to the
+    /// 'archived' sub-scope of the aggregate so that operators can decide to keep them
+    /// for audit purposes, or delete them. The versions of the archived events are
+    /// recorded in the info, so that they are not reported as missing.
+    ///
+    /// Note that the outer write lock is only taken while archiving each individual
+    /// command, so that normal command processing is not blocked for the duration.
+    pub fn archive_old_commands(&self, handle: &Handle, days: i64, eligible: &[&str]) -> StoreResult<()> {
+        self.check_writable()?;
+        let backup_snapshot_version = {
+            let _lock = self.outer_lock.read().unwrap();
+            match self.kv.get::<A>(&Self::key_for_backup_snapshot(handle)) {
+                Ok(Some(agg)) => agg.version(),
+                Ok(None) => {
+                    debug!("No backup snapshot for '{}', will not archive commands", handle);
+                    return Ok(());
+                }
+                Err(e) => {
+                    warn!(
+                        "Could not read backup snapshot for '{}', will not archive commands. Error: {}",
+                        handle, e
+                    );
+                    return Ok(());
+                }
+            }
+        };
 
-        let _lock = self.outer_lock.write().unwrap();
+        let mut crit = CommandHistoryCriteria::default();
+        crit.set_before(Time::now().timestamp() - days * 24 * 3600);
+        crit.set_includes(eligible);
+        crit.set_unlimited_rows();
 
-        // Get the latest arc.
-        let handle = cmd.handle().clone();
+        let command_keys = {
+            let _lock = self.outer_lock.read().unwrap();
+            self.command_keys_ascending(handle, &crit)?
+        };
 
-        let mut info = self.get_info(&handle)?;
-        info.last_update = Time::now();
-        info.last_command += 1;
+        let mut archived = 0;
+        for command_key in command_keys {
+            let _lock = self.outer_lock.write().unwrap();
 
-        let mut latest = self.get_latest_no_lock(&handle)?;
+            let command = self.get_command::<A::StorableCommandDetails>(handle, &command_key)?;
+            let events = command.effect().events().cloned().unwrap_or_default();
 
-        if let Some(version) = cmd.version() {
-            if version != latest.version() {
-                error!(
-                    "Version conflict updating '{}', expected version: {}, found: {}",
+            if events.iter().any(|version| *version >= backup_snapshot_version) {
+                // Commands are in ascending order, so all remaining commands will
+                // be too recent as well.
+                break;
+            }
+
+            // Record the events in the info before archiving them, so that they are
+            // never missing without being accounted for.
+            if !events.is_empty() {
+                let mut info = self.get_info(handle)?;
+                for version in &events {
+                    info.add_archived_event(*version);
+                }
+                self.save_info(handle, &info)?;
+            }
+
+            for version in events {
+                let key = Self::key_for_event(handle, version);
+                if self.kv.has(&key)? {
+                    self.kv.archive(&key)?;
+                }
+            }
+            self.kv.archive(&Self::key_for_command(handle, &command_key))?;
+            archived += 1;
+        }
+
+        if archived > 0 {
+            info!("Archived {} old commands for '{}'", archived, handle);
+        }
+
+        Ok(())
+    }
+
+    /// Compacts the aggregate, so that its storage is bounded even if it is never
+    /// deleted.
+    ///
+    /// The integrity of the aggregate is verified first, and nothing is done if any
+    /// issues are found. Then its latest state is saved as both the current and backup
+    /// snapshot, and its version is recorded as the compaction baseline in the info.
+    /// Finally all events before the baseline, including the init event, are moved to
+    /// the 'archived' sub-scope. The aggregate can then only be rebuilt from a snapshot
+    /// at or after the baseline. Commands are kept, so the history remains available.
+    ///
+    /// Returns the baseline version.
+    pub fn compact(&self, handle: &Handle) -> StoreResult<u64> {
+        self.check_writable()?;
+        let _lock = self.outer_lock.write().unwrap();
+
+        let report = self.check_aggregate_integrity_no_lock(handle)?;
+        if !report.is_ok() {
+            return Err(AggregateStoreError::CouldNotArchive(
+                handle.clone(),
+                format!("will not compact, integrity check failed:\n{}", report),
+            ));
+        }
+
+        let latest = self.get_latest_no_lock(handle)?;
+        let baseline = latest.version();
+
+        // Save the snapshot twice so that the backup snapshot is at the baseline
+        // as well, because older snapshots can no longer be replayed.
+        self.store_snapshot(handle, latest.as_ref())?;
+        let snapshot_hash = self.store_snapshot(handle, latest.as_ref())?;
+
+        // Save the info before archiving, so that the baseline is never missing
+        // while events before it are.
+        let mut info = self.get_info(handle)?;
+        info.snapshot_version = baseline;
+        info.snapshot_hash = Some(snapshot_hash);
+        info.compaction_baseline = Some(baseline);
+        info.archived_events.retain(|(_, last)| *last >= baseline);
+        self.save_info(handle, &info)?;
+
+        let mut archived = 0;
+        for version in self.event_versions(handle)? {
+            if version < baseline {
+                self.kv.archive(&Self::key_for_event(handle, version))?;
+                archived += 1;
+            }
+        }
+
+        info!(
+            "Compacted '{}' at version {}, archived {} events",
+            handle, baseline, archived
+        );
+
+        Ok(baseline)
+    }
+
+    /// Checks the integrity of all aggregates, without changing anything on disk.
+    ///
+    /// This does the same scan as `recover`, i.e. it verifies all commands and the
+    /// events they refer to, and it looks for the best snapshot to rebuild from. But,
+    /// rather than archiving surplus or corrupt entries, it returns a report for each
+    /// aggregate describing what was found. Operators can use this to decide whether
+    /// they need to run the destructive `recover` operation.
+    pub fn check_integrity(&self) -> StoreResult<Vec<AggregateIntegrityReport>> {
+        let mut reports = vec![];
+        for handle in self.list()? {
+            reports.push(self.check_aggregate_integrity(&handle)?);
+        }
+        Ok(reports)
+    }
+
+    /// Checks the integrity of a single aggregate, without changing anything on disk.
+    /// See `check_integrity` for details.
+    pub fn check_aggregate_integrity(&self, handle: &Handle) -> StoreResult<AggregateIntegrityReport> {
+        let _lock = self.outer_lock.read().unwrap();
+        self.check_aggregate_integrity_no_lock(handle)
+    }
+
+    fn check_aggregate_integrity_no_lock(&self, handle: &Handle) -> StoreResult<AggregateIntegrityReport> {
+        let mut report = AggregateIntegrityReport::new(handle.clone());
+        report.info = self.get_info(handle).ok();
+
+        // Events before the compaction baseline, if any, were archived on purpose.
+        let first_kept_event = report.info.as_ref().map(|info| info.first_kept_event()).unwrap_or(0);
+
+        // Check all commands and associated events, in the same way as `recover`
+        let criteria = CommandHistoryCriteria::default();
+        let mut all_ok = true;
+
+        for command_key in self.command_keys_ascending(handle, &criteria)? {
+            if all_ok && command_key.sequence <= report.last_good_command {
+                // duplicate sequence, recover would archive it
+                report.surplus_commands.push(command_key);
+                continue;
+            }
+            if all_ok {
+                let key = Self::key_for_command(handle, &command_key);
+                match self.kv.get::<StoredCommand<A::StorableCommandDetails>>(&key) {
+                    Ok(Some(cmd)) => {
+                        if let Some(events) = cmd.effect().events() {
+                            for version in events {
+                                if *version < first_kept_event {
+                                    report.last_good_event = *version;
+                                    continue;
+                                }
+                                match self.kv.get::<A::Event>(&Self::key_for_event(handle, *version)) {
+                                    Ok(Some(_)) => report.last_good_event = *version,
+                                    Ok(None) => {
+                                        report.missing_events.push(*version);
+                                        all_ok = false;
+                                    }
+                                    Err(_) => {
+                                        report.corrupt_events.push(*version);
+                                        all_ok = false;
+                                    }
+                                }
+                            }
+                        }
+                        report.last_good_command = cmd.sequence();
+                    }
+                    _ => {
+                        report.corrupt_commands.push(command_key.clone());
+                        all_ok = false;
+                    }
+                }
+            }
+            if !all_ok {
+                report.surplus_commands.push(command_key);
+            }
+        }
+
+        let last_good_event = report.last_good_event;
+
+        report.surplus_events = self
+            .event_versions(handle)?
+            .into_iter()
+            .filter(|version| *version > last_good_event)
+            .collect();
+        report.surplus_events.sort_unstable();
+
+        report.snapshot = self.snapshot_status(&Self::key_for_snapshot(handle), last_good_event, report.info.as_ref());
+        report.backup_snapshot = self.snapshot_status(&Self::key_for_backup_snapshot(handle), last_good_event, None);
+
+        // Find the version we could start replaying from, preferring the snapshot
+        // over the backup snapshot over the init event, like `get_aggregate` does.
+        let start = match (&report.snapshot, &report.backup_snapshot) {
+            (SnapshotStatus::Usable(version), _) | (_, SnapshotStatus::Usable(version))
+                if *version >= first_kept_event =>
+            {
+                Some(*version)
+            }
+            _ if first_kept_event > 0 => None,
+            _ => match self.kv.get::<A::InitEvent>(&Self::key_for_event(handle, 0)) {
+                Ok(Some(init)) => A::init(init).ok().map(|agg| agg.version()),
+                _ => None,
+            },
+        };
+
+        report.recoverable_version = start.and_then(|start| {
+            let replayable = (start..last_good_event + 1).all(|version| {
+                matches!(
+                    self.kv.get::<A::Event>(&Self::key_for_event(handle, version)),
+                    Ok(Some(_))
+                )
+            });
+
+            if start <= last_good_event + 1 && replayable {
+                Some(last_good_event + 1)
+            } else {
+                None
+            }
+        });
+
+        Ok(report)
+    }
+
+    /// Writes a copy of all aggregates to a new store with the given name space
+    /// under the work dir. The copy can be used as the data of this store, after
+    /// copying it into place while Krill is not running.
+    ///
+    /// The outer lock is only held while the version, info and snapshots of the
+    /// aggregates are copied. Commands may be processed while their commands and
+    /// events are copied after that, but only the commands and events up to the
+    /// ones recorded in the copied info are included, so the backup reflects the
+    /// state of the store when the lock was held. Commands and events that are
+    /// archived while the backup is made are skipped with a warning, and counted
+    /// as missing. Archived, corrupt and surplus values are never included.
+    pub fn backup(&self, work_dir: &Path, name_space: &str) -> StoreResult<AggregateStoreBackup> {
+        let target = KeyValueStore::disk(work_dir, name_space)?;
+        let mut backup = AggregateStoreBackup::default();
+
+        let infos = {
+            let _lock = self.outer_lock.read().unwrap();
+            target.store(&Self::key_version(), &self.get_version()?)?;
+
+            let mut infos = vec![];
+            for handle in self.aggregates()? {
+                let info = self.get_info(&handle)?;
+                for key in &[Self::key_for_snapshot(&handle), Self::key_for_backup_snapshot(&handle)] {
+                    if let Some(snapshot) = self.kv.get::<Value>(key)? {
+                        self.backup_value(&target, key, &snapshot, false)?;
+                    }
+                }
+                target.store(&Self::key_for_info(&handle), &info)?;
+                infos.push((handle, info));
+            }
+            infos
+        };
+
+        for (handle, info) in infos {
+            let mut crit = CommandHistoryCriteria::default();
+            crit.set_unlimited_rows();
+
+            for command_key in self.command_keys_ascending(&handle, &crit)? {
+                if command_key.sequence > info.last_command {
+                    break;
+                }
+                let key = Self::key_for_command(&handle, &command_key);
+                match self.kv.get::<Value>(&key)? {
+                    Some(command) => {
+                        target.store_new(&key, &command)?;
+                        backup.commands += 1;
+                    }
+                    None => {
+                        warn!("Command {} for '{}' was archived during backup", command_key, handle);
+                        backup.missing += 1;
+                    }
+                }
+            }
+
+            for version in info.first_kept_event()..=info.last_event {
+                if !info.is_kept_event(version) {
+                    continue;
+                }
+                let key = Self::key_for_event(&handle, version);
+                match self.kv.get::<Value>(&key)? {
+                    Some(event) => {
+                        self.backup_value(&target, &key, &event, true)?;
+                        backup.events += 1;
+                    }
+                    None => {
+                        warn!("Event {} for '{}' was archived during backup", version, handle);
+                        backup.missing += 1;
+                    }
+                }
+            }
+
+            backup.aggregates += 1;
+        }
+
+        info!(
+            "Backed up {} aggregates with {} commands and {} events to '{}'",
+            backup.aggregates,
+            backup.commands,
+            backup.events,
+            work_dir.join(name_space).to_string_lossy()
+        );
+
+        Ok(backup)
+    }
+
+    // Stores the value in the backup, compressed if this store compresses new
+    // values, so that the backup looks like the original store.
+    fn backup_value(&self, target: &KeyValueStore, key: &KeyStoreKey, value: &Value, new: bool) -> StoreResult<()> {
+        match (self.compress, new) {
+            (true, true) => target.store_new_compressed(key, value)?,
+            (true, false) => target.store_compressed(key, value)?,
+            (false, true) => target.store_new(key, value)?,
+            (false, false) => target.store(key, value)?,
+        }
+        Ok(())
+    }
+
+    /// Store new snapshots and events gzip compressed. Existing values are always read
+    /// regardless of whether they were compressed, so this can be changed for existing
+    /// stores.
+    pub fn set_compress(&mut self, compress: bool) {
+        self.compress = compress;
+    }
+
+    /// Defers loading aggregates into the cache, from `warm` until they are first used.
+    /// This bounds the time and memory needed at startup for stores with many aggregates
+    /// that are rarely used, at the cost of a slower first use. It is off by default, in
+    /// which case `warm` fully loads every aggregate, and any aggregate that cannot be
+    /// loaded is found at startup rather than when it is used.
+    pub fn set_lazy_warm(&mut self, lazy: bool) {
+        self.lazy_warm = lazy;
+    }
+
+    /// Sets the aggregates that `warm` still loads fully if lazy warming is set: the
+    /// given number of most recently updated aggregates, and the aggregates with the
+    /// given handles. This keeps the first use of busy aggregates fast, while loading
+    /// the others is deferred. Has no effect unless lazy warming is set.
+    pub fn set_warm_set(&mut self, recent: usize, handles: Vec<Handle>) {
+        self.warm_recent = recent;
+        self.warm_handles = handles;
+    }
+
+    /// Limits the number of aggregates kept in memory. When the limit is exceeded the
+    /// least recently used aggregate is evicted, it will be loaded from its snapshot
+    /// again when it is needed. Use 0, the default, for no limit.
+    pub fn set_cache_size(&mut self, max_entries: usize) {
+        self.cache.get_mut().unwrap().set_max_entries(max_entries);
+    }
+
+    /// Logs a warning when a snapshot or event is larger than `warn` bytes, and
+    /// refuses to save it when it is larger than `max` bytes. The size is that of
+    /// the value serialized as compact json. Very large aggregates can exhaust
+    /// memory when they are loaded, so it is better to find out when they are
+    /// saved. Use 0, the default, for no warning or limit.
+    pub fn set_size_limits(&mut self, warn: usize, max: usize) {
+        self.size_warn = warn;
+        self.size_max = max;
+    }
+
+    /// Keeps archived, corrupt and surplus values under the given dir rather than in
+    /// the store itself, and limits how many are kept in each archive scope. Pruning
+    /// is off when max_entries is 0, the default. See `KeyValueStore::set_archive`.
+    pub fn set_archive(&mut self, dir: Option<&Path>, max_entries: usize) {
+        self.kv.set_archive(dir, max_entries);
+    }
+
+    /// Appends an entry for each command to the audit log at the given path, if
+    /// any. The store name is used to keep the entries of stores that share a log
+    /// apart. See `AuditLog`.
+    pub fn set_audit_log(&mut self, path: Option<&Path>, store: &str) -> StoreResult<()> {
+        self.audit_log = match path {
+            Some(path) => Some(AuditLog::open(path, store)?),
+            None => None,
+        };
+        Ok(())
+    }
+
+    /// Returns the counters for commands, events, snapshots, cache use and replays
+    /// for this store, since it was created.
+    pub fn metrics(&self) -> AggregateStoreMetricsReport {
+        self.metrics.report()
+    }
+
+    /// Adds a migration which transforms the stored values of aggregates from one
+    /// `KeyStoreVersion` to a later version. See `migrate`.
+    pub fn add_migration(&mut self, from: KeyStoreVersion, to: KeyStoreVersion, migration: Box<dyn StoreMigration>) {
+        self.migrations.add(from, to, migration);
+    }
+
+    /// Adds a listener that will receive all events before they are stored.
+    pub fn add_pre_save_listener<L: PreSaveEventListener<A>>(&mut self, sync_listener: Arc<L>) {
+        self.pre_save_listeners.push(sync_listener);
+    }
+
+    /// Adds a listener that will receive a reference to all events after they are stored.
+    pub fn add_post_save_listener<L: PostSaveEventListener<A>>(&mut self, listener: Arc<L>) {
+        self.post_save_listeners.push(listener);
+    }
+
+    /// Adds a listener that will receive all events after they are stored, on
+    /// its own worker thread. Up to 'capacity' updates are queued before
+    /// command processing waits for the listener. See
+    /// `PostSaveAsyncEventListener` for the delivery guarantees.
+    ///
+    /// The name identifies the listener across restarts. The events saved after
+    /// the last events delivered to it are queued first, so that it catches up.
+    /// A listener that was never added before only receives new events.
+    ///
+    /// Read-only stores do not accept these listeners, because they cannot
+    /// save which events were delivered.
+    pub fn add_post_save_async_listener<L: PostSaveAsyncEventListener<A>>(
+        &mut self,
+        name: &str,
+        listener: Arc<L>,
+        capacity: usize,
+    ) -> StoreResult<()> {
+        self.check_writable()?;
+        let key = Self::key_for_listener_cursor(name);
+        let mut replay = vec![];
+
+        let cursor = match self.kv.get::<HashMap<Handle, u64>>(&key)? {
+            Some(mut cursor) => {
+                for handle in self.list()? {
+                    let info = self.get_info(&handle)?;
+                    let next = cursor.get(&handle).copied().unwrap_or(1);
+
+                    let mut events = vec![];
+                    for version in next..=info.last_event {
+                        if !info.is_kept_event(version) {
+                            continue;
+                        }
+                        match self.get_event::<A::Event>(&handle, version)? {
+                            Some(event) => events.push(event),
+                            None => warn!(
+                                "Event {} for '{}' is missing, cannot deliver it to listener '{}'",
+                                version, handle, name
+                            ),
+                        }
+                    }
+
+                    if let Some(first) = events.first() {
+                        // Skip events that are no longer kept.
+                        cursor.insert(handle.clone(), first.version());
+                        replay.push((self.get_latest(&handle)?, events));
+                    }
+                }
+                cursor
+            }
+            None => {
+                let mut cursor = HashMap::new();
+                for handle in self.list()? {
+                    let next = self.get_info(&handle)?.last_event + 1;
+                    cursor.insert(handle, next);
+                }
+                self.kv.store(&key, &cursor)?;
+                cursor
+            }
+        };
+
+        let queue = PostSaveAsyncEventQueue::start(listener, capacity, self.kv.clone(), key, cursor);
+        if !replay.is_empty() {
+            info!(
+                "Delivering events saved since last delivery for {} aggregates to listener '{}'",
+                replay.len(),
+                name
+            );
+        }
+        for (agg, events) in replay {
+            queue.send(agg, events);
+        }
+        self.post_save_async_queues.push(queue);
+
+        Ok(())
+    }
+}
+
+/// # Manage Aggregates
+///
+impl<A: Aggregate> AggregateStore<A>
+where
+    A::Error: From<AggregateStoreError>,
+{
+    /// Gets the latest version for the given aggregate. Returns
+    /// an AggregateStoreError::UnknownAggregate in case the aggregate
+    /// does not exist.
+    pub fn get_latest(&self, handle: &Handle) -> StoreResult<Arc<A>> {
+        let _lock = self.outer_lock.read().unwrap();
+        self.get_latest_no_lock(handle)
+    }
+
+    /// Returns the aggregate as it was after applying the event with the given
+    /// version, i.e. the resulting aggregate has version `version + 1`.
+    ///
+    /// The aggregate is rebuilt from the latest snapshot that does not exceed the
+    /// version, or from the init event. Unlike when aggregates are loaded normally,
+    /// snapshots after the version are left alone and the cache is not updated.
+    pub fn get_aggregate_at(&self, handle: &Handle, version: u64) -> StoreResult<A> {
+        let _lock = self.outer_lock.read().unwrap();
+
+        let info = self.get_info(handle)?;
+        if version > info.last_event {
+            return Err(AggregateStoreError::UnknownVersion(
+                handle.clone(),
+                version,
+                info.last_event,
+            ));
+        }
+        if version + 1 < info.first_kept_event() {
+            return Err(AggregateStoreError::Compacted(handle.clone(), info.first_kept_event()));
+        }
+
+        let snapshot_key = Self::key_for_snapshot(handle);
+        let backup_snapshot_key = Self::key_for_backup_snapshot(handle);
+
+        let start_key = match (
+            self.snapshot_status(&snapshot_key, version, Some(&info)),
+            self.snapshot_status(&backup_snapshot_key, version, None),
+        ) {
+            (SnapshotStatus::Usable(_), _) => Some(snapshot_key),
+            (_, SnapshotStatus::Usable(_)) => Some(backup_snapshot_key),
+            _ => None,
+        };
+
+        let mut aggregate = match start_key.and_then(|key| self.kv.get::<A>(&key).ok().flatten()) {
+            Some(aggregate) => aggregate,
+            None => {
+                let init = self
+                    .kv
+                    .get::<A::InitEvent>(&Self::key_for_event(handle, 0))?
+                    .ok_or_else(|| AggregateStoreError::InitEventMissing(handle.clone()))?;
+                A::init(init).map_err(|_| AggregateStoreError::InitError(handle.clone()))?
+            }
+        };
+
+        self.update_aggregate(handle, &mut aggregate, Some(version))?;
+        Ok(aggregate)
+    }
+
+    /// Adds a new aggregate instance based on the init event.
+    pub fn add(&self, init: A::InitEvent) -> StoreResult<Arc<A>> {
+        self.check_writable()?;
+        let _lock = self.outer_lock.write().unwrap();
+
+        self.store_event(&init)?;
+
+        let handle = init.handle().clone();
+
+        let aggregate = A::init(init).map_err(|_| AggregateStoreError::InitError(handle.clone()))?;
+        let snapshot_hash = self.store_snapshot(&handle, &aggregate)?;
+
+        let info = StoredValueInfo {
+            snapshot_version: aggregate.version(),
+            snapshot_hash: Some(snapshot_hash),
+            ..Default::default()
+        };
+        self.save_info(&handle, &info)?;
+
+        let arc = Arc::new(aggregate);
+        self.cache_update(&handle, arc.clone());
+
+        Ok(arc)
+    }
+
+    /// Exports the init event, events and commands of an aggregate, e.g. for a
+    /// backup or to move it to another Krill instance. Commands which were
+    /// archived are not included.
+    pub fn export_aggregate(&self, handle: &Handle) -> StoreResult<AggregateExport<A>> {
+        let _lock = self.outer_lock.read().unwrap();
+
+        let info = self.get_info(handle)?;
+        if let Some(baseline) = info.compaction_baseline {
+            return Err(AggregateStoreError::Compacted(handle.clone(), baseline));
+        }
+
+        let init = self
+            .get_event::<A::InitEvent>(handle, 0)?
+            .ok_or_else(|| AggregateStoreError::InitEventMissing(handle.clone()))?;
+
+        let mut events = vec![];
+        for version in 1..=info.last_event {
+            let event = self
+                .get_event::<A::Event>(handle, version)?
+                .ok_or_else(|| AggregateStoreError::ReplayError(handle.clone(), info.last_event, version))?;
+            events.push(event);
+        }
+
+        let mut commands = vec![];
+        for command_key in self.command_keys_ascending(handle, &CommandHistoryCriteria::default())? {
+            commands.push(self.get_command(handle, &command_key)?);
+        }
+
+        let latest = self.get_latest_no_lock(handle)?;
+        let snapshot_hash = Self::snapshot_hash(latest.as_ref())?;
+
+        Ok(AggregateExport {
+            version: AggregateExport::<A>::VERSION,
+            handle: handle.clone(),
+            init,
+            events,
+            commands,
+            snapshot_hash,
+        })
+    }
+
+    /// Imports an aggregate exported by `export_aggregate`. The export is
+    /// verified before anything is saved: the events must be a contiguous
+    /// sequence for the handle, and replaying them must result in the exported
+    /// state. Existing aggregates are never overwritten.
+    pub fn import_aggregate(&self, export: AggregateExport<A>) -> StoreResult<Arc<A>> {
+        self.check_writable()?;
+        let _lock = self.outer_lock.write().unwrap();
+
+        let handle = export.handle.clone();
+        let invalid = |msg: String| AggregateStoreError::InvalidExport(handle.clone(), msg);
+
+        if export.version != AggregateExport::<A>::VERSION {
+            return Err(invalid(format!("unsupported export version {}", export.version)));
+        }
+
+        if self.kv.has_scope(handle.to_string())? {
+            return Err(AggregateStoreError::AggregateExists(handle.clone()));
+        }
+
+        if export.init.handle() != &handle || export.init.version() != 0 {
+            return Err(invalid("init event does not match".to_string()));
+        }
+
+        for (i, event) in export.events.iter().enumerate() {
+            let expected_version = i as u64 + 1;
+            if event.handle() != &handle || event.version() != expected_version {
+                return Err(invalid(format!(
+                    "expected event {}, found event {} for '{}'",
+                    expected_version,
+                    event.version(),
+                    event.handle()
+                )));
+            }
+        }
+
+        if let Some(command) = export.commands.iter().find(|c| c.handle() != &handle) {
+            return Err(invalid(format!("found command for '{}'", command.handle())));
+        }
+
+        let mut aggregate = A::init(export.init.clone()).map_err(|_| AggregateStoreError::InitError(handle.clone()))?;
+        aggregate.apply_all(export.events.clone());
+
+        let snapshot_hash = Self::snapshot_hash(&aggregate)?;
+        if snapshot_hash != export.snapshot_hash {
+            return Err(invalid("events do not replay to the exported state".to_string()));
+        }
+
+        self.store_event(&export.init)?;
+        for event in &export.events {
+            self.store_event(event)?;
+        }
+
+        let last_command = export.commands.iter().map(|c| c.sequence()).max().unwrap_or(0);
+        for command in export.commands {
+            self.store_command(command)?;
+        }
+
+        self.store_snapshot(&handle, &aggregate)?;
+
+        let info = StoredValueInfo {
+            snapshot_version: aggregate.version(),
+            last_event: export.events.len() as u64,
+            last_command,
+            snapshot_hash: Some(snapshot_hash),
+            ..Default::default()
+        };
+        self.save_info(&handle, &info)?;
+
+        info!("Imported '{}' at version {}", handle, aggregate.version());
+
+        let arc = Arc::new(aggregate);
+        self.cache_update(&handle, arc.clone());
+
+        Ok(arc)
+    }
+
+    /// Send a command to the latest aggregate referenced by the handle in the command.
+    ///
+    /// This will:
+    /// - Retrieve the latest aggregate for this command.
+    /// - Call the A::process_command function
+    /// on success:
+    ///   - call pre-save listeners with events
+    ///   - save command and events
+    ///   - call post-save listeners with events
+    ///   - return aggregate
+    /// on no-op (empty event list):
+    ///   - do not save anything, return aggregate
+    /// on error:
+    ///   - save command and error, return error
+    ///
+    /// If an audit log was set, then the actor, command, handle and outcome are
+    /// appended to it as well.
+    pub fn command(&self, cmd: A::Command) -> Result<Arc<A>, A::Error> {
+        self.audited_command(cmd, &mut false)
+    }
+
+    /// Processes the command and appends it to the audit log, if set. Sets
+    /// 'conflict' if the command was for another version than the latest.
+    fn audited_command(&self, cmd: A::Command, conflict: &mut bool) -> Result<Arc<A>, A::Error> {
+        // Take what we need for the audit log, before the command is consumed.
+        let audit = self.audit_log.as_ref().map(|_| {
+            (
+                cmd.actor().to_string(),
+                cmd.store().summary().label,
+                cmd.handle().clone(),
+            )
+        });
+
+        let res = self.process_command(cmd, conflict);
+
+        // Queue the events for the asynchronous listeners. This is done after the
+        // lock is released, so that a full queue does not hold up other commands.
+        // The queues keep the events for each aggregate in order.
+        if let Ok((agg, events)) = &res {
+            if !events.is_empty() {
+                for queue in &self.post_save_async_queues {
+                    queue.send(agg.clone(), events.clone());
+                }
+            }
+        }
+
+        if let (Some(audit_log), Some((actor, action, handle))) = (&self.audit_log, audit) {
+            let outcome = match &res {
+                Ok((_, events)) if events.is_empty() => AuditOutcome::NoOp,
+                Ok((_, events)) => AuditOutcome::Success { events: events.len() },
+                Err(e) => AuditOutcome::Error { msg: e.to_string() },
+            };
+            audit_log.log(&actor, &action, &handle, outcome);
+        }
+
+        res.map(|(agg, _)| agg)
+    }
+
+    /// Builds a command for the latest version of the aggregate and sends it,
+    /// see `command`. If another command updated the aggregate in between, then
+    /// the command is built again for the new latest version and sent again, up
+    /// to 'retries' times. After that the `ConcurrentModification` is returned.
+    ///
+    /// Commands are only retried if they are `retry_safe`, i.e. if they do not
+    /// depend on the version of the aggregate they were built for. E.g. updating
+    /// the contact of a parent or the resources of a child is safe, but updating
+    /// ROAs is not, because the sender decided on the changes based on the ROAs
+    /// that it saw.
+    pub fn command_with_retry<F>(&self, handle: &Handle, retries: usize, build: F) -> Result<Arc<A>, A::Error>
+    where
+        F: Fn(&A) -> A::Command,
+    {
+        let mut attempt = 0;
+        loop {
+            let latest = self.get_latest(handle)?;
+            let cmd = build(&latest);
+            let retry_safe = cmd.retry_safe();
+
+            let mut conflict = false;
+            let res = self.audited_command(cmd, &mut conflict);
+
+            if conflict && retry_safe && attempt < retries {
+                attempt += 1;
+                debug!(
+                    "Retrying command for '{}' after concurrent modification, attempt {}",
+                    handle, attempt
+                );
+            } else {
+                return res;
+            }
+        }
+    }
+
+    /// Processes the command, see `command`. Returns the aggregate and the new
+    /// events. Sets 'conflict' if the command was for another version than the
+    /// latest.
+    fn process_command(&self, cmd: A::Command, conflict: &mut bool) -> Result<(Arc<A>, Vec<A::Event>), A::Error> {
+        debug!("Processing command {}", cmd);
+
+        self.check_writable()?;
+        let _lock = self.outer_lock.write().unwrap();
+
+        // Get the latest arc.
+        let handle = cmd.handle().clone();
+
+        let mut info = self.get_info(&handle)?;
+        info.last_update = Time::now();
+        info.last_command += 1;
+
+        let mut latest = self.get_latest_no_lock(&handle)?;
+
+        if let Some(version) = cmd.version() {
+            if version != latest.version() {
+                error!(
+                    "Version conflict updating '{}', expected version: {}, found: {}",
                     handle,
                     version,
                     latest.version()
                 );
 
+                *conflict = true;
                 return Err(A::Error::from(AggregateStoreError::ConcurrentModification(handle)));
             }
         }
 
-        let stored_command_builder = StoredCommandBuilder::new(&cmd, latest.version(), info.last_command);
+        let mut stored_command_builder = StoredCommandBuilder::new(&cmd, latest.version(), info.last_command);
 
         let res = match latest.process_command(cmd) {
             Err(e) => {
@@ -451,7 +1743,7 @@ where
             }
             Ok(events) => {
                 if events.is_empty() {
-                    return Ok(latest); // otherwise the version info will be updated
+                    return Ok((latest, events)); // otherwise the version info will be updated
                 } else {
                     let agg = Arc::make_mut(&mut latest);
 
@@ -495,20 +1787,14 @@ where
                         }
                     }
 
-                    // Time to start saving things.
-                    let stored_command = stored_command_builder.finish_with_events(events.as_slice());
-
-                    // If persistence fails, then complain loudly, and exit. Krill should not keep running, because this would
-                    // result in discrepancies between state in memory and state on disk. Let Krill crash and an operator investigate.
-                    // See issue: https://github.com/NLnetLabs/krill/issues/322
-                    if let Err(e) = self.store_command(stored_command) {
-                        error!("Cannot save state for '{}'. Got error: {}", handle, e);
-                        error!("Will now exit Krill - please verify that the disk can be written to and is not full");
-                        std::process::exit(1);
+                    // Refuse events which are too large before anything is saved.
+                    for event in &events {
+                        self.check_event_size(event)?;
                     }
 
                     // Apply events, check that the aggregate can be updated, and make sure
-                    // we have an updated version so we can store it.
+                    // we have an updated version so we can store it. This is a copy of the
+                    // cached aggregate, so nothing changes if it is refused below.
                     for event in &events {
                         agg.apply(event.clone());
                     }
@@ -518,21 +1804,41 @@ where
                         pre_save_listener.as_ref().listen(agg, events.as_slice())?;
                     }
 
-                    // Nothing broke, so it's safe to store the command, events and aggregate
+                    // Likewise refuse a snapshot which is too large, before anything is saved.
+                    let snapshot = self.checked_snapshot_json(&handle, agg)?;
+
+                    // Time to start saving things. Until the cache is updated below, the
+                    // cached aggregate may be behind the saved events.
+                    self.dirty.write().unwrap().insert(handle.clone());
+                    A::record_effect(stored_command_builder.details_mut(), events.as_slice());
+                    let stored_command = stored_command_builder.finish_with_events(events.as_slice());
+
+                    // If persistence fails, then complain loudly, and exit. Krill should not keep running, because this would
+                    // result in discrepancies between state in memory and state on disk. Let Krill crash and an operator investigate.
+                    // See issue: https://github.com/NLnetLabs/krill/issues/322
+                    if let Err(e) = self.store_command(stored_command) {
+                        error!("Cannot save state for '{}'. Got error: {}", handle, e);
+                        error!("Will now exit Krill - please verify that the disk can be written to and is not full");
+                        std::process::exit(1);
+                    }
+
+                    // Nothing broke, so it's safe to store the events and aggregate
                     for event in &events {
                         self.store_event(event)?;
                     }
                     info.snapshot_version = agg.version();
-                    self.store_snapshot(&handle, agg)?;
+                    info.snapshot_hash = Some(self.store_snapshot_json(&handle, agg, &snapshot)?);
 
-                    cache.insert(handle.clone(), Arc::new(agg.clone()));
+                    let evicted = cache.insert(&handle, Arc::new(agg.clone()));
+                    self.metrics.cache_evictions(evicted);
+                    self.dirty.write().unwrap().remove(&handle);
 
                     // Now send the events to the 'post-save' listeners.
                     for listener in &self.post_save_listeners {
                         listener.as_ref().listen(agg, events.as_slice());
                     }
 
-                    Ok(latest)
+                    Ok((latest, events))
                 }
             }
         };
@@ -555,6 +1861,243 @@ where
         let _lock = self.outer_lock.read().unwrap();
         self.aggregates()
     }
+
+    /// Lists the ids from 'offset', at most 'limit' of them if given, in the
+    /// given order.
+    ///
+    /// The lock is only held while finding the ids. When sorting by last
+    /// update the info of each aggregate is read afterwards, so that commands
+    /// are not blocked while the info of many aggregates is read. Aggregates
+    /// of which the info cannot be read are sorted last.
+    pub fn list_page(
+        &self,
+        sort: AggregateListSort,
+        offset: usize,
+        limit: Option<usize>,
+    ) -> Result<AggregateListPage, AggregateStoreError> {
+        let mut handles = {
+            let _lock = self.outer_lock.read().unwrap();
+            self.aggregates()?
+        };
+
+        match sort {
+            AggregateListSort::Handle => handles.sort_by(|a, b| a.as_str().cmp(b.as_str())),
+            AggregateListSort::LastUpdate => {
+                let mut updated: Vec<(Option<i64>, Handle)> = handles
+                    .into_iter()
+                    .map(|handle| (self.get_info(&handle).ok().map(|i| i.last_update.timestamp()), handle))
+                    .collect();
+                updated.sort_by(|(a_time, a), (b_time, b)| b_time.cmp(a_time).then_with(|| a.as_str().cmp(b.as_str())));
+                handles = updated.into_iter().map(|(_, handle)| handle).collect();
+            }
+        }
+
+        let total = handles.len();
+        let handles = handles
+            .into_iter()
+            .skip(offset)
+            .take(limit.unwrap_or(usize::MAX))
+            .collect();
+
+        Ok(AggregateListPage { total, offset, handles })
+    }
+
+    /// Lists the archived, corrupt and surplus values of the aggregate, oldest
+    /// first, so that they can be reviewed and possibly repaired.
+    pub fn archived_entries(&self, handle: &Handle) -> Result<Vec<ArchivedEntry>, AggregateStoreError> {
+        let _lock = self.outer_lock.read().unwrap();
+
+        let mut res: Vec<ArchivedEntry> = self
+            .kv
+            .archived_keys(handle.as_str())?
+            .into_iter()
+            .map(|archived| ArchivedEntry {
+                name: archived.key.name().to_string(),
+                kind: ArchivedEntryKind::for_name(archived.key.name()),
+                archive: archived.archive,
+                archived: archived.archived_at,
+            })
+            .collect();
+        res.sort_by(|a, b| a.archived.cmp(&b.archived).then_with(|| a.name.cmp(&b.name)));
+
+        Ok(res)
+    }
+
+    /// Returns the json of an archived value of the aggregate, or None if it
+    /// no longer exists. Fails if the value is not valid json.
+    pub fn archived_value(&self, handle: &Handle, entry: &ArchivedEntry) -> Result<Option<Value>, AggregateStoreError> {
+        let _lock = self.outer_lock.read().unwrap();
+        let key = KeyStoreKey::scoped(handle.to_string(), entry.name.clone()).sub_scope(&entry.archive);
+        Ok(self.kv.get(&key)?)
+    }
+}
+
+/// # Migrations
+///
+impl<A: Aggregate> AggregateStore<A>
+where
+    A::Error: From<AggregateStoreError>,
+{
+    /// Applies the added migrations to the stored values, one step at a time, for
+    /// as long as there is a migration from the version of this store. This is
+    /// done by `warm` and `recover` before any aggregate is loaded.
+    ///
+    /// Each step is applied per aggregate. Migrated values are first written to a
+    /// temporary scope, and only when that is complete are they moved over the
+    /// original values. If Krill stops before the temporary scope is complete, the
+    /// temporary scope is discarded on the next start and the original values are
+    /// left intact. If Krill stops while values are being moved, then the move is
+    /// resumed on the next start.
+    ///
+    /// Migrated aggregates get their own 'version' key, so that they are not migrated
+    /// again if Krill stops before all aggregates in the store are migrated.
+    pub fn migrate(&self) -> StoreResult<()> {
+        if self.migrations.is_empty() {
+            return Ok(());
+        }
+
+        if self.read_only {
+            // Read-only stores rely on the primary to do the migration.
+            return match self.migrations.step_from(&self.get_version()?) {
+                Some(_) => Err(AggregateStoreError::ReadOnly),
+                None => Ok(()),
+            };
+        }
+
+        let _lock = self.outer_lock.write().unwrap();
+
+        for scope in self.kv.scopes()? {
+            if let Some(handle) = Self::handle_for_migration_scope(&scope) {
+                self.finish_aggregate_migration(&handle)?;
+            }
+        }
+
+        let mut version = self.get_version()?;
+        while let Some((to, migration)) = self.migrations.step_from(&version) {
+            info!("Migrating stored values from version {:?} to {:?}", version, to);
+            for handle in self.aggregates()? {
+                if self.aggregate_version(&handle, &version)? < *to {
+                    self.migrate_aggregate(&handle, to, migration)?;
+                }
+            }
+            self.set_version(to)?;
+            version = to.clone();
+        }
+
+        Ok(())
+    }
+
+    /// Writes the migrated values for an aggregate to its migration scope, and then
+    /// moves them over the existing values.
+    fn migrate_aggregate(
+        &self,
+        handle: &Handle,
+        to: &KeyStoreVersion,
+        migration: &dyn StoreMigration,
+    ) -> StoreResult<()> {
+        debug!("Migrating '{}' to version {:?}", handle, to);
+        let migration_scope = Self::migration_scope(handle);
+
+        for key in self.kv.keys(Some(handle.to_string()), "")? {
+            let name = key.name();
+            if name.starts_with('.') {
+                // skip left over temporary files
+                continue;
+            }
+
+            let value: Value = self
+                .kv
+                .get(&key)?
+                .ok_or_else(|| AggregateStoreError::CouldNotMigrate(handle.clone(), format!("cannot read {}", key)))?;
+
+            let migrated = if name.starts_with("command--") {
+                migration.migrate_command(value)
+            } else if name.starts_with("delta-") {
+                migration.migrate_event(value)
+            } else if name.starts_with("snapshot") {
+                migration.migrate_snapshot(value)
+            } else if name == "info.json" {
+                // the recorded hash does not apply to the migrated snapshot
+                let mut info = value;
+                if let Some(map) = info.as_object_mut() {
+                    map.remove("snapshot_hash");
+                }
+                Ok(info)
+            } else {
+                Ok(value)
+            };
+            let value = migrated
+                .map_err(|e| AggregateStoreError::CouldNotMigrate(handle.clone(), format!("{}: {}", key, e)))?;
+
+            let migrated_key = KeyStoreKey::scoped(migration_scope.clone(), name.to_string());
+            if self.compress {
+                self.kv.store_compressed(&migrated_key, &value)?;
+            } else {
+                self.kv.store(&migrated_key, &value)?;
+            }
+        }
+
+        // The version is written last, it marks the migration scope as complete.
+        self.kv.store(&Self::key_for_migration_version(&migration_scope), to)?;
+
+        self.finish_aggregate_migration(handle)
+    }
+
+    /// Moves the values in a complete migration scope over the values for the aggregate,
+    /// and removes the migration scope.
+    fn finish_aggregate_migration(&self, handle: &Handle) -> StoreResult<()> {
+        let migration_scope = Self::migration_scope(handle);
+        let migration_version_key = Self::key_for_migration_version(&migration_scope);
+
+        if self.kv.has(&migration_version_key)? {
+            for key in self.kv.keys(Some(migration_scope.clone()), "")? {
+                if key.name() != migration_version_key.name() {
+                    self.kv
+                        .move_key(&key, &KeyStoreKey::scoped(handle.to_string(), key.name().to_string()))?;
+                }
+            }
+            // Move the version last, so that an interrupted move is resumed.
+            self.kv.move_key(
+                &migration_version_key,
+                &Self::key_for_migration_version(handle.as_str()),
+            )?;
+        } else if self.kv.has_scope(migration_scope.clone())? {
+            warn!("Discarding incomplete migration for '{}'", handle);
+        }
+
+        self.kv.drop_scope(&migration_scope)?;
+        self.cache_remove(handle);
+
+        Ok(())
+    }
+
+    /// Returns the version for an aggregate, which may be ahead of the store if it was
+    /// migrated before the migration of the store as a whole was finished.
+    fn aggregate_version(&self, handle: &Handle, store_version: &KeyStoreVersion) -> StoreResult<KeyStoreVersion> {
+        match self
+            .kv
+            .get::<KeyStoreVersion>(&Self::key_for_migration_version(handle.as_str()))?
+        {
+            Some(version) if version > *store_version => Ok(version),
+            _ => Ok(store_version.clone()),
+        }
+    }
+
+    fn migration_scope(handle: &Handle) -> String {
+        format!(".{}.migrating", handle)
+    }
+
+    fn handle_for_migration_scope(scope: &str) -> Option<Handle> {
+        if scope.starts_with('.') && scope.ends_with(".migrating") {
+            Handle::from_str(&scope[1..scope.len() - ".migrating".len()]).ok()
+        } else {
+            None
+        }
+    }
+
+    fn key_for_migration_version(scope: &str) -> KeyStoreKey {
+        KeyStoreKey::scoped(scope.to_string(), "version".to_string())
+    }
 }
 
 /// # Manage Commands
@@ -568,35 +2111,68 @@ where
         &self,
         id: &Handle,
         crit: CommandHistoryCriteria,
+    ) -> Result<CommandHistory, AggregateStoreError> {
+        if crit.has_actor() {
+            return self.command_history_for_actor(id, crit);
+        }
+
+        let offset = crit.offset();
+
+        let (total, command_keys) = self.command_keys_page(id, &crit)?;
+
+        let mut commands: Vec<CommandHistoryRecord> = Vec::with_capacity(command_keys.len());
+
+        for command_key in command_keys {
+            let key = Self::key_for_command(id, &command_key);
+            let stored: StoredCommand<A::StorableCommandDetails> = self
+                .kv
+                .get(&key)?
+                .ok_or_else(|| AggregateStoreError::CommandNotFound(id.clone(), command_key))?;
+
+            let stored = stored.into();
+            commands.push(stored);
+        }
+        Ok(CommandHistory::new(offset, total, commands))
+    }
+
+    /// Find all commands that fit the criteria, including an actor filter, and
+    /// return history.
+    ///
+    /// Because the actor is not encoded in the command key we cannot page on
+    /// keys alone here. Instead all commands matching the other criteria are
+    /// loaded in ascending order, so that they can be counted and filtered on
+    /// their actor.
+    fn command_history_for_actor(
+        &self,
+        id: &Handle,
+        crit: CommandHistoryCriteria,
     ) -> Result<CommandHistory, AggregateStoreError> {
         let offset = crit.offset();
+        let rows = crit.rows_limit();
 
-        let command_keys = self.command_keys_ascending(id, &crit)?;
-
-        let rows = match crit.rows_limit() {
-            Some(limit) => limit,
-            None => command_keys.len(),
-        };
-
-        let mut commands: Vec<CommandHistoryRecord> = Vec::with_capacity(rows);
+        let mut commands: Vec<CommandHistoryRecord> = vec![];
         let mut skipped = 0;
         let mut total = 0;
 
-        for command_key in command_keys {
+        for command_key in self.command_keys_ascending(id, &crit)? {
+            let key = Self::key_for_command(id, &command_key);
+            let stored: StoredCommand<A::StorableCommandDetails> = self
+                .kv
+                .get(&key)?
+                .ok_or_else(|| AggregateStoreError::CommandNotFound(id.clone(), command_key))?;
+
+            if !crit.matches_actor(stored.actor()) {
+                continue;
+            }
+
             total += 1;
             if skipped < offset {
                 skipped += 1;
-            } else if commands.len() < rows {
-                let key = Self::key_for_command(id, &command_key);
-                let stored: StoredCommand<A::StorableCommandDetails> = self
-                    .kv
-                    .get(&key)?
-                    .ok_or_else(|| AggregateStoreError::CommandNotFound(id.clone(), command_key))?;
-
-                let stored = stored.into();
-                commands.push(stored);
+            } else if rows.map(|rows| commands.len() < rows).unwrap_or(true) {
+                commands.push(stored.into());
             }
         }
+
         Ok(CommandHistory::new(offset, total, commands))
     }
 
@@ -615,7 +2191,7 @@ where
                     "Found corrupt command at: {}, will try to archive. Error was: {}",
                     key, e
                 );
-                self.kv.archive_corrupt(&key)?;
+                self.archive_corrupt(&key)?;
                 Err(AggregateStoreError::CommandCorrupt(id.clone(), command_key.clone()))
             }
         }
@@ -631,7 +2207,7 @@ where
                     "Found corrupt event for {}, version {}, archiving. Error: {}",
                     id, version, e
                 );
-                self.kv.archive_corrupt(&key)?;
+                self.archive_corrupt(&key)?;
                 Err(AggregateStoreError::EventCorrupt(id.clone(), version))
             }
         }
@@ -646,45 +2222,96 @@ where
         Ok(self.get_event::<A::Event>(id, aggregate.version())?.is_some())
     }
 
+    /// Returns whether events may have been saved for the aggregate since it was
+    /// cached. A writable store is the sole writer of its aggregates, and updates the
+    /// cache whenever it saves events. So, unless saving failed halfway, the cache is
+    /// up to date. A read-only store can never be sure, as the primary may have saved
+    /// new events.
+    fn may_have_updates(&self, id: &Handle) -> bool {
+        self.read_only || self.dirty.read().unwrap().contains(id)
+    }
+
+    /// Returns the last event in the info for the aggregate, if any. Events after it
+    /// are not applied, as they may still be in the process of being saved.
+    fn last_event(&self, handle: &Handle) -> StoreResult<Option<u64>> {
+        let info_key = Self::key_for_info(handle);
+        Ok(self
+            .kv
+            .get::<StoredValueInfo>(&info_key)
+            .map_err(|_| AggregateStoreError::InfoCorrupt(handle.clone()))?
+            .map(|info| info.last_event))
+    }
+
     fn cache_get(&self, id: &Handle) -> Option<Arc<A>> {
-        self.cache.read().unwrap().get(id).cloned()
+        self.cache.read().unwrap().get(id)
     }
 
     fn cache_remove(&self, id: &Handle) {
         self.cache.write().unwrap().remove(id);
+        self.dirty.write().unwrap().remove(id);
     }
 
     fn cache_update(&self, id: &Handle, arc: Arc<A>) {
-        self.cache.write().unwrap().insert(id.clone(), arc);
+        let evicted = self.cache.write().unwrap().insert(id, arc);
+        self.metrics.cache_evictions(evicted);
+        self.dirty.write().unwrap().remove(id);
+    }
+
+    fn check_writable(&self) -> StoreResult<()> {
+        if self.read_only {
+            Err(AggregateStoreError::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Archives a corrupt value. Read-only stores leave the value in place, because
+    /// it may still be in the process of being saved.
+    fn archive_corrupt(&self, key: &KeyStoreKey) -> StoreResult<()> {
+        if !self.read_only {
+            self.kv.archive_corrupt(key)?;
+        }
+        Ok(())
+    }
+
+    /// Archives a surplus value. Read-only stores leave the value in place.
+    fn archive_surplus(&self, key: &KeyStoreKey) -> StoreResult<()> {
+        if !self.read_only {
+            self.kv.archive_surplus(key)?;
+        }
+        Ok(())
     }
 
     fn get_latest_no_lock(&self, handle: &Handle) -> StoreResult<Arc<A>> {
         trace!("Trying to load aggregate id: {}", handle);
 
-        let info_key = Self::key_for_info(handle);
-        let limit = self
-            .kv
-            .get::<StoredValueInfo>(&info_key)
-            .map_err(|_| AggregateStoreError::InfoCorrupt(handle.clone()))?
-            .map(|info| info.last_event);
-
         match self.cache_get(handle) {
-            None => match self.get_aggregate(handle, limit)? {
-                None => {
-                    error!("Could not load aggregate with id: {} from disk", handle);
-                    Err(AggregateStoreError::UnknownAggregate(handle.clone()))
-                }
-                Some(agg) => {
-                    let arc: Arc<A> = Arc::new(agg);
-                    self.cache_update(handle, arc.clone());
-                    trace!("Loaded aggregate id: {} from disk", handle);
-                    Ok(arc)
+            None => {
+                self.metrics.cache_miss();
+                match self.get_aggregate(handle, self.last_event(handle)?)? {
+                    None => {
+                        error!("Could not load aggregate with id: {} from disk", handle);
+                        Err(AggregateStoreError::UnknownAggregate(handle.clone()))
+                    }
+                    Some(agg) => {
+                        let arc: Arc<A> = Arc::new(agg);
+                        self.cache_update(handle, arc.clone());
+                        trace!("Loaded aggregate id: {} from disk", handle);
+                        Ok(arc)
+                    }
                 }
-            },
+            }
             Some(mut arc) => {
-                if self.has_updates(handle, &arc)? {
-                    let agg = Arc::make_mut(&mut arc);
-                    self.update_aggregate(handle, agg, limit)?;
+                self.metrics.cache_hit();
+                // Only look for new events on disk if the cache may be behind.
+                if self.may_have_updates(handle) {
+                    if self.has_updates(handle, &arc)? {
+                        let agg = Arc::make_mut(&mut arc);
+                        self.update_aggregate(handle, agg, self.last_event(handle)?)?;
+                        self.cache_update(handle, arc.clone());
+                    } else {
+                        self.dirty.write().unwrap().remove(handle);
+                    }
                 }
                 trace!("Loaded aggregate id: {} from memory", handle);
                 Ok(arc)
@@ -703,6 +2330,10 @@ where
         KeyStoreKey::simple("version".to_string())
     }
 
+    fn key_for_listener_cursor(name: &str) -> KeyStoreKey {
+        KeyStoreKey::simple(format!("listener-{}.json", name))
+    }
+
     fn key_for_info(agg: &Handle) -> KeyStoreKey {
         KeyStoreKey::scoped(agg.to_string(), "info.json".to_string())
     }
@@ -735,33 +2366,74 @@ where
     }
 
     pub fn set_version(&self, version: &KeyStoreVersion) -> Result<(), AggregateStoreError> {
+        self.check_writable()?;
         self.kv.store(&Self::key_version(), version)?;
         Ok(())
     }
 
+    /// Returns an iterator over all command keys for the aggregate which
+    /// match the criteria, in no particular order.
+    fn command_keys_matching<'a>(
+        &self,
+        id: &Handle,
+        crit: &'a CommandHistoryCriteria,
+    ) -> Result<impl Iterator<Item = CommandKey> + 'a, AggregateStoreError> {
+        let keys = self.kv.keys(Some(id.to_string()), "command--")?;
+
+        Ok(keys
+            .into_iter()
+            .filter_map(|key| match CommandKey::from_str(key.name()) {
+                Ok(command_key) => Some(command_key),
+                Err(_) => {
+                    warn!("Found strange command-like key in disk key-value store: {}", key.name());
+                    None
+                }
+            })
+            .filter(move |command_key| command_key.matches_crit(crit)))
+    }
+
     fn command_keys_ascending(
         &self,
         id: &Handle,
         crit: &CommandHistoryCriteria,
     ) -> Result<Vec<CommandKey>, AggregateStoreError> {
-        let mut command_keys = vec![];
+        let mut command_keys: Vec<CommandKey> = self.command_keys_matching(id, crit)?.collect();
+        command_keys.sort();
+        Ok(command_keys)
+    }
 
-        for key in self.kv.keys(Some(id.to_string()), "command--")? {
-            match CommandKey::from_str(key.name()) {
-                Ok(command_key) => {
-                    if command_key.matches_crit(crit) {
-                        command_keys.push(command_key);
-                    }
-                }
-                Err(_) => {
-                    warn!("Found strange command-like key in disk key-value store: {}", key.name());
+    /// Returns the total number of commands matching the criteria, and the page
+    /// of command keys selected by its offset and rows limit, in ascending order
+    /// of sequence.
+    ///
+    /// If there is a rows limit then only the lowest `offset + rows` keys are kept
+    /// in a bounded heap while scanning, so we do not need to keep and sort all
+    /// keys for aggregates with a long command history.
+    fn command_keys_page(
+        &self,
+        id: &Handle,
+        crit: &CommandHistoryCriteria,
+    ) -> Result<(usize, Vec<CommandKey>), AggregateStoreError> {
+        let offset = crit.offset();
+        let capacity = crit.rows_limit().map(|rows| offset.saturating_add(rows));
+
+        let mut total = 0;
+        let mut heap = BinaryHeap::new();
+
+        for command_key in self.command_keys_matching(id, crit)? {
+            total += 1;
+            heap.push(command_key);
+            if let Some(capacity) = capacity {
+                if heap.len() > capacity {
+                    // drop the highest sequence seen so far
+                    heap.pop();
                 }
             }
         }
 
-        command_keys.sort_by(|a, b| a.sequence.cmp(&b.sequence));
+        let page = heap.into_sorted_vec().into_iter().skip(offset).collect();
 
-        Ok(command_keys)
+        Ok((total, page))
     }
 
     /// Private, should be called through `list` which takes care of locking.
@@ -777,8 +2449,14 @@ where
         Ok(res)
     }
 
-    /// Clean surplus events
-    fn archive_surplus_events(&self, id: &Handle, from: u64) -> Result<(), AggregateStoreError> {
+    /// Returns true if any values, e.g. the info or events, are stored for the aggregate.
+    fn has_values(&self, id: &Handle) -> Result<bool, AggregateStoreError> {
+        Ok(self.kv.has_scope(id.to_string())? && !self.kv.keys(Some(id.to_string()), "")?.is_empty())
+    }
+
+    /// Returns the versions of all events found for the aggregate, in no particular order.
+    fn event_versions(&self, id: &Handle) -> Result<Vec<u64>, AggregateStoreError> {
+        let mut versions = vec![];
         for key in self.kv.keys(Some(id.to_string()), "delta-")? {
             let name = key.name();
             if name.starts_with("delta-") && name.ends_with(".json") {
@@ -786,17 +2464,25 @@ where
                 let end = name.len() - 5;
                 if end > start {
                     if let Ok(v) = u64::from_str(&name[start..end]) {
-                        if v >= from {
-                            let key = Self::key_for_event(id, v);
-                            warn!("Archiving surplus event for '{}': {}", id, key);
-                            self.kv
-                                .archive_surplus(&key)
-                                .map_err(AggregateStoreError::KeyStoreError)?
-                        }
+                        versions.push(v);
                     }
                 }
             }
         }
+        Ok(versions)
+    }
+
+    /// Clean surplus events
+    fn archive_surplus_events(&self, id: &Handle, from: u64) -> Result<(), AggregateStoreError> {
+        for v in self.event_versions(id)? {
+            if v >= from {
+                let key = Self::key_for_event(id, v);
+                warn!("Archiving surplus event for '{}': {}", id, key);
+                self.kv
+                    .archive_surplus(&key)
+                    .map_err(AggregateStoreError::KeyStoreError)?
+            }
+        }
         Ok(())
     }
 
@@ -811,20 +2497,28 @@ where
 
     /// MUST check if the event already exists and return an error if it does.
     fn store_event<V: Event>(&self, event: &V) -> Result<(), AggregateStoreError> {
+        self.check_writable()?;
         let id = event.handle();
         let version = event.version();
         let key = Self::key_for_event(id, version);
-        self.kv.store_new(&key, event)?;
+        if self.compress {
+            self.kv.store_new_compressed(&key, event)?;
+        } else {
+            self.kv.store_new(&key, event)?;
+        }
+        self.metrics.event_stored();
         Ok(())
     }
 
     fn store_command<S: WithStorableDetails>(&self, command: StoredCommand<S>) -> Result<(), AggregateStoreError> {
+        self.check_writable()?;
         let id = command.handle();
 
         let command_key = CommandKey::for_stored(&command);
         let key = Self::key_for_command(id, &command_key);
 
         self.kv.store_new(&key, &command)?;
+        self.metrics.command_processed(id);
         Ok(())
     }
 
@@ -842,6 +2536,10 @@ where
 
         let snapshot_key = Self::key_for_snapshot(id);
 
+        // The info is used to verify the snapshot, but it may be missing or corrupt
+        // if we are called during recovery.
+        let info = self.get_info(id).ok();
+
         match self.kv.get::<A>(&snapshot_key) {
             Err(e) => {
                 // snapshot file was present and corrupt
@@ -849,7 +2547,15 @@ where
                     "Could not parse snapshot for '{}', archiving as corrupt. Error was: {}",
                     id, e
                 );
-                self.kv.archive_corrupt(&snapshot_key)?;
+                self.archive_corrupt(&snapshot_key)?;
+            }
+            Ok(Some(agg)) if !Self::snapshot_matches_info(&agg, info.as_ref()) => {
+                // snapshot present, but it is not the snapshot recorded in the info
+                error!(
+                    "Snapshot for '{}' does not match the snapshot hash and version in its info, archiving as corrupt.",
+                    id
+                );
+                self.archive_corrupt(&snapshot_key)?;
             }
             Ok(Some(agg)) => {
                 // snapshot present and okay
@@ -859,7 +2565,7 @@ where
                         aggregate_opt = Some(agg)
                     } else {
                         trace!("Discarding snapshot after limit '{}'", id);
-                        self.kv.archive_surplus(&snapshot_key)?;
+                        self.archive_surplus(&snapshot_key)?;
                     }
                 } else {
                     debug!("Found valid snapshot for '{}'", id);
@@ -879,7 +2585,7 @@ where
                         "Could not parse backup snapshot for '{}', archiving as corrupt. Error: {}",
                         id, e
                     );
-                    self.kv.archive_corrupt(&backup_snapshot_key)?;
+                    self.archive_corrupt(&backup_snapshot_key)?;
                 }
                 Ok(Some(agg)) => {
                     trace!("Found backup snapshot for '{}'", id);
@@ -888,7 +2594,7 @@ where
                             aggregate_opt = Some(agg)
                         } else {
                             trace!("Discarding backup snapshot after limit '{}'", id);
-                            self.kv.archive_surplus(&backup_snapshot_key)?;
+                            self.archive_surplus(&backup_snapshot_key)?;
                         }
                     } else {
                         debug!("Found valid backup snapshot for '{}'", id);
@@ -899,6 +2605,22 @@ where
             }
         }
 
+        if let Some(baseline) = info.as_ref().and_then(|info| info.compaction_baseline) {
+            // The init event and events before the baseline were archived when the
+            // aggregate was compacted, so it can only be rebuilt from a snapshot.
+            if aggregate_opt
+                .as_ref()
+                .map(|agg| agg.version() < baseline)
+                .unwrap_or(true)
+            {
+                error!(
+                    "No usable snapshot found for '{}', which was compacted at version {}",
+                    id, baseline
+                );
+                return Err(AggregateStoreError::Compacted(id.clone(), baseline));
+            }
+        }
+
         if aggregate_opt.is_none() {
             warn!("No snapshots found for '{}' will try from initialization event.", id);
             let init_key = Self::key_for_event(id, 0);
@@ -907,6 +2629,15 @@ where
                     trace!("Rebuilding aggregate {} from init event", id);
                     Some(A::init(e).map_err(|_| AggregateStoreError::InitError(id.clone()))?)
                 }
+                None if self.has_values(id)? => {
+                    // The aggregate exists, but its data is incomplete, e.g. after a partial
+                    // restore. This should not be mistaken for an aggregate which does not exist.
+                    error!(
+                        "Found values for '{}', but no snapshot or init event to rebuild it from",
+                        id
+                    );
+                    return Err(AggregateStoreError::InitEventMissing(id.clone()));
+                }
                 None => None,
             }
         }
@@ -957,6 +2688,8 @@ where
             return Err(AggregateStoreError::ReplayError(id.clone(), limit, start));
         }
 
+        let replay_start = Instant::now();
+
         for version in start..limit + 1 {
             if let Some(e) = self.get_event(id, version)? {
                 if aggregate.version() != version {
@@ -970,16 +2703,135 @@ where
             }
         }
 
+        self.metrics.replayed(replay_start.elapsed());
+
         Ok(())
     }
 
-    /// Saves the latest snapshot - overwrites any previous snapshot.
-    fn store_snapshot<V: Aggregate>(&self, id: &Handle, aggregate: &V) -> Result<(), AggregateStoreError> {
+    /// Checks whether a snapshot can be used to rebuild an aggregate up to the
+    /// limit (event nr), without archiving it if it cannot. If info is given then
+    /// the snapshot is also verified against the hash recorded in it.
+    fn snapshot_status(&self, key: &KeyStoreKey, limit: u64, info: Option<&StoredValueInfo>) -> SnapshotStatus {
+        match self.kv.get::<A>(key) {
+            Err(_) => SnapshotStatus::Corrupt,
+            Ok(Some(agg)) if !Self::snapshot_matches_info(&agg, info) => SnapshotStatus::Corrupt,
+            Ok(None) => SnapshotStatus::Missing,
+            Ok(Some(agg)) => {
+                if limit >= agg.version() - 1 {
+                    SnapshotStatus::Usable(agg.version())
+                } else {
+                    SnapshotStatus::AfterLimit(agg.version())
+                }
+            }
+        }
+    }
+
+    /// Returns the hex encoded SHA-256 hash for the snapshot of an aggregate.
+    ///
+    /// The aggregate is first converted to a json value, which keeps object members
+    /// sorted, so that the hash does not depend on the iteration order of any hash
+    /// maps in the aggregate.
+    fn snapshot_hash<V: Aggregate>(aggregate: &V) -> Result<String, AggregateStoreError> {
+        Self::snapshot_json(aggregate).map(|json| hex::encode(sha256(&json)))
+    }
+
+    /// Returns the aggregate as compact json, in the canonical form which is used
+    /// for its hash and size.
+    fn snapshot_json<V: Aggregate>(aggregate: &V) -> Result<Vec<u8>, AggregateStoreError> {
+        let value = serde_json::to_value(aggregate).map_err(KeyValueError::JsonError)?;
+        Ok(serde_json::to_vec(&value).map_err(KeyValueError::JsonError)?)
+    }
+
+    /// Returns the snapshot json for the aggregate, or an error if it exceeds the
+    /// size limit. See `set_size_limits`.
+    fn checked_snapshot_json<V: Aggregate>(&self, id: &Handle, aggregate: &V) -> Result<Vec<u8>, AggregateStoreError> {
+        let json = Self::snapshot_json(aggregate)?;
+        self.check_size(id, "snapshot", json.len())?;
+        Ok(json)
+    }
+
+    /// Returns an error if the event exceeds the size limit. Events are only
+    /// serialized for this if there is a limit.
+    fn check_event_size<V: Event>(&self, event: &V) -> Result<(), AggregateStoreError> {
+        if self.size_warn == 0 && self.size_max == 0 {
+            return Ok(());
+        }
+        let size = serde_json::to_vec(event).map_err(KeyValueError::JsonError)?.len();
+        self.check_size(event.handle(), "event", size)
+    }
+
+    fn check_size(&self, id: &Handle, what: &str, size: usize) -> Result<(), AggregateStoreError> {
+        if self.size_max > 0 && size > self.size_max {
+            error!(
+                "Refusing to save {} for '{}' of {} bytes, the limit is {} bytes",
+                what, id, size, self.size_max
+            );
+            Err(AggregateStoreError::ValueTooLarge(
+                id.clone(),
+                what.to_string(),
+                size,
+                self.size_max,
+            ))
+        } else {
+            if self.size_warn > 0 && size > self.size_warn {
+                warn!(
+                    "The {} for '{}' is {} bytes, more than the warning threshold of {} bytes",
+                    what, id, size, self.size_warn
+                );
+            }
+            Ok(())
+        }
+    }
+
+    /// Verifies a snapshot against the hash recorded in the info. A snapshot which is
+    /// older than the snapshot recorded in the info is stale, and does not match. If no
+    /// hash was recorded, or if the snapshot is newer - i.e. the info was not saved after
+    /// the snapshot was - then there is nothing to verify and the snapshot is accepted.
+    fn snapshot_matches_info(agg: &A, info: Option<&StoredValueInfo>) -> bool {
+        match info {
+            Some(StoredValueInfo {
+                snapshot_version,
+                snapshot_hash: Some(hash),
+                ..
+            }) => {
+                if agg.version() < *snapshot_version {
+                    false
+                } else if agg.version() == *snapshot_version {
+                    match Self::snapshot_hash(agg) {
+                        Ok(found) => &found == hash,
+                        Err(_) => false,
+                    }
+                } else {
+                    true
+                }
+            }
+            _ => true,
+        }
+    }
+
+    /// Saves the latest snapshot - overwrites any previous snapshot. Returns the
+    /// hash of the saved snapshot, so that it can be recorded in the info.
+    fn store_snapshot<V: Aggregate>(&self, id: &Handle, aggregate: &V) -> Result<String, AggregateStoreError> {
+        let json = self.checked_snapshot_json(id, aggregate)?;
+        self.store_snapshot_json(id, aggregate, &json)
+    }
+
+    /// Saves the latest snapshot, given its json as returned by `snapshot_json`.
+    fn store_snapshot_json<V: Aggregate>(
+        &self,
+        id: &Handle,
+        aggregate: &V,
+        json: &[u8],
+    ) -> Result<String, AggregateStoreError> {
+        self.check_writable()?;
         let snapshot_new = Self::key_for_new_snapshot(id);
         let snapshot_current = Self::key_for_snapshot(id);
         let snapshot_backup = Self::key_for_backup_snapshot(id);
 
-        self.kv.store(&snapshot_new, aggregate)?;
+        // The new snapshot must be complete before the current snapshot is
+        // replaced. A new snapshot left over by a crash is never used, see
+        // `remove_new_snapshot`.
+        self.kv.store_synced(&snapshot_new, aggregate, self.compress)?;
 
         if self.kv.has(&snapshot_backup)? {
             self.kv.drop_key(&snapshot_backup)?;
@@ -988,14 +2840,35 @@ where
             self.kv.move_key(&snapshot_current, &snapshot_backup)?;
         }
         self.kv.move_key(&snapshot_new, &snapshot_current)?;
+        self.metrics.snapshot_written();
+        self.metrics.snapshot_size(id, json.len());
+
+        Ok(hex::encode(sha256(json)))
+    }
 
+    /// Removes a new snapshot left over when replacing the snapshot was
+    /// interrupted. It may be incomplete, and the snapshot or the backup
+    /// snapshot can be used instead, or else the events. Read-only stores
+    /// leave it in place, but do not use it either.
+    ///
+    /// Called when warming up and recovering, with the outer write lock held.
+    fn remove_new_snapshot(&self, id: &Handle) -> Result<(), AggregateStoreError> {
+        let snapshot_new = Self::key_for_new_snapshot(id);
+        if !self.read_only && self.kv.has(&snapshot_new)? {
+            warn!("Removing new snapshot for '{}' left over from an interrupted write", id);
+            self.kv.drop_key(&snapshot_new)?;
+        }
         Ok(())
     }
 
     /// Drop an aggregate, completely. Handle with care!
     pub fn drop_aggregate(&self, id: &Handle) -> Result<(), AggregateStoreError> {
+        self.check_writable()?;
         self.cache_remove(id);
         self.kv.drop_scope(id.as_str())?;
+        for queue in &self.post_save_async_queues {
+            queue.dropped(id);
+        }
         Ok(())
     }
 
@@ -1009,11 +2882,319 @@ where
     }
 
     fn save_info(&self, id: &Handle, info: &StoredValueInfo) -> Result<(), AggregateStoreError> {
+        self.check_writable()?;
         let key = Self::key_for_info(id);
         self.kv.store(&key, info).map_err(AggregateStoreError::KeyStoreError)
     }
 }
 
+//------------ AggregateCache ------------------------------------------------
+
+/// Keeps aggregates in memory, optionally bounded to a maximum number of
+/// entries, in which case the least recently used entries are evicted.
+///
+/// Getting an entry only needs a read lock on the cache: the time of use is
+/// kept as a tick from an atomic counter.
+struct AggregateCache<A> {
+    entries: HashMap<Handle, CacheEntry<A>>,
+    max_entries: usize,
+    clock: AtomicU64,
+}
+
+struct CacheEntry<A> {
+    arc: Arc<A>,
+    last_used: AtomicU64,
+}
+
+impl<A> Default for AggregateCache<A> {
+    fn default() -> Self {
+        AggregateCache {
+            entries: HashMap::new(),
+            max_entries: 0,
+            clock: AtomicU64::new(0),
+        }
+    }
+}
+
+impl<A> AggregateCache<A> {
+    fn set_max_entries(&mut self, max_entries: usize) {
+        self.max_entries = max_entries;
+    }
+
+    fn get(&self, id: &Handle) -> Option<Arc<A>> {
+        self.entries.get(id).map(|entry| {
+            entry.last_used.store(self.tick(), atomic::Ordering::Relaxed);
+            entry.arc.clone()
+        })
+    }
+
+    fn remove(&mut self, id: &Handle) {
+        self.entries.remove(id);
+    }
+
+    /// Inserts or replaces the aggregate, and evicts the least recently used
+    /// other aggregates if the cache is over its limit. The inserted aggregate
+    /// itself is never evicted, as it is the one being used. Returns the number
+    /// of evicted aggregates.
+    fn insert(&mut self, id: &Handle, arc: Arc<A>) -> usize {
+        let last_used = AtomicU64::new(self.tick());
+        self.entries.insert(id.clone(), CacheEntry { arc, last_used });
+
+        let mut evicted = 0;
+        if self.max_entries > 0 {
+            while self.entries.len() > self.max_entries {
+                let lru = self
+                    .entries
+                    .iter()
+                    .filter(|(handle, _)| *handle != id)
+                    .min_by_key(|(_, entry)| entry.last_used.load(atomic::Ordering::Relaxed))
+                    .map(|(handle, _)| handle.clone());
+
+                match lru {
+                    Some(handle) => {
+                        debug!("Evicting '{}' from the aggregate cache", handle);
+                        self.entries.remove(&handle);
+                        evicted += 1;
+                    }
+                    None => break,
+                }
+            }
+        }
+        evicted
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, atomic::Ordering::Relaxed)
+    }
+}
+
+//------------ AggregateIntegrityReport --------------------------------------
+
+/// Describes the outcome of a read-only integrity check of the commands, events
+/// and snapshots stored for an aggregate. See `AggregateStore::check_integrity`.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct AggregateIntegrityReport {
+    pub handle: Handle,
+    pub info: Option<StoredValueInfo>,
+    pub last_good_command: u64,
+    pub last_good_event: u64,
+    pub corrupt_commands: Vec<CommandKey>,
+    pub surplus_commands: Vec<CommandKey>,
+    pub missing_events: Vec<u64>,
+    pub corrupt_events: Vec<u64>,
+    pub surplus_events: Vec<u64>,
+    pub snapshot: SnapshotStatus,
+    pub backup_snapshot: SnapshotStatus,
+    pub recoverable_version: Option<u64>,
+}
+
+impl AggregateIntegrityReport {
+    fn new(handle: Handle) -> Self {
+        AggregateIntegrityReport {
+            handle,
+            info: None,
+            last_good_command: 0,
+            last_good_event: 0,
+            corrupt_commands: vec![],
+            surplus_commands: vec![],
+            missing_events: vec![],
+            corrupt_events: vec![],
+            surplus_events: vec![],
+            snapshot: SnapshotStatus::Missing,
+            backup_snapshot: SnapshotStatus::Missing,
+            recoverable_version: None,
+        }
+    }
+
+    /// Returns true if no issues were found, i.e. nothing would need to be
+    /// archived by a `recover`, and the aggregate can be rebuilt.
+    pub fn is_ok(&self) -> bool {
+        self.corrupt_commands.is_empty()
+            && self.surplus_commands.is_empty()
+            && self.missing_events.is_empty()
+            && self.corrupt_events.is_empty()
+            && self.surplus_events.is_empty()
+            && self.recoverable_version.is_some()
+    }
+}
+
+impl fmt::Display for AggregateIntegrityReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Aggregate: {}", self.handle)?;
+        writeln!(f, "  last good command: {}", self.last_good_command)?;
+        writeln!(f, "  last good event: {}", self.last_good_event)?;
+        for key in &self.corrupt_commands {
+            writeln!(f, "  corrupt command: {}", key)?;
+        }
+        for key in &self.surplus_commands {
+            writeln!(f, "  surplus command: {}", key)?;
+        }
+        for version in &self.missing_events {
+            writeln!(f, "  missing event: {}", version)?;
+        }
+        for version in &self.corrupt_events {
+            writeln!(f, "  corrupt event: {}", version)?;
+        }
+        for version in &self.surplus_events {
+            writeln!(f, "  surplus event: {}", version)?;
+        }
+        writeln!(f, "  snapshot: {}", self.snapshot)?;
+        writeln!(f, "  backup snapshot: {}", self.backup_snapshot)?;
+        match self.recoverable_version {
+            Some(version) => writeln!(f, "  recoverable to version: {}", version),
+            None => writeln!(f, "  cannot be recovered, use backup!!"),
+        }
+    }
+}
+
+//------------ InfoDiscrepancy -----------------------------------------------
+
+/// A difference between the info of an aggregate, and its stored values. See
+/// `AggregateStore::check_info`.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub enum InfoDiscrepancy {
+    InfoMissing,
+    InfoCorrupt,
+    EventsMissing(Vec<u64>),
+    LastEventTooLow { info: u64, found: u64 },
+    LastEventTooHigh { info: u64, found: u64 },
+    LastCommandMismatch { info: u64, found: u64 },
+    SnapshotMissing(u64),
+}
+
+impl fmt::Display for InfoDiscrepancy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InfoDiscrepancy::InfoMissing => write!(f, "info is missing"),
+            InfoDiscrepancy::InfoCorrupt => write!(f, "info cannot be parsed"),
+            InfoDiscrepancy::EventsMissing(versions) => {
+                let versions: Vec<String> = versions.iter().map(|v| v.to_string()).collect();
+                write!(f, "missing event(s) {}", versions.join(", "))
+            }
+            InfoDiscrepancy::LastEventTooLow { info, found } => {
+                write!(f, "last event is {}, but events up to {} exist", info, found)
+            }
+            InfoDiscrepancy::LastEventTooHigh { info, found } => {
+                write!(f, "last event is {}, but the last event found is {}", info, found)
+            }
+            InfoDiscrepancy::LastCommandMismatch { info, found } => {
+                write!(f, "last command is {}, but the last command found is {}", info, found)
+            }
+            InfoDiscrepancy::SnapshotMissing(version) => {
+                write!(f, "snapshot is at version {}, but no snapshot exists", version)
+            }
+        }
+    }
+}
+
+//------------ AggregateStoreBackup ------------------------------------------
+
+/// Describes what `AggregateStore::backup` copied.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct AggregateStoreBackup {
+    pub aggregates: usize,
+    pub commands: usize,
+    pub events: usize,
+    pub missing: usize,
+}
+
+//------------ RecoveryPlan --------------------------------------------------
+
+/// Describes what `AggregateStore::recover` did, or would do in a dry run, for
+/// an aggregate: which commands and events are archived as surplus, and up to
+/// which command and event the aggregate is recovered.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct RecoveryPlan {
+    pub handle: Handle,
+    pub last_command: u64,
+    pub last_event: u64,
+    pub surplus_commands: Vec<CommandKey>,
+    pub surplus_events: Vec<u64>,
+    pub snapshot: SnapshotStatus,
+    pub backup_snapshot: SnapshotStatus,
+}
+
+impl RecoveryPlan {
+    fn new(handle: Handle) -> Self {
+        RecoveryPlan {
+            handle,
+            last_command: 0,
+            last_event: 0,
+            surplus_commands: vec![],
+            surplus_events: vec![],
+            snapshot: SnapshotStatus::Missing,
+            backup_snapshot: SnapshotStatus::Missing,
+        }
+    }
+}
+
+impl fmt::Display for RecoveryPlan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "recover '{}' to command {} and event {}",
+            self.handle, self.last_command, self.last_event
+        )?;
+        for key in &self.surplus_commands {
+            write!(f, ", archive surplus command {}", key)?;
+        }
+        for version in &self.surplus_events {
+            write!(f, ", archive surplus event {}", version)?;
+        }
+        write!(
+            f,
+            ", snapshot {}, backup snapshot {}",
+            self.snapshot, self.backup_snapshot
+        )
+    }
+}
+
+//------------ AggregateExport -----------------------------------------------
+
+/// A self-describing export of a single aggregate, see
+/// `AggregateStore::export_aggregate`.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct AggregateExport<A: Aggregate> {
+    pub version: u32,
+    pub handle: Handle,
+    pub init: A::InitEvent,
+    pub events: Vec<A::Event>,
+    pub commands: Vec<StoredCommand<A::StorableCommandDetails>>,
+    /// Hex encoded SHA-256 hash of the aggregate after all events, so that
+    /// imports can verify that the events replay to the same state.
+    pub snapshot_hash: String,
+}
+
+impl<A: Aggregate> AggregateExport<A> {
+    /// The version of the export format.
+    pub const VERSION: u32 = 1;
+}
+
+//------------ SnapshotStatus ------------------------------------------------
+
+/// The status of a (backup) snapshot as found by an integrity check.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case", tag = "status", content = "version")]
+pub enum SnapshotStatus {
+    Missing,
+    Corrupt,
+    Usable(u64),
+    AfterLimit(u64),
+}
+
+impl fmt::Display for SnapshotStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotStatus::Missing => write!(f, "missing"),
+            SnapshotStatus::Corrupt => write!(f, "corrupt"),
+            SnapshotStatus::Usable(version) => write!(f, "usable at version {}", version),
+            SnapshotStatus::AfterLimit(version) => {
+                write!(f, "at version {}, which is after the last good event", version)
+            }
+        }
+    }
+}
+
 //------------ AggregateStoreError -------------------------------------------
 
 /// This type defines possible Errors for the AggregateStore
@@ -1034,9 +3215,18 @@ pub enum AggregateStoreError {
     WarmupFailed(Handle, String),
     CouldNotRecover(Handle),
     CouldNotArchive(Handle, String),
+    CouldNotMigrate(Handle, String),
+    ReadOnly,
     CommandCorrupt(Handle, CommandKey),
     CommandNotFound(Handle, CommandKey),
     EventCorrupt(Handle, u64),
+    UnknownVersion(Handle, u64, u64),
+    AggregateExists(Handle),
+    InvalidExport(Handle, String),
+    Compacted(Handle, u64),
+    AuditLogCorrupt(usize, String),
+    InitEventMissing(Handle),
+    ValueTooLarge(Handle, String, usize, usize),
 }
 
 impl fmt::Display for AggregateStoreError {
@@ -1087,6 +3277,10 @@ impl fmt::Display for AggregateStoreError {
                 "Could not archive commands and events for '{}'. Error: {}",
                 handle, e
             ),
+            AggregateStoreError::CouldNotMigrate(handle, e) => {
+                write!(f, "Could not migrate stored values for '{}'. Error: {}", handle, e)
+            }
+            AggregateStoreError::ReadOnly => write!(f, "This aggregate store is read-only"),
             AggregateStoreError::CommandCorrupt(handle, key) => {
                 write!(f, "StoredCommand '{}' for '{}' was corrupt", handle, key)
             }
@@ -1096,6 +3290,31 @@ impl fmt::Display for AggregateStoreError {
             AggregateStoreError::EventCorrupt(handle, version) => {
                 write!(f, "Stored event '{}' for '{}' was corrupt", handle, version)
             }
+            AggregateStoreError::UnknownVersion(handle, version, last_event) => write!(
+                f,
+                "Cannot get '{}' at version '{}', its last event is '{}'",
+                handle, version, last_event
+            ),
+            AggregateStoreError::AggregateExists(handle) => write!(f, "'{}' already exists", handle),
+            AggregateStoreError::InvalidExport(handle, e) => write!(f, "Invalid export for '{}': {}", handle, e),
+            AggregateStoreError::Compacted(handle, baseline) => write!(
+                f,
+                "'{}' was compacted, events before version '{}' are no longer available",
+                handle, baseline
+            ),
+            AggregateStoreError::InitEventMissing(handle) => write!(
+                f,
+                "Data for '{}' exists, but without a snapshot or init event to rebuild it, restore from backup",
+                handle
+            ),
+            AggregateStoreError::AuditLogCorrupt(line, e) => {
+                write!(f, "Audit log is corrupt or was modified at line {}: {}", line, e)
+            }
+            AggregateStoreError::ValueTooLarge(handle, what, size, max) => write!(
+                f,
+                "The {} for '{}' is {} bytes, which exceeds the limit of {} bytes",
+                what, handle, size, max
+            ),
         }
     }
 }
@@ -1124,4 +3343,67 @@ mod tests {
 
         assert_eq!(key, key_with_dot_json);
     }
+
+    #[test]
+    fn command_key_with_delimiter_in_label() {
+        for label in &[
+            "cmd-ca-publish",
+            "cmd--ca--publish",
+            "--cmd",
+            "cmd--",
+            "command--1--2--cmd",
+            "",
+        ] {
+            let key = CommandKey::new(87, Time::now(), label.to_string());
+            let parsed = CommandKey::from_str(&key.to_string()).unwrap();
+            assert_eq!(key, parsed);
+
+            let parsed = CommandKey::from_str(&format!("{}.json", key)).unwrap();
+            assert_eq!(key, parsed);
+        }
+
+        assert!(CommandKey::from_str("command--1576389600--87").is_err());
+        assert!(CommandKey::from_str("command--1576389600--cmd--87").is_err());
+    }
+
+    #[test]
+    fn command_keys_are_ordered_by_sequence() {
+        // The clock went back between commands 2 and 3
+        let mut keys: Vec<CommandKey> = vec![
+            "command--1576389700--3--cmd-ca-publish",
+            "command--1576389800--2--cmd-ca-publish",
+            "command--1576389600--1--cmd-ca-publish",
+            "command--1576389500--4--cmd-ca-publish",
+        ]
+        .into_iter()
+        .map(|s| CommandKey::from_str(s).unwrap())
+        .collect();
+
+        keys.sort();
+
+        let sequences: Vec<u64> = keys.iter().map(|k| k.sequence).collect();
+        assert_eq!(sequences, vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn archived_events_are_merged() {
+        let mut info = StoredValueInfo::default();
+        for version in &[3, 1, 2, 7, 5, 2] {
+            info.add_archived_event(*version);
+        }
+        assert_eq!(info.archived_events, vec![(1, 3), (5, 5), (7, 7)]);
+
+        info.add_archived_event(6);
+        assert_eq!(info.archived_events, vec![(1, 3), (5, 7)]);
+
+        assert!(info.is_kept_event(0));
+        assert!(!info.is_kept_event(2));
+        assert!(info.is_kept_event(4));
+        assert!(!info.is_kept_event(6));
+        assert!(info.is_kept_event(8));
+
+        info.compaction_baseline = Some(5);
+        info.add_archived_event(4);
+        assert_eq!(info.archived_events, vec![(1, 3), (5, 7)]);
+    }
 }
@@ -1,8 +1,10 @@
 //! Support for signing things using software keys (through openssl) and
 //! storing them unencrypted on disk.
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::sync::Arc;
 use std::{fmt, fs};
 
@@ -18,13 +20,25 @@ use rpki::crypto::signer::KeyError;
 use rpki::crypto::{KeyIdentifier, PublicKey, PublicKeyFormat, Signature, SignatureAlgorithm, Signer, SigningError};
 
 use crate::commons::error::KrillIoError;
+use crate::commons::util::file;
+
+const KEY_MAP_FILE: &str = "key_map.json";
 
 //------------ OpenSslSigner -------------------------------------------------
 
 /// An openssl based signer.
+///
+/// Keys are stored as files named by their key identifier. By default all
+/// keys live directly in the keys dir. If sharding is enabled then new keys
+/// are stored in sub-directories named after the first two pairs of hex
+/// characters of the key identifier, e.g. "keys/ab/cd/abcd..". Keys are
+/// always looked up in both locations, so existing keys can still be used
+/// after sharding is enabled or disabled.
 #[derive(Clone, Debug)]
 pub struct OpenSslSigner {
     keys_dir: Arc<Path>,
+    sharded: bool,
+    key_map: KeyMap,
 }
 
 impl OpenSslSigner {
@@ -36,56 +50,349 @@ impl OpenSslSigner {
             )
         })?;
         if meta_data.is_dir() {
-            let mut keys_dir = work_dir.to_path_buf();
-            keys_dir.push("keys");
-            if !keys_dir.is_dir() {
-                fs::create_dir_all(&keys_dir).map_err(|e| {
-                    KrillIoError::new(
-                        format!(
-                            "Could not create dir(s) '{}' for key storage",
-                            keys_dir.to_string_lossy()
-                        ),
-                        e,
-                    )
-                })?;
+            Self::build_with_keys_dir(&work_dir.join("keys"))
+        } else {
+            Err(SignerError::InvalidWorkDir(work_dir.to_path_buf()))
+        }
+    }
+
+    /// Builds a signer which keeps its keys and key map in the given dir,
+    /// rather than in "keys" under the work dir. The dir is created, only
+    /// accessible by the current user, if it does not exist.
+    pub fn build_with_keys_dir(keys_dir: &Path) -> Result<Self, SignerError> {
+        if !keys_dir.is_dir() {
+            Self::create_keys_dir(keys_dir).map_err(|e| {
+                KrillIoError::new(
+                    format!(
+                        "Could not create dir(s) '{}' for key storage",
+                        keys_dir.to_string_lossy()
+                    ),
+                    e,
+                )
+            })?;
+        } else {
+            Self::check_keys_dir_permissions(keys_dir);
+        }
+
+        let key_map = Self::load_key_map(keys_dir)?;
+
+        Ok(OpenSslSigner {
+            keys_dir: keys_dir.into(),
+            sharded: false,
+            key_map,
+        })
+    }
+
+    #[cfg(unix)]
+    fn create_keys_dir(keys_dir: &Path) -> std::io::Result<()> {
+        use std::os::unix::fs::DirBuilderExt;
+        fs::DirBuilder::new().recursive(true).mode(0o700).create(keys_dir)
+    }
+
+    #[cfg(not(unix))]
+    fn create_keys_dir(keys_dir: &Path) -> std::io::Result<()> {
+        fs::create_dir_all(keys_dir)
+    }
+
+    /// Warns if other users may access the private keys in the dir.
+    #[cfg(unix)]
+    fn check_keys_dir_permissions(keys_dir: &Path) {
+        use std::os::unix::fs::PermissionsExt;
+        match fs::metadata(keys_dir) {
+            Ok(meta) => {
+                if meta.permissions().mode() & 0o007 != 0 {
+                    warn!(
+                        "Keys dir '{}' is accessible by other users, consider: chmod o-rwx {}",
+                        keys_dir.to_string_lossy(),
+                        keys_dir.to_string_lossy()
+                    );
+                }
             }
+            Err(e) => warn!(
+                "Could not check the permissions of keys dir '{}': {}",
+                keys_dir.to_string_lossy(),
+                e
+            ),
+        }
+    }
 
-            Ok(OpenSslSigner {
-                keys_dir: keys_dir.into(),
-            })
+    #[cfg(not(unix))]
+    fn check_keys_dir_permissions(_keys_dir: &Path) {}
+
+    fn load_key_map(keys_dir: &Path) -> Result<KeyMap, SignerError> {
+        let key_map_path = keys_dir.join(KEY_MAP_FILE);
+        if key_map_path.exists() {
+            Ok(file::load_json(&key_map_path)?)
         } else {
-            Err(SignerError::InvalidWorkDir(work_dir.to_path_buf()))
+            Ok(KeyMap::default())
+        }
+    }
+
+    /// Opens the keys dir again as a new signer with the same settings, e.g.
+    /// after the keys dir was restored from a backup while Krill was running.
+    /// The dir is created and its permissions are checked as when Krill starts,
+    /// the key map is read again, and keys in the flat layout are moved to the
+    /// sharded layout if that is used.
+    pub fn reopen(&self) -> Result<Self, SignerError> {
+        let mut signer = Self::build_with_keys_dir(&self.keys_dir)?;
+        signer.set_sharded(self.sharded);
+        if self.sharded {
+            signer.migrate_to_sharded()?;
+        }
+        Ok(signer)
+    }
+
+    /// Sets whether new keys are stored in the sharded layout.
+    pub fn set_sharded(&mut self, sharded: bool) {
+        self.sharded = sharded;
+    }
+
+    /// Remembers that the key was created for the given context, e.g. a CA.
+    pub fn set_key_context(&mut self, key_id: &KeyIdentifier, context: &str) -> Result<(), SignerError> {
+        self.key_map.add(context, *key_id);
+        self.save_key_map()
+    }
+
+    /// Destroys all keys which were created for the given context and
+    /// returns their identifiers. Keys which no longer exist are skipped.
+    pub fn destroy_context_keys(&mut self, context: &str) -> Result<Vec<KeyIdentifier>, SignerError> {
+        let key_ids = self.key_map.keys(context);
+
+        for key_id in &key_ids {
+            if let Some(path) = self.find_key_path(key_id) {
+                Self::remove_key_file(&path)?;
+            }
+            info!("Destroyed key '{}' for context '{}'", key_id, context);
+        }
+
+        self.key_map.remove_context(context);
+        self.save_key_map()?;
+
+        Ok(key_ids)
+    }
+
+    fn save_key_map(&self) -> Result<(), SignerError> {
+        file::save_json(&self.key_map, &self.keys_dir.join(KEY_MAP_FILE))?;
+        Ok(())
+    }
+
+    /// Moves all keys which are stored directly in the keys dir into the
+    /// sharded layout. This is safe to run more than once, keys which are
+    /// already sharded are left alone. Returns the number of keys moved.
+    pub fn migrate_to_sharded(&self) -> Result<usize, SignerError> {
+        let entries = fs::read_dir(&self.keys_dir).map_err(|e| {
+            KrillIoError::new(
+                format!("Could not read keys dir '{}'", self.keys_dir.to_string_lossy()),
+                e,
+            )
+        })?;
+
+        let mut moved = 0;
+        for entry in entries {
+            let entry = entry.map_err(|e| {
+                KrillIoError::new(
+                    format!("Could not read entry in keys dir '{}'", self.keys_dir.to_string_lossy()),
+                    e,
+                )
+            })?;
+            let from = entry.path();
+            if !from.is_file() {
+                continue;
+            }
+
+            let key_id = match entry
+                .file_name()
+                .to_str()
+                .and_then(|name| KeyIdentifier::from_str(name).ok())
+            {
+                Some(key_id) => key_id,
+                None => continue,
+            };
+
+            let to = self.sharded_key_path(&key_id);
+            Self::create_parent_dir(&to)?;
+            fs::rename(&from, &to).map_err(|e| {
+                KrillIoError::new(
+                    format!(
+                        "Could not move key file '{}' to '{}'",
+                        from.to_string_lossy(),
+                        to.to_string_lossy()
+                    ),
+                    e,
+                )
+            })?;
+            moved += 1;
+        }
+
+        if moved > 0 {
+            info!("Moved {} key(s) into the sharded keys dir layout", moved);
         }
+
+        Ok(moved)
+    }
+
+    /// Returns the identifiers of all keys stored in the keys dir, in either
+    /// layout, in no particular order. Files which are not named after a key
+    /// identifier, like the key map, are ignored.
+    pub fn list_keys(&self) -> Result<Vec<KeyIdentifier>, SignerError> {
+        let mut keys = vec![];
+        Self::collect_keys(&self.keys_dir, 2, &mut keys)?;
+        Ok(keys)
+    }
+
+    fn collect_keys(dir: &Path, depth: usize, keys: &mut Vec<KeyIdentifier>) -> Result<(), SignerError> {
+        let read_error = |e| KrillIoError::new(format!("Could not read keys dir '{}'", dir.to_string_lossy()), e);
+
+        for entry in fs::read_dir(dir).map_err(read_error)? {
+            let entry = entry.map_err(read_error)?;
+            let path = entry.path();
+            if path.is_dir() {
+                // Sharded keys are two levels down
+                if depth > 0 {
+                    Self::collect_keys(&path, depth - 1, keys)?;
+                }
+            } else if let Some(key_id) = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| KeyIdentifier::from_str(name).ok())
+            {
+                keys.push(key_id);
+            }
+        }
+
+        Ok(())
     }
 }
 
 impl OpenSslSigner {
-    fn sign_with_key<D: AsRef<[u8]> + ?Sized>(pkey: &PKeyRef<Private>, data: &D) -> Result<Signature, SignerError> {
-        let mut signer = ::openssl::sign::Signer::new(MessageDigest::sha256(), pkey)?;
+    /// Signs all data with the given keys, and returns the signatures in the
+    /// same order. Each key is loaded from disk only once, however often it
+    /// is used in the batch.
+    pub fn sign_batch<D: AsRef<[u8]>>(
+        &self,
+        batch: &[(KeyIdentifier, D)],
+        algorithm: SignatureAlgorithm,
+    ) -> Result<Vec<Signature>, SigningError<SignerError>> {
+        let mut keys: HashMap<KeyIdentifier, OpenSslKeyPair> = HashMap::new();
+        let mut signatures = Vec::with_capacity(batch.len());
+
+        for (key_id, data) in batch {
+            if !keys.contains_key(key_id) {
+                keys.insert(*key_id, self.load_key(key_id)?);
+            }
+            let signature = Self::sign_with_key(keys[key_id].pkey.as_ref(), algorithm, data);
+            signatures.push(signature.map_err(SigningError::Signer)?);
+        }
+
+        Ok(signatures)
+    }
+
+    fn sign_with_key<D: AsRef<[u8]> + ?Sized>(
+        pkey: &PKeyRef<Private>,
+        algorithm: SignatureAlgorithm,
+        data: &D,
+    ) -> Result<Signature, SignerError> {
+        let mut signer = ::openssl::sign::Signer::new(Self::message_digest(algorithm)?, pkey)?;
         signer.update(data.as_ref())?;
 
-        let signature = Signature::new(SignatureAlgorithm::default(), Bytes::from(signer.sign_to_vec()?));
+        let signature = Signature::new(algorithm, Bytes::from(signer.sign_to_vec()?));
 
         Ok(signature)
     }
 
+    /// Returns the digest to use for the given signature algorithm. Fails for
+    /// algorithms which are not supported, rather than substituting another.
+    fn message_digest(algorithm: SignatureAlgorithm) -> Result<MessageDigest, SignerError> {
+        if algorithm == SignatureAlgorithm::default() {
+            // RSA PKCS#1 v1.5 with SHA-256, as required by RFC 7935
+            Ok(MessageDigest::sha256())
+        } else {
+            Err(SignerError::UnsupportedAlgorithm(format!("{:?}", algorithm)))
+        }
+    }
+
     fn load_key(&self, id: &KeyIdentifier) -> Result<OpenSslKeyPair, SignerError> {
-        let path = self.key_path(id);
-        if path.exists() {
+        if let Some(path) = self.find_key_path(id) {
             let f = File::open(&path)
                 .map_err(|e| KrillIoError::new(format!("Could not read key file '{}'", path.to_string_lossy()), e))?;
             let kp: OpenSslKeyPair = serde_json::from_reader(f)?;
+
+            // Make sure that the file really holds the requested key, e.g. it
+            // was not mixed up when keys were restored from a backup.
+            let found = kp.subject_public_key_info()?.key_identifier();
+            if &found != id {
+                return Err(SignerError::KeyMismatch(*id, found));
+            }
+
             Ok(kp)
         } else {
             Err(SignerError::KeyNotFound)
         }
     }
 
+    /// Returns the path where a new key should be stored.
     fn key_path(&self, key_id: &KeyIdentifier) -> PathBuf {
+        if self.sharded {
+            self.sharded_key_path(key_id)
+        } else {
+            self.flat_key_path(key_id)
+        }
+    }
+
+    /// Returns the path of an existing key, preferring the current layout.
+    fn find_key_path(&self, key_id: &KeyIdentifier) -> Option<PathBuf> {
+        let (preferred, other) = if self.sharded {
+            (self.sharded_key_path(key_id), self.flat_key_path(key_id))
+        } else {
+            (self.flat_key_path(key_id), self.sharded_key_path(key_id))
+        };
+
+        if preferred.exists() {
+            Some(preferred)
+        } else if other.exists() {
+            Some(other)
+        } else {
+            None
+        }
+    }
+
+    fn flat_key_path(&self, key_id: &KeyIdentifier) -> PathBuf {
         let mut path = self.keys_dir.to_path_buf();
         path.push(&key_id.to_string());
         path
     }
+
+    fn sharded_key_path(&self, key_id: &KeyIdentifier) -> PathBuf {
+        let name = key_id.to_string();
+        let mut path = self.keys_dir.to_path_buf();
+        path.push(&name[0..2]);
+        path.push(&name[2..4]);
+        path.push(&name);
+        path
+    }
+
+    fn remove_key_file(path: &Path) -> Result<(), SignerError> {
+        fs::remove_file(path).map_err(|e| {
+            SignerError::IoError(KrillIoError::new(
+                format!("Could not remove key file '{}'", path.to_string_lossy()),
+                e,
+            ))
+        })
+    }
+
+    fn create_parent_dir(path: &Path) -> Result<(), SignerError> {
+        if let Some(parent) = path.parent() {
+            if !parent.is_dir() {
+                fs::create_dir_all(parent).map_err(|e| {
+                    KrillIoError::new(
+                        format!("Could not create dir(s) '{}' for key storage", parent.to_string_lossy()),
+                        e,
+                    )
+                })?;
+            }
+        }
+        Ok(())
+    }
 }
 
 impl Signer for OpenSslSigner {
@@ -99,6 +406,7 @@ impl Signer for OpenSslSigner {
         let key_id = pk.key_identifier();
 
         let path = self.key_path(&key_id);
+        Self::create_parent_dir(&path)?;
         let json = serde_json::to_string(&kp)?;
 
         let mut f = File::create(&path)
@@ -115,14 +423,11 @@ impl Signer for OpenSslSigner {
     }
 
     fn destroy_key(&mut self, key_id: &Self::KeyId) -> Result<(), KeyError<Self::Error>> {
-        let path = self.key_path(key_id);
-        if path.exists() {
-            fs::remove_file(&path).map_err(|e| {
-                SignerError::IoError(KrillIoError::new(
-                    format!("Could not remove key file '{}'", path.to_string_lossy()),
-                    e,
-                ))
-            })?;
+        if let Some(path) = self.find_key_path(key_id) {
+            Self::remove_key_file(&path)?;
+        }
+        if self.key_map.remove_key(key_id) {
+            self.save_key_map()?;
         }
         Ok(())
     }
@@ -130,21 +435,21 @@ impl Signer for OpenSslSigner {
     fn sign<D: AsRef<[u8]> + ?Sized>(
         &self,
         key_id: &Self::KeyId,
-        _algorithm: SignatureAlgorithm,
+        algorithm: SignatureAlgorithm,
         data: &D,
     ) -> Result<Signature, SigningError<Self::Error>> {
         let key_pair = self.load_key(key_id)?;
-        Self::sign_with_key(key_pair.pkey.as_ref(), data).map_err(SigningError::Signer)
+        Self::sign_with_key(key_pair.pkey.as_ref(), algorithm, data).map_err(SigningError::Signer)
     }
 
     fn sign_one_off<D: AsRef<[u8]> + ?Sized>(
         &self,
-        _algorithm: SignatureAlgorithm,
+        algorithm: SignatureAlgorithm,
         data: &D,
     ) -> Result<(Signature, PublicKey), SignerError> {
         let kp = OpenSslKeyPair::build()?;
 
-        let signature = Self::sign_with_key(kp.pkey.as_ref(), data)?;
+        let signature = Self::sign_with_key(kp.pkey.as_ref(), algorithm, data)?;
 
         let key = kp.subject_public_key_info()?;
 
@@ -156,6 +461,44 @@ impl Signer for OpenSslSigner {
     }
 }
 
+//------------ KeyMap --------------------------------------------------------
+
+/// Keeps track of the context, e.g. the CA, that keys were created for, so
+/// that all keys for a context can be destroyed when it is removed.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+struct KeyMap {
+    contexts: HashMap<String, Vec<KeyIdentifier>>,
+}
+
+impl KeyMap {
+    fn add(&mut self, context: &str, key_id: KeyIdentifier) {
+        let keys = self.contexts.entry(context.to_string()).or_default();
+        if !keys.contains(&key_id) {
+            keys.push(key_id);
+        }
+    }
+
+    fn keys(&self, context: &str) -> Vec<KeyIdentifier> {
+        self.contexts.get(context).cloned().unwrap_or_default()
+    }
+
+    fn remove_context(&mut self, context: &str) {
+        self.contexts.remove(context);
+    }
+
+    /// Removes the key from any context, returns true if it was found.
+    fn remove_key(&mut self, key_id: &KeyIdentifier) -> bool {
+        let mut found = false;
+        for keys in self.contexts.values_mut() {
+            let before = keys.len();
+            keys.retain(|k| k != key_id);
+            found |= keys.len() != before;
+        }
+        self.contexts.retain(|_, keys| !keys.is_empty());
+        found
+    }
+}
+
 //------------ OpenSslKeyPair ------------------------------------------------
 
 /// An openssl based RSA key pair
@@ -219,6 +562,8 @@ pub enum SignerError {
     IoError(KrillIoError),
     KeyNotFound,
     DecodeError,
+    UnsupportedAlgorithm(String),
+    KeyMismatch(KeyIdentifier, KeyIdentifier),
 }
 
 impl fmt::Display for SignerError {
@@ -230,6 +575,10 @@ impl fmt::Display for SignerError {
             SignerError::IoError(e) => e.fmt(f),
             SignerError::KeyNotFound => write!(f, "Could not find key"),
             SignerError::DecodeError => write!(f, "Could not decode key"),
+            SignerError::UnsupportedAlgorithm(alg) => write!(f, "Unsupported signature algorithm: {}", alg),
+            SignerError::KeyMismatch(requested, found) => {
+                write!(f, "Key file for key '{}' holds a different key '{}'", requested, found)
+            }
         }
     }
 }
@@ -270,6 +619,170 @@ pub mod tests {
         })
     }
 
+    #[test]
+    fn should_sign_batch_in_order() {
+        test::test_under_tmp(|d| {
+            let mut s = OpenSslSigner::build(&d).unwrap();
+            let ki_1 = s.create_key(PublicKeyFormat::Rsa).unwrap();
+            let ki_2 = s.create_key(PublicKeyFormat::Rsa).unwrap();
+
+            let batch: Vec<(KeyIdentifier, &[u8])> = vec![(ki_1, b"one"), (ki_2, b"two"), (ki_1, b"three")];
+            let signatures = s.sign_batch(&batch, SignatureAlgorithm::default()).unwrap();
+            assert_eq!(signatures.len(), 3);
+
+            for ((key_id, data), signature) in batch.iter().zip(signatures.iter()) {
+                let key = s.get_key_info(key_id).unwrap();
+                key.verify(data, signature).unwrap();
+            }
+
+            // The batch fails as a whole if a key is missing
+            s.destroy_key(&ki_2).unwrap();
+            assert!(s.sign_batch(&batch, SignatureAlgorithm::default()).is_err());
+        })
+    }
+
+    #[test]
+    fn should_reject_key_file_with_other_key() {
+        test::test_under_tmp(|d| {
+            let mut s = OpenSslSigner::build(&d).unwrap();
+            let ki_1 = s.create_key(PublicKeyFormat::Rsa).unwrap();
+            let ki_2 = s.create_key(PublicKeyFormat::Rsa).unwrap();
+
+            fs::copy(s.key_path(&ki_2), s.key_path(&ki_1)).unwrap();
+
+            match s.load_key(&ki_1) {
+                Err(SignerError::KeyMismatch(requested, found)) => {
+                    assert_eq!(requested, ki_1);
+                    assert_eq!(found, ki_2);
+                }
+                _ => panic!("Expected key mismatch"),
+            }
+        })
+    }
+
+    #[test]
+    fn should_list_keys_in_both_layouts() {
+        test::test_under_tmp(|d| {
+            let mut s = OpenSslSigner::build(&d).unwrap();
+            let flat = s.create_key(PublicKeyFormat::Rsa).unwrap();
+            s.set_key_context(&flat, "ca").unwrap();
+
+            s.set_sharded(true);
+            let sharded = s.create_key(PublicKeyFormat::Rsa).unwrap();
+
+            let mut keys = s.list_keys().unwrap();
+            keys.sort_by_key(|key| key.to_string());
+            let mut expected = vec![flat, sharded];
+            expected.sort_by_key(|key| key.to_string());
+            assert_eq!(keys, expected);
+
+            s.destroy_key(&flat).unwrap();
+            assert_eq!(s.list_keys().unwrap(), vec![sharded]);
+        })
+    }
+
+    #[test]
+    fn should_find_flat_keys_after_sharding() {
+        test::test_under_tmp(|d| {
+            let mut s = OpenSslSigner::build(&d).unwrap();
+            let flat = s.create_key(PublicKeyFormat::Rsa).unwrap();
+
+            s.set_sharded(true);
+            let sharded = s.create_key(PublicKeyFormat::Rsa).unwrap();
+            assert!(s.sharded_key_path(&sharded).is_file());
+            assert!(!s.flat_key_path(&sharded).exists());
+
+            // Existing flat keys are still found, until they are migrated
+            s.get_key_info(&flat).unwrap();
+            assert_eq!(1, s.migrate_to_sharded().unwrap());
+            assert_eq!(0, s.migrate_to_sharded().unwrap());
+            assert!(s.sharded_key_path(&flat).is_file());
+
+            // Sharded keys are also found when sharding is disabled again
+            s.set_sharded(false);
+            s.get_key_info(&flat).unwrap();
+            s.get_key_info(&sharded).unwrap();
+
+            s.destroy_key(&flat).unwrap();
+            assert!(s.get_key_info(&flat).is_err());
+        })
+    }
+
+    #[test]
+    fn should_destroy_all_keys_for_context() {
+        test::test_under_tmp(|d| {
+            let mut s = OpenSslSigner::build(&d).unwrap();
+            let ca_key_1 = s.create_key(PublicKeyFormat::Rsa).unwrap();
+            let ca_key_2 = s.create_key(PublicKeyFormat::Rsa).unwrap();
+            let other_key = s.create_key(PublicKeyFormat::Rsa).unwrap();
+
+            s.set_key_context(&ca_key_1, "ca").unwrap();
+            s.set_key_context(&ca_key_2, "ca").unwrap();
+            s.set_key_context(&other_key, "other").unwrap();
+
+            // The key map survives a restart
+            let mut s = OpenSslSigner::build(&d).unwrap();
+            let destroyed = s.destroy_context_keys("ca").unwrap();
+            assert_eq!(destroyed, vec![ca_key_1, ca_key_2]);
+
+            assert!(s.get_key_info(&ca_key_1).is_err());
+            assert!(s.get_key_info(&ca_key_2).is_err());
+            s.get_key_info(&other_key).unwrap();
+            assert!(s.destroy_context_keys("ca").unwrap().is_empty());
+        })
+    }
+
+    #[test]
+    fn should_use_separate_keys_dir() {
+        test::test_under_tmp(|d| {
+            let keys_dir = d.join("volume").join("krill-keys");
+            let mut s = OpenSslSigner::build_with_keys_dir(&keys_dir).unwrap();
+            let ki = s.create_key(PublicKeyFormat::Rsa).unwrap();
+            s.set_key_context(&ki, "ca").unwrap();
+
+            assert!(s.key_path(&ki).starts_with(&keys_dir));
+            assert!(keys_dir.join(KEY_MAP_FILE).is_file());
+            assert!(!d.join("keys").exists());
+
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                let mode = fs::metadata(&keys_dir).unwrap().permissions().mode();
+                assert_eq!(mode & 0o777, 0o700);
+            }
+
+            let mut s = OpenSslSigner::build_with_keys_dir(&keys_dir).unwrap();
+            assert_eq!(s.destroy_context_keys("ca").unwrap(), vec![ki]);
+        })
+    }
+
+    #[test]
+    fn should_sign_with_requested_algorithm() {
+        test::test_under_tmp(|d| {
+            let mut s = OpenSslSigner::build(&d).unwrap();
+            let ki = s.create_key(PublicKeyFormat::Rsa).unwrap();
+            let algorithm = SignatureAlgorithm::default();
+            let signature = s.sign(&ki, algorithm, b"data").unwrap();
+            assert_eq!(signature.algorithm(), algorithm);
+        })
+    }
+
+    #[test]
+    fn should_not_keep_one_off_keys() {
+        test::test_under_tmp(|d| {
+            let s = OpenSslSigner::build(&d).unwrap();
+            for _ in 0..3 {
+                s.sign_one_off(SignatureAlgorithm::default(), b"data").unwrap();
+            }
+
+            let stored = fs::read_dir(d.join("keys"))
+                .unwrap()
+                .filter(|entry| entry.as_ref().unwrap().file_name() != KEY_MAP_FILE)
+                .count();
+            assert_eq!(stored, 0);
+        })
+    }
+
     #[test]
     fn should_serialize_and_deserialize_key() {
         let key = OpenSslKeyPair::build().unwrap();
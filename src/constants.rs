@@ -68,15 +68,19 @@ pub const KRILL_CLI_MY_CA_ARG: &str = "ca";
 pub const KRILL_CLI_MY_CA_ENV: &str = "KRILL_CLI_MY_CA";
 
 pub const REQUEUE_DELAY_SECONDS: i64 = 300;
+pub const CA_COMMAND_RETRIES: usize = 3;
 
 pub const KRILL_HTTPS_ROOT_CERTS_ENV: &str = "KRILL_HTTPS_ROOT_CERTS";
 
 pub const ID_CERTIFICATE_VALIDITY_YEARS: i32 = 15;
+pub const SIGN_VALIDITY_BACKDATE_SECS_DFLT: i64 = 300;
 
 pub const BGP_RIS_REFRESH_MINUTES: i64 = 60;
 
 pub const HTTP_CLIENT_TIMEOUT_SECS: u64 = 120;
+pub const HTTP_RETRY_AFTER_SECS: u64 = 30;
 pub const OPENID_CONNECT_HTTP_CLIENT_TIMEOUT_SECS: u64 = 30;
+pub const OPENID_CONNECT_HTTP_CLIENT_CONNECT_TIMEOUT_SECS: u64 = 5;
 
 pub const NO_RESOURCE: NoResourceType = NoResourceType;
 
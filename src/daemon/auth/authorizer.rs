@@ -32,28 +32,50 @@ use crate::{commons::api::Token, daemon::auth::common::permissions::Permission};
 ///  * introspection  - who is the currently "logged in" user?
 pub trait AuthProvider: Send + Sync {
     fn get_bearer_token(&self, request: &hyper::Request<hyper::Body>) -> Option<Token> {
-        if let Some(header) = request.headers().get("Authorization") {
-            if let Ok(header) = header.to_str() {
-                if header.len() > 6 {
-                    let (bearer, token) = header.split_at(6);
-                    let bearer = bearer.trim();
-
-                    if "Bearer" == bearer {
-                        return Some(Token::from(token.trim()));
-                    }
-                }
-            }
-        }
+        let header = request.headers().get(hyper::header::AUTHORIZATION)?;
+        parse_bearer_token(header.to_str().ok()?)
+    }
+
+    fn authenticate(&self, request: &hyper::Request<hyper::Body>) -> KrillResult<Option<ActorDef>>;
 
+    /// Returns the number of seconds until the login session of the request
+    /// expires, for providers that have login sessions which expire.
+    fn session_expires_in_secs(&self, _request: &hyper::Request<hyper::Body>) -> Option<u64> {
         None
     }
 
-    fn authenticate(&self, request: &hyper::Request<hyper::Body>) -> KrillResult<Option<ActorDef>>;
-    fn get_login_url(&self) -> KrillResult<HttpResponse>;
+    /// Returns the URL at which the user should login. The request may select
+    /// provider specific options for the login, e.g. extra OpenID Connect
+    /// parameters.
+    fn get_login_url(&self, request: &hyper::Request<hyper::Body>) -> KrillResult<HttpResponse>;
     fn login(&self, request: &hyper::Request<hyper::Body>) -> KrillResult<LoggedInUser>;
+
+    /// Extends the login session of the request and returns the new token,
+    /// for providers that have login sessions which can be refreshed.
+    fn refresh(&self, _request: &hyper::Request<hyper::Body>) -> KrillResult<LoggedInUser> {
+        Err(Error::ApiInvalidCredentials(
+            "Login session cannot be refreshed".to_string(),
+        ))
+    }
+
     fn logout(&self, request: &hyper::Request<hyper::Body>) -> KrillResult<HttpResponse>;
 }
 
+/// Parses the value of an Authorization header with the "Bearer" scheme. As
+/// per RFC 7235 the scheme is case-insensitive, and it may be followed by any
+/// amount of whitespace.
+pub fn parse_bearer_token(header: &str) -> Option<Token> {
+    let mut parts = header.trim().splitn(2, char::is_whitespace);
+    let scheme = parts.next()?;
+    let token = parts.next()?.trim();
+
+    if scheme.eq_ignore_ascii_case("Bearer") && !token.is_empty() {
+        Some(Token::from(token))
+    } else {
+        None
+    }
+}
+
 /// This type is responsible for checking authorizations when the API is
 /// accessed.
 pub struct Authorizer {
@@ -151,8 +173,8 @@ impl Authorizer {
 
     /// Return the URL at which an end-user should be directed to login with the
     /// configured provider.
-    pub fn get_login_url(&self) -> KrillResult<HttpResponse> {
-        self.primary_provider.get_login_url()
+    pub fn get_login_url(&self, request: &hyper::Request<hyper::Body>) -> KrillResult<HttpResponse> {
+        self.primary_provider.get_login_url(request)
     }
 
     /// Submit credentials directly to the configured provider to establish a
@@ -171,25 +193,15 @@ impl Authorizer {
             return Err(Error::ApiInsufficientRights(reason));
         }
 
-        // Exclude private attributes before passing them to Lagosta to be
-        // shown in the web UI.
-        let visible_attributes = user
-            .attributes
-            .clone()
-            .into_iter()
-            .filter(|(k, _)| !self.private_attributes.contains(k))
-            .collect::<HashMap<_, _>>();
-
-        let filtered_user = LoggedInUser {
-            token: user.token,
-            id: user.id,
-            attributes: visible_attributes,
-        };
+        let filtered_user = self.without_private_attributes(user);
 
         if log_enabled!(log::Level::Trace) {
             trace!("User logged in: {:?}", &filtered_user);
         } else {
-            info!("User logged in: {}", &filtered_user.id);
+            match &filtered_user.display_name {
+                Some(name) => info!("User logged in: {} ({})", name, &filtered_user.id),
+                None => info!("User logged in: {}", &filtered_user.id),
+            }
         }
 
         Ok(filtered_user)
@@ -200,13 +212,76 @@ impl Authorizer {
     pub fn logout(&self, request: &hyper::Request<hyper::Body>) -> KrillResult<HttpResponse> {
         self.primary_provider.logout(request)
     }
+
+    /// Extend the login session of the request with the configured provider,
+    /// if supported by the configured provider.
+    pub fn refresh(&self, request: &hyper::Request<hyper::Body>) -> KrillResult<LoggedInUser> {
+        let user = self.primary_provider.refresh(request)?;
+        debug!("User refreshed login session: {}", &user.id);
+        Ok(self.without_private_attributes(user))
+    }
+
+    // Exclude private attributes before passing them to Lagosta to be
+    // shown in the web UI.
+    fn without_private_attributes(&self, user: LoggedInUser) -> LoggedInUser {
+        let visible_attributes = user
+            .attributes
+            .into_iter()
+            .filter(|(k, _)| !self.private_attributes.contains(k))
+            .collect::<HashMap<_, _>>();
+
+        LoggedInUser {
+            token: user.token,
+            id: user.id,
+            display_name: user.display_name,
+            attributes: visible_attributes,
+        }
+    }
+
+    /// Describe the given actor, which was determined for the given request,
+    /// to the client. Fails for anonymous actors, i.e. if the request could
+    /// not be authenticated.
+    pub fn whoami(&self, actor: &Actor, request: &hyper::Request<hyper::Body>) -> KrillResult<WhoAmI> {
+        if let Some(err) = actor.auth_error() {
+            return Err(Error::from(err.clone()));
+        }
+        if actor.is_anonymous() {
+            return Err(Error::ApiInvalidCredentials("Not logged in".to_string()));
+        }
+
+        let attributes = actor
+            .attributes()
+            .into_iter()
+            .filter(|(k, _)| !self.private_attributes.contains(k))
+            .collect::<HashMap<_, _>>();
+
+        Ok(WhoAmI {
+            id: actor.name().to_string(),
+            is_user: actor.is_user(),
+            attributes,
+            session_expires_in: self.primary_provider.session_expires_in_secs(request),
+        })
+    }
 }
 
 #[derive(Serialize, Debug)]
 pub struct LoggedInUser {
     pub token: Token,
     pub id: String,
+    /// The name to show for the user, if it differs from the id.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    pub attributes: HashMap<String, String>,
+}
+
+/// The current actor, as seen by the client, see `Authorizer::whoami`.
+#[derive(Serialize, Debug)]
+pub struct WhoAmI {
+    pub id: String,
+    pub is_user: bool,
     pub attributes: HashMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_expires_in: Option<u64>,
 }
 
 #[derive(Clone, Debug)]
@@ -241,3 +316,27 @@ impl Auth {
         Auth::IdAndPasswordHash { id, password_hash }
     }
 }
+
+//------------ Tests ---------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn parse_bearer_token_variants() {
+        let token = Some(Token::from("secret"));
+
+        assert_eq!(parse_bearer_token("Bearer secret"), token);
+        assert_eq!(parse_bearer_token("bearer secret"), token);
+        assert_eq!(parse_bearer_token("BEARER \t secret  "), token);
+        assert_eq!(parse_bearer_token("  Bearer  secret"), token);
+
+        assert_eq!(parse_bearer_token("Bearer"), None);
+        assert_eq!(parse_bearer_token("Bearer   "), None);
+        assert_eq!(parse_bearer_token("Basic c2VjcmV0"), None);
+        assert_eq!(parse_bearer_token("Bearersecret"), None);
+        assert_eq!(parse_bearer_token(""), None);
+    }
+}
@@ -11,6 +11,10 @@
 //
 // For much more context see the discussion in Krill issue #382 [4].
 //
+// The key can be rotated: when it is older than the configured maximum age at startup, it is kept as the previous key
+// and a new key is generated. Sessions encrypted with the previous key can still be decrypted during a grace period,
+// after which the previous key is removed and users with such sessions have to login again.
+//
 // 1: https://soatok.blog/2020/07/12/comparison-of-symmetric-encryption-methods/#aes-gcm-vs-chacha20poly1305
 // 2: https://latacora.micro.blog/2018/04/03/cryptographic-right-answers.html
 // 3: https://tools.ietf.org/html/rfc8439#section-4
@@ -20,12 +24,14 @@
 use std::{
     fs::File,
     io::Write,
-    path::Path,
+    path::{Path, PathBuf},
     sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime},
 };
 
 use crate::commons::error::{Error, KrillIoError};
 use crate::commons::KrillResult;
+use crate::daemon::config::Config;
 
 const CHACHA20_KEY_BIT_LEN: usize = 256;
 const CHACHA20_KEY_BYTE_LEN: usize = CHACHA20_KEY_BIT_LEN / 8;
@@ -35,6 +41,7 @@ const POLY1305_TAG_BIT_LEN: usize = 128;
 const POLY1305_TAG_BYTE_LEN: usize = POLY1305_TAG_BIT_LEN / 8;
 const CLEARTEXT_PREFIX_LEN: usize = CHACHA20_NONCE_BYTE_LEN + POLY1305_TAG_BYTE_LEN;
 const UNUSED_AAD: [u8; 0] = [0; 0];
+const PREVIOUS_KEY_EXTENSION: &str = "previous";
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct NonceState {
@@ -69,6 +76,8 @@ impl NonceState {
 
 pub struct CryptState {
     pub key: [u8; CHACHA20_KEY_BYTE_LEN],
+    // The key before the last rotation, only used for decryption
+    pub previous_key: Option<[u8; CHACHA20_KEY_BYTE_LEN]>,
     pub nonce: NonceState,
 }
 
@@ -76,6 +85,7 @@ impl CryptState {
     pub fn from_key_bytes(key: [u8; CHACHA20_KEY_BYTE_LEN]) -> KrillResult<CryptState> {
         Ok(CryptState {
             key,
+            previous_key: None,
             nonce: NonceState::new()?,
         })
     }
@@ -90,6 +100,12 @@ impl CryptState {
 
         Self::from_key_bytes(*boxed_array)
     }
+
+    /// Creates a state with a new random key which is not stored, so anything
+    /// encrypted with it cannot be decrypted after a restart.
+    pub fn ephemeral() -> KrillResult<CryptState> {
+        Self::from_key_bytes(new_key()?)
+    }
 }
 
 // Returns nonce + tag + cipher text, or an error.
@@ -126,24 +142,178 @@ pub(crate) fn decrypt(key: &[u8], payload: &[u8]) -> KrillResult<Vec<u8>> {
         .map_err(|err| Error::Custom(format!("Decryption error: {}", &err)))
 }
 
-pub(crate) fn crypt_init(key_path: &Path) -> KrillResult<CryptState> {
-    if key_path.exists() {
-        let key_bytes =
-            std::fs::read(key_path).map_err(|err| Error::Custom(format!("Unable to load symmetric key: {}", err)))?;
-        CryptState::from_key_vec(key_bytes)
+/// When the session encryption key is replaced by a new key, and for how long
+/// sessions encrypted with the previous key are still accepted.
+#[derive(Clone, Copy, Debug)]
+pub struct KeyRotation {
+    pub max_age: Duration,
+    pub grace: Duration,
+}
+
+impl KeyRotation {
+    pub fn from_config(config: &Config) -> Option<Self> {
+        config.auth_session_key_rotation_days.map(|days| KeyRotation {
+            max_age: Duration::from_secs(u64::from(days) * 24 * 3600),
+            grace: Duration::from_secs(u64::from(config.auth_session_key_grace_hours) * 3600),
+        })
+    }
+}
+
+pub(crate) fn crypt_init(key_path: &Path, rotation: Option<KeyRotation>) -> KrillResult<CryptState> {
+    let previous_path = previous_key_path(key_path);
+
+    let mut state = if key_path.exists() {
+        let key = read_key(key_path)?;
+
+        match rotation {
+            Some(rotation) if file_age(key_path)? >= rotation.max_age => {
+                write_key(&previous_path, &key)?;
+                let new_key = new_key()?;
+                write_key(key_path, &new_key)?;
+                info!(
+                    "Rotated login session encryption key {}, the previous key is accepted for {} hours",
+                    key_path.display(),
+                    rotation.grace.as_secs() / 3600
+                );
+                CryptState::from_key_bytes(new_key)?
+            }
+            _ => CryptState::from_key_bytes(key)?,
+        }
     } else {
-        let mut key_bytes = [0; CHACHA20_KEY_BYTE_LEN];
-        openssl::rand::rand_bytes(&mut key_bytes)
-            .map_err(|err| Error::Custom(format!("Unable to generate symmetric key: {}", err)))?;
-
-        let mut f = File::create(key_path)
-            .map_err(|e| KrillIoError::new(format!("Could not create key file '{}'", key_path.to_string_lossy()), e))?;
-        f.write_all(&key_bytes).map_err(|e| {
-            KrillIoError::new(
-                format!("Could not write to key file '{}'", key_path.to_string_lossy()),
-                e,
-            )
-        })?;
-        Ok(CryptState::from_key_bytes(key_bytes)?)
+        let key = new_key()?;
+        write_key(key_path, &key)?;
+        CryptState::from_key_bytes(key)?
+    };
+
+    if previous_path.exists() {
+        match rotation {
+            Some(rotation) if file_age(&previous_path)? < rotation.grace => {
+                state.previous_key = Some(read_key(&previous_path)?);
+            }
+            _ => {
+                std::fs::remove_file(&previous_path).map_err(|e| {
+                    KrillIoError::new(
+                        format!("Could not remove key file '{}'", previous_path.to_string_lossy()),
+                        e,
+                    )
+                })?;
+                info!(
+                    "Removed previous login session encryption key {}, its grace period has passed",
+                    previous_path.display()
+                );
+            }
+        }
+    }
+
+    Ok(state)
+}
+
+fn previous_key_path(key_path: &Path) -> PathBuf {
+    let mut path = key_path.as_os_str().to_owned();
+    path.push(".");
+    path.push(PREVIOUS_KEY_EXTENSION);
+    PathBuf::from(path)
+}
+
+fn new_key() -> KrillResult<[u8; CHACHA20_KEY_BYTE_LEN]> {
+    let mut key_bytes = [0; CHACHA20_KEY_BYTE_LEN];
+    openssl::rand::rand_bytes(&mut key_bytes)
+        .map_err(|err| Error::Custom(format!("Unable to generate symmetric key: {}", err)))?;
+    Ok(key_bytes)
+}
+
+fn read_key(key_path: &Path) -> KrillResult<[u8; CHACHA20_KEY_BYTE_LEN]> {
+    let key_bytes = std::fs::read(key_path)
+        .map_err(|e| KrillIoError::new(format!("Could not read key file '{}'", key_path.to_string_lossy()), e))?;
+
+    // A truncated or otherwise damaged key file would make all sessions
+    // undecryptable, tell the operator which file is to blame.
+    if key_bytes.len() != CHACHA20_KEY_BYTE_LEN {
+        return Err(Error::SessionKeyCorrupt(
+            key_path.to_string_lossy().to_string(),
+            key_bytes.len(),
+        ));
+    }
+
+    let mut key = [0; CHACHA20_KEY_BYTE_LEN];
+    key.copy_from_slice(&key_bytes);
+    Ok(key)
+}
+
+fn write_key(key_path: &Path, key: &[u8; CHACHA20_KEY_BYTE_LEN]) -> KrillResult<()> {
+    let mut f = File::create(key_path)
+        .map_err(|e| KrillIoError::new(format!("Could not create key file '{}'", key_path.to_string_lossy()), e))?;
+    f.write_all(key).map_err(|e| {
+        KrillIoError::new(
+            format!("Could not write to key file '{}'", key_path.to_string_lossy()),
+            e,
+        )
+    })?;
+    Ok(())
+}
+
+// The key files are written when a key is created, so their modification
+// time tells how old the key is.
+fn file_age(path: &Path) -> KrillResult<Duration> {
+    let modified = std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .map_err(|e| KrillIoError::new(format!("Could not get age of key file '{}'", path.to_string_lossy()), e))?;
+    Ok(SystemTime::now().duration_since(modified).unwrap_or_default())
+}
+
+//------------ Tests ---------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::test;
+
+    #[test]
+    fn corrupt_key_file_is_reported() {
+        test::test_under_tmp(|d| {
+            let key_path = d.join("login_session_state.key");
+
+            let state = crypt_init(&key_path, None).unwrap();
+            assert_eq!(state.key, crypt_init(&key_path, None).unwrap().key);
+
+            std::fs::write(&key_path, &state.key[..10]).unwrap();
+            match crypt_init(&key_path, None) {
+                Err(Error::SessionKeyCorrupt(path, 10)) => assert!(path.ends_with("login_session_state.key")),
+                _ => panic!("Expected corrupt key error"),
+            }
+        })
+    }
+
+    #[test]
+    fn rotated_key_is_accepted_during_grace_period() {
+        test::test_under_tmp(|d| {
+            let key_path = d.join("login_session_state.key");
+            let first = crypt_init(&key_path, None).unwrap().key;
+
+            let rotate_now = KeyRotation {
+                max_age: Duration::from_secs(0),
+                grace: Duration::from_secs(3600),
+            };
+            let state = crypt_init(&key_path, Some(rotate_now)).unwrap();
+            assert_ne!(state.key, first);
+            assert_eq!(state.previous_key, Some(first));
+
+            let payload = encrypt(&first, b"session", &state.nonce).unwrap();
+            assert!(decrypt(&state.key, &payload).is_err());
+            assert_eq!(decrypt(&state.previous_key.unwrap(), &payload).unwrap(), b"session");
+
+            // Without a grace period the previous key is removed, and the
+            // new key is not rotated again before it reaches its max age.
+            let no_grace = KeyRotation {
+                max_age: Duration::from_secs(3600),
+                grace: Duration::from_secs(0),
+            };
+            let second = state.key;
+            let state = crypt_init(&key_path, Some(no_grace)).unwrap();
+            assert_eq!(state.key, second);
+            assert_eq!(state.previous_key, None);
+            assert!(!previous_key_path(&key_path).exists());
+        })
     }
 }
@@ -3,6 +3,9 @@ pub mod crypt;
 
 pub mod permissions;
 
+#[cfg(feature = "multi-user")]
+pub mod roles;
+
 #[derive(Debug, Clone)]
 pub struct NoResourceType;
 impl std::fmt::Display for NoResourceType {
@@ -69,6 +69,7 @@ iterable_enum! {
         ROUTES_ANALYSIS,
         RTA_LIST,
         RTA_READ,
-        RTA_UPDATE
+        RTA_UPDATE,
+        AUTH_ADMIN
     }
 }
@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+
+//------------ RoleHierarchy -------------------------------------------------
+
+/// Defines which roles imply other roles, e.g. that "admin" implies
+/// "readwrite" which in turn implies "readonly".
+///
+/// Actors are granted the permissions of their own role and of all roles that
+/// it implies, directly or indirectly. This way policy rules only need to
+/// mention the lowest role that should have a permission.
+#[derive(Clone, Debug, Default)]
+pub struct RoleHierarchy {
+    implies: HashMap<String, Vec<String>>,
+}
+
+impl RoleHierarchy {
+    pub fn new(implies: HashMap<String, Vec<String>>) -> Self {
+        RoleHierarchy { implies }
+    }
+
+    /// Returns the given role followed by all roles it implies. Each role is
+    /// included only once, so cycles in the configuration are harmless.
+    pub fn expand(&self, role: &str) -> Vec<String> {
+        let mut roles = vec![role.to_string()];
+        let mut next = 0;
+
+        while next < roles.len() {
+            if let Some(implied) = self.implies.get(&roles[next]) {
+                for role in implied {
+                    if !roles.contains(role) {
+                        roles.push(role.clone());
+                    }
+                }
+            }
+            next += 1;
+        }
+
+        roles
+    }
+}
+
+//------------ Tests ---------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+
+    use std::sync::Arc;
+
+    use super::*;
+
+    use crate::commons::actor::{Actor, ActorDef};
+    use crate::constants::NO_RESOURCE;
+    use crate::daemon::auth::common::permissions::Permission;
+    use crate::daemon::auth::policy::AuthPolicy;
+    use crate::daemon::config::Config;
+    use crate::test;
+
+    fn user(role: &str) -> ActorDef {
+        let mut attributes = HashMap::new();
+        attributes.insert("role".to_string(), role.to_string());
+        ActorDef::user(role.to_string(), attributes, None)
+    }
+
+    #[test]
+    fn expand_follows_implied_roles() {
+        let mut implies = HashMap::new();
+        implies.insert("admin".to_string(), vec!["readwrite".to_string()]);
+        implies.insert(
+            "readwrite".to_string(),
+            vec!["readonly".to_string(), "admin".to_string()],
+        );
+        let hierarchy = RoleHierarchy::new(implies);
+
+        assert_eq!(hierarchy.expand("admin"), vec!["admin", "readwrite", "readonly"]);
+        assert_eq!(hierarchy.expand("readonly"), vec!["readonly"]);
+    }
+
+    #[test]
+    fn inherited_roles_are_used_in_policy_checks() {
+        test::test_under_tmp(|d| {
+            let mut config = Config::test(&d, false);
+            config
+                .auth_role_hierarchy
+                .insert("operator".to_string(), vec!["readonly".to_string()]);
+            let policy = AuthPolicy::new(Arc::new(config)).unwrap();
+
+            let admin = Actor::new(user("admin"), policy.clone());
+            assert!(admin.roles().contains(&"readonly".to_string()));
+            assert!(admin.is_allowed(Permission::CA_LIST, NO_RESOURCE).unwrap());
+
+            let operator = Actor::new(user("operator"), policy);
+            assert!(operator.is_allowed(Permission::CA_LIST, NO_RESOURCE).unwrap());
+            assert!(!operator.is_allowed(Permission::CA_CREATE, NO_RESOURCE).unwrap());
+        })
+    }
+}
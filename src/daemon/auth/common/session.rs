@@ -4,6 +4,7 @@ use std::{
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+use crate::commons::actor::Attributes;
 use crate::commons::api::Token;
 use crate::commons::error::Error;
 use crate::commons::KrillResult;
@@ -12,12 +13,30 @@ use super::crypt::{self, CryptState, NonceState};
 
 const MAX_CACHE_SECS: u64 = 30;
 
+// The first byte of a decoded token, i.e. before decryption, is the version of
+// the token format. Increase this when the format of the encrypted session
+// changes, so that tokens in an older format are recognised. Tokens made before
+// the version was added start with the random part of the nonce instead, they
+// are outdated unless that happens to match, in which case decryption fails.
+const SESSION_FORMAT_VERSION: u8 = 1;
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ClientSession {
     pub start_time: u64,
+    // The time of the original login, start_time is reset when the session is
+    // refreshed. Sessions created before this was added use start_time.
+    #[serde(default)]
+    pub login_time: Option<u64>,
     pub expires_in: Option<Duration>,
     pub id: String,
+    // The name to show for the user, if it differs from the id.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
     pub attributes: HashMap<String, String>,
+    // All values of attributes that have more than one value, the first value
+    // is also in 'attributes'.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub attribute_values: HashMap<String, Vec<String>>,
     pub secrets: HashMap<String, String>,
 }
 
@@ -29,6 +48,33 @@ pub enum SessionStatus {
 }
 
 impl ClientSession {
+    /// Returns the number of seconds until this session expires, or None if
+    /// the session does not expire. Expired sessions return 0.
+    pub fn expires_in_secs(&self) -> Option<u64> {
+        let expires_in = self.expires_in?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+        let cur_age_secs = now.saturating_sub(self.start_time);
+        Some(expires_in.as_secs().saturating_sub(cur_age_secs))
+    }
+
+    /// Returns true if more than the given maximum lifetime has passed since
+    /// the user logged in, regardless of how often the session was refreshed.
+    pub fn exceeds_lifetime(&self, max_lifetime: Duration) -> bool {
+        match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(now) => {
+                let login_time = self.login_time.unwrap_or(self.start_time);
+                now.as_secs().saturating_sub(login_time) > max_lifetime.as_secs()
+            }
+            Err(err) => {
+                warn!(
+                    "Login session lifetime check: unable to determine the current time: {}",
+                    err
+                );
+                false
+            }
+        }
+    }
+
     pub fn status(&self) -> SessionStatus {
         if let Some(expires_in) = &self.expires_in {
             match SystemTime::now().duration_since(UNIX_EPOCH) {
@@ -69,6 +115,21 @@ impl ClientSession {
     pub fn get_secret(&self, key: &str) -> Option<&String> {
         self.secrets.get(&key.to_string())
     }
+
+    /// Returns the attributes for the actor of this session.
+    pub fn actor_attributes(&self) -> Attributes {
+        if self.attribute_values.is_empty() {
+            Attributes::UserDefined(self.attributes.clone())
+        } else {
+            let mut values: HashMap<String, Vec<String>> = self
+                .attributes
+                .iter()
+                .map(|(k, v)| (k.clone(), vec![v.clone()]))
+                .collect();
+            values.extend(self.attribute_values.clone());
+            Attributes::MultiValued(values)
+        }
+    }
 }
 
 struct CachedSession {
@@ -76,6 +137,14 @@ struct CachedSession {
     pub session: ClientSession,
 }
 
+/// The times at which an administrator invalidated the sessions of all users,
+/// or of a specific user.
+#[derive(Default)]
+struct Invalidations {
+    all: Option<u64>,
+    users: HashMap<String, u64>,
+}
+
 pub type EncryptFn = fn(&[u8], &[u8], &NonceState) -> KrillResult<Vec<u8>>;
 pub type DecryptFn = fn(&[u8], &[u8]) -> KrillResult<Vec<u8>>;
 
@@ -89,6 +158,7 @@ pub struct LoginSessionCache {
     encrypt_fn: EncryptFn,
     decrypt_fn: DecryptFn,
     ttl_secs: u64,
+    invalidated: RwLock<Invalidations>,
 }
 
 impl Default for LoginSessionCache {
@@ -104,6 +174,7 @@ impl LoginSessionCache {
             encrypt_fn: crypt::encrypt,
             decrypt_fn: crypt::decrypt,
             ttl_secs: MAX_CACHE_SECS,
+            invalidated: RwLock::new(Invalidations::default()),
         }
     }
 
@@ -113,6 +184,7 @@ impl LoginSessionCache {
             encrypt_fn: self.encrypt_fn,
             decrypt_fn: self.decrypt_fn,
             ttl_secs,
+            invalidated: self.invalidated,
         }
     }
 
@@ -122,6 +194,7 @@ impl LoginSessionCache {
             encrypt_fn,
             decrypt_fn: self.decrypt_fn,
             ttl_secs: self.ttl_secs,
+            invalidated: self.invalidated,
         }
     }
 
@@ -131,6 +204,7 @@ impl LoginSessionCache {
             encrypt_fn: self.encrypt_fn,
             decrypt_fn,
             ttl_secs: self.ttl_secs,
+            invalidated: self.invalidated,
         }
     }
 
@@ -180,14 +254,75 @@ impl LoginSessionCache {
         crypt_state: &CryptState,
         expires_in: Option<Duration>,
     ) -> KrillResult<Token> {
+        let now = Self::time_now_secs_since_epoch()?;
         let session = ClientSession {
-            start_time: Self::time_now_secs_since_epoch()?,
+            start_time: now,
+            login_time: Some(now),
             expires_in,
             id: id.to_string(),
+            display_name: None,
             attributes: attributes.clone(),
+            attribute_values: HashMap::new(),
             secrets,
         };
 
+        self.encode_session(session, crypt_state)
+    }
+
+    /// Encodes a new session for a user of which attributes may have more
+    /// than one value, and which may have a display name other than the id.
+    pub fn encode_with_values(
+        &self,
+        id: &str,
+        display_name: Option<&str>,
+        attribute_values: &HashMap<String, Vec<String>>,
+        secrets: HashMap<String, String>,
+        crypt_state: &CryptState,
+        expires_in: Option<Duration>,
+    ) -> KrillResult<Token> {
+        let now = Self::time_now_secs_since_epoch()?;
+        let session = ClientSession {
+            start_time: now,
+            login_time: Some(now),
+            expires_in,
+            id: id.to_string(),
+            display_name: display_name.map(|name| name.to_string()),
+            attributes: Attributes::MultiValued(attribute_values.clone()).as_map(),
+            attribute_values: attribute_values
+                .iter()
+                .filter(|(_, values)| values.len() > 1)
+                .map(|(k, values)| (k.clone(), values.clone()))
+                .collect(),
+            secrets,
+        };
+
+        self.encode_session(session, crypt_state)
+    }
+
+    /// Encodes a refreshed session: the session starts again, but keeps the
+    /// time of the original login.
+    pub fn encode_refreshed(
+        &self,
+        session: &ClientSession,
+        secrets: HashMap<String, String>,
+        crypt_state: &CryptState,
+        expires_in: Option<Duration>,
+    ) -> KrillResult<Token> {
+        let refreshed = ClientSession {
+            start_time: Self::time_now_secs_since_epoch()?,
+            login_time: Some(session.login_time.unwrap_or(session.start_time)),
+            expires_in,
+            id: session.id.clone(),
+            display_name: session.display_name.clone(),
+            attributes: session.attributes.clone(),
+            attribute_values: session.attribute_values.clone(),
+            secrets,
+        };
+
+        self.encode_session(refreshed, crypt_state)
+    }
+
+    fn encode_session(&self, session: ClientSession, crypt_state: &CryptState) -> KrillResult<Token> {
         debug!("Creating token for session: {:?}", &session);
 
         let session_json_str = serde_json::to_string(&session)
@@ -195,7 +330,9 @@ impl LoginSessionCache {
         let unencrypted_bytes = session_json_str.as_bytes();
 
         let encrypted_bytes = (self.encrypt_fn)(&crypt_state.key, unencrypted_bytes, &crypt_state.nonce)?;
-        let token = Token::from(base64::encode(&encrypted_bytes));
+        let mut token_bytes = vec![SESSION_FORMAT_VERSION];
+        token_bytes.extend(encrypted_bytes);
+        let token = Token::from(base64::encode(&token_bytes));
 
         self.cache_session(&token, &session);
         Ok(token)
@@ -204,17 +341,36 @@ impl LoginSessionCache {
     pub fn decode(&self, token: Token, key: &CryptState, add_to_cache: bool) -> KrillResult<ClientSession> {
         if let Some(session) = self.lookup_session(&token) {
             trace!("Session cache hit for session id {}", &session.id);
-            return Ok(session);
+            return self.check_invalidated(session);
         } else {
             trace!("Session cache miss, deserializing...");
         }
 
-        let bytes = base64::decode(token.as_ref().as_bytes()).map_err(|err| {
+        let token_bytes = base64::decode(token.as_ref().as_bytes()).map_err(|err| {
             debug!("Invalid bearer token: cannot decode: {}", err);
             Error::ApiInvalidCredentials("Invalid bearer token".to_string())
         })?;
 
-        let unencrypted_bytes = (self.decrypt_fn)(&key.key, &bytes)?;
+        let bytes = match token_bytes.split_first() {
+            Some((&SESSION_FORMAT_VERSION, bytes)) => bytes,
+            version => {
+                debug!(
+                    "Bearer token has session format version {:?}, expected {}",
+                    version.map(|(version, _)| version),
+                    SESSION_FORMAT_VERSION
+                );
+                return Err(Error::ApiAuthSessionExpired(
+                    "Session format outdated, please login again".to_string(),
+                ));
+            }
+        };
+
+        // Sessions encrypted before the key was rotated are accepted until
+        // the previous key expires.
+        let unencrypted_bytes = (self.decrypt_fn)(&key.key, bytes).or_else(|err| match &key.previous_key {
+            Some(previous_key) => (self.decrypt_fn)(previous_key, bytes).map_err(|_| err),
+            None => Err(err),
+        })?;
 
         let session = serde_json::from_slice::<ClientSession>(&unencrypted_bytes).map_err(|err| {
             debug!("Invalid bearer token: cannot deserialize: {}", err);
@@ -223,6 +379,8 @@ impl LoginSessionCache {
 
         trace!("Session cache miss, deserialized session id {}", &session.id);
 
+        let session = self.check_invalidated(session)?;
+
         if add_to_cache {
             self.cache_session(&token, &session);
         }
@@ -230,6 +388,75 @@ impl LoginSessionCache {
         Ok(session)
     }
 
+    /// Invalidates all sessions of the user with the given id which were
+    /// started by a login up to now. The user has to login again.
+    ///
+    /// Note that invalidations are kept in memory only. Because the session
+    /// tokens remain decryptable, invalidated sessions are accepted again
+    /// after Krill is restarted, unless they expired in the meantime.
+    pub fn invalidate_user(&self, id: &str) -> KrillResult<()> {
+        let now = Self::time_now_secs_since_epoch()?;
+        self.invalidated
+            .write()
+            .map_err(|err| Error::Custom(format!("Unable to invalidate sessions: {}", err)))?
+            .users
+            .insert(id.to_string(), now);
+
+        self.cache
+            .write()
+            .map_err(|err| Error::Custom(format!("Unable to purge session cache: {}", err)))?
+            .retain(|_, v| v.session.id != id);
+
+        info!("Invalidated all login sessions of user '{}'", id);
+        Ok(())
+    }
+
+    /// Invalidates the sessions of all users which were started by a login up
+    /// to now. See `invalidate_user`.
+    pub fn invalidate_all(&self) -> KrillResult<()> {
+        let now = Self::time_now_secs_since_epoch()?;
+        self.invalidated
+            .write()
+            .map_err(|err| Error::Custom(format!("Unable to invalidate sessions: {}", err)))?
+            .all = Some(now);
+
+        self.cache
+            .write()
+            .map_err(|err| Error::Custom(format!("Unable to purge session cache: {}", err)))?
+            .clear();
+
+        info!("Invalidated all login sessions");
+        Ok(())
+    }
+
+    // Sessions are compared by the second, so a session that was started in
+    // the same second as the invalidation is invalidated as well.
+    fn check_invalidated(&self, session: ClientSession) -> KrillResult<ClientSession> {
+        let login_time = session.login_time.unwrap_or(session.start_time);
+
+        let invalidated = match self.invalidated.read() {
+            Ok(invalidated) => {
+                invalidated.all.map(|t| login_time <= t).unwrap_or(false)
+                    || invalidated
+                        .users
+                        .get(&session.id)
+                        .map(|t| login_time <= *t)
+                        .unwrap_or(false)
+            }
+            Err(err) => {
+                warn!("Unable to check for invalidated sessions: {}", err);
+                true
+            }
+        };
+
+        if invalidated {
+            debug!("Rejecting invalidated session for user '{}'", &session.id);
+            Err(Error::ApiInvalidCredentials("Session was invalidated".to_string()))
+        } else {
+            Ok(session)
+        }
+    }
+
     pub fn remove(&self, token: &Token) {
         match self.cache.write() {
             Ok(mut writeable_cache) => {
@@ -345,4 +572,174 @@ mod tests {
         cache.sweep().unwrap();
         assert_eq!(cache.size(), 0);
     }
+
+    #[test]
+    fn invalidated_sessions_are_rejected() {
+        use super::*;
+
+        let key: CryptState = CryptState::from_key_bytes([0; 32]).unwrap();
+        let cache = LoginSessionCache::new()
+            .with_encrypter(|_, v, _| Ok(v.to_vec()))
+            .with_decrypter(|_, v| Ok(v.to_vec()));
+
+        let alice = cache
+            .encode("alice", &HashMap::new(), HashMap::new(), &key, None)
+            .unwrap();
+        let bob = cache
+            .encode("bob", &HashMap::new(), HashMap::new(), &key, None)
+            .unwrap();
+
+        cache.invalidate_user("alice").unwrap();
+        assert_eq!(cache.size(), 1);
+
+        // Also when the session is no longer cached
+        assert!(matches!(
+            cache.decode(alice.clone(), &key, true),
+            Err(Error::ApiInvalidCredentials(_))
+        ));
+        assert!(matches!(
+            cache.decode(alice, &key, false),
+            Err(Error::ApiInvalidCredentials(_))
+        ));
+        assert!(cache.decode(bob.clone(), &key, true).is_ok());
+
+        cache.invalidate_all().unwrap();
+        assert!(matches!(
+            cache.decode(bob, &key, true),
+            Err(Error::ApiInvalidCredentials(_))
+        ));
+    }
+
+    #[test]
+    fn sessions_encrypted_with_previous_key_are_accepted() {
+        use super::*;
+
+        let old_key = CryptState::from_key_bytes([1; 32]).unwrap();
+        let token = LoginSessionCache::new()
+            .encode("alice", &HashMap::new(), HashMap::new(), &old_key, None)
+            .unwrap();
+
+        // Use a new cache, so that the session must be decrypted
+        let cache = LoginSessionCache::new();
+        let mut new_key = CryptState::from_key_bytes([2; 32]).unwrap();
+        assert!(cache.decode(token.clone(), &new_key, false).is_err());
+
+        new_key.previous_key = Some([1; 32]);
+        assert_eq!(cache.decode(token, &new_key, false).unwrap().id, "alice");
+    }
+
+    #[test]
+    fn tokens_in_prior_format_are_outdated() {
+        use super::*;
+
+        let key = CryptState::from_key_bytes([0; 32]).unwrap();
+        let cache = LoginSessionCache::new()
+            .with_encrypter(|_, v, _| Ok(v.to_vec()))
+            .with_decrypter(|_, v| Ok(v.to_vec()));
+
+        let token = cache
+            .encode("alice", &HashMap::new(), HashMap::new(), &key, None)
+            .unwrap();
+        let session = cache.decode(token.clone(), &key, false).unwrap();
+        assert_eq!(session.id, "alice");
+
+        // Tokens made before the format version was added hold only the
+        // encrypted session.
+        let old_token = Token::from(base64::encode(serde_json::to_string(&session).unwrap()));
+
+        // Tokens in a format of another version.
+        let mut bytes = base64::decode(token.as_ref()).unwrap();
+        bytes[0] = SESSION_FORMAT_VERSION + 1;
+        let other_token = Token::from(base64::encode(&bytes));
+
+        for token in vec![old_token, other_token] {
+            match cache.decode(token, &key, false) {
+                Err(Error::ApiAuthSessionExpired(msg)) => assert!(msg.contains("outdated")),
+                other => panic!("Expected outdated session, got: {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn expires_in_secs() {
+        use super::*;
+
+        fn session(age_secs: u64, expires_in: Option<Duration>) -> ClientSession {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+            ClientSession {
+                start_time: now - age_secs,
+                login_time: None,
+                expires_in,
+                id: "user".to_string(),
+                display_name: None,
+                attributes: HashMap::new(),
+                attribute_values: HashMap::new(),
+                secrets: HashMap::new(),
+            }
+        }
+
+        assert_eq!(session(10, None).expires_in_secs(), None);
+
+        let remaining = session(10, Some(Duration::from_secs(60))).expires_in_secs().unwrap();
+        assert!(remaining <= 50 && remaining >= 49);
+
+        assert_eq!(session(100, Some(Duration::from_secs(60))).expires_in_secs(), Some(0));
+    }
+
+    #[test]
+    fn refresh_keeps_login_time() {
+        use super::*;
+
+        let key: CryptState = CryptState::from_key_bytes([0; 32]).unwrap();
+        let cache = LoginSessionCache::new()
+            .with_encrypter(|_, v, _| Ok(v.to_vec()))
+            .with_decrypter(|_, v| Ok(v.to_vec()));
+
+        let token = cache.encode("id", &HashMap::new(), HashMap::new(), &key, None).unwrap();
+        let mut session = cache.decode(token, &key, false).unwrap();
+
+        // Pretend that the user logged in two hours ago
+        let login_time = session.login_time.unwrap() - 7200;
+        session.login_time = Some(login_time);
+
+        let token = cache.encode_refreshed(&session, HashMap::new(), &key, None).unwrap();
+        let refreshed = cache.decode(token, &key, false).unwrap();
+        assert_eq!(refreshed.login_time, Some(login_time));
+
+        assert!(refreshed.exceeds_lifetime(Duration::from_secs(3600)));
+        assert!(!refreshed.exceeds_lifetime(Duration::from_secs(3 * 3600)));
+    }
+
+    #[test]
+    fn multi_valued_attributes() {
+        use super::*;
+
+        let key: CryptState = CryptState::from_key_bytes([0; 32]).unwrap();
+        let cache = LoginSessionCache::new()
+            .with_encrypter(|_, v, _| Ok(v.to_vec()))
+            .with_decrypter(|_, v| Ok(v.to_vec()));
+
+        let mut values = HashMap::new();
+        values.insert("role".to_string(), vec!["admin".to_string()]);
+        values.insert("groups".to_string(), vec!["ops".to_string(), "noc".to_string()]);
+
+        let token = cache
+            .encode_with_values("id", None, &values, HashMap::new(), &key, None)
+            .unwrap();
+        let session = cache.decode(token, &key, false).unwrap();
+
+        // The first value is available as a single value attribute
+        assert_eq!(session.attributes.get("groups"), Some(&"ops".to_string()));
+        assert_eq!(session.attribute_values.len(), 1);
+
+        let attributes = session.actor_attributes();
+        assert_eq!(attributes.value("role"), Some("admin".to_string()));
+        assert_eq!(attributes.values("groups"), vec!["ops", "noc"]);
+        assert!(attributes.values("other").is_empty());
+
+        // Single valued sessions are unchanged
+        let token = cache.encode("id", &HashMap::new(), HashMap::new(), &key, None).unwrap();
+        let session = cache.decode(token, &key, false).unwrap();
+        assert_eq!(session.actor_attributes(), Attributes::UserDefined(HashMap::new()));
+    }
 }
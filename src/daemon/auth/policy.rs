@@ -11,7 +11,7 @@ use crate::{
     },
     constants::{ACTOR_DEF_ADMIN_TOKEN, ACTOR_DEF_ANON, ACTOR_DEF_KRILL, ACTOR_DEF_TESTBED},
     daemon::{
-        auth::common::{permissions::Permission, NoResourceType},
+        auth::common::{permissions::Permission, roles::RoleHierarchy, NoResourceType},
         config::Config,
     },
 };
@@ -19,6 +19,7 @@ use crate::{
 #[derive(Clone)]
 pub struct AuthPolicy {
     oso: Arc<Oso>,
+    role_hierarchy: Arc<RoleHierarchy>,
 }
 
 impl std::ops::Deref for AuthPolicy {
@@ -31,6 +32,8 @@ impl std::ops::Deref for AuthPolicy {
 
 impl AuthPolicy {
     pub fn new(config: Arc<Config>) -> KrillResult<Self> {
+        let role_hierarchy = Arc::new(RoleHierarchy::new(config.auth_role_hierarchy.clone()));
+
         let mut oso = Oso::new();
         oso.register_class(Actor::get_polar_class()).unwrap();
         oso.register_class(Handle::get_polar_class()).unwrap();
@@ -84,7 +87,15 @@ impl AuthPolicy {
         // have the "testbed" role.
         Self::exec_query(&mut oso, r#"actor_has_role(Actor.builtin("testbed"), "testbed")"#)?;
 
-        Ok(AuthPolicy { oso: Arc::new(oso) })
+        Ok(AuthPolicy {
+            oso: Arc::new(oso),
+            role_hierarchy,
+        })
+    }
+
+    /// Returns the given role and all roles it implies.
+    pub fn expand_role(&self, role: &str) -> Vec<String> {
+        self.role_hierarchy.expand(role)
     }
 
     pub fn is_allowed<U, A, R>(&self, actor: U, action: A, resource: R) -> Result<bool, Error>
@@ -183,6 +194,8 @@ impl PolarClass for Actor {
             })
             .add_method("attr", Actor::attribute)
             .add_method("attrs", Actor::attributes)
+            .add_method("attr_values", Actor::attribute_values)
+            .add_method("roles", Actor::roles)
             .build()
     }
 
@@ -46,7 +46,7 @@ impl AuthProvider for AdminTokenAuthProvider {
         res
     }
 
-    fn get_login_url(&self) -> KrillResult<HttpResponse> {
+    fn get_login_url(&self, _request: &hyper::Request<hyper::Body>) -> KrillResult<HttpResponse> {
         // Direct Lagosta to show the user the Lagosta API token login form
         Ok(HttpResponse::text_no_cache(LAGOSTA_LOGIN_ROUTE_PATH.into()))
     }
@@ -56,6 +56,7 @@ impl AuthProvider for AdminTokenAuthProvider {
             Some(actor_def) => Ok(LoggedInUser {
                 token: self.required_token.clone(),
                 id: actor_def.name.as_str().to_string(),
+                display_name: None,
                 attributes: actor_def.attributes.as_map(),
             }),
             None => Err(Error::ApiInvalidCredentials("Missing bearer token".to_string())),
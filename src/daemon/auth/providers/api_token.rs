@@ -0,0 +1,338 @@
+//! Long-lived API tokens for automation.
+//!
+//! API tokens are minted by an administrator and map to a fixed actor name and
+//! attributes (e.g. a role). Only a salted hash of each token is stored, the
+//! plaintext token is returned once when it is minted and cannot be retrieved
+//! afterwards.
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use rpki::x509::Time;
+
+use crate::commons::actor::ActorDef;
+use crate::commons::api::Token;
+use crate::commons::error::Error;
+use crate::commons::util::{file, sha256};
+use crate::commons::KrillResult;
+use crate::daemon::auth::{AuthProvider, LoggedInUser};
+use crate::daemon::config::Config;
+use crate::daemon::http::HttpResponse;
+
+// See the comment on the same constant in the admin token provider, API tokens
+// are entered in the same Lagosta token login form.
+const LAGOSTA_LOGIN_ROUTE_PATH: &str = "/login";
+const API_TOKENS_FILE: &str = "api_tokens.json";
+const API_TOKEN_PREFIX: &str = "krill-api-";
+const ID_BYTES: usize = 8;
+const SECRET_BYTES: usize = 32;
+const SALT_BYTES: usize = 16;
+
+//------------ ApiTokenRequest -----------------------------------------------
+
+/// Request to mint a new API token for the given actor name and attributes.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ApiTokenRequest {
+    pub name: String,
+    #[serde(default)]
+    pub attributes: HashMap<String, String>,
+}
+
+//------------ ApiTokenMinted ------------------------------------------------
+
+/// A newly minted API token. This is the only time that the plaintext token
+/// is available.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ApiTokenMinted {
+    pub id: String,
+    pub token: Token,
+}
+
+//------------ ApiTokenInfo --------------------------------------------------
+
+/// The metadata of an API token, i.e. everything except its hash.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ApiTokenInfo {
+    pub id: String,
+    pub name: String,
+    pub attributes: HashMap<String, String>,
+    pub created: i64,
+    pub revoked: Option<i64>,
+}
+
+//------------ StoredApiToken ------------------------------------------------
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct StoredApiToken {
+    #[serde(flatten)]
+    info: ApiTokenInfo,
+    salt: String,
+    hash: String,
+}
+
+impl StoredApiToken {
+    fn matches(&self, secret: &str) -> bool {
+        match hex::decode(&self.salt) {
+            Ok(salt) => constant_time_eq(hash_secret(&salt, secret).as_bytes(), self.hash.as_bytes()),
+            Err(_) => false,
+        }
+    }
+}
+
+fn hash_secret(salt: &[u8], secret: &str) -> String {
+    let mut bytes = salt.to_vec();
+    bytes.extend_from_slice(secret.as_bytes());
+    hex::encode(sha256(&bytes))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn random_hex(len: usize) -> KrillResult<String> {
+    let mut bytes = vec![0; len];
+    openssl::rand::rand_bytes(&mut bytes)
+        .map_err(|e| Error::Custom(format!("Unable to generate random bytes for API token: {}", e)))?;
+    Ok(hex::encode(bytes))
+}
+
+//------------ ApiTokenStore -------------------------------------------------
+
+/// Keeps the hashed API tokens, persisted as a json file in the data dir.
+///
+/// Tokens have the form "krill-api-<id>-<secret>" so that the stored token
+/// can be found by its id, after which the secret is checked against the
+/// stored hash.
+pub struct ApiTokenStore {
+    path: PathBuf,
+    tokens: RwLock<HashMap<String, StoredApiToken>>,
+}
+
+impl ApiTokenStore {
+    pub fn build(config: &Config) -> KrillResult<Self> {
+        let path = config.data_dir.join(API_TOKENS_FILE);
+        let tokens = if path.exists() {
+            let list: Vec<StoredApiToken> = file::load_json(&path)?;
+            list.into_iter().map(|t| (t.info.id.clone(), t)).collect()
+        } else {
+            HashMap::new()
+        };
+
+        Ok(ApiTokenStore {
+            path,
+            tokens: RwLock::new(tokens),
+        })
+    }
+
+    /// Mints a new token. The plaintext token is returned, but not kept.
+    pub fn mint(&self, req: ApiTokenRequest) -> KrillResult<ApiTokenMinted> {
+        if req.name.trim().is_empty() {
+            return Err(Error::custom("API token name cannot be empty"));
+        }
+
+        let id = random_hex(ID_BYTES)?;
+        let secret = random_hex(SECRET_BYTES)?;
+        let salt = random_hex(SALT_BYTES)?;
+        let hash = hash_secret(&hex::decode(&salt).unwrap(), &secret);
+
+        let stored = StoredApiToken {
+            info: ApiTokenInfo {
+                id: id.clone(),
+                name: req.name,
+                attributes: req.attributes,
+                created: Time::now().timestamp(),
+                revoked: None,
+            },
+            salt,
+            hash,
+        };
+
+        let mut tokens = self.tokens.write().unwrap();
+        tokens.insert(id.clone(), stored);
+        self.save(&tokens)?;
+
+        info!("Minted API token with id '{}'", id);
+
+        let token = Token::from(format!("{}{}-{}", API_TOKEN_PREFIX, id, secret));
+        Ok(ApiTokenMinted { id, token })
+    }
+
+    /// Lists the metadata of all tokens, including revoked tokens.
+    pub fn list(&self) -> Vec<ApiTokenInfo> {
+        let mut res: Vec<_> = self.tokens.read().unwrap().values().map(|t| t.info.clone()).collect();
+        res.sort_by_key(|info| info.created);
+        res
+    }
+
+    /// Revokes the token with the given id. Revoked tokens are kept so that
+    /// they show up in the list.
+    pub fn revoke(&self, id: &str) -> KrillResult<()> {
+        let mut tokens = self.tokens.write().unwrap();
+        match tokens.get_mut(id) {
+            Some(token) => {
+                if token.info.revoked.is_none() {
+                    token.info.revoked = Some(Time::now().timestamp());
+                    self.save(&tokens)?;
+                    info!("Revoked API token with id '{}'", id);
+                }
+                Ok(())
+            }
+            None => Err(Error::Custom(format!("Unknown API token '{}'", id))),
+        }
+    }
+
+    /// Returns true if the token looks like an API token, i.e. it may be
+    /// handled by this store rather than by another provider.
+    pub fn is_api_token(token: &Token) -> bool {
+        token.as_ref().starts_with(API_TOKEN_PREFIX)
+    }
+
+    /// Returns the actor for the token, or an error if the token is unknown,
+    /// revoked or malformed.
+    pub fn actor_def(&self, token: &Token) -> KrillResult<ActorDef> {
+        let invalid = || Error::ApiInvalidCredentials("Invalid API token".to_string());
+
+        let (id, secret) = token
+            .as_ref()
+            .strip_prefix(API_TOKEN_PREFIX)
+            .and_then(|rest| {
+                let mut parts = rest.splitn(2, '-');
+                Some((parts.next()?, parts.next()?))
+            })
+            .ok_or_else(invalid)?;
+
+        let tokens = self.tokens.read().unwrap();
+        match tokens.get(id) {
+            Some(stored) if stored.matches(secret) => {
+                if stored.info.revoked.is_some() {
+                    Err(Error::ApiInvalidCredentials("API token was revoked".to_string()))
+                } else {
+                    Ok(ActorDef::user(
+                        stored.info.name.clone(),
+                        stored.info.attributes.clone(),
+                        None,
+                    ))
+                }
+            }
+            _ => Err(invalid()),
+        }
+    }
+
+    fn save(&self, tokens: &HashMap<String, StoredApiToken>) -> KrillResult<()> {
+        let list: Vec<_> = tokens.values().collect();
+        file::save_json(&list, &self.path)?;
+        Ok(())
+    }
+}
+
+//------------ ApiTokenAuthProvider ------------------------------------------
+
+pub struct ApiTokenAuthProvider {
+    store: Arc<ApiTokenStore>,
+}
+
+impl ApiTokenAuthProvider {
+    pub fn new(store: Arc<ApiTokenStore>) -> Self {
+        ApiTokenAuthProvider { store }
+    }
+}
+
+impl AuthProvider for ApiTokenAuthProvider {
+    fn authenticate(&self, request: &hyper::Request<hyper::Body>) -> KrillResult<Option<ActorDef>> {
+        if log_enabled!(log::Level::Trace) {
+            trace!("Attempting to authenticate the request..");
+        }
+
+        // Leave tokens that are not API tokens to other providers, when
+        // used in a ChainedAuthProvider.
+        let res = match self.get_bearer_token(request) {
+            Some(token) if ApiTokenStore::is_api_token(&token) => self.store.actor_def(&token).map(Some),
+            _ => Ok(None),
+        };
+
+        if log_enabled!(log::Level::Trace) {
+            trace!("Authentication result: {:?}", res);
+        }
+
+        res
+    }
+
+    fn get_login_url(&self, _request: &hyper::Request<hyper::Body>) -> KrillResult<HttpResponse> {
+        // Direct Lagosta to show the user the Lagosta API token login form
+        Ok(HttpResponse::text_no_cache(LAGOSTA_LOGIN_ROUTE_PATH.into()))
+    }
+
+    fn login(&self, request: &hyper::Request<hyper::Body>) -> KrillResult<LoggedInUser> {
+        match self.get_bearer_token(request) {
+            Some(token) => {
+                let actor_def = self.store.actor_def(&token)?;
+                Ok(LoggedInUser {
+                    token,
+                    id: actor_def.name.as_str().to_string(),
+                    display_name: None,
+                    attributes: actor_def.attributes.as_map(),
+                })
+            }
+            None => Err(Error::ApiInvalidCredentials("Missing bearer token".to_string())),
+        }
+    }
+
+    fn logout(&self, request: &hyper::Request<hyper::Body>) -> KrillResult<HttpResponse> {
+        if let Ok(Some(actor)) = self.authenticate(request) {
+            info!("User logged out: {}", actor.name.as_str());
+        }
+
+        // API tokens remain valid until revoked, so there is no session to
+        // end. Direct Lagosta to show the user the Lagosta index page.
+        Ok(HttpResponse::text_no_cache(b"/".to_vec()))
+    }
+}
+
+//------------ Tests ---------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::test;
+
+    #[test]
+    fn mint_authenticate_and_revoke() {
+        test::test_under_tmp(|d| {
+            let config = Config::test(&d, false);
+            let store = ApiTokenStore::build(&config).unwrap();
+
+            let mut attributes = HashMap::new();
+            attributes.insert("role".to_string(), "readonly".to_string());
+            let minted = store
+                .mint(ApiTokenRequest {
+                    name: "ci".to_string(),
+                    attributes,
+                })
+                .unwrap();
+
+            assert!(ApiTokenStore::is_api_token(&minted.token));
+            let actor = store.actor_def(&minted.token).unwrap();
+            assert_eq!(actor.name.as_str(), "ci");
+
+            // The plaintext is never stored
+            let json = std::fs::read_to_string(d.join(API_TOKENS_FILE)).unwrap();
+            assert!(!json.contains(minted.token.as_ref()));
+
+            // Tokens survive a restart
+            let store = ApiTokenStore::build(&config).unwrap();
+            assert!(store.actor_def(&minted.token).is_ok());
+
+            // A wrong secret for a known id is rejected
+            let wrong = Token::from(format!("{}{}-{}", API_TOKEN_PREFIX, minted.id, "00"));
+            assert!(matches!(store.actor_def(&wrong), Err(Error::ApiInvalidCredentials(_))));
+
+            store.revoke(&minted.id).unwrap();
+            assert!(matches!(
+                store.actor_def(&minted.token),
+                Err(Error::ApiInvalidCredentials(_))
+            ));
+            assert!(store.list()[0].revoked.is_some());
+        })
+    }
+}
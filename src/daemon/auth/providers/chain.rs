@@ -0,0 +1,142 @@
+use crate::commons::actor::ActorDef;
+use crate::commons::KrillResult;
+use crate::daemon::auth::{AuthProvider, LoggedInUser};
+use crate::daemon::http::HttpResponse;
+
+/// An AuthProvider which combines several providers, e.g. API tokens for
+/// automation and OpenID Connect for humans.
+///
+/// Requests are authenticated by trying the providers in order:
+///
+///  * The first provider that returns an actor wins, later providers are not
+///    tried. So a failing provider never masks a succeeding one.
+///  * Providers that do not recognize the request return `Ok(None)` and are
+///    skipped.
+///  * If no provider returns an actor, but one or more returned an error, the
+///    error of the last failing provider is returned. The interactive provider
+///    is always last, so that its errors (e.g. an expired login session) reach
+///    the client which may act on them.
+///
+/// Login and logout, including authorization code callbacks, are handled by
+/// the interactive provider only.
+pub struct ChainedAuthProvider {
+    providers: Vec<Box<dyn AuthProvider>>,
+}
+
+impl ChainedAuthProvider {
+    /// Creates a chain which tries the given providers in order, followed by
+    /// the interactive provider.
+    pub fn new(providers: Vec<Box<dyn AuthProvider>>, interactive: Box<dyn AuthProvider>) -> Self {
+        let mut providers = providers;
+        providers.push(interactive);
+        ChainedAuthProvider { providers }
+    }
+
+    fn interactive(&self) -> &dyn AuthProvider {
+        // There is always at least the interactive provider
+        self.providers.last().unwrap().as_ref()
+    }
+}
+
+impl AuthProvider for ChainedAuthProvider {
+    fn authenticate(&self, request: &hyper::Request<hyper::Body>) -> KrillResult<Option<ActorDef>> {
+        let mut res = Ok(None);
+
+        for provider in &self.providers {
+            match provider.authenticate(request) {
+                Ok(Some(actor)) => return Ok(Some(actor)),
+                Ok(None) => {}
+                Err(e) => {
+                    trace!("Authentication by chained provider failed: {}", e);
+                    res = Err(e);
+                }
+            }
+        }
+
+        res
+    }
+
+    fn session_expires_in_secs(&self, request: &hyper::Request<hyper::Body>) -> Option<u64> {
+        self.providers
+            .iter()
+            .find_map(|provider| provider.session_expires_in_secs(request))
+    }
+
+    fn get_login_url(&self, request: &hyper::Request<hyper::Body>) -> KrillResult<HttpResponse> {
+        self.interactive().get_login_url(request)
+    }
+
+    fn login(&self, request: &hyper::Request<hyper::Body>) -> KrillResult<LoggedInUser> {
+        self.interactive().login(request)
+    }
+
+    fn logout(&self, request: &hyper::Request<hyper::Body>) -> KrillResult<HttpResponse> {
+        self.interactive().logout(request)
+    }
+
+    fn refresh(&self, request: &hyper::Request<hyper::Body>) -> KrillResult<LoggedInUser> {
+        self.interactive().refresh(request)
+    }
+}
+
+//------------ Tests ---------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::commons::error::Error;
+
+    struct Fixed(fn() -> KrillResult<Option<ActorDef>>);
+
+    impl AuthProvider for Fixed {
+        fn authenticate(&self, _: &hyper::Request<hyper::Body>) -> KrillResult<Option<ActorDef>> {
+            (self.0)()
+        }
+        fn get_login_url(&self, _: &hyper::Request<hyper::Body>) -> KrillResult<HttpResponse> {
+            Err(Error::custom("not supported by the fixed test provider"))
+        }
+        fn login(&self, _: &hyper::Request<hyper::Body>) -> KrillResult<LoggedInUser> {
+            Err(Error::custom("not supported by the fixed test provider"))
+        }
+        fn logout(&self, _: &hyper::Request<hyper::Body>) -> KrillResult<HttpResponse> {
+            Err(Error::custom("not supported by the fixed test provider"))
+        }
+    }
+
+    fn fails() -> KrillResult<Option<ActorDef>> {
+        Err(Error::ApiInvalidCredentials("first".to_string()))
+    }
+
+    fn expired() -> KrillResult<Option<ActorDef>> {
+        Err(Error::ApiAuthSessionExpired("last".to_string()))
+    }
+
+    fn skips() -> KrillResult<Option<ActorDef>> {
+        Ok(None)
+    }
+
+    fn succeeds() -> KrillResult<Option<ActorDef>> {
+        Ok(Some(ActorDef::anonymous()))
+    }
+
+    #[test]
+    fn chain_precedence() {
+        let request = hyper::Request::new(hyper::Body::empty());
+
+        let chain = ChainedAuthProvider::new(vec![Box::new(Fixed(fails))], Box::new(Fixed(succeeds)));
+        assert!(chain.authenticate(&request).unwrap().is_some());
+
+        let chain = ChainedAuthProvider::new(vec![Box::new(Fixed(skips))], Box::new(Fixed(skips)));
+        assert!(chain.authenticate(&request).unwrap().is_none());
+
+        let chain = ChainedAuthProvider::new(
+            vec![Box::new(Fixed(fails)), Box::new(Fixed(skips))],
+            Box::new(Fixed(expired)),
+        );
+        assert!(matches!(
+            chain.authenticate(&request),
+            Err(Error::ApiAuthSessionExpired(_))
+        ));
+    }
+}
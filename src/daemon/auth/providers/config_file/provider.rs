@@ -4,7 +4,7 @@ use urlparse::{urlparse, GetQuery};
 
 use crate::commons::KrillResult;
 use crate::commons::{actor::ActorDef, api::Token};
-use crate::daemon::auth::common::crypt;
+use crate::daemon::auth::common::crypt::{self, KeyRotation};
 use crate::daemon::auth::common::session::*;
 use crate::daemon::auth::providers::config_file::config::ConfigUserDetails;
 use crate::daemon::auth::{Auth, AuthProvider, LoggedInUser};
@@ -82,7 +82,7 @@ impl ConfigFileAuthProvider {
     fn init_session_key(config: Arc<Config>) -> KrillResult<CryptState> {
         let key_path = config.data_dir.join(LOGIN_SESSION_STATE_KEY_PATH);
         info!("Initializing login session encryption key {}", &key_path.display());
-        crypt::crypt_init(key_path.as_path())
+        crypt::crypt_init(key_path.as_path(), KeyRotation::from_config(&config))
     }
 
     fn get_auth(&self, request: &hyper::Request<hyper::Body>) -> Option<Auth> {
@@ -123,7 +123,7 @@ impl AuthProvider for ConfigFileAuthProvider {
         res
     }
 
-    fn get_login_url(&self) -> KrillResult<HttpResponse> {
+    fn get_login_url(&self, _request: &hyper::Request<hyper::Body>) -> KrillResult<HttpResponse> {
         // Direct Lagosta to show the user the Lagosta API token login form
         Ok(HttpResponse::text_no_cache(LAGOSTA_LOGIN_ROUTE_PATH.into()))
     }
@@ -168,6 +168,7 @@ impl AuthProvider for ConfigFileAuthProvider {
                     Ok(LoggedInUser {
                         token: api_token,
                         id: id.to_string(),
+                        display_name: None,
                         attributes: user.attributes.clone(),
                     })
                 } else {
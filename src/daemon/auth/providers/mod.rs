@@ -1,4 +1,8 @@
 pub mod admin_token;
+pub mod chain;
+
+#[cfg(feature = "multi-user")]
+pub mod api_token;
 
 #[cfg(feature = "multi-user")]
 pub mod config_file;
@@ -6,7 +10,10 @@ pub mod config_file;
 pub mod openid_connect;
 
 pub use admin_token::AdminTokenAuthProvider;
+pub use chain::ChainedAuthProvider;
 
+#[cfg(feature = "multi-user")]
+pub use api_token::{ApiTokenAuthProvider, ApiTokenStore};
 #[cfg(feature = "multi-user")]
 pub use config_file::provider::ConfigFileAuthProvider;
 #[cfg(feature = "multi-user")]
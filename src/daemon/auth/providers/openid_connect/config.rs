@@ -1,11 +1,38 @@
 use std::collections::HashMap;
+use std::path::PathBuf;
 
 use serde::{de, Deserialize, Deserializer};
 
+use crate::constants::{
+    test_mode_enabled, OPENID_CONNECT_HTTP_CLIENT_CONNECT_TIMEOUT_SECS, OPENID_CONNECT_HTTP_CLIENT_TIMEOUT_SECS,
+};
+
 pub type ConfigAuthOpenIDConnectClaims = HashMap<String, ConfigAuthOpenIDConnectClaim>;
 
 pub struct ConfigDefaults {}
 
+impl ConfigDefaults {
+    fn post_logout_redirect() -> bool {
+        true
+    }
+
+    fn http_connect_timeout() -> u64 {
+        OPENID_CONNECT_HTTP_CLIENT_CONNECT_TIMEOUT_SECS
+    }
+
+    fn http_timeout() -> u64 {
+        if test_mode_enabled() {
+            5
+        } else {
+            OPENID_CONNECT_HTTP_CLIENT_TIMEOUT_SECS
+        }
+    }
+
+    fn groups_claim() -> String {
+        "groups".to_string()
+    }
+}
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct ConfigAuthOpenIDConnect {
     pub issuer_url: String,
@@ -22,17 +49,135 @@ pub struct ConfigAuthOpenIDConnect {
     #[serde(default)]
     pub extra_login_params: HashMap<String, String>,
 
+    #[serde(default)]
+    pub extra_login_param_sets: HashMap<String, HashMap<String, String>>,
+
+    #[serde(default)]
+    pub client_login_params: Vec<String>,
+
     #[serde(default)]
     pub logout_url: Option<String>,
 
+    #[serde(default = "ConfigDefaults::post_logout_redirect")]
+    pub post_logout_redirect: bool,
+
+    #[serde(default)]
+    pub claim_trace: bool,
+
+    #[serde(default)]
+    pub max_session_lifetime: Option<u64>,
+
+    #[serde(default = "ConfigDefaults::http_connect_timeout")]
+    pub http_connect_timeout: u64,
+
+    #[serde(default = "ConfigDefaults::http_timeout")]
+    pub http_timeout: u64,
+
+    #[serde(default)]
+    pub tls_root_certs: Vec<PathBuf>,
+
+    #[serde(default)]
+    pub tls_client_identity: Option<PathBuf>,
+
+    #[serde(default)]
+    pub tls_client_identity_password: String,
+
+    #[serde(default)]
+    pub http_proxy: Option<String>,
+
+    #[serde(default)]
+    pub http_proxy_username: Option<String>,
+
+    #[serde(default)]
+    pub http_proxy_password: String,
+
+    #[serde(default)]
+    pub http_no_proxy: Vec<String>,
+
     #[serde(default)]
     pub insecure: bool,
+
+    #[serde(default)]
+    pub insecure_i_really_mean_it: bool,
+
+    #[serde(default)]
+    pub id_token_signing_algs: Vec<String>,
+
+    #[serde(default)]
+    pub require_signed_userinfo: bool,
+
+    #[serde(default)]
+    pub role_map: Option<ConfigAuthOpenIDConnectRoleMap>,
 }
 #[derive(Clone, Debug, Deserialize)]
 pub struct ConfigAuthOpenIDConnectClaim {
     pub source: Option<ConfigAuthOpenIDConnectClaimSource>,
     pub jmespath: Option<String>,
     pub dest: Option<String>,
+    #[serde(default)]
+    pub on_multiple: ConfigAuthOpenIDConnectOnMultiple,
+}
+
+/// What to do when a claim is found in more than one of the searched sources.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ConfigAuthOpenIDConnectOnMultiple {
+    /// Use the value from the first source, in the order that they are searched.
+    First,
+    /// Refuse the login if the sources have different values.
+    Fail,
+    /// Use the value from the last source.
+    Last,
+}
+
+impl Default for ConfigAuthOpenIDConnectOnMultiple {
+    fn default() -> Self {
+        ConfigAuthOpenIDConnectOnMultiple::First
+    }
+}
+
+/// Maps the groups that a user is a member of to a role.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ConfigAuthOpenIDConnectRoleMap {
+    /// JMESPath expression to find the groups of the user.
+    #[serde(default = "ConfigDefaults::groups_claim")]
+    pub groups_claim: String,
+
+    /// The groups in order of priority, the role of the first group that
+    /// the user is a member of is used.
+    #[serde(default)]
+    pub groups: Vec<ConfigAuthOpenIDConnectGroupRole>,
+
+    /// The role of users who are not a member of any of the groups. If not
+    /// set such users cannot login.
+    #[serde(default)]
+    pub default_role: Option<String>,
+}
+
+impl ConfigAuthOpenIDConnectRoleMap {
+    pub fn groups_claim_conf(&self) -> ConfigAuthOpenIDConnectClaim {
+        ConfigAuthOpenIDConnectClaim {
+            source: None,
+            jmespath: Some(self.groups_claim.clone()),
+            dest: None,
+            on_multiple: ConfigAuthOpenIDConnectOnMultiple::default(),
+        }
+    }
+
+    /// Returns the role for the given groups, if any.
+    pub fn role_for(&self, groups: &[String]) -> Option<&str> {
+        self.groups
+            .iter()
+            .find(|mapping| groups.contains(&mapping.group))
+            .map(|mapping| mapping.role.as_str())
+            .or_else(|| self.default_role.as_deref())
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ConfigAuthOpenIDConnectGroupRole {
+    pub group: String,
+    pub role: String,
 }
 
 #[derive(Clone, Debug)]
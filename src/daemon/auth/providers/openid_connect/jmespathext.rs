@@ -4,8 +4,21 @@ use jmespatch as jmespath;
 use jmespath::functions::{ArgumentType, CustomFunction, Signature};
 use jmespath::{Context, ErrorReason, JmespathError, Rcvar, Runtime};
 
+use lazy_static::lazy_static;
 use regex::Regex;
 
+lazy_static! {
+    // The runtime is built once and shared by all logins. Compiling and
+    // searching expressions only reads the runtime, and with the "sync"
+    // feature of the jmespath crate the runtime can be used from any thread.
+    static ref RUNTIME: Runtime = init_runtime();
+}
+
+/// Returns the shared runtime, see `init_runtime`.
+pub fn runtime() -> &'static Runtime {
+    &RUNTIME
+}
+
 /// Create a customized instance of the JMESPath runtime with support for the
 /// standard functions and two additional custom functions: recap and resub.
 pub fn init_runtime() -> Runtime {
@@ -5,5 +5,6 @@ pub mod config;
 pub mod httpclient;
 pub mod jmespathext;
 pub mod provider;
+pub mod trace;
 
 pub use config::ConfigAuthOpenIDConnect;
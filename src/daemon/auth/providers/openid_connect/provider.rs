@@ -26,8 +26,8 @@ use std::{
         HashMap,
     },
     ops::Deref,
-    path::Path,
     sync::{Arc, RwLock, RwLockReadGuard},
+    time::Duration,
 };
 
 use basic_cookies::Cookie;
@@ -35,7 +35,6 @@ use hyper::header::{HeaderValue, SET_COOKIE};
 use jmespatch as jmespath;
 use jmespath::ToJmespath;
 
-use openidconnect::UserInfoError;
 use openidconnect::{core::CoreRevocableToken, AccessToken, RequestTokenError, RevocationErrorResponseType};
 use openidconnect::{
     core::{
@@ -48,17 +47,21 @@ use openidconnect::{
     AuthenticationFlow, AuthorizationCode, ClientId, ClientSecret, CsrfToken, IssuerUrl, Nonce, OAuth2TokenResponse,
     RedirectUrl, RefreshToken, Scope,
 };
+use openidconnect::{ClaimsVerificationError, DiscoveryError, UserInfoError};
 
 use urlparse::{urlparse, GetQuery};
 
 use crate::commons::util::sha256;
 use crate::commons::KrillResult;
-use crate::commons::{actor::ActorDef, api::Token};
-use crate::daemon::auth::common::crypt;
+use crate::commons::{
+    actor::{ActorDef, Attributes},
+    api::Token,
+};
+use crate::daemon::auth::common::crypt::{self, KeyRotation};
 use crate::daemon::auth::common::session::*;
 use crate::daemon::auth::providers::config_file::config::ConfigUserDetails;
 use crate::daemon::auth::providers::openid_connect::config::ConfigAuthOpenIDConnectClaims;
-use crate::daemon::auth::providers::openid_connect::httpclient::logging_http_client;
+use crate::daemon::auth::providers::openid_connect::httpclient::{logging_http_client, HttpClientSettings};
 use crate::daemon::auth::providers::openid_connect::jmespathext;
 use crate::daemon::auth::{Auth, AuthProvider, LoggedInUser};
 use crate::daemon::config::Config;
@@ -69,7 +72,9 @@ use crate::{commons::error::Error, daemon::auth::common::crypt::CryptState};
 
 use super::config::{
     ConfigAuthOpenIDConnect, ConfigAuthOpenIDConnectClaim, ConfigAuthOpenIDConnectClaimSource as ClaimSource,
+    ConfigAuthOpenIDConnectOnMultiple, ConfigAuthOpenIDConnectRoleMap,
 };
+use super::trace::{ClaimResolution, ClaimTrace, ClaimTraces};
 use super::util::{
     FlexibleClient, FlexibleIdTokenClaims, FlexibleTokenResponse, FlexibleUserInfoClaims, LogOrFail, WantedMeta,
 };
@@ -82,6 +87,41 @@ const NONCE_COOKIE_NAME: &str = "__Host-krill_login_nonce";
 const CSRF_COOKIE_NAME: &str = "__Host-krill_login_csrf_hash";
 const LOGIN_SESSION_STATE_KEY_PATH: &str = "login_session_state.key"; // TODO: decide on proper location
 
+// Used by try_refresh_token to signal that the provider did not respond in time.
+const PROVIDER_TIMEOUT: &str = "krill_provider_timeout";
+
+// The claim used for the user id when no 'id' claim is configured, and the
+// claim used instead if the provider does not support the 'email' scope and
+// does not return an email claim.
+const DEFAULT_ID_CLAIM: &str = "email";
+const FALLBACK_ID_CLAIM: &str = "sub";
+
+// The claim configuration for the name to show for the user, if it should be
+// different from the id. If this is configured, then the 'id' claim defaults
+// to the stable 'sub' claim instead.
+const DISPLAY_NAME_CLAIM: &str = "display_name";
+
+// The query parameter of a login request which selects one of the configured
+// extra_login_param_sets.
+const LOGIN_PARAM_SET_QUERY_PARAM: &str = "param_set";
+
+// Authorization request parameters which are set by Krill itself, and which
+// must not be influenced by the client.
+const RESERVED_LOGIN_PARAMS: &[&str] = &[
+    "client_id",
+    "code_challenge",
+    "code_challenge_method",
+    "nonce",
+    "prompt",
+    "redirect_uri",
+    "request",
+    "request_uri",
+    "response_mode",
+    "response_type",
+    "scope",
+    "state",
+];
+
 #[allow(clippy::enum_variant_names)]
 enum TokenKind {
     AccessToken,
@@ -122,6 +162,7 @@ enum LogoutMode {
     RPInitiatedLogout {
         provider_url: String,
         post_logout_redirect_url: String,
+        send_post_logout_redirect: bool,
     },
 }
 
@@ -132,23 +173,110 @@ pub struct ProviderConnectionProperties {
     logout_mode: LogoutMode,
 }
 
+/// The user who logged in, as resolved from the claims.
+struct ResolvedUser {
+    id: String,
+    display_name: Option<String>,
+    attributes: HashMap<String, Vec<String>>,
+}
+
 pub struct OpenIDConnectAuthProvider {
     config: Arc<Config>,
     session_cache: Arc<LoginSessionCache>,
     session_key: CryptState,
     conn: Arc<RwLock<Option<ProviderConnectionProperties>>>,
+    claim_traces: Arc<ClaimTraces>,
+    http_client: HttpClientSettings,
+    id_token_signing_algs: Vec<CoreJwsSigningAlgorithm>,
+    claim_exprs: ClaimExpressions,
 }
 
 impl OpenIDConnectAuthProvider {
-    pub fn new(config: Arc<Config>, session_cache: Arc<LoginSessionCache>) -> KrillResult<Self> {
-        let session_key = Self::init_session_key(&config.data_dir)?;
+    pub fn new(
+        config: Arc<Config>,
+        session_cache: Arc<LoginSessionCache>,
+        claim_traces: Arc<ClaimTraces>,
+    ) -> KrillResult<Self> {
+        let session_key = Self::init_session_key(&config)?;
+        let (provider, problems) = Self::build(config, session_cache, claim_traces, session_key)?;
+        if !problems.is_empty() {
+            return Err(Error::ConfigError(problems.join(", ")));
+        }
+        Ok(provider)
+    }
 
-        Ok(OpenIDConnectAuthProvider {
+    /// Builds the provider. Claims with invalid JMESPath expressions are
+    /// returned as problems, rather than as an error, so that they can be
+    /// reported along with other problems when checking the config.
+    fn build(
+        config: Arc<Config>,
+        session_cache: Arc<LoginSessionCache>,
+        claim_traces: Arc<ClaimTraces>,
+        session_key: CryptState,
+    ) -> KrillResult<(Self, Vec<String>)> {
+        let (http_client, id_token_signing_algs, (claim_exprs, problems)) = match &config.auth_openidconnect {
+            Some(oidc_conf) => {
+                check_insecure(oidc_conf)?;
+                check_client_login_params(oidc_conf)?;
+                (
+                    HttpClientSettings::new(oidc_conf)?,
+                    id_token_signing_algs(oidc_conf)?,
+                    compile_claim_expressions(&oidc_conf.claims, oidc_conf.role_map.as_ref()),
+                )
+            }
+            None => {
+                return Err(Error::ConfigError(
+                    "Missing [auth_openidconnect] config section!".into(),
+                ))
+            }
+        };
+
+        let provider = OpenIDConnectAuthProvider {
             config,
             session_cache,
             session_key,
             conn: Arc::new(RwLock::new(None)),
-        })
+            claim_traces,
+            http_client,
+            id_token_signing_algs,
+            claim_exprs,
+        };
+        Ok((provider, problems))
+    }
+
+    /// Checks the configuration without starting Krill: compiles the JMESPath
+    /// expressions of all claims, then discovers the provider and verifies its
+    /// capabilities. Returns a description of each problem found.
+    ///
+    /// The session key in the data directory is left alone, nothing is
+    /// encrypted during the check.
+    pub fn check_config(config: Arc<Config>) -> KrillResult<Vec<String>> {
+        let (provider, mut problems) = Self::build(
+            config,
+            Arc::new(LoginSessionCache::new()),
+            Arc::new(ClaimTraces::new()),
+            CryptState::ephemeral()?,
+        )?;
+
+        match provider.discover() {
+            Ok(meta) => {
+                if let Err(e) = provider.check_provider_capabilities(&meta) {
+                    problems.push(e.to_string());
+                }
+                let extra_scopes = &provider.oidc_conf()?.extra_login_scopes;
+                if let Some(unsupported) = unsupported_scopes(extra_scopes, meta.scopes_supported()) {
+                    if !unsupported.is_empty() {
+                        problems.push(format!(
+                            "OpenID Connect: The provider does not advertise support for extra_login_scopes: {}",
+                            unsupported.join(", ")
+                        ));
+                    }
+                }
+            }
+            Err(e) => problems.push(e.to_string()),
+        }
+
+        Ok(problems)
     }
 
     fn initialize_connection_if_needed(&self) -> KrillResult<()> {
@@ -198,10 +326,18 @@ impl OpenIDConnectAuthProvider {
 
         // Contact the OpenID Connect: identity provider discovery endpoint to
         // learn about and configure ourselves to talk to it.
-        let meta = WantedMeta::discover(&issuer, logging_http_client).map_err(|e| {
+        let meta = WantedMeta::discover(&issuer, logging_http_client(&self.http_client)).map_err(|e| {
+            if let DiscoveryError::Request(Error::ApiAuthProviderTimeout(ref url)) = e {
+                return Error::ApiAuthProviderTimeout(url.clone());
+            }
+            let via = match self.http_client.proxy() {
+                Some(proxy) => format!(" via proxy {}", proxy),
+                None => String::new(),
+            };
             Error::custom(format!(
-                "OpenID Connect: Discovery failed with issuer {}, {}",
+                "OpenID Connect: Discovery failed with issuer {}{}, {}",
                 issuer.as_str(),
+                via,
                 stringify_cause_chain(e)
             ))
         })?;
@@ -214,7 +350,7 @@ impl OpenIDConnectAuthProvider {
     fn check_provider_capabilities(&self, meta: &WantedMeta) -> KrillResult<(bool, bool, LogoutMode)> {
         // TODO: verify token_endpoint_auth_methods_supported?
         // TODO: verify response_types_supported?
-        let mut ok = true;
+        let mut missing = vec![];
         let mut email_scope_supported = false;
 
         info!("OpenID Connect: Verifying provider capabilities..");
@@ -228,11 +364,10 @@ impl OpenIDConnectAuthProvider {
         //     ["query", "fragment"].
         if meta.response_modes_supported().is_some() {
             // Some modes are specified, do they include "query"?
-            if is_supported_opt!(meta.response_modes_supported(), CoreResponseMode::Query)
+            if let Err(e) = is_supported_opt!(meta.response_modes_supported(), CoreResponseMode::Query)
                 .log_or_fail("response_modes_supported", Some("query"))
-                .is_err()
             {
-                ok = false;
+                missing.push(e.to_string());
             }
         }
 
@@ -245,16 +380,31 @@ impl OpenIDConnectAuthProvider {
         //     unless the Response Type used returns no ID Token from the
         //     Authorization Endpoint (such as when using the Authorization
         //     Code Flow).
-        if is_supported!(
+        if let Err(e) = is_supported!(
             meta.id_token_signing_alg_values_supported(),
             CoreJwsSigningAlgorithm::RsaSsaPkcs1V15Sha256
         )
         .log_or_fail("id_token_signing_alg_values_supported", Some("RS256"))
-        .is_err()
         {
             // According to the spec quoted above RS256 MUST be supported so
             // this OpenID Connect provider is not spec compliant.
-            ok = false;
+            missing.push(e.to_string());
+        }
+
+        // If the operator pinned the algorithms that ID tokens may be signed
+        // with, then at least one of them has to be supported.
+        if !self.id_token_signing_algs.is_empty()
+            && !self
+                .id_token_signing_algs
+                .iter()
+                .any(|alg| meta.id_token_signing_alg_values_supported().contains(alg))
+        {
+            let err = format!(
+                "OpenID Connect: The provider supports none of the configured id_token_signing_algs: {}",
+                self.oidc_conf()?.id_token_signing_algs.join(", ")
+            );
+            error!("{}", err);
+            missing.push(err);
         }
 
         // From: https://openid.net/specs/openid-connect-discovery-1_0.html
@@ -265,15 +415,40 @@ impl OpenIDConnectAuthProvider {
         //     advertise some supported scope values even when this parameter is
         //     used, although those defined in [OpenID.Core] SHOULD be listed,
         //     if supported.
-        if is_supported_val_opt!(meta.scopes_supported(), Scope::new("openid".to_string()))
+        if let Err(e) = is_supported_val_opt!(meta.scopes_supported(), Scope::new("openid".to_string()))
             .log_or_fail("scopes_supported", Some("openid"))
-            .is_err()
         {
-            ok = false;
+            missing.push(e.to_string());
         }
 
         if is_supported_val_opt!(meta.scopes_supported(), Scope::new("email".to_string())).is_some() {
             email_scope_supported = true;
+        } else if uses_default_id_claim(&self.oidc_conf()?.claims) {
+            warn!(
+                "OpenID Connect: The provider does not advertise support for the 'email' scope, but no 'id' claim \
+                is configured so the '{}' claim is used as the user id. If the provider does not return it, then \
+                the '{}' claim will be used instead. Configure an 'id' claim in the [auth_openidconnect.claims] \
+                section to use a different claim, e.g. id = {{ jmespath=\"{}\" }}",
+                DEFAULT_ID_CLAIM, FALLBACK_ID_CLAIM, FALLBACK_ID_CLAIM
+            );
+        }
+
+        // A scope that the provider does not support makes it reject the whole
+        // authorization request, so warn about such extra scopes now rather than
+        // leaving users with a confusing error when they login. This is not an
+        // error, as providers may choose not to advertise some scopes.
+        let extra_scopes = &self.oidc_conf()?.extra_login_scopes;
+        match unsupported_scopes(extra_scopes, meta.scopes_supported()) {
+            Some(unsupported) if !unsupported.is_empty() => warn!(
+                "OpenID Connect: The provider does not advertise support for extra_login_scopes: {}. Logins will \
+                fail if the provider rejects them.",
+                unsupported.join(", ")
+            ),
+            None if !extra_scopes.is_empty() => info!(
+                "OpenID Connect: The provider does not advertise scopes_supported, unable to validate \
+                extra_login_scopes"
+            ),
+            _ => {}
         }
 
         // From: https://openid.net/specs/openid-connect-discovery-1_0.html
@@ -352,6 +527,7 @@ impl OpenIDConnectAuthProvider {
             (None, Some(rpinit_url), _) => LogoutMode::RPInitiatedLogout {
                 provider_url: rpinit_url.clone(),
                 post_logout_redirect_url: service_uri,
+                send_post_logout_redirect: self.oidc_conf()?.post_logout_redirect,
             },
             (Some(config_url), _, None) => LogoutMode::OperatorProvidedLogout {
                 operator_provided_logout_url: config_url.clone(),
@@ -362,11 +538,13 @@ impl OpenIDConnectAuthProvider {
             },
         };
 
-        match ok {
-            true => Ok((email_scope_supported, userinfo_endpoint_supported, logout_mode)),
-            false => Err(Error::Custom(
-                "OpenID Connect: The provider lacks support for one or more required capabilities.".to_string(),
-            )),
+        if missing.is_empty() {
+            Ok((email_scope_supported, userinfo_endpoint_supported, logout_mode))
+        } else {
+            Err(Error::Custom(format!(
+                "OpenID Connect: The provider lacks support for one or more required capabilities: {}",
+                missing.join(", ")
+            )))
         }
     }
 
@@ -415,9 +593,8 @@ impl OpenIDConnectAuthProvider {
     }
 
     fn build_rpinitiated_logout_url(
-        &self,
         provider_url: &str,
-        post_logout_redirect_url: &str,
+        post_logout_redirect_url: Option<&str>,
         id_token: Option<&String>,
     ) -> KrillResult<String> {
         // Ask Lagosta to direct the user first the to OpenID Connect provider logout page, and ask it
@@ -426,17 +603,34 @@ impl OpenIDConnectAuthProvider {
         // See: https://openid.net/specs/openid-connect-rpinitiated-1_0.html#RPLogout
         //      https://openid.net/specs/openid-connect-rpinitiated-1_0.html#RedirectionAfterLogout
         // E.g. state or ui_locales?
+        let mut params = vec![];
+
+        if let Some(post_logout_redirect_url) = post_logout_redirect_url {
+            // From https://openid.net/specs/openid-connect-rpinitiated-1_0.html#RedirectionAfterLogout:
+            //   "An id_token_hint carrying an ID Token for the RP is also REQUIRED when requesting
+            //    post-logout redirection"
+            if id_token.is_none() {
+                return Err(Error::custom("Missing id token, required for post-logout redirection"));
+            }
+            params.push(format!(
+                "post_logout_redirect_uri={}",
+                url_encode(post_logout_redirect_url)?
+            ));
+        }
 
-        // From https://openid.net/specs/openid-connect-rpinitiated-1_0.html#RedirectionAfterLogout:
-        //   "An id_token_hint carrying an ID Token for the RP is also REQUIRED when requesting
-        //    post-logout redirection"
-        let id_token = id_token.ok_or_else(|| Error::custom("Missing id token"))?;
-        Ok(format!(
-            "{}?post_logout_redirect_uri={}&id_token_hint={}",
-            provider_url,
-            url_encode(post_logout_redirect_url)?,
-            url_encode(id_token)?
-        ))
+        // Without post-logout redirection the id_token_hint is optional, but some providers require it
+        // anyway to know which session to end, so always send it when we have it.
+        if let Some(id_token) = id_token {
+            params.push(format!("id_token_hint={}", url_encode(id_token)?));
+        }
+
+        if params.is_empty() {
+            return Ok(provider_url.to_string());
+        }
+
+        // The end_session_endpoint MAY contain query parameter components.
+        let separator = if provider_url.contains('?') { '&' } else { '?' };
+        Ok(format!("{}{}{}", provider_url, separator, params.join("&")))
     }
 
     fn try_revoke_token(&self, session: &ClientSession) -> Result<(), RevocationErrorResponseType> {
@@ -471,7 +665,7 @@ impl OpenIDConnectAuthProvider {
                     err.to_string()
                 )))
             })?
-            .request(logging_http_client)
+            .request(logging_http_client(&self.http_client))
         {
             Ok(_) => Ok(()),
             Err(err) => match &err {
@@ -532,13 +726,12 @@ impl OpenIDConnectAuthProvider {
         let token_response = conn
             .client
             .exchange_refresh_token(&RefreshToken::new(refresh_token.to_string()))
-            .request(logging_http_client);
+            .request(logging_http_client(&self.http_client));
 
         match token_response {
             Ok(token_response) => {
-                let new_token_res = self.session_cache.encode(
-                    &session.id,
-                    &session.attributes,
+                let new_token_res = self.session_cache.encode_refreshed(
+                    session,
                     secrets_from_token_response(&token_response),
                     &self.session_key,
                     token_response.expires_in(),
@@ -568,6 +761,10 @@ impl OpenIDConnectAuthProvider {
                     // in the openid-connect crate. These two error messages will
                     // therefore **not** end up in the `ServerResponse` variant.
                     openidconnect::RequestTokenError::ServerResponse(r) => Err(r.error().clone()),
+                    openidconnect::RequestTokenError::Request(Error::ApiAuthProviderTimeout(_)) => {
+                        self.on_connection_issue(lock_guard);
+                        Err(CoreErrorResponseType::Extension(PROVIDER_TIMEOUT.to_string()))
+                    }
                     openidconnect::RequestTokenError::Request(r) => {
                         self.on_connection_issue(lock_guard);
                         Err(CoreErrorResponseType::Extension(format!(
@@ -599,7 +796,8 @@ impl OpenIDConnectAuthProvider {
         claim_conf: &ConfigAuthOpenIDConnectClaim,
         id_token_claims: &FlexibleIdTokenClaims,
         user_info_claims: Option<&FlexibleUserInfoClaims>,
-    ) -> KrillResult<Option<String>> {
+        resolution: &mut ClaimResolution,
+    ) -> KrillResult<Option<Vec<String>>> {
         let searchable_claims = match &claim_conf.source {
             Some(ClaimSource::ConfigFile) => return Ok(None),
             Some(ClaimSource::IdTokenStandardClaim) => Some(id_token_claims.to_jmespath()),
@@ -622,29 +820,24 @@ impl OpenIDConnectAuthProvider {
             })?
             .to_string();
 
-        // Create a new JMESPath Runtime. TODO: Somehow make this a single
-        // persistent runtime to which API request handling threads (such as
-        // ours) dispatch search commands to be compiled and executed and which
-        // can receive results back. Perhaps with a pair of channels, one to
-        // to send search requests and the other to receive search results?
-        let runtime = jmespathext::init_runtime();
-
-        // We don't precompile the JMESPath expression because the jmespath
-        // crate requires it to have a lifetime and storing that in our state
-        // struct would infect the entire struct with lifetimes, plus logins
-        // don't happen very often and are slow anyway (as the user has to visit
-        // the OpenID Connect providers own login form then be redirected back
-        // to us) so this doesn't have to be fast. Note to self: perhaps the
-        // lifetime issue could be worked around using a Box?
-        let expr = &runtime.compile(&jmespath_string).map_err(|e| {
-            OpenIDConnectAuthProvider::internal_error(
-                format!(
-                    "OpenID Connect: Unable to compile JMESPath expression '{}'",
-                    &jmespath_string
-                ),
-                Some(stringify_cause_chain(e)),
-            )
-        })?;
+        // The configured expressions were compiled at startup. They borrow
+        // the shared runtime, which lives for as long as the process does.
+        let compiled;
+        let expr = match self.claim_exprs.get(&jmespath_string) {
+            Some(expr) => expr,
+            None => {
+                compiled = jmespathext::runtime().compile(&jmespath_string).map_err(|e| {
+                    OpenIDConnectAuthProvider::internal_error(
+                        format!(
+                            "OpenID Connect: Unable to compile JMESPath expression '{}'",
+                            &jmespath_string
+                        ),
+                        Some(stringify_cause_chain(e)),
+                    )
+                })?;
+                &compiled
+            }
+        };
 
         let claims_to_search = match searchable_claims {
             Some(claim) => vec![(claim_conf.source.as_ref().unwrap(), claim)],
@@ -671,6 +864,10 @@ impl OpenIDConnectAuthProvider {
             }
         };
 
+        // Collect the values from all sources, so that values found in more
+        // than one source can be reported and handled as configured.
+        let mut matches = vec![];
+
         for (source, claims) in claims_to_search.clone() {
             let claims = claims.map_err(|e| {
                 OpenIDConnectAuthProvider::internal_error(
@@ -680,6 +877,7 @@ impl OpenIDConnectAuthProvider {
             })?;
 
             debug!("Searching {:?} for \"{}\"..", source, &jmespath_string);
+            resolution.searched(source);
 
             let result = expr.search(&claims).map_err(|e| {
                 OpenIDConnectAuthProvider::internal_error(
@@ -691,17 +889,29 @@ impl OpenIDConnectAuthProvider {
 
             // Did the JMESPath search find a match?
             if !matches!(*result, jmespath::Variable::Null) {
-                // Yes. Is it a JMESPath String type?
-                if let Some(result_str) = result.as_string() {
-                    // Yes. Is it non-empty after trimming leading and trailing whitespace?
-                    if !result_str.trim().is_empty() {
-                        // Yes
-                        return Ok(Some(result_str.clone()));
-                    }
+                // Yes. Is it a JMESPath String type, or an Array of which we
+                // keep the String values? E.g. the groups of the user.
+                let values: Vec<String> = match result.as_array() {
+                    Some(array) => array.iter().filter_map(|v| v.as_string().cloned()).collect(),
+                    None => result.as_string().cloned().into_iter().collect(),
+                };
+
+                // Keep the values that are non-empty after trimming leading
+                // and trailing whitespace
+                let values: Vec<String> = values.into_iter().filter(|v| !v.trim().is_empty()).collect();
+                if !values.is_empty() {
+                    matches.push((source, values));
                 }
             }
         }
 
+        if let Some((source, values)) =
+            OpenIDConnectAuthProvider::select_claim_values(&jmespath_string, claim_conf.on_multiple, matches)?
+        {
+            resolution.matched(source, &values.join(","));
+            return Ok(Some(values));
+        }
+
         let err_msg_parts = &claims_to_search
             .iter()
             .map(|(source, claims)| format!("{} {:?}", source, claims))
@@ -713,10 +923,238 @@ impl OpenIDConnectAuthProvider {
         Ok(None)
     }
 
-    fn init_session_key(data_dir: &Path) -> KrillResult<CryptState> {
-        let key_path = data_dir.join(LOGIN_SESSION_STATE_KEY_PATH);
+    /// Selects the values to use for a claim from the values found in each
+    /// source, in the order that the sources were searched. Fails if the
+    /// claim must not be ambiguous, but the sources have different values.
+    fn select_claim_values<'a>(
+        jmespath: &str,
+        on_multiple: ConfigAuthOpenIDConnectOnMultiple,
+        mut matches: Vec<(&'a ClaimSource, Vec<String>)>,
+    ) -> KrillResult<Option<(&'a ClaimSource, Vec<String>)>> {
+        if matches.len() > 1 {
+            info!(
+                "OpenID Connect: Claim \"{}\" was found in more than one source: {}",
+                jmespath,
+                matches
+                    .iter()
+                    .map(|(source, values)| format!("{}: {}", source, values.join(",")))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            );
+
+            if on_multiple == ConfigAuthOpenIDConnectOnMultiple::Fail
+                && matches.iter().any(|(_, values)| *values != matches[0].1)
+            {
+                return Err(OpenIDConnectAuthProvider::internal_error(
+                    format!(
+                        "OpenID Connect: Claim \"{}\" has different values in different sources",
+                        jmespath
+                    ),
+                    None,
+                ));
+            }
+        }
+
+        if on_multiple == ConfigAuthOpenIDConnectOnMultiple::Last {
+            Ok(matches.pop())
+        } else if matches.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(matches.remove(0)))
+        }
+    }
+
+    /// Rejects sessions which exceed the configured maximum lifetime, however
+    /// often they were refreshed, so that the user has to login again with
+    /// the provider.
+    fn check_session_lifetime(&self, session: &ClientSession) -> KrillResult<()> {
+        if let Some(max_secs) = self.oidc_conf()?.max_session_lifetime {
+            if session.exceeds_lifetime(Duration::from_secs(max_secs)) {
+                info!(
+                    "OpenID Connect: Login session for user '{}' exceeded the maximum lifetime of {} seconds",
+                    session.id, max_secs
+                );
+                return Err(Error::ApiInvalidCredentials(
+                    "Login session exceeded the maximum lifetime, please login again".to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Resolves the id, the display name, if configured, and the attributes of
+    /// the user who logged in from the claims, and records how they were
+    /// resolved in the trace.
+    fn resolve_user(
+        &self,
+        id_token_claims: &FlexibleIdTokenClaims,
+        user_info_claims: Option<FlexibleUserInfoClaims>,
+        trace: &mut ClaimTrace,
+    ) -> KrillResult<ResolvedUser> {
+        let configured_claims = &self.oidc_conf()?.claims;
+        let claims_conf = with_default_claims(configured_claims);
+
+        let id_claim_conf = claims_conf
+            .get("id")
+            .ok_or_else(|| OpenIDConnectAuthProvider::internal_error("Missing 'id' claim configuration", None))?;
+
+        let resolution = trace.resolution("id", id_claim_conf);
+        let mut id = self
+            .extract_claim(&id_claim_conf, &id_token_claims, user_info_claims.as_ref(), resolution)?
+            .and_then(|values| values.into_iter().next());
+        if id.is_some() {
+            resolution.used = true;
+        }
+
+        let fallback = fallback_id_claim(configured_claims, self.email_scope_supported());
+        if let (None, Some(fallback_conf)) = (&id, fallback) {
+            warn!(
+                "OpenID Connect: No '{}' claim found for the user id, using the '{}' claim instead",
+                DEFAULT_ID_CLAIM, FALLBACK_ID_CLAIM
+            );
+            let resolution = trace.resolution("id", &fallback_conf);
+            id = self
+                .extract_claim(&fallback_conf, &id_token_claims, user_info_claims.as_ref(), resolution)?
+                .and_then(|values| values.into_iter().next());
+            if id.is_some() {
+                resolution.used = true;
+            }
+        }
+
+        let id = id.ok_or_else(|| {
+            let msg = if uses_display_name_claim(configured_claims) && uses_default_id_claim(configured_claims) {
+                format!(
+                    "No value found for 'id' claim, which defaults to the '{}' claim",
+                    FALLBACK_ID_CLAIM
+                )
+            } else if uses_default_id_claim(configured_claims) {
+                format!(
+                    "No value found for 'id' claim, which defaults to the '{}' claim. Configure an 'id' claim \
+                    in the [auth_openidconnect.claims] section, e.g. id = {{ jmespath=\"{}\" }}",
+                    DEFAULT_ID_CLAIM, FALLBACK_ID_CLAIM
+                )
+            } else {
+                "No value found for 'id' claim".to_string()
+            };
+            OpenIDConnectAuthProvider::internal_error(msg, None)
+        })?;
+        trace.id = Some(id.clone());
+
+        // The display name is only kept if it differs from the id. Without a
+        // value the id is shown, as it is when no display name is configured.
+        let display_name = match claims_conf.get(DISPLAY_NAME_CLAIM) {
+            Some(display_name_conf) => {
+                let resolution = trace.resolution(DISPLAY_NAME_CLAIM, display_name_conf);
+                let display_name = self
+                    .extract_claim(
+                        display_name_conf,
+                        &id_token_claims,
+                        user_info_claims.as_ref(),
+                        resolution,
+                    )?
+                    .and_then(|values| values.into_iter().next());
+                match display_name {
+                    Some(display_name) => {
+                        resolution.used = true;
+                        Some(display_name).filter(|display_name| display_name != &id)
+                    }
+                    None => {
+                        debug!(
+                            "OpenID Connect: No display name found for user '{}', will show the id",
+                            id
+                        );
+                        None
+                    }
+                }
+            }
+            None => None,
+        };
+
+        // Lookup the a user in the config file authentication provider
+        // configuration by the id value that we just obtained, if
+        // present. Any claim configurations that refer to attributes of
+        // users configured in the config file will be looked up on this
+        // user.
+        let user = self.config.auth_users.as_ref().and_then(|users| users.get(&id));
+
+        let role = match &self.oidc_conf()?.role_map {
+            Some(role_map) => {
+                Some(self.map_groups_to_role(role_map, id_token_claims, user_info_claims.as_ref(), &id, trace)?)
+            }
+            None => None,
+        };
+
+        let mut attributes = self.resolve_claims(claims_conf, user, id_token_claims, user_info_claims, &id, trace)?;
+
+        // The role map takes precedence over a role claim
+        if let Some(role) = role {
+            attributes.insert("role".to_string(), vec![role]);
+        }
+
+        Ok(ResolvedUser {
+            id,
+            display_name,
+            attributes,
+        })
+    }
+
+    /// Finds the groups of the user, and returns the role of the first group
+    /// in the role map that the user is a member of, or the default role.
+    /// Users without a role cannot login.
+    fn map_groups_to_role(
+        &self,
+        role_map: &ConfigAuthOpenIDConnectRoleMap,
+        id_token_claims: &FlexibleIdTokenClaims,
+        user_info_claims: Option<&FlexibleUserInfoClaims>,
+        id: &str,
+        trace: &mut ClaimTrace,
+    ) -> KrillResult<String> {
+        let claim_conf = role_map.groups_claim_conf();
+        let resolution = trace.resolution("groups", &claim_conf);
+        let groups = self
+            .extract_claim(&claim_conf, id_token_claims, user_info_claims, resolution)?
+            .unwrap_or_default();
+
+        match role_map.role_for(&groups) {
+            Some(role) => {
+                resolution.used = true;
+                debug!(
+                    "Mapped groups '{}' of user '{}' to role '{}'",
+                    groups.join(","),
+                    id,
+                    role
+                );
+                Ok(role.to_string())
+            }
+            None => {
+                info!(
+                    "User '{}' is not a member of any group in the role map, groups: '{}'",
+                    id,
+                    groups.join(",")
+                );
+                Err(Error::ApiInsufficientRights(format!(
+                    "User '{}' is not a member of any group that maps to a role",
+                    id
+                )))
+            }
+        }
+    }
+
+    /// Returns whether the provider supports the 'email' scope. Assumes that
+    /// it does if the connection to the provider was not initialized yet.
+    fn email_scope_supported(&self) -> bool {
+        self.conn
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|conn| conn.email_scope_supported)
+            .unwrap_or(true)
+    }
+
+    fn init_session_key(config: &Config) -> KrillResult<CryptState> {
+        let key_path = config.data_dir.join(LOGIN_SESSION_STATE_KEY_PATH);
         info!("Initializing session encryption key {}", &key_path.display());
-        crypt::crypt_init(key_path.as_path())
+        crypt::crypt_init(key_path.as_path(), KeyRotation::from_config(config))
     }
 
     fn oidc_conf(&self) -> KrillResult<&ConfigAuthOpenIDConnect> {
@@ -780,6 +1218,58 @@ impl OpenIDConnectAuthProvider {
         Error::ApiLoginError(msg)
     }
 
+    /// Returns the error reported by the provider, if the request is an
+    /// Authentication Error Response rather than an Authorization Response.
+    /// See: https://tools.ietf.org/html/rfc6749#section-4.1.2.1
+    ///      https://openid.net/specs/openid-connect-core-1_0.html#AuthError
+    fn get_auth_error(&self, request: &hyper::Request<hyper::Body>) -> Option<Error> {
+        let query = urlparse(request.uri().to_string()).get_parsed_query()?;
+        let error = query.get_first_from_str("error")?;
+        let description = query.get_first_from_str("error_description");
+        let uri = query.get_first_from_str("error_uri");
+
+        warn!(
+            "OpenID Connect: Received Authentication Error Response: error={}, error_description={}, error_uri={}",
+            error,
+            description.as_deref().unwrap_or("<none>"),
+            uri.as_deref().unwrap_or("<none>")
+        );
+
+        Some(OpenIDConnectAuthProvider::auth_error_response_to_error(
+            &error,
+            description.as_deref(),
+        ))
+    }
+
+    /// Maps the error code of an Authentication Error Response to the error
+    /// to report to the user: whether the user was refused, should try to
+    /// login again, should try again later, or whether Krill or the provider
+    /// need to be reconfigured.
+    fn auth_error_response_to_error(error: &str, description: Option<&str>) -> Error {
+        let msg = match description {
+            Some(description) => format!("Login refused by the provider: {}: {}", error, description),
+            None => format!("Login refused by the provider: {}", error),
+        };
+
+        match error {
+            "access_denied" => Error::ApiInsufficientRights(msg),
+            "login_required" | "interaction_required" | "consent_required" | "account_selection_required" => {
+                Error::ApiLoginError(msg)
+            }
+            "temporarily_unavailable" | "server_error" => Error::ApiAuthTransientError(msg),
+            "invalid_request"
+            | "unauthorized_client"
+            | "unsupported_response_type"
+            | "invalid_scope"
+            | "invalid_request_uri"
+            | "invalid_request_object"
+            | "request_not_supported"
+            | "request_uri_not_supported"
+            | "registration_not_supported" => Error::ApiAuthPermanentError(msg),
+            _ => Error::ApiLoginError(msg),
+        }
+    }
+
     fn get_auth(&self, request: &hyper::Request<hyper::Body>) -> Option<Auth> {
         if let Some(query) = urlparse(request.uri().to_string()).get_parsed_query() {
             if let Some(code) = query.get_first_from_str("code") {
@@ -862,8 +1352,13 @@ impl OpenIDConnectAuthProvider {
         let token_response: FlexibleTokenResponse = conn
             .client
             .exchange_code(AuthorizationCode::new(code.to_string()))
-            .request(logging_http_client)
+            .request(logging_http_client(&self.http_client))
             .map_err(|e| {
+                if let RequestTokenError::Request(Error::ApiAuthProviderTimeout(ref url)) = e {
+                    self.on_connection_issue(lock_guard);
+                    return Error::ApiAuthProviderTimeout(url.clone());
+                }
+
                 let (msg, additional_info) = match e {
                     RequestTokenError::ServerResponse(ref provider_err) => {
                         (format!("Server returned error response: {:?}", provider_err), None)
@@ -915,8 +1410,14 @@ impl OpenIDConnectAuthProvider {
         if self.oidc_conf()?.insecure {
             // This is NOT a good idea. It was needed when testing with
             // one provider and so may be of use to others in future
-            // too.
+            // too. It is only allowed if the operator confirmed it, see
+            // check_insecure.
+            error!("OpenID Connect: INSECURE: the signature of the ID token is NOT verified for this login!");
             id_token_verifier = id_token_verifier.insecure_disable_signature_check();
+        } else if !self.id_token_signing_algs.is_empty() {
+            // Reject tokens signed with any other algorithm, even if the
+            // provider supports it.
+            id_token_verifier = id_token_verifier.set_allowed_algs(self.id_token_signing_algs.clone());
         }
 
         trace!("OpenID Connect: Processing OpenID Connect Core 1.0 section 3.1.3.3 Token Response");
@@ -949,6 +1450,7 @@ impl OpenIDConnectAuthProvider {
         &self,
         token_response: &FlexibleTokenResponse,
     ) -> KrillResult<Option<FlexibleUserInfoClaims>> {
+        let require_signed = self.oidc_conf()?.require_signed_userinfo;
         let lock_guard = self.get_connection()?;
         let conn = lock_guard.deref().as_ref().unwrap(); // safe to unwrap as was tested in get_connection()
 
@@ -966,12 +1468,21 @@ impl OpenIDConnectAuthProvider {
                             Some(&stringify_cause_chain(e)),
                         )
                     })?
-                    // don't require the response to be signed as the spec says
+                    // by default don't require the response to be signed as the spec says
                     // signing it is optional: See: https://openid.net/specs/openid-connect-core-1_0.html#UserInfoResponse
-                    .require_signed_response(false)
-                    .request(logging_http_client)
+                    .require_signed_response(require_signed)
+                    .request(logging_http_client(&self.http_client))
                     .map_err(|e| {
+                        if let UserInfoError::Request(Error::ApiAuthProviderTimeout(ref url)) = e {
+                            self.on_connection_issue(lock_guard);
+                            return Error::ApiAuthProviderTimeout(url.clone());
+                        }
+
                         let msg = match e {
+                            UserInfoError::ClaimsVerification(ClaimsVerificationError::NoSignature) => {
+                                "Provider returned an unsigned response, but require_signed_userinfo is enabled"
+                                    .to_string()
+                            }
                             UserInfoError::ClaimsVerification(ref provider_err) => {
                                 format!("Failed to verify claims: {:?}", provider_err)
                             }
@@ -1015,18 +1526,25 @@ impl OpenIDConnectAuthProvider {
         id_token_claims: &FlexibleIdTokenClaims,
         user_info_claims: Option<FlexibleUserInfoClaims>,
         id: &str,
-    ) -> KrillResult<HashMap<String, String>> {
-        let mut attributes: HashMap<String, String> = HashMap::new();
+        trace: &mut ClaimTrace,
+    ) -> KrillResult<HashMap<String, Vec<String>>> {
+        let mut attributes: HashMap<String, Vec<String>> = HashMap::new();
         for (attr_name, claim_conf) in claims_conf {
-            if attr_name == "id" {
+            if attr_name == "id" || attr_name == DISPLAY_NAME_CLAIM {
                 continue;
             }
+            let resolution = trace.resolution(&attr_name, &claim_conf);
             let attr_value = match &claim_conf.source {
                 Some(ClaimSource::ConfigFile) if user.is_some() => {
                     // Lookup the claim value in the auth_users config file section
-                    user.unwrap().attributes.get(&attr_name.to_string()).cloned()
+                    resolution.searched(ClaimSource::ConfigFile);
+                    let value = user.unwrap().attributes.get(&attr_name.to_string()).cloned();
+                    if let Some(value) = &value {
+                        resolution.matched(ClaimSource::ConfigFile, value);
+                    }
+                    value.map(|value| vec![value])
                 }
-                _ => self.extract_claim(&claim_conf, &id_token_claims, user_info_claims.as_ref())?,
+                _ => self.extract_claim(&claim_conf, &id_token_claims, user_info_claims.as_ref(), resolution)?,
             };
 
             if let Some(attr_value) = attr_value {
@@ -1054,13 +1572,16 @@ impl OpenIDConnectAuthProvider {
                 match attributes.entry(final_attr_name.clone()) {
                     Occupied(found) => {
                         info!("Skipping found value '{}' for claim '{}' as attribute '{}': attribute already has a value: '{}'",
-                            attr_value, attr_name, final_attr_name, found.get());
+                            attr_value.join(","), attr_name, final_attr_name, found.get().join(","));
                     }
                     Vacant(vacant) => {
                         debug!(
                             "Storing found value '{}' for claim '{}' as attribute '{}'",
-                            attr_value, attr_name, final_attr_name
+                            attr_value.join(","),
+                            attr_name,
+                            final_attr_name
                         );
+                        resolution.used = true;
                         vacant.insert(attr_value);
                     }
                 }
@@ -1078,9 +1599,6 @@ impl OpenIDConnectAuthProvider {
 }
 
 impl AuthProvider for OpenIDConnectAuthProvider {
-    // Connect Core 1.0 section 3.1.26 Authentication Error Response
-    // OAuth 2.0 RFC-674 4.1.2.1 (Authorization Request Errors) & 5.2 (Access Token Request Errors)
-
     /// Validate the current login session, extending it with the OIDC provider if needed.
     /// Returns either the session attributes and (if available) the refreshed token, or
     /// an error to report back to the user (one of the ApiAuth* Error types).
@@ -1101,18 +1619,27 @@ impl AuthProvider for OpenIDConnectAuthProvider {
                 // see if we can decode, decrypt and deserialize the users token
                 // into a login session structure
                 let session = self.session_cache.decode(token, &self.session_key, true)?;
+                self.check_session_lifetime(&session)?;
                 let status = session.status();
 
                 // Token found in cache and active; all good, do an early return
                 match status {
                     SessionStatus::Active => {
-                        return Ok(Some(ActorDef::user(session.id, session.attributes, None)));
+                        return Ok(Some(ActorDef::user_with_attributes(
+                            session.id.clone(),
+                            session.actor_attributes(),
+                            None,
+                        )));
                     }
                     SessionStatus::NeedsRefresh => {
                         // If we have a refresh token try and extend the session. Otherwise return the cached token
                         // and continue the login session until it expires.
                         if !session.secrets.contains_key(TokenKind::RefreshToken.into()) {
-                            return Ok(Some(ActorDef::user(session.id, session.attributes, None)));
+                            return Ok(Some(ActorDef::user_with_attributes(
+                                session.id.clone(),
+                                session.actor_attributes(),
+                                None,
+                            )));
                         }
                     }
                     SessionStatus::Expired => {
@@ -1133,63 +1660,14 @@ impl AuthProvider for OpenIDConnectAuthProvider {
                         );
                         auth
                     }
-                    Err(err) => {
-                        trace!("OpenID Connect: RFC 6749 5.2 Error response returned...");
-                        debug!(
-                            "OpenID Connect: Refreshing the token for user '{}' failed: {}",
-                            &session.id, &err
-                        );
-                        match err {
-                            // This is the Error returned by the OpenID Connect Provider if the session was terminated
-                            // by them. The user should be able to create a new session by logging in again.
-                            CoreErrorResponseType::InvalidGrant => {
-                                warn!("OpenID Connect: invalid_grant {:?}", err);
-                                return Err(Error::ApiInvalidCredentials(
-                                    "Unable to extend login session: your session has been terminated.".to_string(),
-                                ));
-                            }
-                            CoreErrorResponseType::InvalidRequest | CoreErrorResponseType::InvalidClient => {
-                                warn!("OpenID Connect: RFC 6749 5.2 {:?}", err);
-                                return Err(Error::ApiAuthPermanentError(
-                                    "Unable to extend login session: the provider rejected the request.".to_string(),
-                                ));
-                            }
-                            // If changes are made to the roles of the user, the client or
-                            // the scope on the side of the OpenID Connect Provider,
-                            // the token refresh may get one of these errors.
-                            CoreErrorResponseType::UnauthorizedClient
-                            | CoreErrorResponseType::UnsupportedGrantType
-                            | CoreErrorResponseType::InvalidScope => {
-                                warn!("OpenID Connect: RFC 6749 5.2 {:?}", err);
-                                return Err(Error::ApiInsufficientRights(
-                                    "Unable to extend login session: the authorization was revoked for this user, client or action.".to_string(),
-                                ));
-                            }
-                            // The Extension Type Errors are used by the try_refresh_token
-                            // method to signal generic problems with either the current
-                            // token, or the freshly received one. Additionally the two
-                            // error responses from [Errata for RFC 6749]
-                            // (https://www.rfc-editor.org/errata/eid4745),
-                            // "temporarily_unavailable" and "server_error", end up here.
-                            CoreErrorResponseType::Extension(err) => match err.as_str() {
-                                "temporarily_unavailable" | "server_error" => {
-                                    warn!("OpenID Connect: RFC 6749 5.2 {:?}", err);
-                                    return Err(Error::ApiAuthTransientError(
-                                        "Unable to extend login session: could not contact the provider".to_string(),
-                                    ));
-                                }
-                                _ => {
-                                    warn!("OpenID Connect: RFC 6749 5.2 unknown error {:?}", err);
-                                    return Err(Error::ApiAuthTransientError(
-                                        "Unable to extend login session: unknown error".to_string(),
-                                    ));
-                                }
-                            },
-                        }
-                    }
+                    Err(err) => return Err(refresh_error(&session, err)),
                 };
 
-                Ok(Some(ActorDef::user(session.id, session.attributes, Some(new_auth))))
+                Ok(Some(ActorDef::user_with_attributes(
+                    session.id.clone(),
+                    session.actor_attributes(),
+                    Some(new_auth),
+                )))
             }
             _ => Ok(None),
         };
@@ -1201,12 +1679,57 @@ impl AuthProvider for OpenIDConnectAuthProvider {
         res
     }
 
+    fn session_expires_in_secs(&self, request: &hyper::Request<hyper::Body>) -> Option<u64> {
+        let token = self.get_bearer_token(request)?;
+        let session = self.session_cache.decode(token, &self.session_key, true).ok()?;
+        session.expires_in_secs()
+    }
+
+    /// Refresh the session of the request now, rather than waiting for it to
+    /// be refreshed implicitly when it is about to expire.
+    fn refresh(&self, request: &hyper::Request<hyper::Body>) -> KrillResult<LoggedInUser> {
+        let token = self
+            .get_bearer_token(request)
+            .ok_or_else(|| Error::ApiInvalidCredentials("Missing session token".to_string()))?;
+
+        let session = self.session_cache.decode(token.clone(), &self.session_key, false)?;
+        self.check_session_lifetime(&session)?;
+        if !session.secrets.contains_key(TokenKind::RefreshToken.into()) {
+            return Err(Error::ApiInvalidCredentials(
+                "Unable to extend login session: no token to be refreshed".to_string(),
+            ));
+        }
+
+        self.initialize_connection_if_needed().map_err(|err| {
+            OpenIDConnectAuthProvider::internal_error(
+                "OpenID Connect: Cannot refresh session: Failed to connect to provider",
+                Some(&stringify_cause_chain(err)),
+            )
+        })?;
+
+        match self.try_refresh_token(&session) {
+            Ok(Auth::Bearer(new_token)) => {
+                self.session_cache.remove(&token);
+                Ok(LoggedInUser {
+                    token: new_token,
+                    id: session.id,
+                    display_name: session.display_name,
+                    attributes: session.attributes,
+                })
+            }
+            Ok(_) => Err(Error::custom(
+                "Internal error: token refresh did not return a bearer token",
+            )),
+            Err(err) => Err(refresh_error(&session, err)),
+        }
+    }
+
     /// Generate the login URL that the client should direct the end-user to so
     /// they can login with the operators chosen OpenID Connect: provider. The
     /// URL should be requested by the client on every login as the intention is
     /// that it contains randomly generated CSFF token and nonce values which
     /// can be used to protect against certain cross-site and replay attacks.
-    fn get_login_url(&self) -> KrillResult<HttpResponse> {
+    fn get_login_url(&self, login_request: &hyper::Request<hyper::Body>) -> KrillResult<HttpResponse> {
         // TODO: we probably should do some more work here to ensure we get the
         // proper security benefits of the CSRF token, currently we are
         // discarding the CSRF token instead of checking it.
@@ -1340,7 +1863,7 @@ impl AuthProvider for OpenIDConnectAuthProvider {
             request = request.add_scope(Scope::new(scope.clone()));
         }
 
-        for (k, v) in oidc_conf.extra_login_params.iter() {
+        for (k, v) in login_params(oidc_conf, login_request)? {
             request = request.add_extra_param(k, v);
         }
 
@@ -1405,6 +1928,10 @@ impl AuthProvider for OpenIDConnectAuthProvider {
             )
         })?;
 
+        if let Some(err) = self.get_auth_error(request) {
+            return Err(err);
+        }
+
         match self.get_auth(request) {
             // OpenID Connect Authorization Code Flow
             // See: https://tools.ietf.org/html/rfc6749#section-4.1
@@ -1516,24 +2043,16 @@ impl AuthProvider for OpenIDConnectAuthProvider {
                 // configuration without the "id" key :-)
                 // ==========================================================================================
 
-                let claims_conf = with_default_claims(&self.oidc_conf()?.claims);
-
-                let id_claim_conf = claims_conf.get("id").ok_or_else(|| {
-                    OpenIDConnectAuthProvider::internal_error("Missing 'id' claim configuration", None)
-                })?;
-
-                let id = self
-                    .extract_claim(&id_claim_conf, &id_token_claims, user_info_claims.as_ref())?
-                    .ok_or_else(|| OpenIDConnectAuthProvider::internal_error("No value found for 'id' claim", None))?;
-
-                // Lookup the a user in the config file authentication provider
-                // configuration by the id value that we just obtained, if
-                // present. Any claim configurations that refer to attributes of
-                // users configured in the config file will be looked up on this
-                // user.
-                let user = self.config.auth_users.as_ref().and_then(|users| users.get(&id));
-
-                let attributes = self.resolve_claims(claims_conf, user, id_token_claims, user_info_claims, &id)?;
+                let mut trace = ClaimTrace::new();
+                let res = self.resolve_user(id_token_claims, user_info_claims, &mut trace);
+                if self.oidc_conf()?.claim_trace {
+                    self.claim_traces.add(trace);
+                }
+                let ResolvedUser {
+                    id,
+                    display_name,
+                    attributes: attribute_values,
+                } = res?;
 
                 // ==========================================================================================
                 // Step 5: Respond to the user: access granted, or access denied
@@ -1557,9 +2076,10 @@ impl AuthProvider for OpenIDConnectAuthProvider {
                 // time of 1800 seconds or 30 minutes, so attempting to refresh
                 // an access token after that much time would also fail.
                 // ==========================================================================================
-                let api_token = self.session_cache.encode(
+                let api_token = self.session_cache.encode_with_values(
                     &id,
-                    &attributes,
+                    display_name.as_deref(),
+                    &attribute_values,
                     secrets_from_token_response(&token_response),
                     &self.session_key,
                     token_response.expires_in(),
@@ -1568,7 +2088,8 @@ impl AuthProvider for OpenIDConnectAuthProvider {
                 Ok(LoggedInUser {
                     token: api_token,
                     id,
-                    attributes,
+                    display_name,
+                    attributes: Attributes::MultiValued(attribute_values).as_map(),
                 })
             }
 
@@ -1656,22 +2177,27 @@ impl AuthProvider for OpenIDConnectAuthProvider {
             LogoutMode::RPInitiatedLogout {
                 provider_url,
                 post_logout_redirect_url,
+                send_post_logout_redirect,
             } => {
                 trace!("OpenID Connect: Directing user to RP-Initiated Logout 1.0 compliant logout endpoint");
 
                 let id_token = session.secrets.get(TokenKind::IdToken.into());
+                let redirect = if *send_post_logout_redirect {
+                    Some(post_logout_redirect_url.as_str())
+                } else {
+                    None
+                };
 
-                self.build_rpinitiated_logout_url(provider_url, post_logout_redirect_url, id_token)
-                    .unwrap_or_else(|err| {
-                        OpenIDConnectAuthProvider::internal_error(
-                            format!(
-                                "Error while building OpenID Connect RP-Initiated Logout URL for user '{}'",
-                                session.id
-                            ),
-                            Some(stringify_cause_chain(err)),
-                        );
-                        post_logout_redirect_url.clone()
-                    })
+                // If the provider logout cannot work, the session was still removed locally above, so fall
+                // back to sending the user to the Krill UI.
+                Self::build_rpinitiated_logout_url(provider_url, redirect, id_token).unwrap_or_else(|err| {
+                    warn!(
+                        "OpenID Connect: Logging out user '{}' locally only: {}",
+                        session.id,
+                        stringify_cause_chain(err)
+                    );
+                    post_logout_redirect_url.clone()
+                })
             }
         };
 
@@ -1680,6 +2206,66 @@ impl AuthProvider for OpenIDConnectAuthProvider {
     }
 }
 
+/// Maps the error of a failed attempt to refresh the token of the given session
+/// to the error to report to the client.
+fn refresh_error(session: &ClientSession, err: CoreErrorResponseType) -> Error {
+    trace!("OpenID Connect: RFC 6749 5.2 Error response returned...");
+    debug!(
+        "OpenID Connect: Refreshing the token for user '{}' failed: {}",
+        &session.id, &err
+    );
+    match err {
+        // This is the Error returned by the OpenID Connect Provider if the session was terminated
+        // by them. The user should be able to create a new session by logging in again.
+        CoreErrorResponseType::InvalidGrant => {
+            warn!("OpenID Connect: invalid_grant {:?}", err);
+            Error::ApiInvalidCredentials(
+                "Unable to extend login session: your session has been terminated.".to_string(),
+            )
+        }
+        CoreErrorResponseType::InvalidRequest | CoreErrorResponseType::InvalidClient => {
+            warn!("OpenID Connect: RFC 6749 5.2 {:?}", err);
+            Error::ApiAuthPermanentError(
+                "Unable to extend login session: the provider rejected the request.".to_string(),
+            )
+        }
+        // If changes are made to the roles of the user, the client or
+        // the scope on the side of the OpenID Connect Provider,
+        // the token refresh may get one of these errors.
+        CoreErrorResponseType::UnauthorizedClient
+        | CoreErrorResponseType::UnsupportedGrantType
+        | CoreErrorResponseType::InvalidScope => {
+            warn!("OpenID Connect: RFC 6749 5.2 {:?}", err);
+            Error::ApiInsufficientRights(
+                "Unable to extend login session: the authorization was revoked for this user, client or action."
+                    .to_string(),
+            )
+        }
+        // The Extension Type Errors are used by the try_refresh_token
+        // method to signal generic problems with either the current
+        // token, or the freshly received one. Additionally the two
+        // error responses from [Errata for RFC 6749]
+        // (https://www.rfc-editor.org/errata/eid4745),
+        // "temporarily_unavailable" and "server_error", end up here.
+        CoreErrorResponseType::Extension(err) => match err.as_str() {
+            PROVIDER_TIMEOUT => {
+                warn!("OpenID Connect: timed out while refreshing the token");
+                Error::ApiAuthProviderTimeout("unable to extend login session".to_string())
+            }
+            "temporarily_unavailable" | "server_error" => {
+                warn!("OpenID Connect: RFC 6749 5.2 {:?}", err);
+                Error::ApiAuthTransientError(
+                    "Unable to extend login session: could not contact the provider".to_string(),
+                )
+            }
+            _ => {
+                warn!("OpenID Connect: RFC 6749 5.2 unknown error {:?}", err);
+                Error::ApiAuthTransientError("Unable to extend login session: unknown error".to_string())
+            }
+        },
+    }
+}
+
 fn secrets_from_token_response(token_response: &FlexibleTokenResponse) -> HashMap<String, String> {
     let mut secrets: HashMap<String, String> = HashMap::new();
 
@@ -1705,21 +2291,110 @@ fn with_default_claims(claims: &Option<ConfigAuthOpenIDConnectClaims>) -> Config
         None => ConfigAuthOpenIDConnectClaims::new(),
     };
 
+    let default_id_claim = if claims.contains_key(DISPLAY_NAME_CLAIM) {
+        FALLBACK_ID_CLAIM
+    } else {
+        DEFAULT_ID_CLAIM
+    };
+
     claims.entry("id".into()).or_insert(ConfigAuthOpenIDConnectClaim {
         source: None,
-        jmespath: Some("email".to_string()),
+        jmespath: Some(default_id_claim.to_string()),
         dest: None,
+        on_multiple: ConfigAuthOpenIDConnectOnMultiple::default(),
     });
 
     claims.entry("role".into()).or_insert(ConfigAuthOpenIDConnectClaim {
         source: None,
         jmespath: Some("role".to_string()),
         dest: None,
+        on_multiple: ConfigAuthOpenIDConnectOnMultiple::default(),
     });
 
     claims
 }
 
+/// Compiled JMESPath expressions by their source text.
+type ClaimExpressions = HashMap<String, jmespath::Expression<'static>>;
+
+/// Compiles the JMESPath expressions of all claims which are looked up in
+/// the tokens, including the fallback id claim and the groups claim of the
+/// role map. Returns the compiled expressions, and a description of each
+/// claim which is not valid.
+fn compile_claim_expressions(
+    claims: &Option<ConfigAuthOpenIDConnectClaims>,
+    role_map: Option<&ConfigAuthOpenIDConnectRoleMap>,
+) -> (ClaimExpressions, Vec<String>) {
+    let mut claims: Vec<_> = with_default_claims(claims).into_iter().collect();
+    claims.sort_by(|a, b| a.0.cmp(&b.0));
+    if let Some(role_map) = role_map {
+        claims.push(("role_map.groups_claim".to_string(), role_map.groups_claim_conf()));
+    }
+
+    let mut exprs = ClaimExpressions::new();
+    let mut problems = vec![];
+
+    let fallback = FALLBACK_ID_CLAIM.to_string();
+    exprs.insert(fallback.clone(), jmespathext::runtime().compile(&fallback).unwrap());
+
+    for (name, claim_conf) in claims {
+        if let Some(ClaimSource::ConfigFile) = claim_conf.source {
+            continue;
+        }
+        match claim_conf.jmespath {
+            Some(expr) => match jmespathext::runtime().compile(&expr) {
+                Ok(compiled) => {
+                    exprs.insert(expr, compiled);
+                }
+                Err(e) => problems.push(format!(
+                    "OpenID Connect: Invalid JMESPath expression '{}' for claim '{}': {}",
+                    expr,
+                    name,
+                    stringify_cause_chain(e)
+                )),
+            },
+            None => problems.push(format!(
+                "OpenID Connect: Missing JMESPath expression for claim '{}'",
+                name
+            )),
+        }
+    }
+    (exprs, problems)
+}
+
+/// Returns true if no 'id' claim is configured, so the default is used.
+fn uses_default_id_claim(claims: &Option<ConfigAuthOpenIDConnectClaims>) -> bool {
+    claims.as_ref().map(|claims| !claims.contains_key("id")).unwrap_or(true)
+}
+
+/// Returns true if a 'display_name' claim is configured.
+fn uses_display_name_claim(claims: &Option<ConfigAuthOpenIDConnectClaims>) -> bool {
+    claims
+        .as_ref()
+        .map(|claims| claims.contains_key(DISPLAY_NAME_CLAIM))
+        .unwrap_or(false)
+}
+
+/// Returns the claim to use for the user id if the default 'id' claim has no
+/// value. This is only done when the default is used and the provider does
+/// not support the 'email' scope, so that users of a provider which supports
+/// it never end up with a different id.
+fn fallback_id_claim(
+    claims: &Option<ConfigAuthOpenIDConnectClaims>,
+    email_scope_supported: bool,
+) -> Option<ConfigAuthOpenIDConnectClaim> {
+    if email_scope_supported || !uses_default_id_claim(claims) || uses_display_name_claim(claims) {
+        None
+    } else {
+        Some(ConfigAuthOpenIDConnectClaim {
+            source: None,
+            jmespath: Some(FALLBACK_ID_CLAIM.to_string()),
+            dest: None,
+            on_multiple: ConfigAuthOpenIDConnectOnMultiple::default(),
+        })
+    }
+}
+
 // Based on: https://github.com/ramosbugs/openidconnect-rs/blob/main/examples/google.rs#L38
 pub fn stringify_cause_chain<F: std::error::Error>(fail: F) -> String {
     let mut cause_chain = String::new();
@@ -1733,3 +2408,370 @@ pub fn stringify_cause_chain<F: std::error::Error>(fail: F) -> String {
     }
     cause_chain
 }
+
+/// Returns the extra parameters to send with an authorization request.
+///
+/// These are the 'extra_login_params', overridden by the params in the set
+/// named by the 'param_set' query parameter of the login request, if any.
+/// Finally the client may set the values of the params listed in
+/// 'client_login_params' by using them as query parameters, e.g. to give a
+/// 'login_hint'. Other query parameters are ignored, so the client cannot add
+/// arbitrary parameters to the request.
+fn login_params(
+    oidc_conf: &ConfigAuthOpenIDConnect,
+    request: &hyper::Request<hyper::Body>,
+) -> KrillResult<HashMap<String, String>> {
+    let mut params = oidc_conf.extra_login_params.clone();
+
+    if let Some(query) = urlparse(request.uri().to_string()).get_parsed_query() {
+        if let Some(name) = query.get_first_from_str(LOGIN_PARAM_SET_QUERY_PARAM) {
+            match oidc_conf.extra_login_param_sets.get(&name) {
+                Some(set) => params.extend(set.clone()),
+                None => return Err(Error::custom(format!("Unknown login parameter set '{}'", name))),
+            }
+        }
+
+        for key in &oidc_conf.client_login_params {
+            if let Some(value) = query.get_first_from_str(key) {
+                params.insert(key.clone(), value);
+            }
+        }
+    }
+
+    Ok(params)
+}
+
+/// Returns the algorithms that ID tokens must be signed with, as pinned by the
+/// 'id_token_signing_algs' setting. An empty list means that the defaults of
+/// the token verifier apply. Unknown algorithms and 'none' are rejected.
+fn id_token_signing_algs(oidc_conf: &ConfigAuthOpenIDConnect) -> KrillResult<Vec<CoreJwsSigningAlgorithm>> {
+    let mut algs = vec![];
+    for name in &oidc_conf.id_token_signing_algs {
+        let alg: CoreJwsSigningAlgorithm =
+            serde_json::from_value(serde_json::Value::String(name.clone())).map_err(|_| {
+                Error::ConfigError(format!(
+                    "OpenID Connect: Unsupported algorithm '{}' in id_token_signing_algs",
+                    name
+                ))
+            })?;
+        if alg == CoreJwsSigningAlgorithm::None {
+            return Err(Error::ConfigError(
+                "OpenID Connect: 'none' cannot be used in id_token_signing_algs".to_string(),
+            ));
+        }
+        algs.push(alg);
+    }
+    Ok(algs)
+}
+
+/// Refuses the 'insecure' setting, which disables the verification of ID
+/// token signatures, unless 'insecure_i_really_mean_it' confirms it. This
+/// makes it unlikely that a config meant for testing ends up in production.
+fn check_insecure(oidc_conf: &ConfigAuthOpenIDConnect) -> KrillResult<()> {
+    if oidc_conf.insecure {
+        if !oidc_conf.insecure_i_really_mean_it {
+            return Err(Error::ConfigError(
+                "OpenID Connect: 'insecure' disables the verification of ID token signatures, and also requires \
+                'insecure_i_really_mean_it = true'"
+                    .to_string(),
+            ));
+        }
+        error!("OpenID Connect: INSECURE: the signatures of ID tokens will NOT be verified!");
+    }
+    Ok(())
+}
+
+/// Returns the configured scopes which are not in the scopes supported by the
+/// provider, or None if the provider does not advertise the scopes it supports.
+fn unsupported_scopes(configured: &[String], supported: Option<&Vec<Scope>>) -> Option<Vec<String>> {
+    supported.map(|supported| {
+        configured
+            .iter()
+            .filter(|scope| !supported.iter().any(|s| s.as_str() == scope.as_str()))
+            .cloned()
+            .collect()
+    })
+}
+
+/// Verifies that the client cannot override the parameters that Krill sets.
+fn check_client_login_params(oidc_conf: &ConfigAuthOpenIDConnect) -> KrillResult<()> {
+    for key in &oidc_conf.client_login_params {
+        if key == LOGIN_PARAM_SET_QUERY_PARAM || RESERVED_LOGIN_PARAMS.contains(&key.as_str()) {
+            return Err(Error::ConfigError(format!(
+                "OpenID Connect: '{}' cannot be used in client_login_params",
+                key
+            )));
+        }
+    }
+    Ok(())
+}
+
+//------------ Tests ---------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+
+    use super::super::config::ConfigAuthOpenIDConnectGroupRole;
+    use super::*;
+
+    #[test]
+    fn rpinitiated_logout_url() {
+        let build = OpenIDConnectAuthProvider::build_rpinitiated_logout_url;
+        let id_token = "a.b.c".to_string();
+
+        assert_eq!(
+            build("https://op/logout", Some("https://krill/"), Some(&id_token)).unwrap(),
+            "https://op/logout?post_logout_redirect_uri=https%3A%2F%2Fkrill%2F&id_token_hint=a.b.c"
+        );
+
+        // Post-logout redirection requires the id token
+        assert!(build("https://op/logout", Some("https://krill/"), None).is_err());
+
+        // Without redirection the id token is sent if we have it
+        assert_eq!(
+            build("https://op/logout?client=x", None, Some(&id_token)).unwrap(),
+            "https://op/logout?client=x&id_token_hint=a.b.c"
+        );
+        assert_eq!(build("https://op/logout", None, None).unwrap(), "https://op/logout");
+    }
+
+    #[test]
+    fn claim_found_in_multiple_sources() {
+        let select = OpenIDConnectAuthProvider::select_claim_values;
+        let id_token = ClaimSource::IdTokenStandardClaim;
+        let user_info = ClaimSource::UserInfoStandardClaim;
+        let values = |v: &str| vec![v.to_string()];
+
+        let matches = || vec![(&id_token, values("a")), (&user_info, values("b"))];
+        let first = select("role", ConfigAuthOpenIDConnectOnMultiple::First, matches()).unwrap();
+        assert_eq!(first.unwrap().1, values("a"));
+        let last = select("role", ConfigAuthOpenIDConnectOnMultiple::Last, matches()).unwrap();
+        assert_eq!(last.unwrap().1, values("b"));
+        assert!(select("role", ConfigAuthOpenIDConnectOnMultiple::Fail, matches()).is_err());
+
+        // The same value in several sources is not ambiguous
+        let same = vec![(&id_token, values("a")), (&user_info, values("a"))];
+        let selected = select("role", ConfigAuthOpenIDConnectOnMultiple::Fail, same).unwrap();
+        assert_eq!(selected.unwrap().1, values("a"));
+
+        assert!(select("role", ConfigAuthOpenIDConnectOnMultiple::Last, vec![])
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn extra_scopes_are_validated() {
+        let configured = vec!["profile".to_string(), "profiel".to_string()];
+        let supported = vec![Scope::new("openid".to_string()), Scope::new("profile".to_string())];
+
+        assert_eq!(
+            unsupported_scopes(&configured, Some(&supported)),
+            Some(vec!["profiel".to_string()])
+        );
+        assert_eq!(unsupported_scopes(&[], Some(&supported)), Some(vec![]));
+
+        // Nothing can be validated if the provider does not advertise scopes
+        assert_eq!(unsupported_scopes(&configured, None), None);
+    }
+
+    #[test]
+    fn auth_error_responses_are_mapped() {
+        let map = OpenIDConnectAuthProvider::auth_error_response_to_error;
+
+        match map("access_denied", Some("User cancelled")) {
+            Error::ApiInsufficientRights(msg) => assert!(msg.contains("User cancelled")),
+            e => panic!("Unexpected error: {}", e),
+        }
+        assert!(matches!(map("login_required", None), Error::ApiLoginError(_)));
+        assert!(matches!(
+            map("temporarily_unavailable", None),
+            Error::ApiAuthTransientError(_)
+        ));
+        assert!(matches!(map("invalid_scope", None), Error::ApiAuthPermanentError(_)));
+        assert!(matches!(map("something_else", None), Error::ApiLoginError(_)));
+    }
+
+    #[test]
+    fn login_params_selected_by_client() {
+        let oidc_conf: ConfigAuthOpenIDConnect = toml::from_str(
+            r#"
+            issuer_url = "https://idp"
+            client_id = "krill"
+            client_secret = "secret"
+            client_login_params = ["login_hint"]
+
+            [extra_login_params]
+            display = "page"
+            kc_idp_hint = "default"
+
+            [extra_login_param_sets.partner]
+            kc_idp_hint = "partner"
+            "#,
+        )
+        .unwrap();
+        check_client_login_params(&oidc_conf).unwrap();
+
+        let params = |uri: &str| {
+            let request = hyper::Request::get(uri).body(hyper::Body::empty()).unwrap();
+            login_params(&oidc_conf, &request)
+        };
+
+        let defaults = params("/auth/login").unwrap();
+        assert_eq!(defaults, oidc_conf.extra_login_params);
+
+        let partner = params("/auth/login?param_set=partner&login_hint=alice&redirect_uri=https://evil").unwrap();
+        assert_eq!(partner.get("kc_idp_hint").unwrap(), "partner");
+        assert_eq!(partner.get("display").unwrap(), "page");
+        assert_eq!(partner.get("login_hint").unwrap(), "alice");
+        assert!(!partner.contains_key("redirect_uri"));
+
+        assert!(params("/auth/login?param_set=unknown").is_err());
+
+        let mut reserved = oidc_conf.clone();
+        reserved.client_login_params.push("redirect_uri".to_string());
+        assert!(matches!(
+            check_client_login_params(&reserved),
+            Err(Error::ConfigError(_))
+        ));
+    }
+
+    #[test]
+    fn insecure_requires_confirmation() {
+        let conf = |extra: &str| -> ConfigAuthOpenIDConnect {
+            toml::from_str(&format!(
+                r#"
+                issuer_url = "https://idp"
+                client_id = "krill"
+                client_secret = "secret"
+                {}
+                "#,
+                extra
+            ))
+            .unwrap()
+        };
+
+        assert!(check_insecure(&conf("")).is_ok());
+        assert!(matches!(
+            check_insecure(&conf("insecure = true")),
+            Err(Error::ConfigError(_))
+        ));
+        assert!(check_insecure(&conf("insecure = true\ninsecure_i_really_mean_it = true")).is_ok());
+    }
+
+    #[test]
+    fn pinned_id_token_signing_algs() {
+        let conf = |algs: &str| -> ConfigAuthOpenIDConnect {
+            toml::from_str(&format!(
+                r#"
+                issuer_url = "https://idp"
+                client_id = "krill"
+                client_secret = "secret"
+                id_token_signing_algs = [{}]
+                "#,
+                algs
+            ))
+            .unwrap()
+        };
+
+        assert!(id_token_signing_algs(&conf("")).unwrap().is_empty());
+        assert_eq!(
+            id_token_signing_algs(&conf(r#""RS256", "ES256""#)).unwrap(),
+            vec![
+                CoreJwsSigningAlgorithm::RsaSsaPkcs1V15Sha256,
+                CoreJwsSigningAlgorithm::EcdsaP256Sha256
+            ]
+        );
+        assert!(matches!(
+            id_token_signing_algs(&conf(r#""none""#)),
+            Err(Error::ConfigError(_))
+        ));
+        assert!(matches!(
+            id_token_signing_algs(&conf(r#""RS1""#)),
+            Err(Error::ConfigError(_))
+        ));
+    }
+
+    #[test]
+    fn sub_is_fallback_for_default_id_claim_without_email_scope() {
+        let fallback = fallback_id_claim(&None, false).unwrap();
+        assert_eq!(fallback.jmespath.as_deref(), Some(FALLBACK_ID_CLAIM));
+
+        // Never fall back if the provider supports the email scope
+        assert!(fallback_id_claim(&None, true).is_none());
+
+        // Or if the operator configured the id claim, even if it uses email
+        let mut claims = ConfigAuthOpenIDConnectClaims::new();
+        claims.insert(
+            "id".to_string(),
+            ConfigAuthOpenIDConnectClaim {
+                source: None,
+                jmespath: Some("email".to_string()),
+                dest: None,
+                on_multiple: ConfigAuthOpenIDConnectOnMultiple::default(),
+            },
+        );
+        assert!(fallback_id_claim(&Some(claims), false).is_none());
+
+        // Or if a display name claim is configured, as the id then is 'sub'
+        let mut claims = ConfigAuthOpenIDConnectClaims::new();
+        claims.insert(
+            DISPLAY_NAME_CLAIM.to_string(),
+            ConfigAuthOpenIDConnectClaim {
+                source: None,
+                jmespath: Some("name".to_string()),
+                dest: None,
+                on_multiple: ConfigAuthOpenIDConnectOnMultiple::default(),
+            },
+        );
+        assert!(fallback_id_claim(&Some(claims), false).is_none());
+    }
+
+    #[test]
+    fn invalid_claim_expressions_are_reported() {
+        let (exprs, problems) = compile_claim_expressions(&None, None);
+        assert!(problems.is_empty());
+        assert!(exprs.contains_key(DEFAULT_ID_CLAIM));
+        assert!(exprs.contains_key(FALLBACK_ID_CLAIM));
+
+        let claim = |source, jmespath: Option<&str>| ConfigAuthOpenIDConnectClaim {
+            source,
+            jmespath: jmespath.map(|s| s.to_string()),
+            dest: None,
+            on_multiple: ConfigAuthOpenIDConnectOnMultiple::default(),
+        };
+
+        let mut claims = ConfigAuthOpenIDConnectClaims::new();
+        claims.insert("id".to_string(), claim(None, Some("recap(email, '(.+)@')")));
+        claims.insert("role".to_string(), claim(None, Some("groups[?")));
+        claims.insert("team".to_string(), claim(None, None));
+        claims.insert("inc".to_string(), claim(Some(ClaimSource::ConfigFile), None));
+
+        let (exprs, problems) = compile_claim_expressions(&Some(claims), None);
+        assert!(exprs.contains_key("recap(email, '(.+)@')"));
+        assert_eq!(problems.len(), 2);
+        assert!(problems[0].contains("'role'"));
+        assert!(problems[1].contains("'team'"));
+    }
+
+    #[test]
+    fn groups_map_to_first_matching_role() {
+        let mapping = |group: &str, role: &str| ConfigAuthOpenIDConnectGroupRole {
+            group: group.to_string(),
+            role: role.to_string(),
+        };
+        let mut role_map = ConfigAuthOpenIDConnectRoleMap {
+            groups_claim: "groups".to_string(),
+            groups: vec![mapping("rpki-admins", "admin"), mapping("rpki-ops", "readwrite")],
+            default_role: None,
+        };
+        let groups = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(role_map.role_for(&groups(&["rpki-ops", "rpki-admins"])), Some("admin"));
+        assert_eq!(role_map.role_for(&groups(&["staff", "rpki-ops"])), Some("readwrite"));
+        assert_eq!(role_map.role_for(&groups(&["staff"])), None);
+        assert_eq!(role_map.role_for(&[]), None);
+
+        role_map.default_role = Some("readonly".to_string());
+        assert_eq!(role_map.role_for(&groups(&["staff"])), Some("readonly"));
+    }
+}
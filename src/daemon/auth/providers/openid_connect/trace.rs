@@ -0,0 +1,129 @@
+//! Records how the attributes of users who logged in were resolved from the
+//! claims in the OpenID Connect provider responses.
+//!
+//! This helps operators to find out why a user got the wrong attributes, e.g.
+//! when onboarding a provider whose claim layout is not yet known. Only the
+//! claim configuration, the names of the searched sources and the resolved
+//! values are recorded. The claims themselves, and tokens, are never kept.
+use std::collections::VecDeque;
+use std::sync::RwLock;
+
+use rpki::x509::Time;
+
+use super::config::ConfigAuthOpenIDConnectClaim;
+
+/// The number of most recent logins for which the trace is kept.
+const MAX_TRACES: usize = 20;
+
+//------------ ClaimResolution -----------------------------------------------
+
+/// How the value for a single configured claim was resolved.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ClaimResolution {
+    pub claim: String,
+    pub dest: Option<String>,
+    pub jmespath: Option<String>,
+    pub searched: Vec<String>,
+    pub matched: Option<String>,
+    pub value: Option<String>,
+    pub used: bool,
+}
+
+impl ClaimResolution {
+    pub fn searched(&mut self, source: impl ToString) {
+        self.searched.push(source.to_string());
+    }
+
+    pub fn matched(&mut self, source: impl ToString, value: &str) {
+        self.matched = Some(source.to_string());
+        self.value = Some(value.to_string());
+    }
+}
+
+//------------ ClaimTrace ----------------------------------------------------
+
+/// The claim resolutions for a single login.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ClaimTrace {
+    pub time: i64,
+    pub id: Option<String>,
+    pub claims: Vec<ClaimResolution>,
+}
+
+impl ClaimTrace {
+    pub fn new() -> Self {
+        ClaimTrace {
+            time: Time::now().timestamp(),
+            id: None,
+            claims: vec![],
+        }
+    }
+
+    /// Starts the resolution of the given claim, and returns it so that the
+    /// search can be recorded in it.
+    pub fn resolution(&mut self, claim: &str, conf: &ConfigAuthOpenIDConnectClaim) -> &mut ClaimResolution {
+        self.claims.push(ClaimResolution {
+            claim: claim.to_string(),
+            dest: conf.dest.clone(),
+            jmespath: conf.jmespath.clone(),
+            ..Default::default()
+        });
+        self.claims.last_mut().unwrap()
+    }
+}
+
+impl Default for ClaimTrace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+//------------ ClaimTraces ---------------------------------------------------
+
+/// Keeps the claim traces of the most recent logins.
+#[derive(Debug, Default)]
+pub struct ClaimTraces {
+    traces: RwLock<VecDeque<ClaimTrace>>,
+}
+
+impl ClaimTraces {
+    pub fn new() -> Self {
+        ClaimTraces::default()
+    }
+
+    pub fn add(&self, trace: ClaimTrace) {
+        let mut traces = self.traces.write().unwrap();
+        if traces.len() == MAX_TRACES {
+            traces.pop_back();
+        }
+        traces.push_front(trace);
+    }
+
+    /// Returns the kept traces, most recent login first.
+    pub fn list(&self) -> Vec<ClaimTrace> {
+        self.traces.read().unwrap().iter().cloned().collect()
+    }
+}
+
+//------------ Tests ---------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn keeps_most_recent_traces() {
+        let traces = ClaimTraces::new();
+        for i in 0..MAX_TRACES + 2 {
+            let mut trace = ClaimTrace::new();
+            trace.id = Some(i.to_string());
+            traces.add(trace);
+        }
+
+        let list = traces.list();
+        assert_eq!(list.len(), MAX_TRACES);
+        assert_eq!(list[0].id, Some((MAX_TRACES + 1).to_string()));
+        assert_eq!(list[MAX_TRACES - 1].id, Some("2".to_string()));
+    }
+}
@@ -437,6 +437,26 @@ impl Aggregate for CertAuth {
             CmdDet::RtaSign(name, request, signer) => self.rta_sign(name, request, signer.deref()),
         }
     }
+
+    fn record_effect(details: &mut StorableCaCommand, events: &[CaEvt]) {
+        let created_keys = match details {
+            StorableCaCommand::GenerateNewIdKey { created_keys }
+            | StorableCaCommand::UpdateResourceEntitlements { created_keys, .. }
+            | StorableCaCommand::KeyRollInitiate { created_keys, .. } => created_keys,
+            _ => return,
+        };
+
+        if let Some(created_keys) = created_keys {
+            for event in events {
+                match event.details() {
+                    CaEvtDet::IdUpdated { id } => created_keys.add(id.key_id()),
+                    CaEvtDet::ResourceClassAdded { pending_key, .. } => created_keys.add(*pending_key),
+                    CaEvtDet::KeyRollPendingKeyAdded { pending_key_id, .. } => created_keys.add(*pending_key_id),
+                    _ => {}
+                }
+            }
+        }
+    }
 }
 
 /// # Data presentation
@@ -509,6 +529,16 @@ impl CertAuth {
         &self.handle
     }
 
+    /// Returns the identifiers of all keys used by this CA, i.e. its identity
+    /// key and the keys of all its resource classes.
+    pub fn key_ids(&self) -> Vec<KeyIdentifier> {
+        let mut key_ids = vec![self.id_key()];
+        for rc in self.resources.values() {
+            key_ids.extend(rc.key_ids().into_iter().cloned());
+        }
+        key_ids
+    }
+
     /// Returns the complete set of all currently received resources, under all parents, for
     /// this `CertAuth`
     pub fn all_resources(&self) -> ResourceSet {
@@ -544,7 +574,7 @@ impl CertAuth {
 
         let repo_info = self.repository_contact()?.repo_info();
 
-        let key = signer.create_key()?;
+        let key = signer.create_key_in_context(self.handle.as_str())?;
 
         let resources = ResourceSet::all_resources();
 
@@ -768,6 +798,7 @@ impl CertAuth {
     ) -> KrillResult<Vec<CaEvt>> {
         let (rcn, limit, csr) = request.unpack();
         let csr_info = CsrInfo::try_from(&csr)?;
+        csr_info.check_key(config.min_rsa_key_size)?;
 
         if !csr_info.global_uris() && !test_mode_enabled() {
             return Err(Error::invalid_csr(
@@ -971,6 +1002,7 @@ impl CertAuth {
     /// Generates a new ID key for this CA.
     fn generate_new_id_key(&self, signer: Arc<KrillSigner>) -> KrillResult<Vec<CaEvt>> {
         let id = Rfc8183Id::generate(&signer)?;
+        signer.set_key_context(&id.key_id(), self.handle.as_str())?;
 
         info!(
             "CA '{}' generated new ID certificate with key id: {}",
@@ -1202,7 +1234,7 @@ impl CertAuth {
                 }
                 None => {
                     // Create a resource class with a pending key
-                    let pending_key = signer.create_key()?;
+                    let pending_key = signer.create_key_in_context(self.handle.as_str())?;
 
                     let resource_class_name = ResourceClassName::from(next_class_name);
                     next_class_name += 1;
@@ -1287,6 +1319,9 @@ impl CertAuth {
             let mut started = false;
             let repo = self.repository_contact()?;
             for details in rc.keyroll_initiate(repo.repo_info(), duration, &signer)?.into_iter() {
+                if let CaEvtDet::KeyRollPendingKeyAdded { pending_key_id, .. } = &details {
+                    signer.set_key_context(pending_key_id, self.handle.as_str())?;
+                }
                 started = true;
                 res.push(StoredEvent::new(self.handle(), version, details));
                 version += 1;
@@ -1710,7 +1745,7 @@ impl CertAuth {
         for (rcn, rc) in self.resources.iter() {
             if let Some(rc_resources) = rc.current_resources() {
                 if !rc_resources.intersection(&resources).is_empty() {
-                    let key = signer.create_key()?;
+                    let key = signer.create_key_in_context(self.handle.as_str())?;
                     keys.insert(rcn.clone(), key);
                 }
             }
@@ -1753,7 +1788,11 @@ impl CertAuth {
 
 #[cfg(test)]
 mod tests {
+    use std::str::FromStr;
+
     use super::*;
+    use crate::commons::eventsourcing::WithStorableDetails;
+    use crate::daemon::ca::IniDet;
     use crate::test;
 
     #[test]
@@ -1764,4 +1803,45 @@ mod tests {
             id.cert.validate_ta().unwrap();
         });
     }
+
+    #[test]
+    fn record_created_keys_in_command() {
+        test::test_under_tmp(|d| {
+            let signer = Arc::new(KrillSigner::build(&d).unwrap());
+            let handle = Handle::from_str("ca").unwrap();
+
+            let mut details = StorableCaCommand::from(CmdDet::GenerateNewIdKey(signer.clone()));
+            let id = Rfc8183Id::generate(&signer).unwrap();
+            let key_id = id.key_id();
+            let events = vec![CaEvtDet::id_updated(&handle, 1, id)];
+
+            CertAuth::record_effect(&mut details, &events);
+
+            let summary = details.summary();
+            assert_eq!(summary.args.get("signer"), Some(&"openssl".to_string()));
+            assert_eq!(summary.args.get("created_keys"), Some(&key_id.to_string()));
+
+            // Commands stored before keys were recorded can still be read
+            let json = serde_json::to_string(&StorableCaCommand::GenerateNewIdKey { created_keys: None }).unwrap();
+            assert_eq!(json, r#"{"type":"generate_new_id_key"}"#);
+            let old: StorableCaCommand = serde_json::from_str(&json).unwrap();
+            assert!(old.summary().args.is_empty());
+        });
+    }
+
+    #[test]
+    fn key_ids_include_id_key() {
+        test::test_under_tmp(|d| {
+            let signer = KrillSigner::build(&d).unwrap();
+            let handle = Handle::from_str("ca").unwrap();
+
+            let ca = CertAuth::init(IniDet::init(&handle, &signer).unwrap()).unwrap();
+            let key_ids = ca.key_ids();
+            assert_eq!(key_ids, vec![ca.id_key()]);
+            assert!(signer.get_key_info(&key_ids[0]).is_ok());
+
+            signer.destroy_key(&key_ids[0]).unwrap();
+            assert!(signer.get_key_info(&key_ids[0]).is_err());
+        });
+    }
 }
@@ -8,9 +8,9 @@ use rpki::uri;
 use crate::commons::{
     actor::Actor,
     api::{
-        ChildHandle, Entitlements, Handle, IssuanceRequest, ParentCaContact, ParentHandle, RcvdCert, RepositoryContact,
-        ResourceClassName, ResourceSet, RevocationRequest, RevocationResponse, RtaName, StorableCaCommand,
-        StorableRcEntitlement,
+        ChildHandle, CreatedKeys, Entitlements, Handle, IssuanceRequest, ParentCaContact, ParentHandle, RcvdCert,
+        RepositoryContact, ResourceClassName, ResourceSet, RevocationRequest, RevocationResponse, RtaName,
+        StorableCaCommand, StorableRcEntitlement,
     },
     crypto::{IdCert, KrillSigner},
     eventsourcing::{self, StoredCommand},
@@ -154,6 +154,19 @@ impl eventsourcing::CommandDetails for CmdDet {
     fn store(&self) -> Self::StorableDetails {
         self.clone().into()
     }
+
+    // Only commands which set something to the given value can be retried,
+    // e.g. not ROA updates which add and remove authorizations.
+    fn retry_safe(&self) -> bool {
+        matches!(
+            self,
+            CmdDet::ChildUpdateResources(_, _)
+                | CmdDet::ChildUpdateId(_, _)
+                | CmdDet::UpdateParentContact(_, _)
+                | CmdDet::RepoUpdate(_, _)
+                | CmdDet::RouteAuthorizationsRenew(_, _)
+        )
+    }
 }
 
 impl fmt::Display for CmdDet {
@@ -201,7 +214,9 @@ impl From<CmdDet> for StorableCaCommand {
             // ------------------------------------------------------------
             // Being a child
             // ------------------------------------------------------------
-            CmdDet::GenerateNewIdKey(_) => StorableCaCommand::GenerateNewIdKey,
+            CmdDet::GenerateNewIdKey(signer) => StorableCaCommand::GenerateNewIdKey {
+                created_keys: Some(CreatedKeys::new(signer.backend())),
+            },
             CmdDet::AddParent(parent, contact) => StorableCaCommand::AddParent {
                 parent,
                 contact: contact.into(),
@@ -211,7 +226,7 @@ impl From<CmdDet> for StorableCaCommand {
                 contact: contact.into(),
             },
             CmdDet::RemoveParent(parent) => StorableCaCommand::RemoveParent { parent },
-            CmdDet::UpdateEntitlements(parent, cmd_entitlements, _) => {
+            CmdDet::UpdateEntitlements(parent, cmd_entitlements, signer) => {
                 let mut entitlements = vec![];
                 for entitlement in cmd_entitlements.classes() {
                     entitlements.push(StorableRcEntitlement {
@@ -220,7 +235,11 @@ impl From<CmdDet> for StorableCaCommand {
                     });
                 }
 
-                StorableCaCommand::UpdateResourceEntitlements { parent, entitlements }
+                StorableCaCommand::UpdateResourceEntitlements {
+                    parent,
+                    entitlements,
+                    created_keys: Some(CreatedKeys::new(signer.backend())),
+                }
             }
             CmdDet::UpdateRcvdCert(resource_class_name, rcvd_cert, _, _) => StorableCaCommand::UpdateRcvdCert {
                 resource_class_name,
@@ -230,8 +249,9 @@ impl From<CmdDet> for StorableCaCommand {
             // ------------------------------------------------------------
             // Key rolls
             // ------------------------------------------------------------
-            CmdDet::KeyRollInitiate(older_than, _) => StorableCaCommand::KeyRollInitiate {
+            CmdDet::KeyRollInitiate(older_than, signer) => StorableCaCommand::KeyRollInitiate {
                 older_than_seconds: older_than.num_seconds(),
+                created_keys: Some(CreatedKeys::new(signer.backend())),
             },
             CmdDet::KeyRollActivate(staged_for, _, _) => StorableCaCommand::KeyRollActivate {
                 staged_for_seconds: staged_for.num_seconds(),
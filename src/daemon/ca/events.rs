@@ -43,6 +43,7 @@ impl IniDet {
 
     pub fn init(handle: &Handle, signer: &KrillSigner) -> KrillResult<Ini> {
         let id = Rfc8183Id::generate(signer)?;
+        signer.set_key_context(&id.key_id(), handle.as_str())?;
         Ok(Self::new(handle, id))
     }
 }
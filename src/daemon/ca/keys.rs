@@ -567,6 +567,17 @@ impl KeyState {
         }
     }
 
+    /// Returns the identifiers of all keys in this state.
+    pub fn key_ids(&self) -> Vec<&KeyIdentifier> {
+        match self {
+            KeyState::Pending(pending) => vec![pending.key_id()],
+            KeyState::Active(current) => vec![current.key_id()],
+            KeyState::RollPending(pending, current) => vec![pending.key_id(), current.key_id()],
+            KeyState::RollNew(new, current) => vec![new.key_id(), current.key_id()],
+            KeyState::RollOld(current, old) => vec![current.key_id(), old.key_id()],
+        }
+    }
+
     fn knows_key(&self, key_id: KeyIdentifier) -> bool {
         match self {
             KeyState::Pending(pending) => pending.key_id == key_id,
@@ -1,5 +1,6 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::ops::Deref;
+use std::path::Path;
 use std::sync::Arc;
 
 use api::{Publish, Update, Withdraw};
@@ -25,13 +26,16 @@ use crate::{
         },
         crypto::{IdCert, KrillSigner, ProtocolCms, ProtocolCmsBuilder},
         error::Error,
-        eventsourcing::{Aggregate, AggregateStore, Command, CommandKey},
+        eventsourcing::{
+            Aggregate, AggregateIntegrityReport, AggregateStore, AggregateStoreBackup, AggregateStoreMetricsReport,
+            Command, CommandKey, RecoveryPlan,
+        },
         remote::cmslogger::CmsLogger,
         remote::{rfc6492, rfc8181, rfc8183},
         util::httpclient,
         KrillResult,
     },
-    constants::{CASERVER_DIR, REQUEUE_DELAY_SECONDS, STATUS_DIR},
+    constants::{CASERVER_DIR, CA_COMMAND_RETRIES, REQUEUE_DELAY_SECONDS, STATUS_DIR},
     daemon::{
         auth::common::permissions::Permission,
         ca::{
@@ -48,6 +52,18 @@ use super::DeprecatedRepository;
 
 //------------ CaLocks ------------------------------------------------------
 
+//------------ MissingKey ----------------------------------------------------
+
+/// A key used by a CA which could not be found by the signer.
+#[derive(Clone, Debug, Serialize)]
+pub struct MissingKey {
+    pub ca: Handle,
+    pub key_id: KeyIdentifier,
+    pub msg: String,
+}
+
+//------------ CaLockMap -----------------------------------------------------
+
 pub struct CaLockMap(HashMap<Handle, tokio::sync::RwLock<()>>);
 
 impl CaLockMap {
@@ -140,6 +156,17 @@ impl CaManager {
         // Create the AggregateStore for the event-sourced `CertAuth` structures that handle
         // most CA functions.
         let mut ca_store = AggregateStore::<CertAuth>::disk(&config.data_dir, CASERVER_DIR)?;
+        ca_store.set_compress(config.storage_compress);
+        ca_store.set_cache_size(config.storage_cache_size);
+        ca_store.set_lazy_warm(config.storage_lazy_warm);
+        ca_store.set_warm_set(config.storage_warm_recent, config.storage_warm_cas.clone());
+        let (size_warn, size_max) = config.storage_size_limits();
+        ca_store.set_size_limits(size_warn, size_max);
+        ca_store.set_archive(
+            config.storage_archive_dir.as_deref(),
+            config.storage_archive_max_entries,
+        );
+        ca_store.set_audit_log(config.audit_log_file.as_deref(), CASERVER_DIR)?;
 
         if config.always_recover_data {
             // If the user chose to 'always recover data' then do so.
@@ -257,10 +284,20 @@ impl CaManager {
     }
 
     /// Send a command to a CA
+    ///
+    /// Commands that are `retry_safe` are sent for the latest version of the
+    /// CA, and sent again for the new latest version if the CA was updated in
+    /// between, up to `CA_COMMAND_RETRIES` times.
     async fn send_command(&self, cmd: Cmd) -> KrillResult<Arc<CertAuth>> {
         let lock = self.locks.ca(cmd.handle()).await;
         let _ = lock.write().await;
-        self.ca_store.command(cmd)
+        if cmd.retry_safe() {
+            let handle = cmd.handle().clone();
+            self.ca_store
+                .command_with_retry(&handle, CA_COMMAND_RETRIES, |ca| cmd.with_version(ca.version()))
+        } else {
+            self.ca_store.command(cmd)
+        }
     }
 
     /// Republish the embedded TA and CAs if needed, i.e. if they are close
@@ -268,6 +305,78 @@ impl CaManager {
     pub async fn republish_all(&self) -> KrillResult<Vec<Handle>> {
         self.ca_objects_store.reissue_all()
     }
+
+    /// Archive old commands with an eligible label for all CAs. See
+    /// `AggregateStore::archive_old_commands` for details.
+    pub fn cas_archive_old_commands(&self, days: i64, eligible: &[&str]) -> KrillResult<()> {
+        for ca in self.ca_store.list()? {
+            self.ca_store.archive_old_commands(&ca, days, eligible)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the metrics for the CA aggregate store.
+    pub fn ca_store_metrics(&self) -> AggregateStoreMetricsReport {
+        self.ca_store.metrics()
+    }
+
+    /// Checks the stored commands, events and snapshots of all CAs. Nothing is
+    /// changed, see `AggregateStore::check_integrity`.
+    pub fn cas_check_integrity(&self) -> KrillResult<Vec<AggregateIntegrityReport>> {
+        Ok(self.ca_store.check_integrity()?)
+    }
+
+    /// Writes a copy of the stored state of all CAs to the given dir. See
+    /// `AggregateStore::backup`.
+    pub fn cas_backup(&self, dir: &Path) -> KrillResult<AggregateStoreBackup> {
+        Ok(self.ca_store.backup(dir, CASERVER_DIR)?)
+    }
+
+    /// Recovers the stored state of the given CA, or of all CAs one by one.
+    /// See `AggregateStore::recover`.
+    pub fn cas_recover(&self, ca: Option<&Handle>) -> KrillResult<Vec<RecoveryPlan>> {
+        let cas = match ca {
+            Some(ca) if !self.ca_store.has(ca)? => return Err(Error::CaUnknown(ca.clone())),
+            Some(ca) => vec![ca.clone()],
+            None => self.ca_store.list()?,
+        };
+
+        let mut plans = vec![];
+        for ca in cas {
+            plans.push(self.ca_store.recover_aggregate(&ca)?);
+        }
+        Ok(plans)
+    }
+
+    /// Returns the identifiers of all keys used by all CAs, including their ID
+    /// keys.
+    pub fn cas_key_ids(&self) -> KrillResult<HashSet<KeyIdentifier>> {
+        let mut key_ids = HashSet::new();
+        for handle in self.ca_store.list()? {
+            key_ids.extend(self.ca_store.get_latest(&handle)?.key_ids());
+        }
+        Ok(key_ids)
+    }
+
+    /// Asks the signer for each key used by each CA, and returns the keys that
+    /// it could not find. E.g. because key files were not restored from a
+    /// backup. Nothing is changed.
+    pub fn cas_missing_keys(&self) -> KrillResult<Vec<MissingKey>> {
+        let mut missing = vec![];
+        for handle in self.ca_store.list()? {
+            let ca = self.ca_store.get_latest(&handle)?;
+            for key_id in ca.key_ids() {
+                if let Err(e) = self.signer.get_key_info(&key_id) {
+                    missing.push(MissingKey {
+                        ca: handle.clone(),
+                        key_id,
+                        msg: e.to_string(),
+                    });
+                }
+            }
+        }
+        Ok(missing)
+    }
 }
 
 /// # CA instances and identity
@@ -377,6 +486,12 @@ impl CaManager {
 
         self.ca_store.drop_aggregate(ca_handle)?;
         self.locks.drop_ca(ca_handle).await;
+
+        // Destroy the keys of the CA - best effort, the CA is gone already
+        if let Err(e) = self.signer.destroy_context_keys(ca_handle.as_str()) {
+            warn!("Removed CA '{}', but could not destroy all its keys: {}", ca_handle, e);
+        }
+
         Ok(())
     }
 }
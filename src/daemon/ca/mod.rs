@@ -29,7 +29,7 @@ mod events;
 pub use self::events::*;
 
 mod manager;
-pub use self::manager::CaManager;
+pub use self::manager::{CaManager, MissingKey};
 
 mod rta;
 pub use self::rta::*;
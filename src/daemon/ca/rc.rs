@@ -142,6 +142,12 @@ impl ResourceClass {
         matches!(&self.key_state, KeyState::Active(_))
     }
 
+    /// Returns the identifiers of all keys of this RC, i.e. including pending,
+    /// new and old keys.
+    pub fn key_ids(&self) -> Vec<&KeyIdentifier> {
+        self.key_state.key_ids()
+    }
+
     /// Gets the new key for a key roll, or returns an error if there is none.
     pub fn get_new_key(&self) -> KrillResult<&NewKey> {
         if let KeyState::RollNew(new_key, _) = &self.key_state {
@@ -566,6 +572,7 @@ impl ResourceClass {
             replaces,
             signing_key,
             issuance_timing.timing_child_certificate_valid_weeks,
+            issuance_timing.issuance_require_global_uris,
             signer,
         )?;
 
@@ -593,6 +600,7 @@ impl ResourceClass {
             Some(replaced),
             signing_key,
             issuance_timing.timing_child_certificate_valid_weeks,
+            issuance_timing.issuance_require_global_uris,
             signer,
         )?;
 
@@ -802,7 +802,7 @@ impl Roas {
 
         let mut object_builder = SignedObjectBuilder::new(
             signer.random_serial()?,
-            SignSupport::sign_validity_weeks(weeks),
+            SignSupport::sign_validity(Duration::weeks(weeks))?,
             crl_uri,
             aia.clone(),
             roa_uri,
@@ -1,3 +1,5 @@
+#[cfg(feature = "multi-user")]
+use std::collections::HashMap;
 use std::io;
 use std::io::Read;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
@@ -6,18 +8,20 @@ use std::str::FromStr;
 use std::{env, fmt};
 use std::{fs::File, path::Path};
 
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches};
 use log::{error, LevelFilter};
 use serde::de;
 use serde::{Deserialize, Deserializer};
 #[cfg(unix)]
 use syslog::Facility;
 
+use hyper::header::HeaderName;
+
 use rpki::uri;
 
 use crate::commons::util::ext_serde;
 use crate::commons::{
-    api::{PublicationServerUris, PublisherHandle, Token},
+    api::{is_known_command_label, Handle, PublicationServerUris, PublisherHandle, Token},
     error::KrillIoError,
 };
 use crate::constants::*;
@@ -98,10 +102,77 @@ impl ConfigDefaults {
     fn auth_private_attributes() -> Vec<String> {
         vec![]
     }
+    #[cfg(feature = "multi-user")]
+    fn auth_chain() -> Vec<AuthType> {
+        vec![]
+    }
+    #[cfg(feature = "multi-user")]
+    fn auth_role_hierarchy() -> HashMap<String, Vec<String>> {
+        let mut hierarchy = HashMap::new();
+        hierarchy.insert("admin".to_string(), vec!["readwrite".to_string()]);
+        hierarchy.insert("readwrite".to_string(), vec!["readonly".to_string()]);
+        hierarchy
+    }
+    #[cfg(feature = "multi-user")]
+    fn auth_session_key_grace_hours() -> u32 {
+        24
+    }
     fn ca_refresh() -> u32 {
         600
     }
 
+    fn storage_compress() -> bool {
+        false
+    }
+
+    fn storage_shard_keys() -> bool {
+        false
+    }
+
+    fn storage_cache_size() -> usize {
+        0
+    }
+
+    fn storage_lazy_warm() -> bool {
+        false
+    }
+
+    fn storage_warm_recent() -> usize {
+        0
+    }
+
+    fn storage_warm_cas() -> Vec<Handle> {
+        vec![]
+    }
+
+    fn storage_verify_keys() -> bool {
+        false
+    }
+
+    fn storage_size_warn_mb() -> usize {
+        100
+    }
+
+    fn storage_size_limit_mb() -> usize {
+        0
+    }
+
+    fn storage_archive_max_entries() -> usize {
+        0
+    }
+
+    fn archive_threshold_days() -> Option<i64> {
+        None
+    }
+
+    fn archive_command_labels() -> Vec<String> {
+        vec!["cmd-ca-publish".to_string(), "pubd-publish".to_string()]
+    }
+
+    fn archive_interval_seconds() -> u32 {
+        24 * 3600
+    }
+
     fn post_limit_api() -> u64 {
         256 * 1024 // 256kB
     }
@@ -122,6 +193,14 @@ impl ConfigDefaults {
         None
     }
 
+    fn login_rate_limit() -> u32 {
+        60
+    }
+
+    fn login_rate_limit_window_seconds() -> u64 {
+        60
+    }
+
     fn bgp_risdumps_enabled() -> bool {
         true
     }
@@ -152,6 +231,10 @@ impl ConfigDefaults {
         90
     }
 
+    fn min_rsa_key_size() -> u32 {
+        2048
+    }
+
     fn timing_publish_valid_days() -> i64 {
         7
     }
@@ -179,6 +262,14 @@ impl ConfigDefaults {
     fn timing_roa_reissue_weeks_before() -> i64 {
         4
     }
+
+    fn issuance_require_global_uris() -> bool {
+        false
+    }
+
+    fn issuance_backdate_seconds() -> i64 {
+        SIGN_VALIDITY_BACKDATE_SECS_DFLT
+    }
 }
 
 //------------ Config --------------------------------------------------------
@@ -205,6 +296,44 @@ pub struct Config {
     #[serde(default = "ConfigDefaults::always_recover_data")]
     pub always_recover_data: bool,
 
+    #[serde(default = "ConfigDefaults::storage_compress")]
+    pub storage_compress: bool,
+
+    #[serde(default = "ConfigDefaults::storage_cache_size")]
+    pub storage_cache_size: usize,
+
+    #[serde(default = "ConfigDefaults::storage_lazy_warm")]
+    pub storage_lazy_warm: bool,
+
+    #[serde(default = "ConfigDefaults::storage_warm_recent")]
+    pub storage_warm_recent: usize,
+
+    #[serde(default = "ConfigDefaults::storage_warm_cas")]
+    pub storage_warm_cas: Vec<Handle>,
+
+    #[serde(default = "ConfigDefaults::storage_shard_keys")]
+    pub storage_shard_keys: bool,
+
+    pub storage_keys_dir: Option<PathBuf>,
+
+    #[serde(default = "ConfigDefaults::storage_verify_keys")]
+    pub storage_verify_keys: bool,
+
+    #[serde(default = "ConfigDefaults::storage_size_warn_mb")]
+    pub storage_size_warn_mb: usize,
+
+    #[serde(default = "ConfigDefaults::storage_size_limit_mb")]
+    pub storage_size_limit_mb: usize,
+
+    pub storage_archive_dir: Option<PathBuf>,
+
+    #[serde(default = "ConfigDefaults::storage_archive_max_entries")]
+    pub storage_archive_max_entries: usize,
+
+    pub storage_backup_dir: Option<PathBuf>,
+
+    pub audit_log_file: Option<PathBuf>,
+
     pub pid_file: Option<PathBuf>,
 
     #[serde(default = "ConfigDefaults::service_uri")]
@@ -239,6 +368,22 @@ pub struct Config {
     #[serde(default = "ConfigDefaults::auth_private_attributes")]
     pub auth_private_attributes: Vec<String>,
 
+    #[cfg(feature = "multi-user")]
+    #[serde(default = "ConfigDefaults::auth_chain")]
+    pub auth_chain: Vec<AuthType>,
+
+    #[cfg(feature = "multi-user")]
+    #[serde(default = "ConfigDefaults::auth_role_hierarchy")]
+    pub auth_role_hierarchy: HashMap<String, Vec<String>>,
+
+    #[cfg(feature = "multi-user")]
+    #[serde(default)]
+    pub auth_session_key_rotation_days: Option<u32>,
+
+    #[cfg(feature = "multi-user")]
+    #[serde(default = "ConfigDefaults::auth_session_key_grace_hours")]
+    pub auth_session_key_grace_hours: u32,
+
     #[cfg(feature = "multi-user")]
     pub auth_users: Option<ConfigAuthUsers>,
 
@@ -248,6 +393,15 @@ pub struct Config {
     #[serde(default = "ConfigDefaults::ca_refresh")]
     pub ca_refresh: u32,
 
+    #[serde(default = "ConfigDefaults::archive_threshold_days")]
+    pub archive_threshold_days: Option<i64>,
+
+    #[serde(default = "ConfigDefaults::archive_command_labels")]
+    pub archive_command_labels: Vec<String>,
+
+    #[serde(default = "ConfigDefaults::archive_interval_seconds")]
+    pub archive_interval_seconds: u32,
+
     #[serde(default = "ConfigDefaults::post_limit_api")]
     pub post_limit_api: u64,
 
@@ -263,6 +417,15 @@ pub struct Config {
     #[serde(default = "ConfigDefaults::rfc6492_log_dir")]
     pub rfc6492_log_dir: Option<PathBuf>,
 
+    #[serde(default = "ConfigDefaults::login_rate_limit")]
+    pub login_rate_limit: u32,
+
+    #[serde(default = "ConfigDefaults::login_rate_limit_window_seconds")]
+    pub login_rate_limit_window_seconds: u64,
+
+    #[serde(default)]
+    pub login_rate_limit_proxy_header: Option<String>,
+
     // RIS BGP
     #[serde(default = "ConfigDefaults::bgp_risdumps_enabled")]
     pub bgp_risdumps_enabled: bool,
@@ -278,6 +441,10 @@ pub struct Config {
     #[serde(default = "ConfigDefaults::roa_deaggregate_threshold")]
     pub roa_deaggregate_threshold: usize,
 
+    // Minimum size of RSA keys in certificate requests from child CAs
+    #[serde(default = "ConfigDefaults::min_rsa_key_size")]
+    pub min_rsa_key_size: u32,
+
     #[serde(flatten)]
     pub issuance_timing: IssuanceTimingConfig,
 
@@ -303,6 +470,10 @@ pub struct IssuanceTimingConfig {
     pub timing_roa_valid_weeks: i64,
     #[serde(default = "ConfigDefaults::timing_roa_reissue_weeks_before")]
     pub timing_roa_reissue_weeks_before: i64,
+    #[serde(default = "ConfigDefaults::issuance_require_global_uris")]
+    pub issuance_require_global_uris: bool,
+    #[serde(default = "ConfigDefaults::issuance_backdate_seconds")]
+    pub issuance_backdate_seconds: i64,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -435,6 +606,25 @@ impl Config {
         uri::Https::from_string(format!("{}rfc8181/{}/", self.service_uri, publisher)).unwrap()
     }
 
+    /// Returns the warning threshold and the limit for the size of stored
+    /// snapshots and events, in bytes. See `AggregateStore::set_size_limits`.
+    pub fn storage_size_limits(&self) -> (usize, usize) {
+        const MB: usize = 1024 * 1024;
+        (
+            self.storage_size_warn_mb.saturating_mul(MB),
+            self.storage_size_limit_mb.saturating_mul(MB),
+        )
+    }
+
+    /// Returns the directory where the private keys and the key map are kept.
+    /// Defaults to "keys" under the data dir.
+    pub fn keys_dir(&self) -> PathBuf {
+        match &self.storage_keys_dir {
+            None => self.data_dir.join("keys"),
+            Some(dir) => dir.clone(),
+        }
+    }
+
     pub fn pid_file(&self) -> PathBuf {
         match &self.pid_file {
             None => {
@@ -471,6 +661,20 @@ impl Config {
         let https_mode = HttpsMode::Generate;
         let data_dir = data_dir.to_path_buf();
         let always_recover_data = false;
+        let storage_compress = ConfigDefaults::storage_compress();
+        let storage_cache_size = ConfigDefaults::storage_cache_size();
+        let storage_lazy_warm = ConfigDefaults::storage_lazy_warm();
+        let storage_warm_recent = ConfigDefaults::storage_warm_recent();
+        let storage_warm_cas = ConfigDefaults::storage_warm_cas();
+        let storage_shard_keys = ConfigDefaults::storage_shard_keys();
+        let storage_keys_dir = None;
+        let storage_verify_keys = ConfigDefaults::storage_verify_keys();
+        let storage_size_warn_mb = ConfigDefaults::storage_size_warn_mb();
+        let storage_size_limit_mb = ConfigDefaults::storage_size_limit_mb();
+        let storage_archive_dir = None;
+        let storage_archive_max_entries = ConfigDefaults::storage_archive_max_entries();
+        let storage_backup_dir = None;
+        let audit_log_file = None;
         let service_uri = ConfigDefaults::service_uri();
 
         let log_level = LevelFilter::Debug;
@@ -485,10 +689,21 @@ impl Config {
         #[cfg(feature = "multi-user")]
         let auth_private_attributes = vec![];
         #[cfg(feature = "multi-user")]
+        let auth_chain = vec![];
+        #[cfg(feature = "multi-user")]
+        let auth_role_hierarchy = ConfigDefaults::auth_role_hierarchy();
+        #[cfg(feature = "multi-user")]
+        let auth_session_key_rotation_days = None;
+        #[cfg(feature = "multi-user")]
+        let auth_session_key_grace_hours = ConfigDefaults::auth_session_key_grace_hours();
+        #[cfg(feature = "multi-user")]
         let auth_users = None;
         #[cfg(feature = "multi-user")]
         let auth_openidconnect = None;
         let ca_refresh = 1;
+        let archive_threshold_days = ConfigDefaults::archive_threshold_days();
+        let archive_command_labels = ConfigDefaults::archive_command_labels();
+        let archive_interval_seconds = ConfigDefaults::archive_interval_seconds();
         let post_limit_api = ConfigDefaults::post_limit_api();
         let post_limit_rfc8181 = ConfigDefaults::post_limit_rfc8181();
         let rfc8181_log_dir = {
@@ -502,6 +717,9 @@ impl Config {
             dir.push("rfc6492");
             Some(dir)
         };
+        let login_rate_limit = ConfigDefaults::login_rate_limit();
+        let login_rate_limit_window_seconds = ConfigDefaults::login_rate_limit_window_seconds();
+        let login_rate_limit_proxy_header = None;
 
         let bgp_risdumps_enabled = false;
         let bgp_risdumps_v4_uri = ConfigDefaults::bgp_risdumps_v4_uri();
@@ -510,6 +728,8 @@ impl Config {
         let roa_aggregate_threshold = 3;
         let roa_deaggregate_threshold = 2;
 
+        let min_rsa_key_size = ConfigDefaults::min_rsa_key_size();
+
         let timing_publish_valid_days = ConfigDefaults::timing_publish_valid_days();
         let timing_publish_next_hours = ConfigDefaults::timing_publish_next_hours();
         let timing_publish_hours_before_next = ConfigDefaults::timing_publish_hours_before_next();
@@ -518,6 +738,8 @@ impl Config {
             ConfigDefaults::timing_child_certificate_reissue_weeks_before();
         let timing_roa_valid_weeks = ConfigDefaults::timing_roa_valid_weeks();
         let timing_roa_reissue_weeks_before = ConfigDefaults::timing_roa_reissue_weeks_before();
+        let issuance_require_global_uris = ConfigDefaults::issuance_require_global_uris();
+        let issuance_backdate_seconds = ConfigDefaults::issuance_backdate_seconds();
 
         let issuance_timing = IssuanceTimingConfig {
             timing_publish_valid_days,
@@ -527,6 +749,8 @@ impl Config {
             timing_child_certificate_reissue_weeks_before,
             timing_roa_valid_weeks,
             timing_roa_reissue_weeks_before,
+            issuance_require_global_uris,
+            issuance_backdate_seconds,
         };
 
         let repository_retention = RepositoryRetentionConfig {
@@ -555,6 +779,20 @@ impl Config {
             https_mode,
             data_dir,
             always_recover_data,
+            storage_compress,
+            storage_cache_size,
+            storage_lazy_warm,
+            storage_warm_recent,
+            storage_warm_cas,
+            storage_shard_keys,
+            storage_keys_dir,
+            storage_verify_keys,
+            storage_size_warn_mb,
+            storage_size_limit_mb,
+            storage_archive_dir,
+            storage_archive_max_entries,
+            storage_backup_dir,
+            audit_log_file,
             pid_file,
             service_uri,
             log_level,
@@ -568,20 +806,35 @@ impl Config {
             #[cfg(feature = "multi-user")]
             auth_private_attributes,
             #[cfg(feature = "multi-user")]
+            auth_chain,
+            #[cfg(feature = "multi-user")]
+            auth_role_hierarchy,
+            #[cfg(feature = "multi-user")]
+            auth_session_key_rotation_days,
+            #[cfg(feature = "multi-user")]
+            auth_session_key_grace_hours,
+            #[cfg(feature = "multi-user")]
             auth_users,
             #[cfg(feature = "multi-user")]
             auth_openidconnect,
             ca_refresh,
+            archive_threshold_days,
+            archive_command_labels,
+            archive_interval_seconds,
             post_limit_api,
             post_limit_rfc8181,
             rfc8181_log_dir,
             post_limit_rfc6492,
             rfc6492_log_dir,
+            login_rate_limit,
+            login_rate_limit_window_seconds,
+            login_rate_limit_proxy_header,
             bgp_risdumps_enabled,
             bgp_risdumps_v4_uri,
             bgp_risdumps_v6_uri,
             roa_aggregate_threshold,
             roa_deaggregate_threshold,
+            min_rsa_key_size,
             issuance_timing,
             repository_retention,
             testbed,
@@ -599,8 +852,8 @@ impl Config {
         config
     }
 
-    pub fn get_config_filename() -> String {
-        let matches = App::new(KRILL_SERVER_APP)
+    fn get_args() -> ArgMatches<'static> {
+        App::new(KRILL_SERVER_APP)
             .version(KRILL_VERSION)
             .arg(
                 Arg::with_name("config")
@@ -610,13 +863,27 @@ impl Config {
                     .help("Override the path to the config file (default: './defaults/krill.conf')")
                     .required(false),
             )
-            .get_matches();
+            .arg(
+                Arg::with_name("check-config")
+                    .long("check-config")
+                    .help("Check the config, including the OpenID Connect provider if used, and exit")
+                    .required(false),
+            )
+            .get_matches()
+    }
 
+    pub fn get_config_filename() -> String {
+        let matches = Self::get_args();
         let config_file = matches.value_of("config").unwrap_or(KRILL_DEFAULT_CONFIG_FILE);
 
         config_file.to_string()
     }
 
+    /// Returns true if Krill should only check its config, and then exit.
+    pub fn check_only() -> bool {
+        Self::get_args().is_present("check-config")
+    }
+
     /// Creates the config (at startup). Panics in case of issues.
     pub fn create() -> Result<Self, ConfigError> {
         let config_file = Self::get_config_filename();
@@ -668,6 +935,41 @@ impl Config {
             }
         }
 
+        if let Some(days) = self.archive_threshold_days {
+            if days < 1 {
+                return Err(ConfigError::other("archive_threshold_days must be at least 1"));
+            }
+        }
+
+        if let Some(label) = self
+            .archive_command_labels
+            .iter()
+            .find(|label| !is_known_command_label(label))
+        {
+            return Err(ConfigError::Other(format!(
+                "archive_command_labels contains unknown command label: {}",
+                label
+            )));
+        }
+
+        if self.archive_interval_seconds < 60 {
+            return Err(ConfigError::other("archive_interval_seconds must be at least 60"));
+        }
+
+        #[cfg(feature = "multi-user")]
+        if self.auth_session_key_rotation_days == Some(0) {
+            return Err(ConfigError::other("auth_session_key_rotation_days must be at least 1"));
+        }
+
+        if self.login_rate_limit_window_seconds < 1 {
+            return Err(ConfigError::other("login_rate_limit_window_seconds must be at least 1"));
+        }
+
+        if let Some(header) = &self.login_rate_limit_proxy_header {
+            HeaderName::from_str(header)
+                .map_err(|_| ConfigError::Other(format!("Invalid login_rate_limit_proxy_header: {}", header)))?;
+        }
+
         if self.issuance_timing.timing_publish_next_hours < 2 {
             return Err(ConfigError::other("timing_publish_next_hours must be at least 2"));
         }
@@ -708,6 +1010,13 @@ impl Config {
             return Err(ConfigError::other("timing_child_certificate_reissue_weeks_before must be smaller than timing_child_certificate_valid_weeks"));
         }
 
+        if self.issuance_timing.issuance_backdate_seconds < 0 || self.issuance_timing.issuance_backdate_seconds >= 86400
+        {
+            return Err(ConfigError::other(
+                "issuance_backdate_seconds must be 0 or bigger, and less than a day",
+            ));
+        }
+
         if self.issuance_timing.timing_roa_valid_weeks < 2 {
             return Err(ConfigError::other("timing_roa_valid_weeks must be at least 2"));
         }
@@ -722,6 +1031,16 @@ impl Config {
             ));
         }
 
+        #[cfg(feature = "multi-user")]
+        for (i, auth_type) in self.auth_chain.iter().enumerate() {
+            if auth_type == &self.auth_type || self.auth_chain[..i].contains(auth_type) {
+                return Err(ConfigError::Other(format!(
+                    "auth_chain cannot contain the auth_type, or the same type twice: {:?}",
+                    auth_type
+                )));
+            }
+        }
+
         Ok(())
     }
 
@@ -960,6 +1279,8 @@ impl<'de> Deserialize<'de> for HttpsMode {
 pub enum AuthType {
     AdminToken,
     #[cfg(feature = "multi-user")]
+    ApiToken,
+    #[cfg(feature = "multi-user")]
     ConfigFile,
     #[cfg(feature = "multi-user")]
     OpenIDConnect,
@@ -974,6 +1295,8 @@ impl<'de> Deserialize<'de> for AuthType {
         match string.as_str() {
             "admin-token" => Ok(AuthType::AdminToken),
             #[cfg(feature = "multi-user")]
+            "api-token" => Ok(AuthType::ApiToken),
+            #[cfg(feature = "multi-user")]
             "config-file" => Ok(AuthType::ConfigFile),
             #[cfg(feature = "multi-user")]
             "openid-connect" => Ok(AuthType::OpenIDConnect),
@@ -982,7 +1305,7 @@ impl<'de> Deserialize<'de> for AuthType {
                 let msg = format!("expected \"admin-token\", found: \"{}\"", string);
                 #[cfg(feature = "multi-user")]
                 let msg = format!(
-                    "expected \"config-file\", \"admin-token\", or \"openid-connect\", found: \"{}\"",
+                    "expected \"config-file\", \"admin-token\", \"api-token\", or \"openid-connect\", found: \"{}\"",
                     string
                 );
                 Err(de::Error::custom(msg))
@@ -1178,6 +1501,20 @@ mod tests {
         }
     }
 
+    #[test]
+    fn config_should_reject_unknown_archive_command_labels() {
+        test::test_under_tmp(|d| {
+            let mut c = Config::test(&d, false);
+            assert!(c.verify().is_ok());
+
+            c.archive_command_labels = vec!["cmd-ca-roas-updated".to_string(), "pubd-publish".to_string()];
+            assert!(c.verify().is_ok());
+
+            c.archive_command_labels = vec!["cmd-ca-publish".to_string(), "cmd-ca-pubish".to_string()];
+            assert!(c.verify().is_err());
+        })
+    }
+
     #[test]
     fn config_should_accept_and_warn_about_auth_token() {
         let old_config = b"auth_token = \"secret\"";
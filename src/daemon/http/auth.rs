@@ -11,6 +11,8 @@ use hyper::Method;
 pub const AUTH_CALLBACK_ENDPOINT: &str = "/auth/callback";
 pub const AUTH_LOGIN_ENDPOINT: &str = "/auth/login";
 pub const AUTH_LOGOUT_ENDPOINT: &str = "/auth/logout";
+pub const AUTH_REFRESH_ENDPOINT: &str = "/auth/refresh";
+pub const AUTH_WHOAMI_ENDPOINT: &str = "/auth/whoami";
 
 #[cfg(feature = "multi-user")]
 pub fn url_encode<S: AsRef<str>>(s: S) -> Result<String, Error> {
@@ -29,12 +31,41 @@ fn build_auth_redirect_location(user: LoggedInUser) -> Result<String, Error> {
 
     let attributes = b64_encode_attributes_with_mapped_error(&user.attributes)?;
 
-    Ok(format!(
+    // Lagosta only shows the id, so give it the display name if there is one.
+    let location = format!(
         "/index.html#/login?token={}&id={}&attributes={}",
         &url_encode(user.token)?,
-        &url_encode(user.id)?,
+        &url_encode(user.display_name.unwrap_or(user.id))?,
         &url_encode(attributes)?
-    ))
+    );
+
+    check_internal_location(&location)?;
+    Ok(location)
+}
+
+/// Verifies that a redirect location is a path on this Krill instance.
+///
+/// The values in the location after login come from the login provider, e.g.
+/// from OpenID Connect claims. They are URL encoded, so they cannot change the
+/// target of the redirect, but we check the result anyway so that a mistake
+/// can never send the browser, and the session token, to another origin. Note
+/// that the OpenID Connect 'state' is only used to protect against CSRF, it
+/// never determines where the browser is redirected to.
+#[cfg(feature = "multi-user")]
+fn check_internal_location(location: &str) -> Result<(), Error> {
+    let internal = location.starts_with('/')
+        && !location.starts_with("//")
+        && !location.starts_with("/\\")
+        && !location.chars().any(|c| c.is_ascii_control() || c.is_whitespace());
+
+    if internal {
+        Ok(())
+    } else {
+        Err(Error::custom(format!(
+            "Refusing to redirect to location outside of Krill: {}",
+            location
+        )))
+    }
 }
 
 #[allow(clippy::unnecessary_wraps)]
@@ -44,6 +75,9 @@ fn render_error(err: Error) -> RoutingResult {
 
 pub async fn auth(req: Request) -> RoutingResult {
     match req.path.full() {
+        AUTH_CALLBACK_ENDPOINT | AUTH_LOGIN_ENDPOINT if !req.login_permitted() => render_error(
+            Error::ApiTooManyRequests("too many login requests, please try again later".to_string()),
+        ),
         #[cfg(feature = "multi-user")]
         AUTH_CALLBACK_ENDPOINT if *req.method() == Method::GET => {
             if log_enabled!(log::Level::Trace) {
@@ -66,9 +100,51 @@ pub async fn auth(req: Request) -> RoutingResult {
         AUTH_LOGIN_ENDPOINT if *req.method() == Method::GET => req.get_login_url().await.or_else(render_error),
         AUTH_LOGIN_ENDPOINT if *req.method() == Method::POST => match req.login().await {
             Ok(logged_in_user) => Ok(HttpResponse::json(&logged_in_user)),
-            Err(err) => render_error(err),
+            Err(err) => Ok(HttpResponse::login_error(err)),
         },
         AUTH_LOGOUT_ENDPOINT if *req.method() == Method::POST => req.logout().await.or_else(render_error),
+        AUTH_REFRESH_ENDPOINT if *req.method() == Method::POST => match req.refresh().await {
+            Ok(refreshed_user) => Ok(HttpResponse::json(&refreshed_user)),
+            Err(err) => render_error(err),
+        },
+        AUTH_WHOAMI_ENDPOINT if *req.method() == Method::GET => match req.whoami().await {
+            Ok(whoami) => Ok(HttpResponse::json(&whoami)),
+            Err(err) => render_error(err),
+        },
         _ => Err(req),
     }
 }
+
+//------------ Tests ---------------------------------------------------------
+
+#[cfg(all(test, feature = "multi-user"))]
+mod tests {
+
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::commons::api::Token;
+
+    #[test]
+    fn no_open_redirect_after_login() {
+        let mut attributes = HashMap::new();
+        attributes.insert("role".to_string(), "//evil.example".to_string());
+        let user = LoggedInUser {
+            token: Token::from("https://evil.example/"),
+            id: "//evil.example".to_string(),
+            display_name: None,
+            attributes,
+        };
+
+        let location = build_auth_redirect_location(user).unwrap();
+        assert!(location.starts_with("/index.html#/login?token="));
+        assert!(location.to_lowercase().contains("&id=%2f%2fevil.example&"));
+        assert!(!location.contains("//"));
+
+        assert!(check_internal_location("/index.html#/login").is_ok());
+        assert!(check_internal_location("//evil.example").is_err());
+        assert!(check_internal_location("/\\evil.example").is_err());
+        assert!(check_internal_location("https://evil.example").is_err());
+        assert!(check_internal_location("/index.html\r\nLocation: https://evil.example").is_err());
+    }
+}
@@ -1,5 +1,6 @@
 use serde::de::DeserializeOwned;
 use std::io;
+use std::net::SocketAddr;
 use std::str::FromStr;
 use std::{convert::TryInto, str::from_utf8};
 
@@ -10,16 +11,19 @@ use hyper::http::uri::PathAndQuery;
 use hyper::{body::HttpBody, HeaderMap};
 use hyper::{Body, Method, StatusCode};
 
+use crate::commons::api::ErrorResponse;
 use crate::commons::error::Error;
 use crate::commons::remote::{rfc6492, rfc8181};
 use crate::commons::{
     actor::{Actor, ActorDef},
     KrillResult,
 };
-use crate::daemon::auth::LoggedInUser;
+use crate::constants::HTTP_RETRY_AFTER_SECS;
+use crate::daemon::auth::{LoggedInUser, WhoAmI};
 use crate::daemon::http::server::State;
 
 pub mod auth;
+pub mod ratelimit;
 pub mod server;
 pub mod statics;
 pub mod testbed;
@@ -104,6 +108,11 @@ impl Response {
             builder = builder.header("WWW-Authenticate", "Bearer");
         }
 
+        // Tell clients to back off, rather than treat this as a fatal error
+        if self.status == StatusCode::SERVICE_UNAVAILABLE {
+            builder = builder.header("Retry-After", HTTP_RETRY_AFTER_SECS.to_string());
+        }
+
         let response = builder.body(self.body.into()).unwrap();
 
         let mut r = HttpResponse::new(response);
@@ -318,6 +327,48 @@ impl HttpResponse {
         Response::new(StatusCode::NOT_FOUND).finalize()
     }
 
+    /// Responds to a failed login attempt. Clients get the error label and a
+    /// generic message, which tell them whether they should retry with other
+    /// credentials or later. The details can be sensitive and are only
+    /// logged by the server.
+    pub fn login_error(error: Error) -> Self {
+        let (status, msg) = match &error {
+            Error::ApiInvalidCredentials(_) | Error::ApiLoginError(_) | Error::ApiAuthSessionExpired(_) => {
+                (StatusCode::UNAUTHORIZED, "Login failed: invalid credentials")
+            }
+            Error::ApiInsufficientRights(_) => (StatusCode::FORBIDDEN, "Login failed: insufficient rights"),
+            Error::ApiAuthTransientError(_) | Error::HttpClientError(_) => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Login failed: the login provider is unavailable",
+            ),
+            Error::ApiAuthProviderTimeout(_) => (
+                StatusCode::GATEWAY_TIMEOUT,
+                "Login failed: the login provider timed out",
+            ),
+            Error::ApiAuthPermanentError(_) => (
+                StatusCode::BAD_GATEWAY,
+                "Login failed: the login provider rejected the request",
+            ),
+            _ => (StatusCode::INTERNAL_SERVER_ERROR, "Login failed: internal error"),
+        };
+
+        let response = ErrorResponse::new(error.to_error_response().label(), msg);
+        Response {
+            status,
+            content_type: ContentType::Json,
+            max_age: None,
+            body: serde_json::to_string(&response).unwrap().into_bytes(),
+            cause: Some(error),
+        }
+        .finalize()
+    }
+
+    pub fn service_unavailable(reason: String) -> Self {
+        let mut response = Response::new(StatusCode::SERVICE_UNAVAILABLE);
+        response.body = reason.into_bytes();
+        response.finalize()
+    }
+
     pub fn unauthorized(reason: String) -> Self {
         Self::response_from_error(Error::ApiInvalidCredentials(reason))
     }
@@ -334,10 +385,11 @@ pub struct Request {
     path: RequestPath,
     state: State,
     actor: Actor,
+    remote_addr: Option<SocketAddr>,
 }
 
 impl Request {
-    pub async fn new(request: hyper::Request<hyper::Body>, state: State) -> Self {
+    pub async fn new(request: hyper::Request<hyper::Body>, state: State, remote_addr: Option<SocketAddr>) -> Self {
         let path = RequestPath::from_request(&request);
         let actor = state.actor_from_request(&request);
 
@@ -346,6 +398,7 @@ impl Request {
             path,
             state,
             actor,
+            remote_addr,
         }
     }
 
@@ -487,8 +540,14 @@ impl Request {
         Ok(vec.into())
     }
 
+    /// Returns false if this client made too many anonymous login requests
+    /// recently. Authenticated actors are always permitted.
+    pub fn login_permitted(&self) -> bool {
+        !self.actor.is_anonymous() || self.state.login_permitted(&self.request, self.remote_addr)
+    }
+
     pub async fn get_login_url(&self) -> KrillResult<HttpResponse> {
-        self.state.get_login_url()
+        self.state.get_login_url(&self.request)
     }
 
     pub async fn login(&self) -> KrillResult<LoggedInUser> {
@@ -498,6 +557,14 @@ impl Request {
     pub async fn logout(&self) -> KrillResult<HttpResponse> {
         self.state.logout(&self.request)
     }
+
+    pub async fn refresh(&self) -> KrillResult<LoggedInUser> {
+        self.state.refresh(&self.request)
+    }
+
+    pub async fn whoami(&self) -> KrillResult<WhoAmI> {
+        self.state.whoami(&self.actor, &self.request)
+    }
 }
 
 //------------ RequestPath ---------------------------------------------------
@@ -569,3 +636,46 @@ impl RequestPath {
         self.next().map(|s| T::from_str(s).ok()).flatten()
     }
 }
+
+//------------ Tests ---------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn login_error_hides_details() {
+        let res = HttpResponse::login_error(Error::ApiInvalidCredentials("secret detail".to_string()));
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let res = HttpResponse::login_error(Error::ApiAuthTransientError("secret detail".to_string()));
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(res.cause().is_some());
+
+        let body = format!("{:?}", res.body());
+        assert!(body.contains("api-auth-transient-error"));
+        assert!(!body.contains("secret detail"));
+
+        let res = HttpResponse::login_error(Error::ApiAuthProviderTimeout("https://idp/token".to_string()));
+        assert_eq!(res.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert!(format!("{:?}", res.body()).contains("api-auth-provider-timeout"));
+    }
+
+    #[test]
+    fn transient_signer_errors_are_retryable() {
+        let err: Error = crate::commons::crypto::Error::unavailable("session reset").into();
+        let res = HttpResponse::response_from_error(err);
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            res.headers().get("Retry-After").unwrap(),
+            HTTP_RETRY_AFTER_SECS.to_string().as_str()
+        );
+        assert!(format!("{:?}", res.body()).contains("sys-signer-unavailable"));
+
+        let err: Error = crate::commons::crypto::Error::signer("key is broken").into();
+        let res = HttpResponse::response_from_error(err);
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(res.headers().get("Retry-After").is_none());
+    }
+}
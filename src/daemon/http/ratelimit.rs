@@ -0,0 +1,211 @@
+//! Rate limiting of anonymous login requests.
+//!
+//! Starting a login, or completing one in the OpenID Connect callback, can
+//! cause round-trips to the login provider. To stop a single client from
+//! flooding Krill and the provider with such requests the number of anonymous
+//! requests to these endpoints is limited per client IP address.
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use hyper::header::HeaderName;
+
+use crate::daemon::config::Config;
+
+// Windows of clients which made no requests recently are only removed when
+// there are more than this many clients, so that pruning is rare.
+const PRUNE_THRESHOLD: usize = 1024;
+
+// At most this many clients are tracked. If a new client comes along when
+// this many clients made requests in their current window, then the client
+// with the oldest window is forgotten, so that memory use stays bounded.
+const MAX_CLIENTS: usize = 16 * 1024;
+
+//------------ LoginRateLimiter ----------------------------------------------
+
+/// Counts the requests of each client IP address in fixed windows.
+///
+/// A client may do up to 'limit' requests in each window, further requests
+/// are refused until the window has passed. A limit of 0 disables the rate
+/// limiter.
+pub struct LoginRateLimiter {
+    limit: u32,
+    window: Duration,
+    proxy_header: Option<HeaderName>,
+    max_clients: usize,
+    windows: Mutex<HashMap<IpAddr, ClientWindow>>,
+}
+
+struct ClientWindow {
+    start: Instant,
+    requests: u32,
+}
+
+impl LoginRateLimiter {
+    pub fn new(limit: u32, window: Duration, proxy_header: Option<HeaderName>) -> Self {
+        LoginRateLimiter {
+            limit,
+            window,
+            proxy_header,
+            max_clients: MAX_CLIENTS,
+            windows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Creates the rate limiter for the 'login_rate_limit' config. The proxy
+    /// header name was checked when the config was verified.
+    pub fn from_config(config: &Config) -> Self {
+        let proxy_header = config
+            .login_rate_limit_proxy_header
+            .as_ref()
+            .and_then(|name| HeaderName::from_str(name).ok());
+
+        Self::new(
+            config.login_rate_limit,
+            Duration::from_secs(config.login_rate_limit_window_seconds),
+            proxy_header,
+        )
+    }
+
+    /// Returns true if the client of this request may do another request, and
+    /// counts the request.
+    pub fn permit(&self, request: &hyper::Request<hyper::Body>, remote_addr: Option<SocketAddr>) -> bool {
+        if self.limit == 0 {
+            return true;
+        }
+
+        match self.client_ip(request, remote_addr) {
+            Some(ip) => self.permit_at(ip, Instant::now()),
+            None => true,
+        }
+    }
+
+    /// Returns the address of the client. If a trusted proxy header is
+    /// configured its last address is used, i.e. the address that the proxy
+    /// added. Earlier addresses are ignored, as the client can set them to
+    /// anything. If there is no proxy header, or if it is missing or cannot
+    /// be parsed, the address of the connection is used.
+    fn client_ip(&self, request: &hyper::Request<hyper::Body>, remote_addr: Option<SocketAddr>) -> Option<IpAddr> {
+        self.proxy_header
+            .as_ref()
+            .and_then(|name| request.headers().get(name))
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.rsplit(',').next())
+            .and_then(|last| IpAddr::from_str(last.trim()).ok())
+            .or_else(|| remote_addr.map(|addr| addr.ip()))
+    }
+
+    fn permit_at(&self, ip: IpAddr, now: Instant) -> bool {
+        let mut windows = self.windows.lock().unwrap();
+
+        if windows.len() > PRUNE_THRESHOLD {
+            let window = self.window;
+            windows.retain(|_, client| now.duration_since(client.start) < window);
+        }
+
+        if windows.len() >= self.max_clients && !windows.contains_key(&ip) {
+            let oldest = windows.iter().min_by_key(|(_, client)| client.start).map(|(ip, _)| *ip);
+            if let Some(oldest) = oldest {
+                windows.remove(&oldest);
+            }
+        }
+
+        let client = windows.entry(ip).or_insert(ClientWindow {
+            start: now,
+            requests: 0,
+        });
+
+        if now.duration_since(client.start) >= self.window {
+            client.start = now;
+            client.requests = 0;
+        }
+
+        if client.requests >= self.limit {
+            warn!("Refused login request from {}: rate limit exceeded", ip);
+            false
+        } else {
+            client.requests += 1;
+            true
+        }
+    }
+}
+
+//------------ Tests ---------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        IpAddr::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn limit_boundary_and_reset() {
+        let limiter = LoginRateLimiter::new(3, Duration::from_secs(60), None);
+        let start = Instant::now();
+        let client = ip("192.0.2.1");
+
+        for _ in 0..3 {
+            assert!(limiter.permit_at(client, start));
+        }
+        assert!(!limiter.permit_at(client, start + Duration::from_secs(59)));
+
+        // Other clients have their own window
+        assert!(limiter.permit_at(ip("2001:db8::1"), start));
+
+        // The limit resets once the window has passed
+        let later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(limiter.permit_at(client, later));
+        }
+        assert!(!limiter.permit_at(client, later));
+    }
+
+    #[test]
+    fn client_ip_from_trusted_proxy_header() {
+        let remote = Some(SocketAddr::from_str("10.0.0.1:443").unwrap());
+        let request = hyper::Request::builder()
+            .header("X-Forwarded-For", "192.0.2.1")
+            .body(hyper::Body::empty())
+            .unwrap();
+
+        let direct = LoginRateLimiter::new(1, Duration::from_secs(60), None);
+        assert_eq!(direct.client_ip(&request, remote), Some(ip("10.0.0.1")));
+
+        let header = HeaderName::from_static("x-forwarded-for");
+        let proxied = LoginRateLimiter::new(1, Duration::from_secs(60), Some(header));
+        assert_eq!(proxied.client_ip(&request, remote), Some(ip("192.0.2.1")));
+
+        // The client can put anything in the header before it reaches the
+        // proxy, only the address added by the proxy is used.
+        let spoofed = hyper::Request::builder()
+            .header("X-Forwarded-For", "198.51.100.7, 192.0.2.1")
+            .body(hyper::Body::empty())
+            .unwrap();
+        assert_eq!(proxied.client_ip(&spoofed, remote), Some(ip("192.0.2.1")));
+
+        let request = hyper::Request::new(hyper::Body::empty());
+        assert_eq!(proxied.client_ip(&request, remote), Some(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn number_of_clients_is_bounded() {
+        let mut limiter = LoginRateLimiter::new(1, Duration::from_secs(60), None);
+        limiter.max_clients = 3;
+        let start = Instant::now();
+
+        let first = ip("192.0.2.1");
+        assert!(limiter.permit_at(first, start));
+        assert!(limiter.permit_at(ip("192.0.2.2"), start + Duration::from_secs(1)));
+        assert!(limiter.permit_at(ip("192.0.2.3"), start + Duration::from_secs(1)));
+
+        // A new client makes room by evicting the client with the oldest window
+        assert!(limiter.permit_at(ip("2001:db8::1"), start + Duration::from_secs(2)));
+        assert_eq!(limiter.windows.lock().unwrap().len(), 3);
+        assert!(!limiter.windows.lock().unwrap().contains_key(&first));
+    }
+}
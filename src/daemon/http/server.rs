@@ -4,6 +4,7 @@ use std::collections::HashMap;
 use std::convert::Infallible;
 use std::env;
 use std::fs::File;
+use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::process;
 use std::str::FromStr;
@@ -36,6 +37,7 @@ use crate::daemon::config::Config;
 use crate::daemon::http::auth::auth;
 use crate::daemon::http::statics::statics;
 use crate::daemon::http::testbed::testbed;
+use crate::daemon::http::tls::Transport;
 use crate::daemon::http::{tls, tls_keys, HttpResponse, Request, RequestPath, RoutingResult};
 use crate::daemon::krillserver::KrillServer;
 use crate::upgrades::{pre_start_upgrade, update_storage_version};
@@ -107,12 +109,13 @@ pub async fn start_krill_daemon(config: Arc<Config>) -> Result<(), Error> {
 
     let state = Arc::new(krill);
 
-    let service = make_service_fn(move |_| {
+    let service = make_service_fn(move |conn: &tls::TlsStream| {
         let state = state.clone();
+        let remote_addr = conn.remote_addr();
         async move {
             Ok::<_, Infallible>(service_fn(move |req: hyper::Request<hyper::Body>| {
                 let state = state.clone();
-                map_requests(req, state)
+                map_requests(req, state, remote_addr)
             }))
         }
     });
@@ -193,10 +196,14 @@ impl RequestLogger {
     }
 }
 
-async fn map_requests(req: hyper::Request<hyper::Body>, state: State) -> Result<hyper::Response<hyper::Body>, Error> {
+async fn map_requests(
+    req: hyper::Request<hyper::Body>,
+    state: State,
+    remote_addr: Option<SocketAddr>,
+) -> Result<hyper::Response<hyper::Body>, Error> {
     let logger = RequestLogger::begin(&req);
 
-    let req = Request::new(req, state).await;
+    let req = Request::new(req, state, remote_addr).await;
 
     // Save any updated auth details, e.g. if an OpenID Connect token needed
     // refreshing.
@@ -321,10 +328,19 @@ pub async fn render_not_found(_req: Request) -> RoutingResult {
     Ok(HttpResponse::not_found())
 }
 
-/// Returns the server health.
+/// Returns the server health, and readiness. The server is healthy if it
+/// responds, but it is only ready if it can sign.
 pub async fn health(req: Request) -> RoutingResult {
     if req.is_get() && req.path().segment() == "health" {
         render_ok()
+    } else if req.is_get() && req.path().segment() == "ready" {
+        match req.state().ready().await {
+            Ok(()) => render_ok(),
+            Err(e) => {
+                warn!("Readiness check failed: {}", e);
+                Ok(HttpResponse::service_unavailable(e.to_string()))
+            }
+        }
     } else {
         Err(req)
     }
@@ -384,6 +400,30 @@ pub async fn metrics(req: Request) -> RoutingResult {
         res.push_str("# TYPE krill_version_patch gauge\n");
         res.push_str(&format!("krill_version_patch {}\n", KRILL_VERSION_PATCH));
 
+        let signer_stats = server.signer_stats();
+        if let Some(last_success) = signer_stats.last_success {
+            res.push('\n');
+            res.push_str("# HELP krill_signer_last_success timestamp of last successful key creation or signing\n");
+            res.push_str("# TYPE krill_signer_last_success gauge\n");
+            res.push_str(&format!("krill_signer_last_success {}\n", last_success));
+        }
+
+        res.push('\n');
+        res.push_str("# HELP krill_signer_failures number of failed signer operations since server start\n");
+        res.push_str("# TYPE krill_signer_failures counter\n");
+        res.push_str(&format!(
+            "krill_signer_failures{{operation=\"create_key\"}} {}\n",
+            signer_stats.create_key_failures
+        ));
+        res.push_str(&format!(
+            "krill_signer_failures{{operation=\"sign\"}} {}\n",
+            signer_stats.sign_failures
+        ));
+        res.push_str(&format!(
+            "krill_signer_failures{{operation=\"sign_one_off\"}} {}\n",
+            signer_stats.sign_one_off_failures
+        ));
+
         if let Ok(stats) = server.repo_stats() {
             let publishers = stats.get_publishers();
 
@@ -559,6 +599,96 @@ pub async fn metrics(req: Request) -> RoutingResult {
             }
         }
 
+        let store_metrics = server.store_metrics();
+
+        res.push('\n');
+        res.push_str("# HELP krill_store_commands number of commands processed for aggregate\n");
+        res.push_str("# TYPE krill_store_commands counter\n");
+        for (store, metrics) in store_metrics.iter() {
+            for (handle, nr) in metrics.commands.iter() {
+                res.push_str(&format!(
+                    "krill_store_commands{{store=\"{}\",aggregate=\"{}\"}} {}\n",
+                    store, handle, nr
+                ));
+            }
+        }
+
+        res.push('\n');
+        res.push_str("# HELP krill_store_events_stored number of events stored\n");
+        res.push_str("# TYPE krill_store_events_stored counter\n");
+        for (store, metrics) in store_metrics.iter() {
+            res.push_str(&format!(
+                "krill_store_events_stored{{store=\"{}\"}} {}\n",
+                store, metrics.events_stored
+            ));
+        }
+
+        res.push('\n');
+        res.push_str("# HELP krill_store_snapshots_written number of snapshots written\n");
+        res.push_str("# TYPE krill_store_snapshots_written counter\n");
+        for (store, metrics) in store_metrics.iter() {
+            res.push_str(&format!(
+                "krill_store_snapshots_written{{store=\"{}\"}} {}\n",
+                store, metrics.snapshots_written
+            ));
+        }
+
+        res.push('\n');
+        res.push_str("# HELP krill_store_snapshot_bytes size of the snapshot last written for aggregate\n");
+        res.push_str("# TYPE krill_store_snapshot_bytes gauge\n");
+        for (store, metrics) in store_metrics.iter() {
+            for (handle, size) in metrics.snapshot_bytes.iter() {
+                res.push_str(&format!(
+                    "krill_store_snapshot_bytes{{store=\"{}\",aggregate=\"{}\"}} {}\n",
+                    store, handle, size
+                ));
+            }
+        }
+
+        res.push('\n');
+        res.push_str("# HELP krill_store_cache_hits number of aggregates found in memory\n");
+        res.push_str("# TYPE krill_store_cache_hits counter\n");
+        for (store, metrics) in store_metrics.iter() {
+            res.push_str(&format!(
+                "krill_store_cache_hits{{store=\"{}\"}} {}\n",
+                store, metrics.cache_hits
+            ));
+        }
+
+        res.push('\n');
+        res.push_str("# HELP krill_store_cache_misses number of aggregates loaded from disk\n");
+        res.push_str("# TYPE krill_store_cache_misses counter\n");
+        for (store, metrics) in store_metrics.iter() {
+            res.push_str(&format!(
+                "krill_store_cache_misses{{store=\"{}\"}} {}\n",
+                store, metrics.cache_misses
+            ));
+        }
+
+        res.push('\n');
+        res.push_str("# HELP krill_store_cache_evictions number of aggregates evicted from memory\n");
+        res.push_str("# TYPE krill_store_cache_evictions counter\n");
+        for (store, metrics) in store_metrics.iter() {
+            res.push_str(&format!(
+                "krill_store_cache_evictions{{store=\"{}\"}} {}\n",
+                store, metrics.cache_evictions
+            ));
+        }
+
+        res.push('\n');
+        res.push_str("# HELP krill_store_replay_seconds time spent replaying events onto aggregates\n");
+        res.push_str("# TYPE krill_store_replay_seconds summary\n");
+        for (store, metrics) in store_metrics.iter() {
+            res.push_str(&format!(
+                "krill_store_replay_seconds_sum{{store=\"{}\"}} {}\n",
+                store, metrics.replay_seconds
+            ));
+            res.push_str(&format!(
+                "krill_store_replay_seconds_count{{store=\"{}\"}} {}\n",
+                store, metrics.replays
+            ));
+        }
+
         #[cfg(feature = "multi-user")]
         {
             res.push('\n');
@@ -682,6 +812,10 @@ fn add_authorization_headers_to_response(org_response: HttpResponse, token: Toke
     new_header_names.push(HeaderName::from_str("Authorization"));
     new_header_values.push(HeaderValue::from_str(&format!("Bearer {}", &token)));
 
+    // The response carries a credential, it must never be served from a cache.
+    new_header_names.push(HeaderName::from_str("Cache-Control"));
+    new_header_values.push(HeaderValue::from_str("no-store"));
+
     let okay = !new_header_names
         .iter()
         .zip(new_header_values.iter())
@@ -717,6 +851,14 @@ fn add_authorization_headers_to_response(org_response: HttpResponse, token: Toke
     }
 }
 
+/// Returns a refreshed bearer token to the client, if the token sent with the
+/// request was replaced while authenticating it, e.g. because the OpenID Connect
+/// access token behind it had expired and was refreshed.
+///
+/// The new token is sent in an "Authorization: Bearer <token>" response header,
+/// on whichever response the request results in, including error responses.
+/// Clients should use it for all further requests, as the old token may no
+/// longer be accepted. Lagosta does this transparently.
 fn add_new_auth_to_response(res: Result<HttpResponse, Error>, opt_auth: Option<Auth>) -> Result<HttpResponse, Error> {
     if let Some(Auth::Bearer(token)) = opt_auth {
         res.map(|ok_res| add_authorization_headers_to_response(ok_res, token))
@@ -773,6 +915,7 @@ macro_rules! aa {
                     | Error::ApiAuthPermanentError(_)
                     | Error::ApiAuthTransientError(_)
                     | Error::ApiAuthSessionExpired(_)
+                    | Error::ApiAuthProviderTimeout(_)
                     | Error::ApiLoginError(_) => Ok(HttpResponse::response_from_error(err).with_benign($benign)),
                     _ => Ok(HttpResponse::forbidden(format!("{}", err)).with_benign($benign)),
                 }
@@ -796,9 +939,16 @@ async fn api(req: Request) -> RoutingResult {
                 // Make sure access is allowed
                 aa!(req, Permission::LOGIN, {
                     match restricted_endpoint {
+                        #[cfg(feature = "multi-user")]
+                        Some("authtokens") => api_auth_tokens(req, &mut path).await,
+                        #[cfg(feature = "multi-user")]
+                        Some("authclaims") => api_auth_claims(req).await,
+                        #[cfg(feature = "multi-user")]
+                        Some("authsessions") => api_auth_sessions(req, &mut path).await,
                         Some("bulk") => api_bulk(req, &mut path).await,
                         Some("cas") => api_cas(req, &mut path).await,
                         Some("pubd") => aa!(req, Permission::PUB_ADMIN, api_publication_server(req, &mut path).await),
+                        Some("store") => api_store(req, &mut path).await,
                         _ => render_unknown_method(),
                     }
                 })
@@ -822,6 +972,52 @@ async fn api_authorized(req: Request) -> RoutingResult {
     )
 }
 
+/// Mint, list and revoke API tokens
+#[cfg(feature = "multi-user")]
+async fn api_auth_tokens(req: Request, path: &mut RequestPath) -> RoutingResult {
+    aa!(req, Permission::AUTH_ADMIN, {
+        match path.next() {
+            None => match *req.method() {
+                Method::GET => render_json(req.state().api_token_list()),
+                Method::POST => {
+                    let server = req.state().clone();
+                    match req.json().await {
+                        Ok(token_req) => render_json_res(server.api_token_mint(token_req)),
+                        Err(e) => render_error(e),
+                    }
+                }
+                _ => render_unknown_method(),
+            },
+            Some(id) => match *req.method() {
+                Method::DELETE => render_empty_res(req.state().api_token_revoke(id)),
+                _ => render_unknown_method(),
+            },
+        }
+    })
+}
+
+/// Invalidate the login sessions of a user, or of all users
+#[cfg(feature = "multi-user")]
+async fn api_auth_sessions(req: Request, path: &mut RequestPath) -> RoutingResult {
+    aa!(req, Permission::AUTH_ADMIN, {
+        match *req.method() {
+            Method::DELETE => render_empty_res(req.state().login_sessions_invalidate(path.next())),
+            _ => render_unknown_method(),
+        }
+    })
+}
+
+/// Show how the attributes of recent OpenID Connect logins were resolved
+#[cfg(feature = "multi-user")]
+async fn api_auth_claims(req: Request) -> RoutingResult {
+    aa!(req, Permission::AUTH_ADMIN, {
+        match *req.method() {
+            Method::GET => render_json(req.state().auth_claim_traces()),
+            _ => render_unknown_method(),
+        }
+    })
+}
+
 async fn api_bulk(req: Request, path: &mut RequestPath) -> RoutingResult {
     match path.full() {
         "/api/v1/bulk/cas/issues" => api_all_ca_issues(req).await,
@@ -1255,10 +1451,35 @@ async fn api_ca_history_commands(req: Request, path: &mut RequestPath, handle: H
     }
 }
 
+async fn api_ca_history_since(req: Request, path: &mut RequestPath, handle: Handle) -> RoutingResult {
+    match *req.method() {
+        Method::GET => aa!(req, Permission::CA_READ, handle.clone(), {
+            // /api/v1/cas/{ca}/history/since/<sequence>/<rows>
+            let mut crit = CommandHistoryCriteria::default();
+
+            match path.path_arg() {
+                Some(sequence) => crit.set_after_sequence(sequence),
+                None => return render_unknown_method(),
+            }
+
+            if let Some(rows) = path.path_arg() {
+                crit.set_rows(rows);
+            }
+
+            match req.state().ca_history(&handle, crit).await {
+                Ok(history) => render_json(history),
+                Err(e) => render_error(e),
+            }
+        }),
+        _ => render_unknown_method(),
+    }
+}
+
 async fn api_ca_history(req: Request, path: &mut RequestPath, ca: Handle) -> RoutingResult {
     match path.next() {
         Some("details") => api_ca_command_details(req, path, ca).await,
         Some("commands") => api_ca_history_commands(req, path, ca).await,
+        Some("since") => api_ca_history_since(req, path, ca).await,
         _ => render_unknown_method(),
     }
 }
@@ -1600,6 +1821,52 @@ async fn api_refresh_all(req: Request) -> RoutingResult {
     }
 }
 
+//------------ Admin: Store integrity and recovery ---------------------------
+
+/// Check the integrity of the stored state, or start and monitor a recovery
+/// job. Recovery runs in the background, so the job status is returned when
+/// it is started and can be polled until it is finished. The keys that the
+/// signer has can be listed and compared to the keys used by CAs, and the
+/// signer can be re-initialized, e.g. after keys were restored.
+async fn api_store(req: Request, path: &mut RequestPath) -> RoutingResult {
+    aa!(req, Permission::CA_ADMIN, {
+        match path.next() {
+            Some("integrity") => match *req.method() {
+                Method::GET => render_json_res(req.state().store_integrity()),
+                _ => render_unknown_method(),
+            },
+            Some("recover") => match *req.method() {
+                Method::GET => render_json(req.state().store_recover_status()),
+                Method::POST => {
+                    let server = req.state().clone();
+                    let actor = req.actor();
+                    match req.json().await {
+                        Ok(target) => render_json_res(server.store_recover(target, &actor)),
+                        Err(e) => render_error(e),
+                    }
+                }
+                _ => render_unknown_method(),
+            },
+            Some("keys") => match path.next() {
+                None => match *req.method() {
+                    Method::GET => render_json_res(req.state().signer_keys()),
+                    _ => render_unknown_method(),
+                },
+                Some("reinit") => match *req.method() {
+                    Method::POST => render_json_res(req.state().signer_reinit(&req.actor())),
+                    _ => render_unknown_method(),
+                },
+                _ => render_unknown_method(),
+            },
+            Some("backup") => match *req.method() {
+                Method::POST => render_json_res(req.state().store_backup(&req.actor()).await),
+                _ => render_unknown_method(),
+            },
+            _ => render_unknown_method(),
+        }
+    })
+}
+
 //------------ Serve RRDP Files ----------------------------------------------
 
 async fn rrdp(req: Request) -> RoutingResult {
@@ -1,5 +1,7 @@
 //! An RPKI publication protocol server.
 use std::collections::HashMap;
+use std::fs;
+use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::sync::Arc;
 
@@ -19,8 +21,9 @@ use crate::commons::api::{
     RtaPrepResponse, ServerInfo, TaCertDetails, UpdateChildRequest,
 };
 use crate::commons::bgp::{BgpAnalyser, BgpAnalysisReport, BgpAnalysisSuggestion};
-use crate::commons::crypto::KrillSigner;
-use crate::commons::eventsourcing::CommandKey;
+use crate::commons::crypto::{KrillSigner, SignSupport, SignerStats};
+use crate::commons::error::{Error, KrillIoError};
+use crate::commons::eventsourcing::{AggregateStoreMetricsReport, CommandKey};
 use crate::commons::remote::rfc8183;
 use crate::commons::{KrillEmptyResult, KrillResult};
 use crate::constants::*;
@@ -28,15 +31,25 @@ use crate::constants::*;
 use crate::daemon::auth::common::session::LoginSessionCache;
 use crate::daemon::auth::providers::AdminTokenAuthProvider;
 #[cfg(feature = "multi-user")]
-use crate::daemon::auth::providers::{ConfigFileAuthProvider, OpenIDConnectAuthProvider};
-use crate::daemon::auth::{Authorizer, LoggedInUser};
+use crate::daemon::auth::providers::{
+    api_token::{ApiTokenInfo, ApiTokenMinted, ApiTokenRequest},
+    openid_connect::trace::{ClaimTrace, ClaimTraces},
+    ApiTokenAuthProvider, ApiTokenStore, ChainedAuthProvider, ConfigFileAuthProvider, OpenIDConnectAuthProvider,
+};
+#[cfg(feature = "multi-user")]
+use crate::daemon::auth::AuthProvider;
+use crate::daemon::auth::{Authorizer, LoggedInUser, WhoAmI};
 use crate::daemon::ca::{
     self, ta_handle, testbed_ca_handle, ResourceTaggedAttestation, RouteAuthorizationUpdates, RtaContentRequest,
     RtaPrepareRequest,
 };
 use crate::daemon::config::{AuthType, Config};
+use crate::daemon::http::ratelimit::LoginRateLimiter;
 use crate::daemon::http::HttpResponse;
 use crate::daemon::mq::MessageQueue;
+use crate::daemon::recovery::{
+    RecoveryJob, RecoveryJobs, RecoveryTarget, SignerKeysReport, StoreBackupReport, StoreIntegrityReport,
+};
 use crate::daemon::scheduler::Scheduler;
 use crate::pubd::{RepoStats, RepositoryManager};
 
@@ -69,13 +82,33 @@ pub struct KrillServer {
     // Time this server was started
     started: Time,
 
+    // Used to check that the signer is (still) ready
+    signer: Arc<KrillSigner>,
+
     // Global size constraints on things which can be posted
     post_limits: PostLimits,
 
+    // Limits the number of anonymous login requests per client
+    login_rate_limiter: LoginRateLimiter,
+
+    // The current, or last, operator triggered recovery of stored state
+    recovery_jobs: Arc<RecoveryJobs>,
+
+    // Where backups of the stored state are written, if enabled
+    backup_dir: Option<PathBuf>,
+
     #[cfg(feature = "multi-user")]
     // Global login session cache
     login_session_cache: Arc<LoginSessionCache>,
 
+    #[cfg(feature = "multi-user")]
+    // Hashed API tokens, for use by the api-token auth provider
+    api_tokens: Arc<ApiTokenStore>,
+
+    #[cfg(feature = "multi-user")]
+    // How the attributes of recent OpenID Connect logins were resolved
+    claim_traces: Arc<ClaimTraces>,
+
     // System actor
     system_actor: Actor,
 }
@@ -104,6 +137,28 @@ impl PostLimits {
 
 /// # Set up and initialization
 impl KrillServer {
+    /// Logs a warning for each key used by a CA that the signer cannot find.
+    /// This does not stop the server, so that other CAs keep working, but it
+    /// tells operators up front which CAs will fail to sign.
+    fn verify_keys(ca_manager: &ca::CaManager) {
+        match ca_manager.cas_missing_keys() {
+            Ok(missing) if missing.is_empty() => info!("Verified that the signer has all keys used by CAs"),
+            Ok(missing) => {
+                for key in &missing {
+                    warn!(
+                        "CA '{}' uses key '{}' which the signer cannot find: {}",
+                        key.ca, key.key_id, key.msg
+                    );
+                }
+                warn!(
+                    "The signer cannot find {} keys used by CAs, these CAs will fail when they need to sign",
+                    missing.len()
+                );
+            }
+            Err(e) => warn!("Could not verify the keys used by CAs: {}", e),
+        }
+    }
+
     /// Creates a new publication server. Note that state is preserved
     /// on disk in the work_dir provided.
     pub async fn build(config: Arc<Config>) -> KrillResult<Self> {
@@ -120,10 +175,19 @@ impl KrillServer {
         let mut repo_dir = work_dir.clone();
         repo_dir.push("repo");
 
-        let signer = Arc::new(KrillSigner::build(work_dir)?);
+        let signer = Arc::new(KrillSigner::build_with_keys_dir(&config.keys_dir())?);
+        signer.set_sharded_keys(config.storage_shard_keys)?;
+        signer
+            .health_check()
+            .map_err(|e| Error::signer(format!("Signer failed its health check at startup: {}", e)))?;
+        SignSupport::set_backdate_seconds(config.issuance_timing.issuance_backdate_seconds);
 
         #[cfg(feature = "multi-user")]
         let login_session_cache = Arc::new(LoginSessionCache::new());
+        #[cfg(feature = "multi-user")]
+        let api_tokens = Arc::new(ApiTokenStore::build(&config)?);
+        #[cfg(feature = "multi-user")]
+        let claim_traces = Arc::new(ClaimTraces::new());
 
         // Construct the authorizer used to verify API access requests and to
         // tell Lagosta where to send end-users to login and logout.
@@ -132,8 +196,15 @@ impl KrillServer {
         // dyn AuthProvider, or concrete type needs to be known in async fn,
         // etc.
         let authorizer = match config.auth_type {
+            #[cfg(feature = "multi-user")]
+            _ if !config.auth_chain.is_empty() => Authorizer::new(
+                config.clone(),
+                Self::chained_auth_provider(&config, &login_session_cache, &api_tokens, &claim_traces)?,
+            )?,
             AuthType::AdminToken => Authorizer::new(config.clone(), AdminTokenAuthProvider::new(config.clone()))?,
             #[cfg(feature = "multi-user")]
+            AuthType::ApiToken => Authorizer::new(config.clone(), ApiTokenAuthProvider::new(api_tokens.clone()))?,
+            #[cfg(feature = "multi-user")]
             AuthType::ConfigFile => Authorizer::new(
                 config.clone(),
                 ConfigFileAuthProvider::new(config.clone(), login_session_cache.clone())?,
@@ -141,7 +212,7 @@ impl KrillServer {
             #[cfg(feature = "multi-user")]
             AuthType::OpenIDConnect => Authorizer::new(
                 config.clone(),
-                OpenIDConnectAuthProvider::new(config.clone(), login_session_cache.clone())?,
+                OpenIDConnectAuthProvider::new(config.clone(), login_session_cache.clone(), claim_traces.clone())?,
             )?,
         };
         let system_actor = authorizer.actor_from_def(ACTOR_DEF_KRILL);
@@ -153,7 +224,11 @@ impl KrillServer {
         // Used to have a shared queue for the caserver and the background job scheduler.
         let event_queue = Arc::new(MessageQueue::default());
 
-        let ca_manager = Arc::new(ca::CaManager::build(config.clone(), event_queue.clone(), signer).await?);
+        let ca_manager = Arc::new(ca::CaManager::build(config.clone(), event_queue.clone(), signer.clone()).await?);
+
+        if config.storage_verify_keys {
+            Self::verify_keys(&ca_manager);
+        }
 
         if let Some(testbed) = config.testbed() {
             let uris = testbed.publication_server_uris();
@@ -220,6 +295,7 @@ impl KrillServer {
         let scheduler = Scheduler::build(
             event_queue,
             ca_manager.clone(),
+            repo_manager.clone(),
             bgp_analyser.clone(),
             #[cfg(feature = "multi-user")]
             login_session_cache.clone(),
@@ -242,13 +318,56 @@ impl KrillServer {
             bgp_analyser,
             scheduler,
             started: Time::now(),
+            signer,
             post_limits,
+            login_rate_limiter: LoginRateLimiter::from_config(&config),
+            recovery_jobs: Arc::new(RecoveryJobs::default()),
+            backup_dir: config.storage_backup_dir.clone(),
             #[cfg(feature = "multi-user")]
             login_session_cache,
+            #[cfg(feature = "multi-user")]
+            api_tokens,
+            #[cfg(feature = "multi-user")]
+            claim_traces,
             system_actor,
         })
     }
 
+    /// Builds a provider which tries the providers in the 'auth_chain' config
+    /// in order, followed by the 'auth_type' provider which is also used for
+    /// logging in and out.
+    #[cfg(feature = "multi-user")]
+    fn chained_auth_provider(
+        config: &Arc<Config>,
+        login_session_cache: &Arc<LoginSessionCache>,
+        api_tokens: &Arc<ApiTokenStore>,
+        claim_traces: &Arc<ClaimTraces>,
+    ) -> KrillResult<ChainedAuthProvider> {
+        let provider = |auth_type: &AuthType| -> KrillResult<Box<dyn AuthProvider>> {
+            let boxed: Box<dyn AuthProvider> = match auth_type {
+                AuthType::AdminToken => Box::new(AdminTokenAuthProvider::new(config.clone())),
+                AuthType::ApiToken => Box::new(ApiTokenAuthProvider::new(api_tokens.clone())),
+                AuthType::ConfigFile => Box::new(ConfigFileAuthProvider::new(
+                    config.clone(),
+                    login_session_cache.clone(),
+                )?),
+                AuthType::OpenIDConnect => Box::new(OpenIDConnectAuthProvider::new(
+                    config.clone(),
+                    login_session_cache.clone(),
+                    claim_traces.clone(),
+                )?),
+            };
+            Ok(boxed)
+        };
+
+        let providers = config
+            .auth_chain
+            .iter()
+            .map(&provider)
+            .collect::<KrillResult<Vec<_>>>()?;
+        Ok(ChainedAuthProvider::new(providers, provider(&config.auth_type)?))
+    }
+
     pub fn service_base_uri(&self) -> &uri::Https {
         &self.service_uri
     }
@@ -256,6 +375,37 @@ impl KrillServer {
     pub fn server_info(&self) -> ServerInfo {
         ServerInfo::new(KRILL_VERSION, self.started)
     }
+
+    pub fn signer_stats(&self) -> SignerStats {
+        self.signer.stats()
+    }
+
+    /// Checks the parts of the config which can only be verified by talking
+    /// to other services, i.e. the OpenID Connect provider, without building
+    /// the server. Returns a description of each problem found.
+    #[cfg(feature = "multi-user")]
+    pub fn check_config(config: Arc<Config>) -> KrillResult<Vec<String>> {
+        let oidc = AuthType::OpenIDConnect;
+        if config.auth_type == oidc || config.auth_chain.contains(&oidc) {
+            OpenIDConnectAuthProvider::check_config(config)
+        } else {
+            Ok(vec![])
+        }
+    }
+
+    #[cfg(not(feature = "multi-user"))]
+    pub fn check_config(_: Arc<Config>) -> KrillResult<Vec<String>> {
+        Ok(vec![])
+    }
+
+    /// Checks that the server is ready to do its work, i.e. that the signer
+    /// responds. The full signing round-trip is only done at startup, because
+    /// anyone can ask whether the server is ready.
+    pub async fn ready(&self) -> KrillEmptyResult {
+        self.signer
+            .ready_check()
+            .map_err(|e| Error::signer(format!("Signer failed its readiness check: {}", e)))
+    }
 }
 
 /// # Authentication and Access
@@ -272,8 +422,8 @@ impl KrillServer {
         self.authorizer.actor_from_def(actor_def)
     }
 
-    pub fn get_login_url(&self) -> KrillResult<HttpResponse> {
-        self.authorizer.get_login_url()
+    pub fn get_login_url(&self, request: &hyper::Request<hyper::Body>) -> KrillResult<HttpResponse> {
+        self.authorizer.get_login_url(request)
     }
 
     pub fn login(&self, request: &hyper::Request<hyper::Body>) -> KrillResult<LoggedInUser> {
@@ -284,6 +434,18 @@ impl KrillServer {
         self.authorizer.logout(request)
     }
 
+    pub fn refresh(&self, request: &hyper::Request<hyper::Body>) -> KrillResult<LoggedInUser> {
+        self.authorizer.refresh(request)
+    }
+
+    pub fn whoami(&self, actor: &Actor, request: &hyper::Request<hyper::Body>) -> KrillResult<WhoAmI> {
+        self.authorizer.whoami(actor, request)
+    }
+
+    pub fn login_permitted(&self, request: &hyper::Request<hyper::Body>, remote_addr: Option<SocketAddr>) -> bool {
+        self.login_rate_limiter.permit(request, remote_addr)
+    }
+
     pub fn limit_api(&self) -> u64 {
         self.post_limits.api()
     }
@@ -300,10 +462,50 @@ impl KrillServer {
         self.ca_manager.testbed_enabled()
     }
 
+    /// Returns the metrics for the CA and publication server aggregate stores, by name.
+    pub fn store_metrics(&self) -> Vec<(&'static str, AggregateStoreMetricsReport)> {
+        vec![
+            ("cas", self.ca_manager.ca_store_metrics()),
+            ("pubd", self.repo_manager.store_metrics()),
+        ]
+    }
+
     #[cfg(feature = "multi-user")]
     pub fn login_session_cache_size(&self) -> usize {
         self.login_session_cache.size()
     }
+
+    /// Invalidates the login sessions of the given user, or of all users.
+    #[cfg(feature = "multi-user")]
+    pub fn login_sessions_invalidate(&self, id: Option<&str>) -> KrillEmptyResult {
+        match id {
+            Some(id) => self.login_session_cache.invalidate_user(id),
+            None => self.login_session_cache.invalidate_all(),
+        }
+    }
+
+    /// Mints a new API token, the plaintext token is only returned here.
+    #[cfg(feature = "multi-user")]
+    pub fn api_token_mint(&self, req: ApiTokenRequest) -> KrillResult<ApiTokenMinted> {
+        self.api_tokens.mint(req)
+    }
+
+    #[cfg(feature = "multi-user")]
+    pub fn api_token_list(&self) -> Vec<ApiTokenInfo> {
+        self.api_tokens.list()
+    }
+
+    #[cfg(feature = "multi-user")]
+    pub fn api_token_revoke(&self, id: &str) -> KrillEmptyResult {
+        self.api_tokens.revoke(id)
+    }
+
+    /// Returns how the attributes of recent OpenID Connect logins were
+    /// resolved, if 'claim_trace' is enabled.
+    #[cfg(feature = "multi-user")]
+    pub fn auth_claim_traces(&self) -> Vec<ClaimTrace> {
+        self.claim_traces.list()
+    }
 }
 
 /// # Configure publishers
@@ -556,6 +758,118 @@ impl KrillServer {
     }
 }
 
+/// # Integrity and recovery of stored state
+///
+impl KrillServer {
+    /// Checks the stored state of all CAs and the publication server, without
+    /// changing anything.
+    pub fn store_integrity(&self) -> KrillResult<StoreIntegrityReport> {
+        Ok(StoreIntegrityReport {
+            cas: self.ca_manager.cas_check_integrity()?,
+            pubd: self.repo_manager.check_integrity()?,
+        })
+    }
+
+    /// Lists the keys that the signer has, and compares them to the keys used
+    /// by CAs. Nothing is changed.
+    pub fn signer_keys(&self) -> KrillResult<SignerKeysReport> {
+        let keys = self.signer.list_keys()?;
+        let used = self.ca_manager.cas_key_ids()?;
+        let unused = keys.iter().filter(|key| !used.contains(key)).cloned().collect();
+
+        Ok(SignerKeysReport {
+            keys,
+            missing: self.ca_manager.cas_missing_keys()?,
+            unused,
+        })
+    }
+
+    /// Re-initializes the signer backend from its keys dir, e.g. after keys were
+    /// restored while Krill was running, and then lists its keys as `signer_keys`
+    /// does. See `KrillSigner::reinit`.
+    pub fn signer_reinit(&self, actor: &Actor) -> KrillResult<SignerKeysReport> {
+        info!("Signer re-initialization started by '{}'", actor.name());
+        self.signer.reinit()?;
+        self.signer_keys()
+    }
+
+    /// Starts a background job to recover the stored state of the target, and
+    /// returns it. Fails if a previous job is still running.
+    pub fn store_recover(&self, target: RecoveryTarget, actor: &Actor) -> KrillResult<RecoveryJob> {
+        if let RecoveryTarget::Ca { ca } = &target {
+            if !self.ca_manager.has_ca(ca)? {
+                return Err(Error::CaUnknown(ca.clone()));
+            }
+        }
+
+        let job = self.recovery_jobs.start(target.clone(), actor.name())?;
+        info!("Recovery job {} for {:?} started by '{}'", job.id, target, actor.name());
+
+        let id = job.id;
+        let jobs = self.recovery_jobs.clone();
+        let ca_manager = self.ca_manager.clone();
+        let repo_manager = self.repo_manager.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let recover_pubd = || repo_manager.recover().map(|plan| plan.into_iter().collect::<Vec<_>>());
+            let res = match target {
+                RecoveryTarget::All => ca_manager.cas_recover(None).and_then(|mut plans| {
+                    plans.append(&mut recover_pubd()?);
+                    Ok(plans)
+                }),
+                RecoveryTarget::Cas => ca_manager.cas_recover(None),
+                RecoveryTarget::Ca { ca } => ca_manager.cas_recover(Some(&ca)),
+                RecoveryTarget::Pubd => recover_pubd(),
+            };
+            jobs.finish(id, res);
+        });
+
+        Ok(job)
+    }
+
+    /// Returns the current, or last, recovery job.
+    pub fn store_recover_status(&self) -> Option<RecoveryJob> {
+        self.recovery_jobs.status()
+    }
+
+    /// Writes a backup of the stored state of all CAs and the publication
+    /// server to a new directory in the configured backup dir, and returns
+    /// what was written. Commands can still be processed while this runs.
+    pub async fn store_backup(&self, actor: &Actor) -> KrillResult<StoreBackupReport> {
+        let backup_dir = self
+            .backup_dir
+            .as_ref()
+            .ok_or_else(|| Error::custom("No storage_backup_dir is configured"))?;
+
+        let dir = backup_dir.join(format!("backup-{}", Time::now().timestamp()));
+        fs::create_dir_all(backup_dir)
+            .and_then(|_| fs::create_dir(&dir))
+            .map_err(|e| KrillIoError::new(format!("Could not create backup dir '{}'", dir.to_string_lossy()), e))?;
+
+        info!("Backup to '{}' started by '{}'", dir.to_string_lossy(), actor.name());
+
+        let ca_manager = self.ca_manager.clone();
+        let repo_manager = self.repo_manager.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let report = StoreBackupReport {
+                cas: ca_manager.cas_backup(&dir)?,
+                pubd: repo_manager.backup(&dir)?,
+                dir,
+            };
+            if !report.is_complete() {
+                warn!(
+                    "Backup to '{}' does not include commands or events that were archived while it was made",
+                    report.dir.to_string_lossy()
+                );
+            }
+            Ok(report)
+        })
+        .await
+        .map_err(|e| Error::Custom(format!("Backup did not finish: {}", e)))?
+    }
+}
+
 /// # Admin CAS
 ///
 impl KrillServer {
@@ -4,4 +4,5 @@ pub mod config;
 pub mod http;
 pub mod krillserver;
 pub mod mq;
+pub mod recovery;
 pub mod scheduler;
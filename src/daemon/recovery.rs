@@ -0,0 +1,206 @@
+//! Operator triggered integrity checks and recovery of the stored state of
+//! CAs and the publication server.
+//!
+//! Recovery can take a long time and archives commands and events, so it is
+//! run as a background job. Only one job runs at a time, and its status can be
+//! polled until it is finished.
+//!
+//! Backups of the stored state are written to a new directory in the backup
+//! dir, while commands keep on being processed. See `AggregateStore::backup`
+//! for what is included.
+//!
+//! The keys that the signer has can be compared to the keys used by CAs, to
+//! find keys that went missing, e.g. because they were not restored.
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use rpki::crypto::KeyIdentifier;
+use rpki::x509::Time;
+
+use crate::commons::api::Handle;
+use crate::commons::error::Error;
+use crate::commons::eventsourcing::{AggregateIntegrityReport, AggregateStoreBackup, RecoveryPlan};
+use crate::commons::KrillResult;
+use crate::daemon::ca::MissingKey;
+
+//------------ StoreIntegrityReport ------------------------------------------
+
+/// The outcome of a read-only integrity check of all stored state.
+#[derive(Clone, Debug, Serialize)]
+pub struct StoreIntegrityReport {
+    pub cas: Vec<AggregateIntegrityReport>,
+    pub pubd: Option<AggregateIntegrityReport>,
+}
+
+impl StoreIntegrityReport {
+    /// Returns true if all aggregates are ok.
+    pub fn is_ok(&self) -> bool {
+        self.cas.iter().chain(self.pubd.iter()).all(|report| report.is_ok())
+    }
+}
+
+//------------ StoreBackupReport ---------------------------------------------
+
+/// Where a backup of all stored state was written, and what it contains.
+#[derive(Clone, Debug, Serialize)]
+pub struct StoreBackupReport {
+    pub dir: PathBuf,
+    pub cas: AggregateStoreBackup,
+    pub pubd: Option<AggregateStoreBackup>,
+}
+
+impl StoreBackupReport {
+    /// Returns true if no commands or events were archived while the backup
+    /// was made.
+    pub fn is_complete(&self) -> bool {
+        self.cas.missing == 0 && self.pubd.as_ref().map(|pubd| pubd.missing == 0).unwrap_or(true)
+    }
+}
+
+//------------ SignerKeysReport ----------------------------------------------
+
+/// The keys that the signer has, compared to the keys used by CAs.
+#[derive(Clone, Debug, Serialize)]
+pub struct SignerKeysReport {
+    /// All keys that the signer has.
+    pub keys: Vec<KeyIdentifier>,
+
+    /// Keys used by CAs that the signer cannot find.
+    pub missing: Vec<MissingKey>,
+
+    /// Keys that the signer has, but that are not used by any CA. These can
+    /// be old keys that were not destroyed, or the key of the publication
+    /// server.
+    pub unused: Vec<KeyIdentifier>,
+}
+
+//------------ RecoveryTarget ------------------------------------------------
+
+/// What to recover.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case", tag = "target")]
+pub enum RecoveryTarget {
+    All,
+    Cas,
+    Ca { ca: Handle },
+    Pubd,
+}
+
+//------------ RecoveryJobState ----------------------------------------------
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "snake_case", tag = "state")]
+pub enum RecoveryJobState {
+    Running,
+    Finished { plans: Vec<RecoveryPlan> },
+    Failed { msg: String },
+}
+
+//------------ RecoveryJob ---------------------------------------------------
+
+/// A recovery job, and what it did once it is finished.
+#[derive(Clone, Debug, Serialize)]
+pub struct RecoveryJob {
+    pub id: u64,
+    #[serde(flatten)]
+    pub target: RecoveryTarget,
+    pub actor: String,
+    pub started: i64,
+    pub finished: Option<i64>,
+    #[serde(flatten)]
+    pub state: RecoveryJobState,
+}
+
+impl RecoveryJob {
+    pub fn is_running(&self) -> bool {
+        matches!(self.state, RecoveryJobState::Running)
+    }
+}
+
+//------------ RecoveryJobs --------------------------------------------------
+
+/// Keeps the current, or last, recovery job.
+#[derive(Default)]
+pub struct RecoveryJobs {
+    last: Mutex<Option<RecoveryJob>>,
+}
+
+impl RecoveryJobs {
+    /// Registers a new running job, unless the previous job is still running.
+    pub fn start(&self, target: RecoveryTarget, actor: &str) -> KrillResult<RecoveryJob> {
+        let mut last = self.last.lock().unwrap();
+
+        let id = match last.as_ref() {
+            Some(job) if job.is_running() => {
+                return Err(Error::Custom(format!(
+                    "Recovery job {} started by '{}' is still running",
+                    job.id, job.actor
+                )))
+            }
+            Some(job) => job.id + 1,
+            None => 1,
+        };
+
+        let job = RecoveryJob {
+            id,
+            target,
+            actor: actor.to_string(),
+            started: Time::now().timestamp(),
+            finished: None,
+            state: RecoveryJobState::Running,
+        };
+        *last = Some(job.clone());
+        Ok(job)
+    }
+
+    /// Records the outcome of the job.
+    pub fn finish(&self, id: u64, res: KrillResult<Vec<RecoveryPlan>>) {
+        let mut last = self.last.lock().unwrap();
+        if let Some(job) = last.as_mut().filter(|job| job.id == id) {
+            job.finished = Some(Time::now().timestamp());
+            job.state = match res {
+                Ok(plans) => {
+                    info!("Recovery job {} finished, recovered {} aggregates", id, plans.len());
+                    RecoveryJobState::Finished { plans }
+                }
+                Err(e) => {
+                    error!("Recovery job {} failed: {}", id, e);
+                    RecoveryJobState::Failed { msg: e.to_string() }
+                }
+            };
+        }
+    }
+
+    /// Returns the current, or last, job if any.
+    pub fn status(&self) -> Option<RecoveryJob> {
+        self.last.lock().unwrap().clone()
+    }
+}
+
+//------------ Tests ---------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn one_job_at_a_time() {
+        let jobs = RecoveryJobs::default();
+        assert!(jobs.status().is_none());
+
+        let job = jobs.start(RecoveryTarget::All, "admin").unwrap();
+        assert!(jobs.status().unwrap().is_running());
+        assert!(jobs.start(RecoveryTarget::Pubd, "admin").is_err());
+
+        jobs.finish(job.id, Ok(vec![]));
+        let status = jobs.status().unwrap();
+        assert!(!status.is_running());
+        assert!(status.finished.is_some());
+
+        let job = jobs.start(RecoveryTarget::Pubd, "admin").unwrap();
+        assert_eq!(job.id, 2);
+        jobs.finish(job.id, Err(Error::custom("disk full")));
+        assert!(matches!(jobs.status().unwrap().state, RecoveryJobState::Failed { .. }));
+    }
+}
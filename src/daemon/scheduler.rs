@@ -24,6 +24,7 @@ use crate::{
         config::Config,
         mq::{MessageQueue, QueueTask},
     },
+    pubd::RepositoryManager,
 };
 
 #[cfg(feature = "multi-user")]
@@ -53,6 +54,10 @@ pub struct Scheduler {
     #[allow(dead_code)] // just need to keep this in scope
     announcements_refresh: ScheduleHandle,
 
+    /// Responsible for archiving old commands, if configured
+    #[allow(dead_code)] // just need to keep this in scope
+    archive_old_commands: Option<ScheduleHandle>,
+
     #[cfg(feature = "multi-user")]
     /// Responsible for purging expired cached login tokens
     #[allow(dead_code)] // just need to keep this in scope
@@ -63,6 +68,7 @@ impl Scheduler {
     pub fn build(
         event_queue: Arc<MessageQueue>,
         ca_manager: Arc<CaManager>,
+        repo_manager: Arc<RepositoryManager>,
         bgp_analyser: Arc<BgpAnalyser>,
         #[cfg(feature = "multi-user")] login_session_cache: Arc<LoginSessionCache>,
         config: &Config,
@@ -72,10 +78,14 @@ impl Scheduler {
 
         let cas_republish = make_cas_republish(ca_manager.clone(), event_queue);
         let cas_roas_renew = make_cas_roa_renew(ca_manager.clone(), actor.clone());
-        let cas_refresh = make_cas_refresh(ca_manager, config.ca_refresh, actor.clone());
+        let cas_refresh = make_cas_refresh(ca_manager.clone(), config.ca_refresh, actor.clone());
 
         let announcements_refresh = make_announcements_refresh(bgp_analyser);
 
+        let archive_old_commands = config
+            .archive_threshold_days
+            .map(|days| make_archive_old_commands(ca_manager, repo_manager, days, config));
+
         #[cfg(feature = "multi-user")]
         let login_cache_sweeper_sh = make_login_cache_sweeper_sh(login_session_cache);
 
@@ -85,6 +95,7 @@ impl Scheduler {
             cas_roas_renew,
             cas_refresh,
             announcements_refresh,
+            archive_old_commands,
             #[cfg(feature = "multi-user")]
             login_cache_sweeper_sh,
         }
@@ -273,6 +284,26 @@ fn make_announcements_refresh(bgp_analyser: Arc<BgpAnalyser>) -> ScheduleHandle
     })
 }
 
+fn make_archive_old_commands(
+    ca_manager: Arc<CaManager>,
+    repo_manager: Arc<RepositoryManager>,
+    days: i64,
+    config: &Config,
+) -> ScheduleHandle {
+    let labels = config.archive_command_labels.clone();
+    SkippingScheduler::run(config.archive_interval_seconds, "archive old commands", move || {
+        let eligible: Vec<&str> = labels.iter().map(|s| s.as_str()).collect();
+
+        debug!("Archiving commands older than {} days", days);
+        if let Err(e) = ca_manager.cas_archive_old_commands(days, &eligible) {
+            error!("Failed to archive old commands for CAs: {}", e);
+        }
+        if let Err(e) = repo_manager.archive_old_commands(days, &eligible) {
+            error!("Failed to archive old commands for the publication server: {}", e);
+        }
+    })
+}
+
 #[cfg(feature = "multi-user")]
 fn make_login_cache_sweeper_sh(cache: Arc<LoginSessionCache>) -> ScheduleHandle {
     SkippingScheduler::run(60, "sweep session decryption cache", move || {
@@ -1,3 +1,4 @@
+use std::path::Path;
 use std::sync::Arc;
 
 use bytes::Bytes;
@@ -5,6 +6,9 @@ use bytes::Bytes;
 use crate::commons::api::PublicationServerUris;
 use crate::commons::crypto::KrillSigner;
 use crate::commons::error::Error;
+use crate::commons::eventsourcing::{
+    AggregateIntegrityReport, AggregateStoreBackup, AggregateStoreMetricsReport, RecoveryPlan,
+};
 use crate::commons::remote::cmslogger::CmsLogger;
 use crate::commons::remote::rfc8181;
 use crate::commons::remote::rfc8183;
@@ -73,6 +77,32 @@ impl RepositoryManager {
     pub fn publishers(&self) -> KrillResult<Vec<PublisherHandle>> {
         self.access.publishers()
     }
+
+    /// Archive old commands with an eligible label for the publication server.
+    pub fn archive_old_commands(&self, days: i64, eligible: &[&str]) -> KrillResult<()> {
+        self.access.archive_old_commands(days, eligible)
+    }
+
+    /// Returns the metrics for the publication server access aggregate store.
+    pub fn store_metrics(&self) -> AggregateStoreMetricsReport {
+        self.access.store_metrics()
+    }
+
+    /// Checks the stored state of the publication server, if it is initialized.
+    pub fn check_integrity(&self) -> KrillResult<Option<AggregateIntegrityReport>> {
+        self.access.check_integrity()
+    }
+
+    /// Writes a copy of the stored state of the publication server to the given
+    /// dir, if it is initialized.
+    pub fn backup(&self, dir: &Path) -> KrillResult<Option<AggregateStoreBackup>> {
+        self.access.backup(dir)
+    }
+
+    /// Recovers the stored state of the publication server, if it is initialized.
+    pub fn recover(&self) -> KrillResult<Option<RecoveryPlan>> {
+        self.access.recover()
+    }
 }
 
 /// # Publication Protocol support
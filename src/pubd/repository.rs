@@ -26,7 +26,10 @@ use crate::{
         },
         crypto::{IdCert, KrillSigner, ProtocolCms, ProtocolCmsBuilder},
         error::{Error, KrillIoError},
-        eventsourcing::{Aggregate, AggregateStore, KeyStoreKey, KeyValueStore},
+        eventsourcing::{
+            Aggregate, AggregateIntegrityReport, AggregateStore, AggregateStoreBackup, AggregateStoreMetricsReport,
+            KeyStoreKey, KeyValueStore, RecoveryPlan,
+        },
         remote::rfc8183,
         util::file,
         KrillResult,
@@ -928,7 +931,17 @@ pub struct RepositoryAccessProxy {
 
 impl RepositoryAccessProxy {
     pub fn disk(config: &Config) -> KrillResult<Self> {
-        let store = AggregateStore::<RepositoryAccess>::disk(&config.data_dir, PUBSERVER_DIR)?;
+        let mut store = AggregateStore::<RepositoryAccess>::disk(&config.data_dir, PUBSERVER_DIR)?;
+        store.set_compress(config.storage_compress);
+        store.set_cache_size(config.storage_cache_size);
+        store.set_lazy_warm(config.storage_lazy_warm);
+        let (size_warn, size_max) = config.storage_size_limits();
+        store.set_size_limits(size_warn, size_max);
+        store.set_archive(
+            config.storage_archive_dir.as_deref(),
+            config.storage_archive_max_entries,
+        );
+        store.set_audit_log(config.audit_log_file.as_deref(), PUBSERVER_DIR)?;
         let key = Handle::from_str(PUBSERVER_DFLT).unwrap();
 
         if store.has(&key)? {
@@ -975,6 +988,41 @@ impl RepositoryAccessProxy {
         }
     }
 
+    pub fn archive_old_commands(&self, days: i64, eligible: &[&str]) -> KrillResult<()> {
+        if self.initialized()? {
+            self.store.archive_old_commands(&self.key, days, eligible)?;
+        }
+        Ok(())
+    }
+
+    pub fn store_metrics(&self) -> AggregateStoreMetricsReport {
+        self.store.metrics()
+    }
+
+    pub fn check_integrity(&self) -> KrillResult<Option<AggregateIntegrityReport>> {
+        if self.initialized()? {
+            Ok(Some(self.store.check_aggregate_integrity(&self.key)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn backup(&self, dir: &Path) -> KrillResult<Option<AggregateStoreBackup>> {
+        if self.initialized()? {
+            Ok(Some(self.store.backup(dir, PUBSERVER_DIR)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn recover(&self) -> KrillResult<Option<RecoveryPlan>> {
+        if self.initialized()? {
+            Ok(Some(self.store.recover_aggregate(&self.key)?))
+        } else {
+            Ok(None)
+        }
+    }
+
     fn read(&self) -> KrillResult<Arc<RepositoryAccess>> {
         self.store
             .get_latest(&self.key)
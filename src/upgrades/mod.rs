@@ -188,7 +188,7 @@ fn upgrade_0_9_0(config: Arc<Config>) -> Result<(), UpgradeError> {
     }
 
     if needs_v0_9_0_upgrade(work_dir, "cas") {
-        let signer = Arc::new(KrillSigner::build(work_dir)?);
+        let signer = Arc::new(KrillSigner::build_with_keys_dir(&config.keys_dir())?);
         let repo_manager = RepositoryManager::build(config.clone(), signer)?;
         
         CaObjectsMigration::migrate(config, repo_manager)?;
@@ -202,7 +202,7 @@ impl From<OldStorableCaCommand> for StorableCaCommand {
             }
             OldStorableCaCommand::ChildRemove(child) => StorableCaCommand::ChildRemove { child },
 
-            OldStorableCaCommand::GenerateNewIdKey => StorableCaCommand::GenerateNewIdKey,
+            OldStorableCaCommand::GenerateNewIdKey => StorableCaCommand::GenerateNewIdKey { created_keys: None },
 
             OldStorableCaCommand::AddParent(parent, contact) => StorableCaCommand::AddParent {
                 parent,
@@ -221,16 +221,21 @@ impl From<OldStorableCaCommand> for StorableCaCommand {
                         resources,
                     })
                     .collect();
-                StorableCaCommand::UpdateResourceEntitlements { parent, entitlements }
+                StorableCaCommand::UpdateResourceEntitlements {
+                    parent,
+                    entitlements,
+                    created_keys: None,
+                }
             }
             OldStorableCaCommand::UpdateRcvdCert(resource_class_name, resources) => StorableCaCommand::UpdateRcvdCert {
                 resource_class_name,
                 resources,
             },
 
-            OldStorableCaCommand::KeyRollInitiate(older_than_seconds) => {
-                StorableCaCommand::KeyRollInitiate { older_than_seconds }
-            }
+            OldStorableCaCommand::KeyRollInitiate(older_than_seconds) => StorableCaCommand::KeyRollInitiate {
+                older_than_seconds,
+                created_keys: None,
+            },
             OldStorableCaCommand::KeyRollActivate(staged_for_seconds) => {
                 StorableCaCommand::KeyRollActivate { staged_for_seconds }
             }
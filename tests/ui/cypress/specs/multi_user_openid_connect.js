@@ -345,6 +345,40 @@ describe('OpenID Connect provider with RP-Initiated logout', () => {
     }
   });
 
+  it('Refreshed token is returned to the client in the Authorization response header', () => {
+    let token_secs = 2;
+
+    // login
+    cy.visit('/')
+    cy.url().should('not.include', Cypress.config('baseUrl'))
+    cy.contains('Mock OpenID Connect login form')
+    cy.get('input[name="username"]').clear().type(shortrefresh.u)
+    cy.get('input[name="userattr1"]').clear().type('role')         // a role is required to be able to login
+    cy.get('input[name="userattrval1"]').clear().type('readonly')
+    cy.get('input[name="token_secs"]').clear().type(token_secs)    // control the lifetime of the issued access token
+    cy.contains('Sign In').click()
+
+    cy.contains('Sign In').should('not.exist')
+    cy.url().should('include', Cypress.config('baseUrl'))
+
+    // wait until the token has expired, the next request with it should then
+    // cause Krill to refresh it and to return the new token to the client
+    cy.wait(1000 * (token_secs + 1))
+
+    cy.window().then((win) => {
+      let old_token = JSON.parse(win.localStorage.getItem('krill_user')).authdata
+      cy.request({
+        url: '/api/v1/authorized',
+        headers: { Authorization: 'Bearer ' + old_token },
+      }).then((response) => {
+        expect(response.status).to.eq(200)
+        expect(response.headers['authorization']).to.match(/^Bearer .+/)
+        expect(response.headers['authorization']).not.to.eq('Bearer ' + old_token)
+        expect(response.headers['cache-control']).to.eq('no-store')
+      })
+    })
+  });
+
   [-2, +2].forEach((timeout_adjust_secs) =>
     it('Slow provider response (' + (timeout_adjust_secs < 0 ? 'within' : 'beyond') + ' Krill HTTP client timeout) is handled correctly', () => {
       let name_prefix = 'slow-response-';